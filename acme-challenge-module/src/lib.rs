@@ -0,0 +1,338 @@
+// Copyright 2024 Wladimir Palant
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! # ACME Challenge Module for Pandora Web Server
+//!
+//! This crate answers [ACME HTTP-01 challenges](https://www.rfc-editor.org/rfc/rfc8555#section-8.3)
+//! from memory, allowing an ACME client running within the same process to obtain certificates
+//! without writing challenge files to the file system. It is enabled via the `acme_challenges`
+//! setting:
+//!
+//! ```yaml
+//! acme_challenges: true
+//! ```
+//!
+//! Once enabled, the handler responds to all requests for paths under
+//! `/.well-known/acme-challenge/`. If the token in the path has been registered, the response
+//! contains the corresponding key authorization. Requests for unknown tokens receive a
+//! `404 Not Found` response.
+//!
+//! ## Registering tokens
+//!
+//! Tokens are kept in an [`AcmeTokens`] store. Handlers created from configuration use the
+//! process-wide store returned by [`AcmeTokens::global`], so the ACME client can register tokens
+//! there without access to the handler:
+//!
+//! ```rust
+//! use acme_challenge_module::AcmeTokens;
+//!
+//! let tokens = AcmeTokens::global();
+//! tokens.add_token("LoqXcYV8q5ONbJQxbmR7SCTNo3tiAXDfowyjxAjEuX0", "LoqXcYV8q5ONbJQxbmR7SCTNo3tiAXDfowyjxAjEuX0.9jg46WB3rR_AHD-EBXdN7cBkH1WOu0tA3M9fm21mqTI");
+//!
+//! // Once the challenge has been validated
+//! tokens.remove_token("LoqXcYV8q5ONbJQxbmR7SCTNo3tiAXDfowyjxAjEuX0");
+//! ```
+//!
+//! The same methods are available on the handler as well.
+//!
+//! ## Using the module
+//!
+//! This module’s handler should be called prior to handlers that might otherwise handle the
+//! challenge paths, e.g. Static Files module or authentication:
+//!
+//! ```rust
+//! use acme_challenge_module::AcmeChallengeHandler;
+//! use pandora_module_utils::{merge_conf, FromYaml, RequestFilter};
+//! use startup_module::{DefaultApp, StartupConf};
+//! use static_files_module::StaticFilesHandler;
+//!
+//! #[derive(Debug, RequestFilter)]
+//! struct Handler {
+//!     acme_challenge: AcmeChallengeHandler,
+//!     static_files: StaticFilesHandler,
+//! }
+//!
+//! #[merge_conf]
+//! struct Conf {
+//!     startup: StartupConf,
+//!     handler: <Handler as RequestFilter>::Conf,
+//! }
+//!
+//! let conf = Conf::from_yaml("acme_challenges: true").unwrap();
+//!
+//! let app = DefaultApp::<Handler>::from_conf(conf.handler)
+//!     .unwrap()
+//!     .with_startup_conf(&conf.startup);
+//! let server = conf.startup.into_server(app, None).unwrap();
+//!
+//! // Do something with the server here, e.g. call server.run_forever()
+//! ```
+
+use async_trait::async_trait;
+use http::{header, Method, StatusCode};
+use pandora_module_utils::pingora::{Error, ResponseHeader, SessionWrapper};
+use pandora_module_utils::standard_response::error_response;
+use pandora_module_utils::{DeserializeMap, RequestFilter, RequestFilterResult};
+use std::collections::HashMap;
+use std::sync::{Arc, OnceLock, RwLock};
+
+/// Path prefix of ACME HTTP-01 challenge requests
+const CHALLENGE_PREFIX: &str = "/.well-known/acme-challenge/";
+
+/// ACME challenge configuration
+#[derive(Debug, Default, Clone, PartialEq, Eq, DeserializeMap)]
+pub struct AcmeChallengeConf {
+    /// If `true`, requests for paths under `/.well-known/acme-challenge/` are answered from the
+    /// registered tokens
+    pub acme_challenges: bool,
+}
+
+/// A thread-safe store mapping ACME challenge tokens to their key authorizations
+///
+/// Clones of the store share the state, changing it via one copy affects all of them.
+#[derive(Debug, Clone, Default)]
+pub struct AcmeTokens(Arc<RwLock<HashMap<String, String>>>);
+
+impl AcmeTokens {
+    /// Returns the process-wide store, this is the one used by handlers created from
+    /// configuration.
+    pub fn global() -> Self {
+        static GLOBAL: OnceLock<AcmeTokens> = OnceLock::new();
+        GLOBAL.get_or_init(Default::default).clone()
+    }
+
+    /// Registers a challenge token along with the key authorization to be sent for it.
+    pub fn add_token(&self, token: impl Into<String>, key_authorization: impl Into<String>) {
+        if let Ok(mut tokens) = self.0.write() {
+            tokens.insert(token.into(), key_authorization.into());
+        }
+    }
+
+    /// Removes a challenge token, requests for it will receive `404 Not Found` afterwards.
+    pub fn remove_token(&self, token: &str) {
+        if let Ok(mut tokens) = self.0.write() {
+            tokens.remove(token);
+        }
+    }
+
+    /// Returns the key authorization for a challenge token if it has been registered.
+    pub fn key_authorization(&self, token: &str) -> Option<String> {
+        self.0.read().ok()?.get(token).cloned()
+    }
+}
+
+impl PartialEq for AcmeTokens {
+    fn eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.0, &other.0)
+    }
+}
+
+impl Eq for AcmeTokens {}
+
+/// Handler for Pingora’s `request_filter` phase
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AcmeChallengeHandler {
+    conf: AcmeChallengeConf,
+    tokens: AcmeTokens,
+}
+
+impl AcmeChallengeHandler {
+    /// Makes the handler use a different token store than [`AcmeTokens::global`].
+    pub fn with_tokens(mut self, tokens: AcmeTokens) -> Self {
+        self.tokens = tokens;
+        self
+    }
+
+    /// Registers a challenge token along with the key authorization to be sent for it.
+    pub fn add_token(&self, token: impl Into<String>, key_authorization: impl Into<String>) {
+        self.tokens.add_token(token, key_authorization);
+    }
+
+    /// Removes a challenge token, requests for it will receive `404 Not Found` afterwards.
+    pub fn remove_token(&self, token: &str) {
+        self.tokens.remove_token(token);
+    }
+}
+
+impl TryFrom<AcmeChallengeConf> for AcmeChallengeHandler {
+    type Error = Box<Error>;
+
+    fn try_from(conf: AcmeChallengeConf) -> Result<Self, Self::Error> {
+        Ok(Self {
+            conf,
+            tokens: AcmeTokens::global(),
+        })
+    }
+}
+
+#[async_trait]
+impl RequestFilter for AcmeChallengeHandler {
+    type Conf = AcmeChallengeConf;
+
+    type CTX = ();
+
+    fn new_ctx() -> Self::CTX {}
+
+    async fn request_filter(
+        &self,
+        session: &mut impl SessionWrapper,
+        _ctx: &mut Self::CTX,
+    ) -> Result<RequestFilterResult, Box<Error>> {
+        if !self.conf.acme_challenges {
+            return Ok(RequestFilterResult::Unhandled);
+        }
+
+        let token = if let Some(token) = session.uri().path().strip_prefix(CHALLENGE_PREFIX) {
+            token
+        } else {
+            return Ok(RequestFilterResult::Unhandled);
+        };
+
+        let key_authorization =
+            if let Some(key_authorization) = self.tokens.key_authorization(token) {
+                key_authorization
+            } else {
+                error_response(session, StatusCode::NOT_FOUND).await?;
+                return Ok(RequestFilterResult::ResponseSent);
+            };
+
+        let method = &session.req_header().method;
+        if method != Method::GET && method != Method::HEAD {
+            error_response(session, StatusCode::METHOD_NOT_ALLOWED).await?;
+            return Ok(RequestFilterResult::ResponseSent);
+        }
+
+        let mut header = ResponseHeader::build(StatusCode::OK, Some(3))?;
+        header.append_header(header::CONTENT_LENGTH, key_authorization.len().to_string())?;
+        header.append_header(header::CONTENT_TYPE, "application/octet-stream")?;
+        header.append_header(header::CACHE_CONTROL, "no-store")?;
+        session.write_response_header(Box::new(header)).await?;
+
+        if session.req_header().method != Method::HEAD {
+            session
+                .write_response_body(key_authorization.into())
+                .await?;
+        }
+
+        Ok(RequestFilterResult::ResponseSent)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use pandora_module_utils::pingora::{RequestHeader, TestSession};
+    use pandora_module_utils::FromYaml;
+    use test_log::test;
+
+    fn make_handler(conf: &str) -> AcmeChallengeHandler {
+        AcmeChallengeHandler::try_from(AcmeChallengeConf::from_yaml(conf).unwrap())
+            .unwrap()
+            .with_tokens(AcmeTokens::default())
+    }
+
+    async fn make_session(method: &str, path: &str) -> TestSession {
+        let header = RequestHeader::build(method, path.as_bytes(), None).unwrap();
+        TestSession::from(header).await
+    }
+
+    async fn status(
+        handler: &AcmeChallengeHandler,
+        session: &mut TestSession,
+    ) -> Result<Option<u16>, Box<Error>> {
+        match handler.request_filter(session, &mut ()).await? {
+            RequestFilterResult::ResponseSent => {
+                Ok(Some(session.response_written().unwrap().status.as_u16()))
+            }
+            _ => Ok(None),
+        }
+    }
+
+    #[test(tokio::test)]
+    async fn unconfigured() -> Result<(), Box<Error>> {
+        let handler = make_handler("{}");
+        handler.add_token("token", "token.key");
+
+        let mut session = make_session("GET", "/.well-known/acme-challenge/token").await;
+        assert_eq!(status(&handler, &mut session).await?, None);
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn registered_token() -> Result<(), Box<Error>> {
+        let handler = make_handler("acme_challenges: true");
+        handler.add_token("token", "token.key");
+
+        let mut session = make_session("GET", "/.well-known/acme-challenge/token").await;
+        assert_eq!(status(&handler, &mut session).await?, Some(200));
+        let response = session.response_written().unwrap();
+        assert_eq!(response.headers.get("Content-Length").unwrap(), "9");
+        assert_eq!(
+            response.headers.get("Content-Type").unwrap(),
+            "application/octet-stream"
+        );
+        assert_eq!(session.response_body, "token.key");
+
+        let mut session = make_session("HEAD", "/.well-known/acme-challenge/token").await;
+        assert_eq!(status(&handler, &mut session).await?, Some(200));
+        assert!(session.response_body.is_empty());
+
+        let mut session = make_session("POST", "/.well-known/acme-challenge/token").await;
+        assert_eq!(status(&handler, &mut session).await?, Some(405));
+
+        // Removed token is no longer served
+        handler.remove_token("token");
+        let mut session = make_session("GET", "/.well-known/acme-challenge/token").await;
+        assert_eq!(status(&handler, &mut session).await?, Some(404));
+
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn unknown_token() -> Result<(), Box<Error>> {
+        let handler = make_handler("acme_challenges: true");
+        handler.add_token("token", "token.key");
+
+        for path in [
+            "/.well-known/acme-challenge/other",
+            "/.well-known/acme-challenge/token/",
+            "/.well-known/acme-challenge/",
+        ] {
+            let mut session = make_session("GET", path).await;
+            assert_eq!(status(&handler, &mut session).await?, Some(404), "{path}");
+        }
+
+        // Other paths are left alone
+        for path in ["/", "/token", "/.well-known/acme-challenge"] {
+            let mut session = make_session("GET", path).await;
+            assert_eq!(status(&handler, &mut session).await?, None, "{path}");
+        }
+
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn shared_store() -> Result<(), Box<Error>> {
+        let tokens = AcmeTokens::default();
+        let handler = make_handler("acme_challenges: true").with_tokens(tokens.clone());
+
+        tokens.add_token("token", "token.key");
+        let mut session = make_session("GET", "/.well-known/acme-challenge/token").await;
+        assert_eq!(status(&handler, &mut session).await?, Some(200));
+        assert_eq!(session.response_body, "token.key");
+
+        Ok(())
+    }
+}
@@ -21,7 +21,7 @@ use pandora_module_utils::standard_response::error_response;
 use pandora_module_utils::RequestFilterResult;
 
 use crate::{
-    common::{is_rate_limited, validate_login},
+    common::{is_rate_limited, validate_login, MAX_DRAIN_SIZE},
     AuthConf,
 };
 
@@ -30,6 +30,8 @@ async fn unauthorized_response(
     realm: &str,
     suggestion: Option<String>,
 ) -> Result<(), Box<Error>> {
+    session.drain_request_body(MAX_DRAIN_SIZE).await;
+
     let text = html! {
         (DOCTYPE)
         html {
@@ -120,6 +122,7 @@ pub(crate) async fn basic_auth(
     };
 
     if is_rate_limited(session, &conf.auth_rate_limits, &user) {
+        session.drain_request_body(MAX_DRAIN_SIZE).await;
         error_response(session, StatusCode::TOO_MANY_REQUESTS).await?;
         return Ok(RequestFilterResult::ResponseSent);
     }
@@ -238,6 +241,33 @@ auth_rate_limits:
         Ok(())
     }
 
+    #[test(tokio::test)]
+    async fn request_body_drained() -> Result<(), Box<Error>> {
+        let handler = make_handler(default_conf());
+
+        let request = RequestHeader::build("POST", b"/", None)?;
+        let mut session = TestSession::with_body(request, "some data").await;
+        assert_eq!(
+            handler.request_filter(&mut session, &mut ()).await?,
+            RequestFilterResult::ResponseSent
+        );
+        check_unauthorized_response(&session);
+        assert!(session.is_body_done());
+        assert!(session.will_keepalive());
+
+        // Oversized request bodies aren’t read completely, the connection is closed instead
+        let request = RequestHeader::build("POST", b"/", None)?;
+        let body = "0123456789".repeat(MAX_DRAIN_SIZE);
+        let mut session = TestSession::with_body(request, body).await;
+        assert_eq!(
+            handler.request_filter(&mut session, &mut ()).await?,
+            RequestFilterResult::ResponseSent
+        );
+        check_unauthorized_response(&session);
+        assert!(!session.will_keepalive());
+        Ok(())
+    }
+
     #[test(tokio::test)]
     async fn unknown_auth_scheme() -> Result<(), Box<Error>> {
         let handler = make_handler(default_conf());
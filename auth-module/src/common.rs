@@ -21,6 +21,10 @@ use std::{net::Ipv4Addr, sync::Mutex, time::Duration};
 
 use crate::{AuthConf, AuthRateLimits};
 
+/// Maximal size of the request body to be discarded when rejecting a request, larger request
+/// bodies result in the connection being closed
+pub(crate) const MAX_DRAIN_SIZE: usize = 64 * 1024;
+
 pub(crate) fn is_rate_limited(
     session: &impl SessionWrapper,
     limits: &AuthRateLimits,
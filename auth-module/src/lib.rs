@@ -77,6 +77,8 @@
 //!   * `cookie_name`: The cookie used to store the token issued upon successful login.
 //!   * `secure_cookie`: If set, determines explicitly whether the `Secure` attribute should be
 //!     used for the login cookie. Default behavior is to set this attribute for HTTPS sessions.
+//!     The cookie’s `SameSite` attribute is `Lax` unless a different cookie policy has been set
+//!     for the session, see `SessionWrapper::set_cookie_policy`.
 //!   * `session_expiration`: The time interval after which a login session will expire, requiring
 //!     the user to log in again. This interval can be specified in hours (e.g. `2h`) or days (e.g.
 //!     `7d`).
@@ -144,7 +146,9 @@
 //! conf.handler.auth.merge_with_opt(opt.auth);
 //! conf.handler.static_files.merge_with_opt(opt.static_files);
 //!
-//! let app = DefaultApp::<Handler>::from_conf(conf.handler).unwrap();
+//! let app = DefaultApp::<Handler>::from_conf(conf.handler)
+//!     .unwrap()
+//!     .with_startup_conf(&conf.startup);
 //! let server = conf.startup.into_server(app, Some(opt.startup)).unwrap();
 //!
 //! // Do something with the server here, e.g. call server.run_forever()
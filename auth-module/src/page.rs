@@ -25,7 +25,7 @@ use serde::{Deserialize, Serialize};
 use sha2::Sha256;
 use std::time::{Duration, SystemTime};
 
-use crate::common::{is_rate_limited, validate_login};
+use crate::common::{is_rate_limited, validate_login, MAX_DRAIN_SIZE};
 use crate::AuthConf;
 
 #[derive(Debug, Deserialize)]
@@ -47,6 +47,8 @@ async fn login_response(
     login_failure: bool,
     suggestion: Option<String>,
 ) -> Result<RequestFilterResult, Box<Error>> {
+    session.drain_request_body(MAX_DRAIN_SIZE).await;
+
     if let Some(login_page) = &conf.auth_page_session.login_page {
         session.set_uri(login_page.clone());
         if session.req_header().method != Method::HEAD {
@@ -130,6 +132,8 @@ async fn login_response_json(
     suggestion: Option<String>,
     cookie: Option<String>,
 ) -> Result<RequestFilterResult, Box<Error>> {
+    session.drain_request_body(MAX_DRAIN_SIZE).await;
+
     let mut text = String::from("{");
     if cookie.is_some() {
         text.push_str("\"success\":true");
@@ -239,6 +243,7 @@ pub(crate) async fn page_auth(
     }
 
     const MAX_BODY_SIZE: usize = 4096;
+    session.send_continue().await?;
     let mut data = BytesMut::with_capacity(MAX_BODY_SIZE);
     loop {
         match session.read_request_body().await {
@@ -267,6 +272,7 @@ pub(crate) async fn page_auth(
     };
 
     if is_rate_limited(session, &conf.auth_rate_limits, &request.username) {
+        session.drain_request_body(MAX_DRAIN_SIZE).await;
         error_response(session, StatusCode::TOO_MANY_REQUESTS).await?;
         return Ok(RequestFilterResult::ResponseSent);
     }
@@ -290,18 +296,18 @@ pub(crate) async fn page_auth(
         .sign_with_key(&key)
         .map_err(|err| Error::because(ErrorType::InternalError, "failed signing JTW token", err))?;
 
-    let secure = conf.auth_page_session.secure_cookie.unwrap_or_else(|| {
-        session
-            .digest()
-            .and_then(|digest| digest.ssl_digest.as_ref())
-            .is_some()
-    });
-
-    let cookie = format!(
-        "{}={token}; Max-Age={}; HttpOnly{}",
-        conf.auth_page_session.cookie_name,
-        conf.auth_page_session.session_expiration.as_secs(),
-        if secure { "; Secure" } else { "" }
+    let mut policy = session.cookie_policy();
+    if let Some(secure) = conf.auth_page_session.secure_cookie {
+        policy.secure = Some(secure);
+    }
+    let cookie = policy.format(
+        session.tls_version().is_some(),
+        &conf.auth_page_session.cookie_name,
+        &token,
+        &format!(
+            "Max-Age={}; HttpOnly",
+            conf.auth_page_session.session_expiration.as_secs()
+        ),
     );
 
     if request.r#type.is_some_and(|t| t == "json") {
@@ -699,6 +705,7 @@ auth_page_session:
         let mut exp = None;
         let mut http_only = false;
         let mut secure = false;
+        let mut same_site = None;
         for param in cookie.split(';') {
             let param = param.trim();
             if param.to_ascii_lowercase() == "httponly" {
@@ -710,6 +717,7 @@ auth_page_session:
                 match param.to_ascii_lowercase().as_str() {
                     "auth_cookie" => token = Some(value.to_owned()),
                     "max-age" => exp = Some(value.parse::<u64>().unwrap()),
+                    "samesite" => same_site = Some(value.to_owned()),
                     other => panic!("unexpected cookie parameter {other}"),
                 }
             }
@@ -717,6 +725,7 @@ auth_page_session:
         assert_eq!(exp, Some(200000 * 24 * 60 * 60));
         assert!(http_only);
         assert!(!secure);
+        assert_eq!(same_site.as_deref(), Some("Lax"));
 
         if let Some(token) = token {
             // Test whether this cookie is valid
@@ -762,6 +771,41 @@ auth_page_session:
         Ok(())
     }
 
+    #[test(tokio::test)]
+    async fn secure_cookie() -> Result<(), Box<Error>> {
+        async fn login_cookie(handler: &AuthHandler, https: bool) -> Result<String, Box<Error>> {
+            let mut session = make_session_with_body("/", "username=me&password=test").await;
+            if https {
+                session = session.with_tls(None, "TLSv1.3");
+            }
+            session
+                .req_header_mut()
+                .insert_header("Content-Type", "application/x-www-form-urlencoded")?;
+            session.req_header_mut().set_method(Method::POST);
+            handler.request_filter(&mut session, &mut ()).await?;
+            Ok(session
+                .response_written()
+                .unwrap()
+                .headers
+                .get("Set-Cookie")
+                .unwrap()
+                .to_str()
+                .unwrap()
+                .to_owned())
+        }
+
+        let handler = make_handler(default_conf());
+        assert!(login_cookie(&handler, true).await?.ends_with("; Secure"));
+        assert!(!login_cookie(&handler, false).await?.contains("Secure"));
+
+        let mut conf = default_conf().to_owned();
+        conf.push_str("\n    secure_cookie: false");
+        let handler = make_handler(&conf);
+        assert!(!login_cookie(&handler, true).await?.contains("Secure"));
+
+        Ok(())
+    }
+
     #[test(tokio::test)]
     async fn display_hash() -> Result<(), Box<Error>> {
         let mut conf = default_conf().to_owned();
@@ -0,0 +1,409 @@
+// Copyright 2024 Wladimir Palant
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! In-memory storage of cached responses
+
+use bytes::{Bytes, BytesMut};
+use http::{header, HeaderName, HeaderValue, Method, StatusCode};
+use log::error;
+use pandora_module_utils::pingora::{RequestHeader, ResponseHeader, SessionWrapper};
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::watch;
+
+/// Identifies a cached resource
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub(crate) struct CacheKey {
+    method: Method,
+    host: String,
+    uri: String,
+}
+
+impl CacheKey {
+    /// Produces the cache key for the current request.
+    pub(crate) fn new(session: &impl SessionWrapper) -> Self {
+        Self {
+            method: session.req_header().method.clone(),
+            host: session.host().unwrap_or_default().into_owned(),
+            uri: session.uri().to_string(),
+        }
+    }
+}
+
+/// Request header values a response depends on, as listed in its `Vary` header
+type VaryValues = Vec<(HeaderName, Option<HeaderValue>)>;
+
+/// Determines the request header values a response depends on. Returns `None` if the response
+/// varies on `*` or an invalid header name.
+fn vary_values(request: &RequestHeader, response: &ResponseHeader) -> Option<VaryValues> {
+    let mut result = Vec::new();
+    for value in response.headers.get_all(header::VARY) {
+        for name in value.to_str().ok()?.split(',') {
+            let name = name.trim();
+            if name.is_empty() {
+                continue;
+            }
+            let name = HeaderName::try_from(name).ok()?;
+            result.push((name.clone(), request.headers.get(&name).cloned()));
+        }
+    }
+    Some(result)
+}
+
+/// Checks whether the request header values match the ones the response was cached for.
+fn vary_matches(vary: &VaryValues, request: &RequestHeader) -> bool {
+    vary.iter()
+        .all(|(name, value)| request.headers.get(name) == value.as_ref())
+}
+
+/// A response that is being received and will be cached once complete
+#[derive(Debug)]
+pub(crate) struct PendingEntry {
+    vary: VaryValues,
+    header: ResponseHeader,
+    body: BytesMut,
+}
+
+impl PendingEntry {
+    /// Creates a new pending cache entry if the response can be cached.
+    pub(crate) fn new(
+        request: &RequestHeader,
+        response: &ResponseHeader,
+        max_size: usize,
+    ) -> Option<Self> {
+        if response.status != StatusCode::OK {
+            return None;
+        }
+
+        let no_store = response
+            .headers
+            .get_all(header::CACHE_CONTROL)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .map(|directive| directive.split('=').next().unwrap_or_default().trim())
+            .any(|directive| {
+                directive.eq_ignore_ascii_case("no-store")
+                    || directive.eq_ignore_ascii_case("private")
+            });
+        if no_store {
+            return None;
+        }
+
+        // Cookies are specific to one client, these must never be served to others
+        if response.headers.contains_key(header::SET_COOKIE) {
+            return None;
+        }
+
+        let too_large = response
+            .headers
+            .get(header::CONTENT_LENGTH)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.parse::<usize>().ok())
+            .is_some_and(|size| size > max_size);
+        if too_large {
+            return None;
+        }
+
+        Some(Self {
+            vary: vary_values(request, response)?,
+            header: response.clone(),
+            body: BytesMut::new(),
+        })
+    }
+
+    /// Adds a chunk of the response body. Returns `false` if the entry exceeds the maximal size
+    /// and should be discarded.
+    pub(crate) fn push(&mut self, data: &[u8], max_size: usize) -> bool {
+        if self.body.len() + data.len() > max_size {
+            false
+        } else {
+            self.body.extend_from_slice(data);
+            true
+        }
+    }
+
+    /// Converts into a cache entry, adding an `ETag` header if the response has none.
+    pub(crate) fn finish(mut self, ttl: Duration) -> CacheEntry {
+        if !self.header.headers.contains_key(header::ETAG) {
+            let mut hasher = DefaultHasher::new();
+            self.body.hash(&mut hasher);
+            let _ = self
+                .header
+                .insert_header(header::ETAG, format!("\"{:016x}\"", hasher.finish()));
+        }
+
+        CacheEntry {
+            vary: self.vary,
+            header: self.header,
+            body: self.body.freeze(),
+            expires: Instant::now() + ttl,
+        }
+    }
+}
+
+/// A cached response
+#[derive(Debug, Clone)]
+pub(crate) struct CacheEntry {
+    vary: VaryValues,
+    pub(crate) header: ResponseHeader,
+    pub(crate) body: Bytes,
+    pub(crate) expires: Instant,
+}
+
+/// Marks a request as being sent to the upstream server, identical requests wait until this is
+/// dropped.
+#[derive(Debug)]
+pub(crate) struct FetchGuard {
+    cache: Arc<Cache>,
+    key: CacheKey,
+    _done: watch::Sender<()>,
+}
+
+impl Drop for FetchGuard {
+    fn drop(&mut self) {
+        match self.cache.in_flight.lock() {
+            Ok(mut in_flight) => {
+                in_flight.remove(&self.key);
+            }
+            Err(err) => error!("Failed acquiring in-flight requests mutex: {err}"),
+        }
+    }
+}
+
+/// Result of registering a request about to be sent to the upstream server
+#[derive(Debug)]
+pub(crate) enum Fetch {
+    /// No identical request is in flight, the guard needs to be kept until the response is cached
+    Started(Box<FetchGuard>),
+    /// An identical request is in flight, the receiver is notified once it completes
+    InProgress(watch::Receiver<()>),
+}
+
+/// Cached responses by key, a key can have multiple responses varying by request headers
+#[derive(Debug, Default)]
+pub(crate) struct Cache {
+    pub(crate) entries: Mutex<HashMap<CacheKey, Vec<CacheEntry>>>,
+    in_flight: Mutex<HashMap<CacheKey, watch::Receiver<()>>>,
+}
+
+impl Cache {
+    /// Registers a request about to be sent to the upstream server. Returns `None` if the
+    /// request should go upstream without coalescing.
+    pub(crate) fn start_fetch(self: &Arc<Self>, key: &CacheKey) -> Option<Fetch> {
+        let mut in_flight = match self.in_flight.lock() {
+            Ok(in_flight) => in_flight,
+            Err(err) => {
+                error!("Failed acquiring in-flight requests mutex, not coalescing: {err}");
+                return None;
+            }
+        };
+
+        if let Some(receiver) = in_flight.get(key) {
+            return Some(Fetch::InProgress(receiver.clone()));
+        }
+
+        let (sender, receiver) = watch::channel(());
+        in_flight.insert(key.clone(), receiver);
+        Some(Fetch::Started(Box::new(FetchGuard {
+            cache: self.clone(),
+            key: key.clone(),
+            _done: sender,
+        })))
+    }
+
+    /// Looks up a cached response that didn’t expire yet and matches the request headers.
+    pub(crate) fn lookup(&self, key: &CacheKey, request: &RequestHeader) -> Option<CacheEntry> {
+        let entries = match self.entries.lock() {
+            Ok(entries) => entries,
+            Err(err) => {
+                error!("Failed acquiring cache mutex, bypassing cache: {err}");
+                return None;
+            }
+        };
+
+        let now = Instant::now();
+        entries
+            .get(key)?
+            .iter()
+            .find(|entry| entry.expires > now && vary_matches(&entry.vary, request))
+            .cloned()
+    }
+
+    /// Adds a response to the cache, replacing any previous response for the same request
+    /// headers. If the cache is full, expired responses are removed first and then the ones
+    /// closest to expiring.
+    pub(crate) fn insert(&self, key: CacheKey, entry: CacheEntry, max_entries: usize) {
+        if max_entries == 0 {
+            return;
+        }
+
+        let mut entries = match self.entries.lock() {
+            Ok(entries) => entries,
+            Err(err) => {
+                error!("Failed acquiring cache mutex, not caching response: {err}");
+                return;
+            }
+        };
+
+        if let Some(list) = entries.get_mut(&key) {
+            list.retain(|existing| existing.vary != entry.vary);
+        }
+
+        let mut count = entries.values().map(Vec::len).sum::<usize>();
+        if count >= max_entries {
+            let now = Instant::now();
+            for list in entries.values_mut() {
+                list.retain(|existing| existing.expires > now);
+            }
+            entries.retain(|_, list| !list.is_empty());
+            count = entries.values().map(Vec::len).sum();
+        }
+
+        while count >= max_entries {
+            let oldest = entries
+                .iter()
+                .flat_map(|(key, list)| {
+                    list.iter()
+                        .enumerate()
+                        .map(move |(index, existing)| (existing.expires, key, index))
+                })
+                .min_by_key(|(expires, _, _)| *expires)
+                .map(|(_, key, index)| (key.clone(), index));
+            if let Some((key, index)) = oldest {
+                if let Some(list) = entries.get_mut(&key) {
+                    list.remove(index);
+                    if list.is_empty() {
+                        entries.remove(&key);
+                    }
+                }
+                count -= 1;
+            } else {
+                break;
+            }
+        }
+
+        entries.entry(key).or_default().push(entry);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use test_log::test;
+
+    fn make_key(uri: &str) -> CacheKey {
+        CacheKey {
+            method: Method::GET,
+            host: "localhost".to_owned(),
+            uri: uri.to_owned(),
+        }
+    }
+
+    fn make_entry(request: &RequestHeader, vary: Option<&str>, ttl: u64) -> CacheEntry {
+        let mut response = ResponseHeader::build(200, None).unwrap();
+        if let Some(vary) = vary {
+            response.insert_header("Vary", vary).unwrap();
+        }
+        let mut entry = PendingEntry::new(request, &response, 100).unwrap();
+        assert!(entry.push(b"Hi!", 100));
+        entry.finish(Duration::from_secs(ttl))
+    }
+
+    #[test]
+    fn not_cacheable() {
+        let request = RequestHeader::build("GET", b"/", None).unwrap();
+
+        let response = ResponseHeader::build(404, None).unwrap();
+        assert!(PendingEntry::new(&request, &response, 100).is_none());
+
+        for value in ["no-store", "max-age=10, private", "private=\"Set-Cookie\""] {
+            let mut response = ResponseHeader::build(200, None).unwrap();
+            response.insert_header("Cache-Control", value).unwrap();
+            assert!(PendingEntry::new(&request, &response, 100).is_none());
+        }
+
+        let mut response = ResponseHeader::build(200, None).unwrap();
+        response.insert_header("Set-Cookie", "session=abc").unwrap();
+        assert!(PendingEntry::new(&request, &response, 100).is_none());
+
+        let mut response = ResponseHeader::build(200, None).unwrap();
+        response.insert_header("Vary", "*").unwrap();
+        assert!(PendingEntry::new(&request, &response, 100).is_none());
+
+        let mut response = ResponseHeader::build(200, None).unwrap();
+        response.insert_header("Content-Length", "101").unwrap();
+        assert!(PendingEntry::new(&request, &response, 100).is_none());
+
+        let response = ResponseHeader::build(200, None).unwrap();
+        let mut entry = PendingEntry::new(&request, &response, 100).unwrap();
+        assert!(entry.push(&[0; 60], 100));
+        assert!(!entry.push(&[0; 60], 100));
+    }
+
+    #[test]
+    fn etag() {
+        let request = RequestHeader::build("GET", b"/", None).unwrap();
+        let entry = make_entry(&request, None, 10);
+        assert!(entry.header.headers.get("ETag").is_some());
+
+        let mut response = ResponseHeader::build(200, None).unwrap();
+        response.insert_header("ETag", "\"abc\"").unwrap();
+        let entry = PendingEntry::new(&request, &response, 100)
+            .unwrap()
+            .finish(Duration::from_secs(10));
+        assert_eq!(entry.header.headers.get("ETag").unwrap(), "\"abc\"");
+    }
+
+    #[test]
+    fn vary() {
+        let cache = Cache::default();
+        let mut request = RequestHeader::build("GET", b"/", None).unwrap();
+        request.insert_header("Accept-Language", "de").unwrap();
+        cache.insert(
+            make_key("/"),
+            make_entry(&request, Some("Accept-Language"), 10),
+            10,
+        );
+        assert!(cache.lookup(&make_key("/"), &request).is_some());
+        assert!(cache.lookup(&make_key("/other"), &request).is_none());
+
+        request.insert_header("Accept-Language", "en").unwrap();
+        assert!(cache.lookup(&make_key("/"), &request).is_none());
+
+        request.remove_header("Accept-Language");
+        assert!(cache.lookup(&make_key("/"), &request).is_none());
+    }
+
+    #[test]
+    fn max_entries() {
+        let cache = Cache::default();
+        let request = RequestHeader::build("GET", b"/", None).unwrap();
+        for (uri, ttl) in [("/1", 10), ("/2", 20), ("/3", 30)] {
+            cache.insert(make_key(uri), make_entry(&request, None, ttl), 2);
+        }
+        assert!(cache.lookup(&make_key("/1"), &request).is_none());
+        assert!(cache.lookup(&make_key("/2"), &request).is_some());
+        assert!(cache.lookup(&make_key("/3"), &request).is_some());
+
+        // Replacing an existing entry doesn’t evict anything
+        cache.insert(make_key("/3"), make_entry(&request, None, 5), 2);
+        assert!(cache.lookup(&make_key("/2"), &request).is_some());
+        assert!(cache.lookup(&make_key("/3"), &request).is_some());
+    }
+}
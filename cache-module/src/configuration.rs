@@ -0,0 +1,71 @@
+// Copyright 2024 Wladimir Palant
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Structures required to deserialize Cache Module configuration from YAML configuration files.
+
+use clap::Parser;
+use pandora_module_utils::DeserializeMap;
+
+/// Command line options of the cache module
+#[derive(Debug, Default, Parser)]
+pub struct CacheOpt {
+    /// Number of seconds upstream responses should be cached for (omit to disable caching)
+    #[clap(long)]
+    pub cache_ttl: Option<u64>,
+}
+
+/// Configuration settings of the cache module
+#[derive(Debug, Clone, PartialEq, Eq, DeserializeMap)]
+pub struct CacheConf {
+    /// Number of seconds upstream responses should be cached for. The default value `0` disables
+    /// caching.
+    pub cache_ttl: u64,
+
+    /// Maximal number of responses to be kept in the cache. If the cache is full, responses
+    /// closest to expiring are removed first.
+    pub cache_max_entries: usize,
+
+    /// Maximal size of a response body in bytes, larger responses won’t be cached.
+    pub cache_max_entry_size: usize,
+
+    /// If `true`, identical requests arriving while a response is being fetched from upstream
+    /// wait for that response instead of being sent to the upstream server as well.
+    pub cache_coalesce: bool,
+
+    /// If `true`, requests with a `Cookie` header are served from the cache as well. By default,
+    /// such requests always go to the upstream server as the response might be personalized.
+    pub cache_with_cookies: bool,
+}
+
+impl Default for CacheConf {
+    fn default() -> Self {
+        Self {
+            cache_ttl: 0,
+            cache_max_entries: 1000,
+            cache_max_entry_size: 1024 * 1024,
+            cache_coalesce: false,
+            cache_with_cookies: false,
+        }
+    }
+}
+
+impl CacheConf {
+    /// Merges the command line options into the current configuration. Any command line options
+    /// present overwrite existing settings.
+    pub fn merge_with_opt(&mut self, opt: CacheOpt) {
+        if let Some(cache_ttl) = opt.cache_ttl {
+            self.cache_ttl = cache_ttl;
+        }
+    }
+}
@@ -0,0 +1,599 @@
+// Copyright 2024 Wladimir Palant
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use async_trait::async_trait;
+use bytes::Bytes;
+use http::{header, Method};
+use log::trace;
+use pandora_module_utils::pingora::{Error, ResponseHeader, SessionWrapper};
+use pandora_module_utils::{RequestFilter, RequestFilterResult};
+use pingora::protocols::http::conditional_filter::{not_modified_filter, to_304};
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::cache::{Cache, CacheEntry, CacheKey, Fetch, FetchGuard, PendingEntry};
+use crate::configuration::CacheConf;
+
+/// Context data of the handler
+#[derive(Debug)]
+pub struct CacheCtx {
+    key: Option<CacheKey>,
+    pending: Option<PendingEntry>,
+    fetch: Option<FetchGuard>,
+}
+
+/// Handler for Pingora’s `request_filter` phase, caches upstream responses
+#[derive(Debug, Clone)]
+pub struct CacheHandler {
+    conf: CacheConf,
+    cache: Arc<Cache>,
+}
+
+impl PartialEq for CacheHandler {
+    fn eq(&self, other: &Self) -> bool {
+        self.conf == other.conf
+    }
+}
+
+impl Eq for CacheHandler {}
+
+/// Sends a cached response, or `304 Not Modified` if the request’s `If-None-Match` header matches.
+async fn serve_entry(
+    session: &mut impl SessionWrapper,
+    entry: CacheEntry,
+) -> Result<RequestFilterResult, Box<Error>> {
+    let mut header = entry.header;
+    if not_modified_filter(session.req_header(), &header) {
+        to_304(&mut header);
+        session.write_response_header(Box::new(header)).await?;
+    } else {
+        session.write_response_header(Box::new(header)).await?;
+        session.write_response_body(entry.body).await?;
+    }
+    Ok(RequestFilterResult::ResponseSent)
+}
+
+impl TryFrom<CacheConf> for CacheHandler {
+    type Error = Box<Error>;
+
+    fn try_from(conf: CacheConf) -> Result<Self, Self::Error> {
+        Ok(Self {
+            conf,
+            cache: Default::default(),
+        })
+    }
+}
+
+#[async_trait]
+impl RequestFilter for CacheHandler {
+    type Conf = CacheConf;
+
+    type CTX = CacheCtx;
+
+    fn new_ctx() -> Self::CTX {
+        Self::CTX {
+            key: None,
+            pending: None,
+            fetch: None,
+        }
+    }
+
+    async fn request_filter(
+        &self,
+        session: &mut impl SessionWrapper,
+        ctx: &mut Self::CTX,
+    ) -> Result<RequestFilterResult, Box<Error>> {
+        if self.conf.cache_ttl == 0 {
+            return Ok(RequestFilterResult::Unhandled);
+        }
+
+        let request = session.req_header();
+        if request.method != Method::GET
+            || request.headers.contains_key(header::AUTHORIZATION)
+            || (!self.conf.cache_with_cookies && request.headers.contains_key(header::COOKIE))
+        {
+            return Ok(RequestFilterResult::Unhandled);
+        }
+
+        let key = CacheKey::new(session);
+        if let Some(entry) = self.cache.lookup(&key, session.req_header()) {
+            trace!("Serving cached response for {key:?}");
+            return serve_entry(session, entry).await;
+        }
+
+        if self.conf.cache_coalesce {
+            match self.cache.start_fetch(&key) {
+                Some(Fetch::Started(guard)) => ctx.fetch = Some(*guard),
+                Some(Fetch::InProgress(mut receiver)) => {
+                    trace!("Waiting for identical request in flight for {key:?}");
+                    // The sender is never used, this only returns once it is dropped.
+                    let _ = receiver.changed().await;
+                    if let Some(entry) = self.cache.lookup(&key, session.req_header()) {
+                        trace!("Serving coalesced response for {key:?}");
+                        return serve_entry(session, entry).await;
+                    }
+                }
+                None => {}
+            }
+        }
+
+        trace!("No cached response for {key:?}");
+        ctx.key = Some(key);
+        Ok(RequestFilterResult::Unhandled)
+    }
+
+    fn response_filter(
+        &self,
+        session: &mut impl SessionWrapper,
+        response: &mut ResponseHeader,
+        ctx: Option<&mut Self::CTX>,
+    ) {
+        // Only upstream responses have a context, these are the ones to be cached.
+        if let Some(ctx) = ctx {
+            if ctx.key.is_some() {
+                ctx.pending = PendingEntry::new(
+                    session.req_header(),
+                    response,
+                    self.conf.cache_max_entry_size,
+                );
+            }
+
+            if ctx.pending.is_none() {
+                // Response won’t be cached, let any waiting requests go upstream.
+                ctx.fetch = None;
+            }
+        }
+    }
+
+    fn response_body_filter(
+        &self,
+        _session: &mut impl SessionWrapper,
+        body: &mut Option<Bytes>,
+        end_of_stream: bool,
+        ctx: &mut Self::CTX,
+    ) {
+        let pending = if let Some(pending) = &mut ctx.pending {
+            pending
+        } else {
+            return;
+        };
+
+        if let Some(body) = body {
+            if !pending.push(body, self.conf.cache_max_entry_size) {
+                trace!("Response too large, not caching");
+                ctx.pending = None;
+                ctx.fetch = None;
+                return;
+            }
+        }
+
+        if end_of_stream {
+            if let (Some(key), Some(pending)) = (ctx.key.take(), ctx.pending.take()) {
+                trace!("Caching response for {key:?}");
+                let entry = pending.finish(Duration::from_secs(self.conf.cache_ttl));
+                self.cache.insert(key, entry, self.conf.cache_max_entries);
+            }
+            ctx.fetch = None;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use pandora_module_utils::pingora::{ProxyHttp, RequestHeader, TestSession};
+    use pandora_module_utils::FromYaml;
+    use startup_module::DefaultApp;
+    use std::time::Instant;
+    use test_log::test;
+    use tokio::task::JoinHandle;
+
+    fn make_handler() -> CacheHandler {
+        CacheConf::from_yaml(
+            r#"
+                cache_ttl: 10
+                cache_max_entries: 10
+                cache_max_entry_size: 100
+            "#,
+        )
+        .unwrap()
+        .try_into()
+        .unwrap()
+    }
+
+    fn make_coalescing_handler() -> CacheHandler {
+        let mut handler = make_handler();
+        handler.conf.cache_coalesce = true;
+        handler
+    }
+
+    async fn make_session(if_none_match: Option<&str>) -> TestSession {
+        let mut header = RequestHeader::build("GET", b"/file.txt", None).unwrap();
+        header.insert_header("Host", "localhost").unwrap();
+        if let Some(value) = if_none_match {
+            header.insert_header("If-None-Match", value).unwrap();
+        }
+        TestSession::from(header).await
+    }
+
+    /// Simulates a request going to the upstream server, returns the context.
+    async fn upstream_request(
+        handler: &CacheHandler,
+        mut response: ResponseHeader,
+        body: &[&'static [u8]],
+    ) -> Result<CacheCtx, Box<Error>> {
+        let mut session = make_session(None).await;
+        let mut ctx = CacheHandler::new_ctx();
+        assert_eq!(
+            handler.request_filter(&mut session, &mut ctx).await?,
+            RequestFilterResult::Unhandled
+        );
+
+        handler.response_filter(&mut session, &mut response, Some(&mut ctx));
+        for (index, &chunk) in body.iter().enumerate() {
+            let mut chunk = Some(Bytes::from_static(chunk));
+            let end_of_stream = index == body.len() - 1;
+            handler.response_body_filter(&mut session, &mut chunk, end_of_stream, &mut ctx);
+        }
+        Ok(ctx)
+    }
+
+    fn make_response(etag: Option<&str>, cache_control: Option<&str>) -> ResponseHeader {
+        let mut response = ResponseHeader::build(200, None).unwrap();
+        response
+            .insert_header("Content-Type", "text/plain")
+            .unwrap();
+        if let Some(etag) = etag {
+            response.insert_header("ETag", etag).unwrap();
+        }
+        if let Some(cache_control) = cache_control {
+            response
+                .insert_header("Cache-Control", cache_control)
+                .unwrap();
+        }
+        response
+    }
+
+    #[test(tokio::test)]
+    async fn cache_hit() -> Result<(), Box<Error>> {
+        let handler = make_handler();
+        upstream_request(
+            &handler,
+            make_response(Some("\"abc\""), None),
+            &[b"Hello, ", b"world!"],
+        )
+        .await?;
+
+        let mut session = make_session(None).await;
+        let mut ctx = CacheHandler::new_ctx();
+        assert_eq!(
+            handler.request_filter(&mut session, &mut ctx).await?,
+            RequestFilterResult::ResponseSent
+        );
+        let response = session.response_header.as_ref().unwrap();
+        assert_eq!(response.status, 200);
+        assert_eq!(response.headers.get("ETag").unwrap(), "\"abc\"");
+        assert_eq!(session.response_body, "Hello, world!");
+
+        let mut session = make_session(Some("\"xyz\", \"abc\"")).await;
+        assert_eq!(
+            handler.request_filter(&mut session, &mut ctx).await?,
+            RequestFilterResult::ResponseSent
+        );
+        let response = session.response_header.as_ref().unwrap();
+        assert_eq!(response.status, 304);
+        assert_eq!(response.headers.get("ETag").unwrap(), "\"abc\"");
+        assert!(session.response_body.is_empty());
+
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn generated_etag() -> Result<(), Box<Error>> {
+        let handler = make_handler();
+        upstream_request(&handler, make_response(None, None), &[b"Hello, world!"]).await?;
+
+        let mut session = make_session(None).await;
+        let mut ctx = CacheHandler::new_ctx();
+        handler.request_filter(&mut session, &mut ctx).await?;
+        let etag = session.response_header.as_ref().unwrap().headers["ETag"].clone();
+
+        let mut session = make_session(Some(etag.to_str().unwrap())).await;
+        handler.request_filter(&mut session, &mut ctx).await?;
+        assert_eq!(session.response_header.as_ref().unwrap().status, 304);
+
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn expiry() -> Result<(), Box<Error>> {
+        let handler = make_handler();
+        upstream_request(
+            &handler,
+            make_response(Some("\"abc\""), None),
+            &[b"Hello, world!"],
+        )
+        .await?;
+
+        for list in handler.cache.entries.lock().unwrap().values_mut() {
+            for entry in list {
+                entry.expires = Instant::now();
+            }
+        }
+
+        let mut session = make_session(None).await;
+        let mut ctx = CacheHandler::new_ctx();
+        assert_eq!(
+            handler.request_filter(&mut session, &mut ctx).await?,
+            RequestFilterResult::Unhandled
+        );
+        assert!(session.response_header.is_none());
+
+        // The new upstream response replaces the expired one
+        upstream_request(&handler, make_response(Some("\"def\""), None), &[b"Hi!"]).await?;
+        let mut session = make_session(None).await;
+        assert_eq!(
+            handler.request_filter(&mut session, &mut ctx).await?,
+            RequestFilterResult::ResponseSent
+        );
+        let response = session.response_header.as_ref().unwrap();
+        assert_eq!(response.headers.get("ETag").unwrap(), "\"def\"");
+        assert_eq!(session.response_body, "Hi!");
+
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn no_store() -> Result<(), Box<Error>> {
+        let handler = make_handler();
+        upstream_request(&handler, make_response(None, Some("no-store")), &[b"Hi!"]).await?;
+        upstream_request(&handler, make_response(None, Some("private")), &[b"Hi!"]).await?;
+
+        let mut session = make_session(None).await;
+        let mut ctx = CacheHandler::new_ctx();
+        assert_eq!(
+            handler.request_filter(&mut session, &mut ctx).await?,
+            RequestFilterResult::Unhandled
+        );
+
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn set_cookie() -> Result<(), Box<Error>> {
+        let handler = make_handler();
+        let mut response = make_response(None, None);
+        response.insert_header("Set-Cookie", "session=abc").unwrap();
+        upstream_request(&handler, response, &[b"Hi!"]).await?;
+
+        let mut session = make_session(None).await;
+        let mut ctx = CacheHandler::new_ctx();
+        assert_eq!(
+            handler.request_filter(&mut session, &mut ctx).await?,
+            RequestFilterResult::Unhandled
+        );
+        assert!(session.response_header.is_none());
+
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn cookie() -> Result<(), Box<Error>> {
+        let make_cookie_session = || async {
+            let mut session = make_session(None).await;
+            session
+                .req_header_mut()
+                .insert_header("Cookie", "session=abc")
+                .unwrap();
+            session
+        };
+
+        let handler = make_handler();
+        upstream_request(&handler, make_response(None, None), &[b"Hi!"]).await?;
+
+        // Requests with cookies bypass the cache
+        let mut session = make_cookie_session().await;
+        let mut ctx = CacheHandler::new_ctx();
+        assert_eq!(
+            handler.request_filter(&mut session, &mut ctx).await?,
+            RequestFilterResult::Unhandled
+        );
+        assert!(session.response_header.is_none());
+        assert!(ctx.key.is_none());
+
+        // Unless explicitly configured otherwise
+        let mut handler = make_handler();
+        handler.conf.cache_with_cookies = true;
+        upstream_request(&handler, make_response(None, None), &[b"Hi!"]).await?;
+
+        let mut session = make_cookie_session().await;
+        let mut ctx = CacheHandler::new_ctx();
+        assert_eq!(
+            handler.request_filter(&mut session, &mut ctx).await?,
+            RequestFilterResult::ResponseSent
+        );
+        assert_eq!(session.response_body, "Hi!");
+
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn too_large() -> Result<(), Box<Error>> {
+        let handler = make_handler();
+        upstream_request(
+            &handler,
+            make_response(None, None),
+            &[&[b'a'; 60], &[b'b'; 60]],
+        )
+        .await?;
+
+        let mut session = make_session(None).await;
+        let mut ctx = CacheHandler::new_ctx();
+        assert_eq!(
+            handler.request_filter(&mut session, &mut ctx).await?,
+            RequestFilterResult::Unhandled
+        );
+
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn content_length_mismatch() -> Result<(), Box<Error>> {
+        let handler = make_handler();
+        let app = DefaultApp::new(handler.clone());
+
+        for chunks in [&["Hello, ", "world"][..], &["Hello, ", "world!!"]] {
+            let mut session = make_session(None).await;
+            let mut ctx = app.new_ctx();
+            assert!(!app.request_filter(&mut session, &mut ctx).await?);
+
+            let mut response = make_response(None, None);
+            response.insert_header("Content-Length", "13")?;
+            app.upstream_response_filter(&mut session, &mut response, &mut ctx);
+            for (index, chunk) in chunks.iter().enumerate() {
+                let mut body = Some(Bytes::from_static(chunk.as_bytes()));
+                let end_of_stream = index == chunks.len() - 1;
+                app.upstream_response_body_filter(&mut session, &mut body, end_of_stream, &mut ctx);
+            }
+
+            // Aborted response isn’t cached
+            let mut session = make_session(None).await;
+            let mut ctx = CacheHandler::new_ctx();
+            assert_eq!(
+                handler.request_filter(&mut session, &mut ctx).await?,
+                RequestFilterResult::Unhandled
+            );
+        }
+
+        Ok(())
+    }
+
+    /// Starts a number of identical requests in the background, these return the filter result
+    /// and the response body.
+    async fn spawn_requests(
+        handler: &CacheHandler,
+        count: usize,
+    ) -> Vec<JoinHandle<(RequestFilterResult, String)>> {
+        let tasks = (0..count)
+            .map(|_| {
+                let handler = handler.clone();
+                tokio::spawn(async move {
+                    let mut session = make_session(None).await;
+                    let mut ctx = CacheHandler::new_ctx();
+                    let result = handler
+                        .request_filter(&mut session, &mut ctx)
+                        .await
+                        .unwrap();
+                    let body = String::from_utf8_lossy(&session.response_body).into_owned();
+                    (result, body)
+                })
+            })
+            .collect::<Vec<_>>();
+
+        // Give the requests a chance to start waiting
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        tasks
+    }
+
+    #[test(tokio::test)]
+    async fn coalescing() -> Result<(), Box<Error>> {
+        let handler = make_coalescing_handler();
+
+        let mut session = make_session(None).await;
+        let mut ctx = CacheHandler::new_ctx();
+        assert_eq!(
+            handler.request_filter(&mut session, &mut ctx).await?,
+            RequestFilterResult::Unhandled
+        );
+
+        let tasks = spawn_requests(&handler, 5).await;
+        assert!(tasks.iter().all(|task| !task.is_finished()));
+
+        let mut response = make_response(Some("\"abc\""), None);
+        handler.response_filter(&mut session, &mut response, Some(&mut ctx));
+        let mut body = Some(Bytes::from_static(b"Hello, world!"));
+        handler.response_body_filter(&mut session, &mut body, true, &mut ctx);
+
+        for task in tasks {
+            let (result, body) = task.await.unwrap();
+            assert_eq!(result, RequestFilterResult::ResponseSent);
+            assert_eq!(body, "Hello, world!");
+        }
+
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn coalescing_not_cacheable() -> Result<(), Box<Error>> {
+        let handler = make_coalescing_handler();
+
+        let mut session = make_session(None).await;
+        let mut ctx = CacheHandler::new_ctx();
+        assert_eq!(
+            handler.request_filter(&mut session, &mut ctx).await?,
+            RequestFilterResult::Unhandled
+        );
+
+        let tasks = spawn_requests(&handler, 5).await;
+        assert!(tasks.iter().all(|task| !task.is_finished()));
+
+        // Waiting requests go upstream as soon as the response turns out not cacheable
+        let mut response = make_response(None, Some("no-store"));
+        handler.response_filter(&mut session, &mut response, Some(&mut ctx));
+
+        for task in tasks {
+            let (result, _) = task.await.unwrap();
+            assert_eq!(result, RequestFilterResult::Unhandled);
+        }
+
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn coalescing_disabled() -> Result<(), Box<Error>> {
+        let handler = make_handler();
+
+        let mut session = make_session(None).await;
+        let mut ctx = CacheHandler::new_ctx();
+        assert_eq!(
+            handler.request_filter(&mut session, &mut ctx).await?,
+            RequestFilterResult::Unhandled
+        );
+
+        for task in spawn_requests(&handler, 5).await {
+            let (result, _) = task.await.unwrap();
+            assert_eq!(result, RequestFilterResult::Unhandled);
+        }
+
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn disabled() -> Result<(), Box<Error>> {
+        let handler: CacheHandler = CacheConf::default().try_into()?;
+        let ctx =
+            upstream_request(&handler, make_response(None, None), &[b"Hello, world!"]).await?;
+        assert!(ctx.key.is_none());
+
+        let mut session = make_session(None).await;
+        let mut ctx = CacheHandler::new_ctx();
+        assert_eq!(
+            handler.request_filter(&mut session, &mut ctx).await?,
+            RequestFilterResult::Unhandled
+        );
+
+        Ok(())
+    }
+}
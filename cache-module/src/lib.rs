@@ -0,0 +1,122 @@
+// Copyright 2024 Wladimir Palant
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! # Cache Module for Pandora Web Server
+//!
+//! This crate implements a microcache: upstream responses are kept in memory for a short time,
+//! so that a slow backend doesn’t need to produce the same response repeatedly. The following
+//! configuration options are available:
+//!
+//! * `cache_ttl` (`--cache-ttl` as command line option): Number of seconds responses should be
+//!   cached for. The default value `0` disables caching.
+//! * `cache_max_entries`: Maximal number of responses kept in memory, `1000` by default. If the
+//!   cache is full, the responses closest to expiring are removed first.
+//! * `cache_max_entry_size`: Maximal size of a response body in bytes, `1048576` (1 MiB) by
+//!   default. Larger responses aren’t cached.
+//! * `cache_coalesce`: If `true`, concurrent identical requests are coalesced, see below.
+//! * `cache_with_cookies`: If `true`, requests with a `Cookie` header can be served from the cache
+//!   as well. This is only safe if the upstream server doesn’t personalize responses based on
+//!   cookies or marks such responses with `Cache-Control: private`.
+//!
+//! For example:
+//!
+//! ```yaml
+//! cache_ttl: 5
+//! cache_max_entries: 100
+//! ```
+//!
+//! ## Caching rules
+//!
+//! Only successful (`200 OK`) upstream responses to `GET` requests are cached. Responses produced
+//! by other handlers, e.g. the Static Files Module, aren’t cached. Requests with an
+//! `Authorization` header always go to the upstream server, so do requests with a `Cookie` header
+//! unless `cache_with_cookies` is enabled.
+//!
+//! Responses are identified by request method, host name and URI. If a response has a `Vary`
+//! header, the values of the request headers listed there have to match as well. Responses with
+//! `Vary: *` aren’t cached.
+//!
+//! Responses with `Cache-Control: no-store` or `Cache-Control: private` aren’t cached, neither are
+//! responses setting cookies via `Set-Cookie` header. Other `Cache-Control` directives are
+//! ignored, all responses are cached for the configured duration.
+//!
+//! Cached responses are served with their `ETag` header, if the upstream response didn’t have one
+//! it is generated from the response body. A request with a matching `If-None-Match` header
+//! receives a `304 Not Modified` response.
+//!
+//! ## Request coalescing
+//!
+//! Without request coalescing, a popular resource that isn’t cached yet (or just expired) might
+//! be requested from the upstream server many times in parallel. With `cache_coalesce: true`,
+//! only the first request goes to the upstream server. Identical requests arriving while its
+//! response is still being received wait for it to complete and are then served from the cache.
+//!
+//! If the response turns out not to be cacheable, the waiting requests go to the upstream server
+//! as soon as the response header is received. The same happens if the cached response doesn’t
+//! match the waiting request’s headers listed in `Vary`.
+//!
+//! Note that waiting requests are delayed for as long as the first request takes. This setting
+//! shouldn’t be enabled for resources producing responses that never complete, such as
+//! long-polling endpoints or event streams.
+//!
+//! ## Code example
+//!
+//! You would normally put this handler in front of the Upstream Module, so that it can serve
+//! cached responses before the request reaches the upstream server:
+//!
+//! ```rust
+//! use cache_module::{CacheHandler, CacheOpt};
+//! use clap::Parser;
+//! use pandora_module_utils::{merge_conf, merge_opt, FromYaml, RequestFilter};
+//! use startup_module::{DefaultApp, StartupConf, StartupOpt};
+//! use upstream_module::{UpstreamHandler, UpstreamOpt};
+//!
+//! #[derive(Debug, RequestFilter)]
+//! struct Handler {
+//!     cache: CacheHandler,
+//!     upstream: UpstreamHandler,
+//! }
+//!
+//! #[merge_opt]
+//! struct Opt {
+//!     startup: StartupOpt,
+//!     cache: CacheOpt,
+//!     upstream: UpstreamOpt,
+//! }
+//!
+//! #[merge_conf]
+//! struct Conf {
+//!     startup: StartupConf,
+//!     handler: <Handler as RequestFilter>::Conf,
+//! }
+//!
+//! let opt = Opt::parse();
+//! let mut conf = Conf::load_from_files(opt.startup.conf.as_deref().unwrap_or(&[])).unwrap();
+//! conf.handler.cache.merge_with_opt(opt.cache);
+//! conf.handler.upstream.merge_with_opt(opt.upstream);
+//!
+//! let app = DefaultApp::<Handler>::from_conf(conf.handler)
+//!     .unwrap()
+//!     .with_startup_conf(&conf.startup);
+//! let server = conf.startup.into_server(app, Some(opt.startup)).unwrap();
+//!
+//! // Do something with the server here, e.g. call server.run_forever()
+//! ```
+
+mod cache;
+pub mod configuration;
+mod handler;
+
+pub use configuration::{CacheConf, CacheOpt};
+pub use handler::{CacheCtx, CacheHandler};
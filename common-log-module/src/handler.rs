@@ -19,7 +19,7 @@ use http::header;
 use log::error;
 use once_cell::sync::Lazy;
 use pandora_module_utils::pingora::{Error, ErrorType, SessionWrapper};
-use pandora_module_utils::{RequestFilter, RequestFilterResult};
+use pandora_module_utils::{AccessLogConf, RequestFilter, RequestFilterResult};
 use std::path::PathBuf;
 use std::sync::Arc;
 use std::time::SystemTime;
@@ -57,12 +57,172 @@ fn normalize_path(path: PathBuf) -> Result<PathBuf, Box<Error>> {
     }
 }
 
+/// Produces the log token for a field that is determined during the `request_filter` phase,
+/// `None` for fields determined during the `logging` phase.
+fn request_token(session: &impl SessionWrapper, field: &LogField) -> Option<LogToken> {
+    Some(match field {
+        LogField::None => LogToken::None,
+        LogField::RemoteAddr => {
+            if let Some(client_addr) = session.client_addr() {
+                LogToken::RemoteAddr(client_addr.clone())
+            } else {
+                LogToken::None
+            }
+        }
+        LogField::RemotePort => {
+            if let Some(client_addr) = session.client_addr() {
+                LogToken::RemotePort(client_addr.clone())
+            } else {
+                LogToken::None
+            }
+        }
+        LogField::TimeLocal => LogToken::TimeLocal,
+        LogField::TimeISO => LogToken::TimeISO,
+        LogField::Request => {
+            let header = session.req_header();
+            let method = &header.method;
+
+            let uri = session
+                .original_uri()
+                .path_and_query()
+                .map(|p| p.as_str())
+                .unwrap_or("");
+            let version = &header.version;
+            LogToken::Request(format!("{method} {uri} {version:?}"))
+        }
+        LogField::RequestHeader(name) => {
+            if let Some(value) = session.req_header().headers.get(name) {
+                LogToken::Header(value.clone())
+            } else {
+                LogToken::None
+            }
+        }
+        LogField::RemoteName
+        | LogField::Status
+        | LogField::BytesSent
+        | LogField::ProcessingTime
+        | LogField::ResponseHeader(_) => return None,
+    })
+}
+
+/// Produces the log token for a field that is determined during the `logging` phase, `None` for
+/// fields determined during the `request_filter` phase.
+fn response_token(
+    session: &impl SessionWrapper,
+    field: &LogField,
+    start_time: SystemTime,
+) -> Option<LogToken> {
+    Some(match field {
+        LogField::None
+        | LogField::RemoteAddr
+        | LogField::RemotePort
+        | LogField::TimeLocal
+        | LogField::TimeISO
+        | LogField::Request
+        | LogField::RequestHeader(_) => return None,
+        LogField::RemoteName => {
+            if let Some(remote_name) = session.remote_user() {
+                LogToken::RemoteName(remote_name.to_owned())
+            } else {
+                LogToken::None
+            }
+        }
+        LogField::Status => {
+            if let Some(header) = session.response_written() {
+                LogToken::Status(header.status.as_u16())
+            } else {
+                LogToken::None
+            }
+        }
+        LogField::BytesSent => LogToken::BytesSent(session.bytes_sent()),
+        LogField::ProcessingTime => {
+            if let Ok(time) = SystemTime::now().duration_since(start_time) {
+                LogToken::ProcessingTime(time)
+            } else {
+                LogToken::None
+            }
+        }
+        LogField::ResponseHeader(name) => {
+            if let Some(value) = session.response_written().and_then(|h| h.headers.get(name)) {
+                LogToken::Header(value.clone())
+            } else {
+                LogToken::None
+            }
+        }
+    })
+}
+
 /// Handler for Pingora’s `request_filter` phase
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct CommonLogHandler {
     conf: CommonLogConf,
 }
 
+impl CommonLogHandler {
+    /// Produces the tokens of the log line for the current request, `None` if the request
+    /// shouldn’t be logged.
+    ///
+    /// If [`AccessLogConf`] is present in session extensions, it can disable logging for the
+    /// request or replace the log format.
+    fn log_tokens(
+        &self,
+        session: &impl SessionWrapper,
+        ctx: &mut RequestCtx,
+    ) -> Option<Vec<LogToken>> {
+        if self.conf.log_file.as_os_str().is_empty() {
+            // Logging disabled
+            return None;
+        }
+
+        let access_log = session.extensions().get::<AccessLogConf>();
+        if access_log.is_some_and(|access_log| !access_log.enabled) {
+            // Logging disabled for this request
+            return None;
+        }
+
+        if let Some(format) = access_log.filter(|access_log| !access_log.format.is_empty()) {
+            let format = format
+                .format
+                .iter()
+                .map(|field| LogField::try_from(field.as_str()))
+                .collect::<Result<Vec<_>, _>>();
+            match format {
+                Ok(format) => {
+                    // All tokens are determined now, request data is still available
+                    return Some(
+                        format
+                            .iter()
+                            .map(|field| {
+                                request_token(session, field)
+                                    .or_else(|| response_token(session, field, ctx.time))
+                                    .unwrap_or(LogToken::None)
+                            })
+                            .collect(),
+                    );
+                }
+                Err(err) => {
+                    error!("Invalid access log format, using default: {err}");
+                }
+            }
+        }
+
+        let mut existing_tokens = ctx.tokens.split_off(0).into_iter();
+        Some(
+            self.conf
+                .log_format
+                .iter()
+                .map(|field| {
+                    response_token(session, field, ctx.time).unwrap_or_else(|| {
+                        // This is a token we’ve added previously. Panic if we don’t have one,
+                        // it’s a bug that needs investigating.
+                        existing_tokens.next().unwrap()
+                    })
+                })
+                .collect(),
+        )
+    }
+}
+
 impl TryFrom<CommonLogConf> for CommonLogHandler {
     type Error = Box<Error>;
 
@@ -118,51 +278,12 @@ impl RequestFilter for CommonLogHandler {
             return Ok(RequestFilterResult::Unhandled);
         }
 
-        for field in &self.conf.log_format {
-            ctx.tokens.push(match field {
-                LogField::None => LogToken::None,
-                LogField::RemoteAddr => {
-                    if let Some(client_addr) = session.client_addr() {
-                        LogToken::RemoteAddr(client_addr.clone())
-                    } else {
-                        LogToken::None
-                    }
-                }
-                LogField::RemotePort => {
-                    if let Some(client_addr) = session.client_addr() {
-                        LogToken::RemotePort(client_addr.clone())
-                    } else {
-                        LogToken::None
-                    }
-                }
-                LogField::TimeLocal => LogToken::TimeLocal,
-                LogField::TimeISO => LogToken::TimeISO,
-                LogField::Request => {
-                    let header = session.req_header();
-                    let method = &header.method;
-
-                    let uri = session
-                        .original_uri()
-                        .path_and_query()
-                        .map(|p| p.as_str())
-                        .unwrap_or("");
-                    let version = &header.version;
-                    LogToken::Request(format!("{method} {uri} {version:?}"))
-                }
-                LogField::RequestHeader(name) => {
-                    if let Some(value) = session.req_header().headers.get(name) {
-                        LogToken::Header(value.clone())
-                    } else {
-                        LogToken::None
-                    }
-                }
-                LogField::RemoteName
-                | LogField::Status
-                | LogField::BytesSent
-                | LogField::ProcessingTime
-                | LogField::ResponseHeader(_) => continue,
-            });
-        }
+        ctx.tokens = self
+            .conf
+            .log_format
+            .iter()
+            .filter_map(|field| request_token(session, field))
+            .collect();
 
         Ok(RequestFilterResult::Unhandled)
     }
@@ -173,60 +294,11 @@ impl RequestFilter for CommonLogHandler {
         _e: Option<&Error>,
         ctx: &mut RequestCtx,
     ) {
-        if self.conf.log_file.as_os_str().is_empty() {
-            // Logging disabled
+        let tokens = if let Some(tokens) = self.log_tokens(session, ctx) {
+            tokens
+        } else {
             return;
-        }
-
-        let mut existing_tokens = ctx.tokens.split_off(0).into_iter();
-        let mut tokens = Vec::new();
-
-        for field in &self.conf.log_format {
-            tokens.push(match field {
-                LogField::None
-                | LogField::RemoteAddr
-                | LogField::RemotePort
-                | LogField::TimeLocal
-                | LogField::TimeISO
-                | LogField::Request
-                | LogField::RequestHeader(_) => {
-                    // This is a token we’ve added previously. Panic if we don’t have one, it’s
-                    // a bug that needs investigating.
-                    existing_tokens.next().unwrap()
-                }
-                LogField::RemoteName => {
-                    if let Some(remote_name) = session.remote_user() {
-                        LogToken::RemoteName(remote_name.to_owned())
-                    } else {
-                        LogToken::None
-                    }
-                }
-                LogField::Status => {
-                    if let Some(header) = session.response_written() {
-                        LogToken::Status(header.status.as_u16())
-                    } else {
-                        LogToken::None
-                    }
-                }
-                LogField::BytesSent => LogToken::BytesSent(session.body_bytes_sent()),
-                LogField::ProcessingTime => {
-                    if let Ok(time) = SystemTime::now().duration_since(ctx.time) {
-                        LogToken::ProcessingTime(time)
-                    } else {
-                        LogToken::None
-                    }
-                }
-                LogField::ResponseHeader(name) => {
-                    if let Some(value) =
-                        session.response_written().and_then(|h| h.headers.get(name))
-                    {
-                        LogToken::Header(value.clone())
-                    } else {
-                        LogToken::None
-                    }
-                }
-            });
-        }
+        };
 
         static LOG_SENDER: Lazy<Arc<Sender<WriterMessage>>> = Lazy::new(|| {
             let (sender, receiver) = channel(100);
@@ -253,7 +325,10 @@ impl RequestFilter for CommonLogHandler {
 mod tests {
     use super::*;
 
+    use pandora_module_utils::pingora::{RequestHeader, ResponseHeader, TestSession};
+    use pandora_module_utils::FromYaml;
     use std::env::current_dir;
+    use test_log::test;
 
     #[test]
     fn path_normalization() {
@@ -286,4 +361,68 @@ mod tests {
             root.join("file.txt")
         );
     }
+
+    #[test(tokio::test)]
+    async fn access_log_conf() {
+        async fn log_tokens(
+            handler: &CommonLogHandler,
+            access_log: Option<AccessLogConf>,
+        ) -> Option<Vec<LogToken>> {
+            let header = RequestHeader::build("GET", b"/file?x", None).unwrap();
+            let mut session = TestSession::from(header).await;
+            if let Some(access_log) = access_log {
+                session.extensions_mut().insert(access_log);
+            }
+
+            let mut ctx = CommonLogHandler::new_ctx();
+            handler
+                .request_filter(&mut session, &mut ctx)
+                .await
+                .unwrap();
+            let response = ResponseHeader::build(204, None).unwrap();
+            session
+                .write_response_header(Box::new(response))
+                .await
+                .unwrap();
+            handler.log_tokens(&session, &mut ctx)
+        }
+
+        let handler: CommonLogHandler = CommonLogConf::from_yaml("log_format: [request, status]")
+            .unwrap()
+            .try_into()
+            .unwrap();
+        let request = LogToken::Request("GET /file?x HTTP/1.1".to_owned());
+
+        assert_eq!(
+            log_tokens(&handler, None).await,
+            Some(vec![request.clone(), LogToken::Status(204)])
+        );
+
+        // Silent virtual host
+        let access_log = AccessLogConf {
+            enabled: false,
+            format: Default::default(),
+        };
+        assert_eq!(log_tokens(&handler, Some(access_log)).await, None);
+
+        // Virtual host with its own log format
+        let access_log = AccessLogConf {
+            enabled: true,
+            format: vec!["status".to_owned(), "-".to_owned(), "request".to_owned()].into(),
+        };
+        assert_eq!(
+            log_tokens(&handler, Some(access_log)).await,
+            Some(vec![LogToken::Status(204), LogToken::None, request.clone()])
+        );
+
+        // Invalid log format is ignored
+        let access_log = AccessLogConf {
+            enabled: true,
+            format: vec!["bogus".to_owned()].into(),
+        };
+        assert_eq!(
+            log_tokens(&handler, Some(access_log)).await,
+            Some(vec![request, LogToken::Status(204)])
+        );
+    }
 }
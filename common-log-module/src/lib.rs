@@ -37,7 +37,8 @@
 //! * `time_iso8601`: date and time in the ISO 8601 format, e.g. `[2000-10-10T13:55:36-07:00]`
 //! * `request`: quoted request line, e.g. `"GET / HTTP/1.1"`
 //! * `status`: status code of the response, e.g. `200`
-//! * `bytes_sent`: number of bytes sent as response
+//! * `bytes_sent`: number of bytes sent as response, for HTTP/1 connections this includes the
+//!   response header
 //! * `processing_time`: time from request being received to response in milliseconds
 //! * `http_<header>`: quoted value of an HTTP request header. For example, `http_user_agent` adds
 //!   the value of the `User-Agent` HTTP header to the log.
@@ -51,6 +52,11 @@
 //! Module to its host handler will make sure that each virtual host has its own logging
 //! configuration.
 //!
+//! Virtual hosts can also adjust top-level logging via their `access_log` setting: requests for a
+//! virtual host with `access_log: {enabled: false}` won’t be logged, and a `format` list replaces
+//! `log_format` for its requests. Invalid fields in such a format are reported as errors when a
+//! request is logged, the default format is used then.
+//!
 //! On Unix-based systems, the process can be sent a `HUP` or `USR1` signal to make it re-open log
 //! files. This is useful after the logs have been rotated for example.
 //!
@@ -88,7 +94,9 @@
 //! let mut conf = Conf::load_from_files(opt.startup.conf.as_deref().unwrap_or(&[])).unwrap();
 //! conf.handler.log.merge_with_opt(opt.log);
 //!
-//! let app = DefaultApp::<Handler>::from_conf(conf.handler).unwrap();
+//! let app = DefaultApp::<Handler>::from_conf(conf.handler)
+//!     .unwrap()
+//!     .with_startup_conf(&conf.startup);
 //! let server = conf.startup.into_server(app, Some(opt.startup)).unwrap();
 //!
 //! // Do something with the server here, e.g. call server.run_forever()
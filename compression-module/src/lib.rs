@@ -14,7 +14,7 @@
 
 //! # Compression Module for Pandora Web Server
 //!
-//! This crate helps configure Pingora’s built-in compression mechanism. It provides two
+//! This crate helps configure Pingora’s built-in compression mechanism. It provides the following
 //! configuration options:
 //!
 //! * `compression_level` (`--compression-level` as command-line option): If present, will enable
@@ -23,6 +23,25 @@
 //!   [Pingora issue #228](https://github.com/cloudflare/pingora/issues/228)).
 //! * `decompress_upstream` (`--decompress-upstream` as command-line flag): If `true`,
 //!   decompression of upstream responses will be enabled.
+//! * `flush` (`--flush` as command-line option): Determines when compressed data of upstream
+//!   responses is sent to the client. With the default value `never` Pingora’s compression is
+//!   used, it might hold back data until enough of it accumulates. `each_chunk` will flush
+//!   compressed data after each chunk received from upstream, an interval like `100ms` or `2s`
+//!   will flush whenever a chunk is received and the interval elapsed since the last flush. This
+//!   is useful for server-sent events and other long-lived streaming responses.
+//!
+//! * `algorithms` (`--compression-algorithm` as command-line option): Restricts dynamic
+//!   compression to the listed algorithms: `gzip`, `br` (Brotli) and `zstd` (Zstandard). Names
+//!   are parsed like the Static Files Module’s `precompressed` setting, so aliases like `brotli`
+//!   or `.br` are accepted as well. If a client prefers an algorithm that isn’t listed, the next
+//!   acceptable algorithm is used or the response is sent uncompressed. All algorithms are enabled
+//!   if this setting is omitted.
+//! * `compress_statuses` (`--compress-status` as command-line option): Restricts compression to
+//!   responses with the listed status codes, e.g. `[200]` to leave error pages uncompressed.
+//!   Responses with any status code are compressed if this setting is omitted.
+//!
+//! Flushing requires `compression_level` to be set. It is only supported for gzip compression,
+//! clients that don’t accept gzip will receive uncompressed responses.
 //!
 //! ## Code example
 //!
@@ -62,7 +81,9 @@
 //! conf.handler.compression.merge_with_opt(opt.compression);
 //! conf.handler.static_files.merge_with_opt(opt.static_files);
 //!
-//! let app = DefaultApp::<Handler>::from_conf(conf.handler).unwrap();
+//! let app = DefaultApp::<Handler>::from_conf(conf.handler)
+//!     .unwrap()
+//!     .with_startup_conf(&conf.startup);
 //! let server = conf.startup.into_server(app, Some(opt.startup)).unwrap();
 //!
 //! // Do something with the server here, e.g. call server.run_forever()
@@ -70,8 +91,74 @@
 
 use async_trait::async_trait;
 use clap::Parser;
-use pandora_module_utils::pingora::{Error, SessionWrapper};
-use pandora_module_utils::{DeserializeMap, RequestFilter, RequestFilterResult};
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use http::{header, Method, StatusCode};
+use log::error;
+use pandora_module_utils::compression_algorithm::find_matches;
+pub use pandora_module_utils::compression_algorithm::CompressionAlgorithm;
+use pandora_module_utils::pingora::{
+    Bytes, Error, ErrorType, RequestHeader, ResponseHeader, SessionWrapper,
+};
+use pandora_module_utils::{DeserializeMap, OneOrMany, RequestFilter, RequestFilterResult};
+use serde::Deserialize;
+use std::io::Write;
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+
+/// Determines when compressed data is sent to the client
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "String")]
+pub enum FlushPolicy {
+    /// Leave compression to Pingora, compressed data is sent whenever the compressor produces it
+    #[default]
+    Never,
+    /// Flush compressed data after each chunk of the response body, `each_chunk` in config file
+    EachChunk,
+    /// Flush compressed data when a chunk is received and the interval elapsed since the last
+    /// flush, e.g. `100ms` or `2s` in config file
+    Interval(Duration),
+}
+
+impl FromStr for FlushPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "never" => Ok(Self::Never),
+            "each_chunk" => Ok(Self::EachChunk),
+            interval => {
+                let (value, factor) = if let Some(value) = interval.strip_suffix("ms") {
+                    (value, 1)
+                } else if let Some(value) = interval.strip_suffix('s') {
+                    (value, 1000)
+                } else {
+                    return Err(format!("Unsupported flush policy {interval}"));
+                };
+                let value = u64::from_str(value)
+                    .map_err(|_| format!("Invalid flush interval {interval}"))?;
+                Ok(Self::Interval(Duration::from_millis(
+                    value.saturating_mul(factor),
+                )))
+            }
+        }
+    }
+}
+
+impl TryFrom<String> for FlushPolicy {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+/// Compression algorithms supported by Pingora’s dynamic compression
+const SUPPORTED_ALGORITHMS: &[CompressionAlgorithm] = &[
+    CompressionAlgorithm::Gzip,
+    CompressionAlgorithm::Brotli,
+    CompressionAlgorithm::Zstandard,
+];
 
 /// Command line options of the compression module
 #[derive(Debug, Default, Parser)]
@@ -83,6 +170,20 @@ pub struct CompressionOpt {
     /// Decompress upstream responses before passing them on
     #[clap(long)]
     pub decompress_upstream: bool,
+
+    /// When to flush compressed data: never, each_chunk or an interval like 100ms or 2s
+    #[clap(long)]
+    pub flush: Option<FlushPolicy>,
+
+    /// Compression algorithm to be used for dynamic compression: gzip, br or zstd. This command
+    /// line flag can be specified multiple times.
+    #[clap(long)]
+    pub compression_algorithm: Option<Vec<CompressionAlgorithm>>,
+
+    /// Status code of responses to be compressed. This command line flag can be specified
+    /// multiple times.
+    #[clap(long)]
+    pub compress_status: Option<Vec<u16>>,
 }
 
 /// Configuration settings of the compression module
@@ -93,6 +194,15 @@ pub struct CompressionConf {
 
     /// If `true`, upstream responses will be decompressed
     pub decompress_upstream: bool,
+
+    /// Determines when compressed data of upstream responses is flushed to the client.
+    pub flush: FlushPolicy,
+
+    /// Compression algorithms allowed for dynamic compression (empty list allows all).
+    pub algorithms: OneOrMany<CompressionAlgorithm>,
+
+    /// Status codes of responses to be compressed (empty list allows all).
+    pub compress_statuses: OneOrMany<u16>,
 }
 
 impl CompressionConf {
@@ -106,10 +216,97 @@ impl CompressionConf {
         if opt.decompress_upstream {
             self.decompress_upstream = opt.decompress_upstream;
         }
+
+        if let Some(flush) = opt.flush {
+            self.flush = flush;
+        }
+
+        if let Some(algorithms) = opt.compression_algorithm {
+            self.algorithms = algorithms.into();
+        }
+
+        if let Some(statuses) = opt.compress_status {
+            self.compress_statuses = statuses.into();
+        }
     }
+
+    fn is_allowed(&self, algorithm: CompressionAlgorithm) -> bool {
+        self.algorithms.is_empty() || self.algorithms.contains(&algorithm)
+    }
+
+    fn is_status_allowed(&self, status: StatusCode) -> bool {
+        self.compress_statuses.is_empty() || self.compress_statuses.contains(&status.as_u16())
+    }
+}
+
+/// Per-request state of the compression handler
+#[derive(Debug)]
+pub struct CompressionCtx {
+    encoder: Option<GzEncoder<Vec<u8>>>,
+    last_flush: Instant,
 }
 
-/// Handler for Pingora’s `request_filter` phase
+/// Checks whether the client accepts gzip-compressed responses.
+fn accepts_gzip(session: &impl SessionWrapper) -> bool {
+    session.accepted_encodings().accepts("gzip")
+}
+
+/// Passes the allowed algorithms acceptable to the client on to Pingora’s compression, the
+/// request itself stays unchanged. Compression is disabled if there are no such algorithms.
+///
+/// Pingora will add the algorithms from the request’s `Accept-Encoding` header after the request
+/// filter phase. As it always compresses with the first algorithm on its list, the algorithms
+/// passed in here take precedence.
+fn restrict_algorithms(session: &mut impl SessionWrapper, algorithms: &[CompressionAlgorithm]) {
+    let matches = find_matches(&session.accepted_encodings(), algorithms);
+    if matches.is_empty() {
+        session.downstream_compression.adjust_level(0);
+        return;
+    }
+
+    let value = matches
+        .iter()
+        .map(|algorithm| algorithm.encoding_token())
+        .collect::<Vec<_>>()
+        .join(", ");
+    let request = RequestHeader::build(Method::GET, b"/", None).and_then(|mut request| {
+        request.insert_header(header::ACCEPT_ENCODING, value)?;
+        Ok(request)
+    });
+    match request {
+        Ok(request) => session.downstream_compression.request_filter(&request),
+        Err(err) => error!("Failed restricting compression algorithms: {err}"),
+    }
+}
+
+/// Checks whether the response is worth compressing, same rules as in Pingora.
+fn compressible(response: &ResponseHeader) -> bool {
+    const PREFIXES: &[&str] = &[
+        "text/",
+        "application/",
+        "font/",
+        "image/x-icon",
+        "image/svg+xml",
+        "image/vnd.microsoft.icon",
+        "binary/octet-stream",
+    ];
+
+    if let Some(content_type) = response
+        .headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+    {
+        !content_type.contains("zip")
+            && PREFIXES
+                .iter()
+                .any(|prefix| content_type.starts_with(prefix))
+    } else {
+        false
+    }
+}
+
+/// Handler for Pingora’s `request_filter` phase, also handles compression of upstream responses
+/// if a flush policy is configured
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct CompressionHandler {
     conf: CompressionConf,
@@ -119,6 +316,24 @@ impl TryFrom<CompressionConf> for CompressionHandler {
     type Error = Box<Error>;
 
     fn try_from(conf: CompressionConf) -> Result<Self, Self::Error> {
+        if let Some(algorithm) = conf
+            .algorithms
+            .iter()
+            .find(|algorithm| !SUPPORTED_ALGORITHMS.contains(algorithm))
+        {
+            error!("Compression algorithm {algorithm} isn’t supported for dynamic compression");
+            return Err(Error::new(ErrorType::InternalError));
+        }
+
+        if let Some(status) = conf
+            .compress_statuses
+            .iter()
+            .find(|status| StatusCode::from_u16(**status).is_err())
+        {
+            error!("Invalid status code in compress_statuses: {status}");
+            return Err(Error::new(ErrorType::InternalError));
+        }
+
         Ok(Self { conf })
     }
 }
@@ -126,8 +341,13 @@ impl TryFrom<CompressionConf> for CompressionHandler {
 #[async_trait]
 impl RequestFilter for CompressionHandler {
     type Conf = CompressionConf;
-    type CTX = ();
-    fn new_ctx() -> Self::CTX {}
+    type CTX = CompressionCtx;
+    fn new_ctx() -> Self::CTX {
+        Self::CTX {
+            encoder: None,
+            last_flush: Instant::now(),
+        }
+    }
 
     async fn request_filter(
         &self,
@@ -136,6 +356,10 @@ impl RequestFilter for CompressionHandler {
     ) -> Result<RequestFilterResult, Box<Error>> {
         if let Some(level) = self.conf.compression_level {
             session.downstream_compression.adjust_level(level);
+
+            if !self.conf.algorithms.is_empty() {
+                restrict_algorithms(session, &self.conf.algorithms);
+            }
         }
 
         if self.conf.decompress_upstream {
@@ -144,13 +368,114 @@ impl RequestFilter for CompressionHandler {
 
         Ok(RequestFilterResult::Unhandled)
     }
+
+    fn response_filter(
+        &self,
+        session: &mut impl SessionWrapper,
+        response: &mut ResponseHeader,
+        ctx: Option<&mut Self::CTX>,
+    ) {
+        if !self.conf.is_status_allowed(response.status) {
+            if self.conf.compression_level.is_some() {
+                // Keep Pingora’s compression from handling this response.
+                session.downstream_compression.adjust_level(0);
+            }
+            return;
+        }
+
+        // Only upstream responses have a context, only these go through the body filter.
+        let ctx = if let Some(ctx) = ctx {
+            ctx
+        } else {
+            return;
+        };
+
+        let level = match self.conf.compression_level {
+            Some(level) if level > 0 => level,
+            _ => return,
+        };
+
+        if self.conf.flush == FlushPolicy::Never
+            || response.status.is_informational()
+            || response.status == StatusCode::NO_CONTENT
+            || response.status == StatusCode::NOT_MODIFIED
+            || session.req_header().method == Method::HEAD
+            || response.headers.contains_key(header::CONTENT_ENCODING)
+            || !compressible(response)
+            || !self.conf.is_allowed(CompressionAlgorithm::Gzip)
+            || !accepts_gzip(session)
+        {
+            return;
+        }
+
+        // Pingora’s compression would buffer data, disable it.
+        session.downstream_compression.adjust_level(0);
+
+        response.remove_header(&header::CONTENT_LENGTH);
+        let _ = response.insert_header(header::CONTENT_ENCODING, "gzip");
+        let _ = response.insert_header(header::TRANSFER_ENCODING, "chunked");
+        let _ = response.append_header(header::VARY, "Accept-Encoding");
+
+        ctx.encoder = Some(GzEncoder::new(Vec::new(), Compression::new(level.min(9))));
+        ctx.last_flush = Instant::now();
+    }
+
+    fn response_body_filter(
+        &self,
+        _session: &mut impl SessionWrapper,
+        body: &mut Option<Bytes>,
+        end_of_stream: bool,
+        ctx: &mut Self::CTX,
+    ) {
+        let encoder = if let Some(encoder) = &mut ctx.encoder {
+            encoder
+        } else {
+            return;
+        };
+
+        if let Some(data) = body {
+            if let Err(err) = encoder.write_all(data) {
+                error!("Failed compressing response body: {err}");
+            }
+        }
+
+        let flush = match self.conf.flush {
+            FlushPolicy::Never => false,
+            FlushPolicy::EachChunk => true,
+            FlushPolicy::Interval(interval) => ctx.last_flush.elapsed() >= interval,
+        };
+
+        let result = if end_of_stream {
+            encoder.try_finish()
+        } else if flush {
+            ctx.last_flush = Instant::now();
+            encoder.flush()
+        } else {
+            Ok(())
+        };
+        if let Err(err) = result {
+            error!("Failed compressing response body: {err}");
+        }
+
+        let output = std::mem::take(encoder.get_mut());
+        *body = if output.is_empty() && !end_of_stream {
+            None
+        } else {
+            Some(output.into())
+        };
+
+        if end_of_stream {
+            ctx.encoder = None;
+        }
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
 
-    use pandora_module_utils::pingora::{RequestHeader, TestSession};
+    use flate2::write::GzDecoder;
+    use pandora_module_utils::pingora::{HttpTask, RequestHeader, TestSession};
     use pandora_module_utils::FromYaml;
     use test_log::test;
 
@@ -244,4 +569,390 @@ mod tests {
         );
         Ok(())
     }
+
+    fn make_flushing_handler(flush: &str) -> CompressionHandler {
+        CompressionConf::from_yaml(format!(
+            r#"
+                compression_level: 6
+                flush: {flush}
+            "#
+        ))
+        .unwrap()
+        .try_into()
+        .unwrap()
+    }
+
+    async fn make_gzip_session() -> TestSession {
+        let mut header = RequestHeader::build("GET", b"/", None).unwrap();
+        header.insert_header("Accept-Encoding", "gzip").unwrap();
+        TestSession::from(header).await
+    }
+
+    fn make_response() -> ResponseHeader {
+        let mut response = ResponseHeader::build(200, None).unwrap();
+        response
+            .insert_header("Content-Type", "text/event-stream")
+            .unwrap();
+        response
+    }
+
+    #[test]
+    fn flush_policy() {
+        assert_eq!("never".parse(), Ok(FlushPolicy::Never));
+        assert_eq!("each_chunk".parse(), Ok(FlushPolicy::EachChunk));
+        assert_eq!(
+            "100ms".parse(),
+            Ok(FlushPolicy::Interval(Duration::from_millis(100)))
+        );
+        assert_eq!(
+            "2s".parse(),
+            Ok(FlushPolicy::Interval(Duration::from_secs(2)))
+        );
+        assert!("2".parse::<FlushPolicy>().is_err());
+        assert!("xs".parse::<FlushPolicy>().is_err());
+        assert!("always".parse::<FlushPolicy>().is_err());
+    }
+
+    #[test(tokio::test)]
+    async fn flush_never() -> Result<(), Box<Error>> {
+        let handler = make_flushing_handler("never");
+        let mut session = make_gzip_session().await;
+        let mut ctx = CompressionHandler::new_ctx();
+        handler.request_filter(&mut session, &mut ctx).await?;
+
+        let mut response = make_response();
+        handler.response_filter(&mut session, &mut response, Some(&mut ctx));
+        assert!(response.headers.get("Content-Encoding").is_none());
+
+        let mut body = Some(Bytes::from_static(b"data: hi\n\n"));
+        handler.response_body_filter(&mut session, &mut body, false, &mut ctx);
+        assert_eq!(body, Some(Bytes::from_static(b"data: hi\n\n")));
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn flush_no_gzip() -> Result<(), Box<Error>> {
+        let handler = make_flushing_handler("each_chunk");
+        let mut session = make_session().await;
+        let mut ctx = CompressionHandler::new_ctx();
+        handler.request_filter(&mut session, &mut ctx).await?;
+
+        let mut response = make_response();
+        handler.response_filter(&mut session, &mut response, Some(&mut ctx));
+        assert!(response.headers.get("Content-Encoding").is_none());
+        assert!(ctx.encoder.is_none());
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn flush_each_chunk() -> Result<(), Box<Error>> {
+        let handler = make_flushing_handler("each_chunk");
+        let mut session = make_gzip_session().await;
+        let mut ctx = CompressionHandler::new_ctx();
+        handler.request_filter(&mut session, &mut ctx).await?;
+
+        let mut response = make_response();
+        response.insert_header("Content-Length", "1000")?;
+        handler.response_filter(&mut session, &mut response, Some(&mut ctx));
+        assert_eq!(response.headers.get("Content-Encoding").unwrap(), "gzip");
+        assert_eq!(response.headers.get("Vary").unwrap(), "Accept-Encoding");
+        assert!(response.headers.get("Content-Length").is_none());
+
+        let chunks: &[&[u8]] = &[b"data: first\n\n", b"data: second\n\n", b"data: third\n\n"];
+        let mut decoder = GzDecoder::new(Vec::new());
+        let mut expected = Vec::new();
+        for &chunk in chunks {
+            let mut body = Some(Bytes::from_static(chunk));
+            handler.response_body_filter(&mut session, &mut body, false, &mut ctx);
+
+            // Compressed output is produced for each chunk and decompresses to all data so far
+            let body = body.unwrap();
+            assert!(!body.is_empty());
+            decoder.write_all(&body).unwrap();
+            decoder.flush().unwrap();
+            expected.extend_from_slice(chunk);
+            assert_eq!(decoder.get_ref(), &expected);
+        }
+
+        let mut body = None;
+        handler.response_body_filter(&mut session, &mut body, true, &mut ctx);
+        decoder.write_all(&body.unwrap()).unwrap();
+        assert_eq!(decoder.finish().unwrap(), expected);
+        assert!(ctx.encoder.is_none());
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn flush_interval() -> Result<(), Box<Error>> {
+        let handler = make_flushing_handler("3600s");
+        let mut session = make_gzip_session().await;
+        let mut ctx = CompressionHandler::new_ctx();
+        handler.request_filter(&mut session, &mut ctx).await?;
+
+        let mut response = make_response();
+        handler.response_filter(&mut session, &mut response, Some(&mut ctx));
+        assert_eq!(response.headers.get("Content-Encoding").unwrap(), "gzip");
+
+        // Interval didn’t elapse yet, data is held back apart from the gzip header
+        let mut output = Vec::new();
+        let mut body = Some(Bytes::from_static(b"data: first\n\n"));
+        handler.response_body_filter(&mut session, &mut body, false, &mut ctx);
+        if let Some(body) = body {
+            output.extend_from_slice(&body);
+        }
+        let mut decoder = GzDecoder::new(Vec::new());
+        decoder.write_all(&output).unwrap();
+        decoder.flush().unwrap();
+        assert!(decoder.get_ref().is_empty());
+
+        let mut body = Some(Bytes::from_static(b"data: second\n\n"));
+        handler.response_body_filter(&mut session, &mut body, true, &mut ctx);
+        output.extend_from_slice(&body.unwrap());
+        let mut decoder = GzDecoder::new(Vec::new());
+        decoder.write_all(&output).unwrap();
+        assert_eq!(
+            decoder.finish().unwrap(),
+            b"data: first\n\ndata: second\n\n"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn compression_algorithm() {
+        let conf = CompressionConf::from_yaml("algorithms: [gzip, ZSTD, brotli, .br]").unwrap();
+        assert_eq!(
+            conf.algorithms,
+            vec![
+                CompressionAlgorithm::Gzip,
+                CompressionAlgorithm::Zstandard,
+                CompressionAlgorithm::Brotli,
+                CompressionAlgorithm::Brotli
+            ]
+            .into()
+        );
+        assert!(CompressionHandler::try_from(conf).is_ok());
+
+        assert!(CompressionConf::from_yaml("algorithms: rar").is_err());
+
+        // Pingora doesn’t support deflate compression
+        let conf = CompressionConf::from_yaml("algorithms: deflate").unwrap();
+        assert!(CompressionHandler::try_from(conf).is_err());
+    }
+
+    async fn compress_response(
+        algorithms: &str,
+        accept_encoding: &str,
+    ) -> Result<Option<String>, Box<Error>> {
+        let handler: CompressionHandler = CompressionConf::from_yaml(format!(
+            r#"
+                compression_level: 6
+                algorithms: {algorithms}
+            "#
+        ))?
+        .try_into()?;
+
+        let mut header = RequestHeader::build("GET", b"/", None)?;
+        header.insert_header("Accept-Encoding", accept_encoding)?;
+        let mut session = TestSession::from(header).await;
+        handler
+            .request_filter(&mut session, &mut CompressionHandler::new_ctx())
+            .await?;
+
+        // This is what Pingora does after the request filter phase
+        let req_header = session.req_header().clone();
+        session.downstream_compression.request_filter(&req_header);
+
+        let mut response = ResponseHeader::build(200, None)?;
+        response.insert_header("Content-Type", "text/html")?;
+        response.insert_header("Content-Length", "1000")?;
+        let mut task = HttpTask::Header(Box::new(response), false);
+        session.downstream_compression.response_filter(&mut task);
+
+        // The request is passed on to the upstream server and other handlers unchanged
+        assert_eq!(
+            session.req_header().headers.get("Accept-Encoding").unwrap(),
+            accept_encoding
+        );
+
+        match task {
+            HttpTask::Header(response, _) => Ok(response
+                .headers
+                .get("Content-Encoding")
+                .map(|value| value.to_str().unwrap().to_owned())),
+            _ => unreachable!(),
+        }
+    }
+
+    #[test(tokio::test)]
+    async fn algorithms() -> Result<(), Box<Error>> {
+        assert_eq!(
+            compress_response("[]", "br, gzip").await?,
+            Some("br".to_owned())
+        );
+        assert_eq!(
+            compress_response("[gzip]", "br, gzip").await?,
+            Some("gzip".to_owned())
+        );
+        assert_eq!(
+            compress_response("[gzip, zstd]", "br;q=1.0, zstd, deflate, gzip").await?,
+            Some("zstd".to_owned())
+        );
+        assert_eq!(compress_response("zstd", "br, gzip").await?, None);
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn algorithms_quality() -> Result<(), Box<Error>> {
+        // Algorithms are ordered by quality value, rejected algorithms are never used
+        assert_eq!(
+            compress_response("[br, gzip]", "br;q=0.5, gzip;q=0.8").await?,
+            Some("gzip".to_owned())
+        );
+        assert_eq!(
+            compress_response("[br, gzip]", "BR;q=0, gzip").await?,
+            Some("gzip".to_owned())
+        );
+        assert_eq!(compress_response("gzip", "gzip;q=0, br").await?, None);
+
+        // Wildcard makes all allowed algorithms acceptable unless rejected explicitly
+        assert_eq!(
+            compress_response("[zstd, gzip]", "*").await?,
+            Some("zstd".to_owned())
+        );
+        assert_eq!(
+            compress_response("[zstd, gzip]", "zstd;q=0, *").await?,
+            Some("gzip".to_owned())
+        );
+        assert_eq!(compress_response("br", "gzip, *;q=0").await?, None);
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn no_accept_encoding() -> Result<(), Box<Error>> {
+        // Without Accept-Encoding header only identity encoding is acceptable
+        for algorithms in ["[]", "[gzip, br]"] {
+            let handler: CompressionHandler = CompressionConf::from_yaml(format!(
+                r#"
+                    compression_level: 6
+                    algorithms: {algorithms}
+                "#
+            ))?
+            .try_into()?;
+
+            let header = RequestHeader::build("GET", b"/", None)?;
+            let mut session = TestSession::from(header).await;
+            handler
+                .request_filter(&mut session, &mut CompressionHandler::new_ctx())
+                .await?;
+            assert!(session
+                .req_header()
+                .headers
+                .get("Accept-Encoding")
+                .is_none());
+
+            // This is what Pingora does after the request filter phase
+            let req_header = session.req_header().clone();
+            session.downstream_compression.request_filter(&req_header);
+
+            let mut response = ResponseHeader::build(200, None)?;
+            response.insert_header("Content-Type", "text/html")?;
+            response.insert_header("Content-Length", "1000")?;
+            let mut task = HttpTask::Header(Box::new(response), false);
+            session.downstream_compression.response_filter(&mut task);
+            match task {
+                HttpTask::Header(response, _) => {
+                    assert!(response.headers.get("Content-Encoding").is_none());
+                    assert_eq!(response.headers.get("Content-Length").unwrap(), "1000");
+                }
+                _ => unreachable!(),
+            }
+        }
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn flush_gzip_disabled() -> Result<(), Box<Error>> {
+        let handler: CompressionHandler = CompressionConf::from_yaml(
+            r#"
+                compression_level: 6
+                flush: each_chunk
+                algorithms: br
+            "#,
+        )?
+        .try_into()?;
+        let mut session = make_gzip_session().await;
+        let mut ctx = CompressionHandler::new_ctx();
+        handler.request_filter(&mut session, &mut ctx).await?;
+
+        let mut response = make_response();
+        handler.response_filter(&mut session, &mut response, Some(&mut ctx));
+        assert!(response.headers.get("Content-Encoding").is_none());
+        assert!(ctx.encoder.is_none());
+        Ok(())
+    }
+
+    async fn compress_status(conf: &str, status: u16) -> Result<Option<String>, Box<Error>> {
+        let handler: CompressionHandler = CompressionConf::from_yaml(conf)?.try_into()?;
+        let mut session = make_gzip_session().await;
+        handler
+            .request_filter(&mut session, &mut CompressionHandler::new_ctx())
+            .await?;
+
+        // This is what Pingora does after the request filter phase
+        let req_header = session.req_header().clone();
+        session.downstream_compression.request_filter(&req_header);
+
+        let mut response = ResponseHeader::build(status, None)?;
+        response.insert_header("Content-Type", "text/html")?;
+        response.insert_header("Content-Length", "1000")?;
+        handler.response_filter(&mut session, &mut response, None);
+
+        let mut task = HttpTask::Header(Box::new(response), false);
+        session.downstream_compression.response_filter(&mut task);
+        match task {
+            HttpTask::Header(response, _) => Ok(response
+                .headers
+                .get("Content-Encoding")
+                .map(|value| value.to_str().unwrap().to_owned())),
+            _ => unreachable!(),
+        }
+    }
+
+    #[test(tokio::test)]
+    async fn compress_statuses() -> Result<(), Box<Error>> {
+        let conf = r#"
+            compression_level: 6
+            compress_statuses: [200]
+        "#;
+        assert_eq!(compress_status(conf, 200).await?, Some("gzip".to_owned()));
+        assert_eq!(compress_status(conf, 500).await?, None);
+        assert_eq!(compress_status(conf, 404).await?, None);
+
+        // All status codes are compressed by default
+        let conf = "compression_level: 6";
+        assert_eq!(compress_status(conf, 200).await?, Some("gzip".to_owned()));
+        assert_eq!(compress_status(conf, 500).await?, Some("gzip".to_owned()));
+
+        // Flushing compression respects the restriction as well
+        let handler: CompressionHandler = CompressionConf::from_yaml(
+            r#"
+                compression_level: 6
+                flush: each_chunk
+                compress_statuses: [200, 206]
+            "#,
+        )?
+        .try_into()?;
+        let mut session = make_gzip_session().await;
+        let mut ctx = CompressionHandler::new_ctx();
+        handler.request_filter(&mut session, &mut ctx).await?;
+        let mut response = make_response();
+        response.set_status(500)?;
+        handler.response_filter(&mut session, &mut response, Some(&mut ctx));
+        assert!(response.headers.get("Content-Encoding").is_none());
+        assert!(ctx.encoder.is_none());
+
+        let conf = CompressionConf::from_yaml("compress_statuses: [200, 1000]")?;
+        assert!(CompressionHandler::try_from(conf).is_err());
+        Ok(())
+    }
 }
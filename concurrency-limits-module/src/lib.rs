@@ -0,0 +1,332 @@
+// Copyright 2024 Wladimir Palant
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! # Concurrency Limits Module for Pandora Web Server
+//!
+//! This crate limits the number of requests a single client IP address can have in flight
+//! simultaneously, mitigating abuse by individual clients. Excess requests are rejected with
+//! `429 Too Many Requests`. The limit is set via `concurrency_limit_per_ip` setting:
+//!
+//! ```yaml
+//! concurrency_limit_per_ip: 20
+//! ```
+//!
+//! No limit is enforced if the setting isn’t present. A request is considered in flight from the
+//! moment this module’s handler sees it until its processing completes, regardless of whether a
+//! response has been sent successfully or processing was aborted due to an error. Requests
+//! received via Unix sockets aren’t limited.
+//!
+//! *Note*: If the IP Anonymization module’s handler is called before this one, only anonymized
+//! addresses will be seen. All clients sharing an anonymized address will count towards the same
+//! limit then.
+//!
+//! ## Using the module
+//!
+//! This module’s handler should be called prior to any handlers producing responses:
+//!
+//! ```rust
+//! use concurrency_limits_module::ConcurrencyLimitsHandler;
+//! use pandora_module_utils::{merge_conf, FromYaml, RequestFilter};
+//! use startup_module::{DefaultApp, StartupConf};
+//! use static_files_module::StaticFilesHandler;
+//!
+//! #[derive(Debug, RequestFilter)]
+//! struct Handler {
+//!     concurrency_limits: ConcurrencyLimitsHandler,
+//!     static_files: StaticFilesHandler,
+//! }
+//!
+//! #[merge_conf]
+//! struct Conf {
+//!     startup: StartupConf,
+//!     handler: <Handler as RequestFilter>::Conf,
+//! }
+//!
+//! let conf = Conf::from_yaml("concurrency_limit_per_ip: 20").unwrap();
+//!
+//! let app = DefaultApp::<Handler>::from_conf(conf.handler)
+//!     .unwrap()
+//!     .with_startup_conf(&conf.startup);
+//! let server = conf.startup.into_server(app, None).unwrap();
+//!
+//! // Do something with the server here, e.g. call server.run_forever()
+//! ```
+
+use async_trait::async_trait;
+use http::StatusCode;
+use log::error;
+use pandora_module_utils::pingora::{Error, SessionWrapper, SocketAddr};
+use pandora_module_utils::standard_response::error_response;
+use pandora_module_utils::{DeserializeMap, RequestFilter, RequestFilterResult};
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+
+/// Concurrency limits configuration
+#[derive(Debug, Default, Clone, PartialEq, Eq, DeserializeMap)]
+pub struct ConcurrencyLimitsConf {
+    /// Maximal number of requests a single client IP address can have in flight simultaneously
+    pub concurrency_limit_per_ip: Option<usize>,
+}
+
+type InFlight = Arc<Mutex<HashMap<IpAddr, usize>>>;
+
+/// Handler for Pingora’s `request_filter` phase
+///
+/// Clones share the counters of requests in flight.
+#[derive(Debug, Clone)]
+pub struct ConcurrencyLimitsHandler {
+    conf: ConcurrencyLimitsConf,
+    in_flight: InFlight,
+}
+
+impl PartialEq for ConcurrencyLimitsHandler {
+    fn eq(&self, other: &Self) -> bool {
+        self.conf == other.conf
+    }
+}
+
+impl Eq for ConcurrencyLimitsHandler {}
+
+impl ConcurrencyLimitsHandler {
+    /// Counts a new request from the given IP address. Returns `None` if the limit has been
+    /// reached already, the request isn’t counted then. Otherwise the returned guard keeps the
+    /// request counted until it is dropped.
+    fn acquire(&self, ip: IpAddr, limit: usize) -> Option<InFlightGuard> {
+        let mut in_flight = match self.in_flight.lock() {
+            Ok(in_flight) => in_flight,
+            Err(err) => {
+                error!("Failed acquiring concurrency limits mutex, not limiting request: {err}");
+                return Some(InFlightGuard {
+                    in_flight: None,
+                    ip,
+                });
+            }
+        };
+
+        let count = in_flight.entry(ip).or_default();
+        if *count >= limit {
+            None
+        } else {
+            *count += 1;
+            Some(InFlightGuard {
+                in_flight: Some(self.in_flight.clone()),
+                ip,
+            })
+        }
+    }
+}
+
+/// Per-request state of [`ConcurrencyLimitsHandler`]
+///
+/// Keeps a request counted as in flight, the request is marked as completed when this guard is
+/// dropped. This happens once Pingora is done with the request, even if processing was aborted.
+#[derive(Debug)]
+pub struct InFlightGuard {
+    in_flight: Option<InFlight>,
+    ip: IpAddr,
+}
+
+impl InFlightGuard {
+    /// Client IP address the request is counted for
+    pub fn ip(&self) -> IpAddr {
+        self.ip
+    }
+}
+
+impl Drop for InFlightGuard {
+    fn drop(&mut self) {
+        let Some(in_flight) = self.in_flight.take() else {
+            return;
+        };
+
+        let mut in_flight = match in_flight.lock() {
+            Ok(in_flight) => in_flight,
+            Err(err) => {
+                error!("Failed acquiring concurrency limits mutex, not releasing request: {err}");
+                return;
+            }
+        };
+
+        if let Some(count) = in_flight.get_mut(&self.ip) {
+            *count = count.saturating_sub(1);
+            if *count == 0 {
+                in_flight.remove(&self.ip);
+            }
+        }
+    }
+}
+
+impl TryFrom<ConcurrencyLimitsConf> for ConcurrencyLimitsHandler {
+    type Error = Box<Error>;
+
+    fn try_from(conf: ConcurrencyLimitsConf) -> Result<Self, Self::Error> {
+        Ok(Self {
+            conf,
+            in_flight: Default::default(),
+        })
+    }
+}
+
+#[async_trait]
+impl RequestFilter for ConcurrencyLimitsHandler {
+    type Conf = ConcurrencyLimitsConf;
+
+    type CTX = Option<InFlightGuard>;
+
+    fn new_ctx() -> Self::CTX {
+        None
+    }
+
+    async fn request_filter(
+        &self,
+        session: &mut impl SessionWrapper,
+        ctx: &mut Self::CTX,
+    ) -> Result<RequestFilterResult, Box<Error>> {
+        let limit = match self.conf.concurrency_limit_per_ip {
+            Some(limit) if ctx.is_none() => limit,
+            _ => return Ok(RequestFilterResult::Unhandled),
+        };
+
+        let ip = match session.client_addr() {
+            Some(SocketAddr::Inet(addr)) => addr.ip(),
+            _ => return Ok(RequestFilterResult::Unhandled),
+        };
+
+        if let Some(guard) = self.acquire(ip, limit) {
+            *ctx = Some(guard);
+            return Ok(RequestFilterResult::Unhandled);
+        }
+
+        error_response(session, StatusCode::TOO_MANY_REQUESTS).await?;
+        Ok(RequestFilterResult::ResponseSent)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use pandora_module_utils::pingora::{RequestHeader, TestSession};
+    use pandora_module_utils::standard_response::response_text;
+    use pandora_module_utils::FromYaml;
+    use test_log::test;
+
+    fn make_handler(conf: &str) -> ConcurrencyLimitsHandler {
+        ConcurrencyLimitsHandler::try_from(ConcurrencyLimitsConf::from_yaml(conf).unwrap()).unwrap()
+    }
+
+    async fn make_session(ip: [u8; 4]) -> TestSession {
+        let header = RequestHeader::build("GET", b"/", None).unwrap();
+        TestSession::from(header)
+            .await
+            .with_client_addr(SocketAddr::Inet((ip, 8000).into()))
+    }
+
+    async fn start_request(
+        handler: &ConcurrencyLimitsHandler,
+        session: &mut TestSession,
+    ) -> (Option<u16>, Option<InFlightGuard>) {
+        let mut ctx = ConcurrencyLimitsHandler::new_ctx();
+        let status = match handler.request_filter(session, &mut ctx).await.unwrap() {
+            RequestFilterResult::ResponseSent => {
+                Some(session.response_written().unwrap().status.as_u16())
+            }
+            _ => None,
+        };
+        (status, ctx)
+    }
+
+    #[test(tokio::test)]
+    async fn unconfigured() {
+        let handler = make_handler("{}");
+        for _ in 0..100 {
+            let mut session = make_session([1, 2, 3, 4]).await;
+            let (status, ctx) = start_request(&handler, &mut session).await;
+            assert_eq!(status, None);
+            assert!(ctx.is_none());
+        }
+    }
+
+    #[test(tokio::test)]
+    async fn limit() {
+        let handler = make_handler("concurrency_limit_per_ip: 2");
+        let ip = IpAddr::from([1, 2, 3, 4]);
+
+        let mut requests = Vec::new();
+        for _ in 0..2 {
+            let mut session = make_session([1, 2, 3, 4]).await;
+            let (status, ctx) = start_request(&handler, &mut session).await;
+            assert_eq!(status, None);
+            assert_eq!(ctx.as_ref().map(InFlightGuard::ip), Some(ip));
+            requests.push(ctx);
+        }
+
+        // Limit reached for this IP address
+        let mut session = make_session([1, 2, 3, 4]).await;
+        let (status, ctx) = start_request(&handler, &mut session).await;
+        assert_eq!(status, Some(429));
+        assert!(ctx.is_none());
+        assert_eq!(
+            session.response_body,
+            response_text(StatusCode::TOO_MANY_REQUESTS)
+        );
+
+        // Rejected request doesn’t release anything
+        drop(ctx);
+        let mut session = make_session([1, 2, 3, 4]).await;
+        assert_eq!(start_request(&handler, &mut session).await.0, Some(429));
+
+        // A different IP address is unaffected
+        let mut session = make_session([1, 2, 3, 5]).await;
+        let (status, ctx) = start_request(&handler, &mut session).await;
+        assert_eq!(status, None);
+        drop(ctx);
+
+        // Clones share the counters
+        let mut session = make_session([1, 2, 3, 4]).await;
+        assert_eq!(
+            start_request(&handler.clone(), &mut session).await.0,
+            Some(429)
+        );
+
+        // Once a request completes, a new one is accepted
+        drop(requests.pop());
+        let mut session = make_session([1, 2, 3, 4]).await;
+        let (status, ctx) = start_request(&handler, &mut session).await;
+        assert_eq!(status, None);
+        requests.push(ctx);
+
+        // Counters are removed once all requests complete
+        drop(requests);
+        assert!(handler.in_flight.lock().unwrap().is_empty());
+    }
+
+    #[test(tokio::test)]
+    async fn aborted_request() {
+        let handler = make_handler("concurrency_limit_per_ip: 1");
+
+        // Request processing aborted without reaching the logging phase
+        let mut session = make_session([1, 2, 3, 4]).await;
+        let (status, ctx) = start_request(&handler, &mut session).await;
+        assert_eq!(status, None);
+        let mut session = make_session([1, 2, 3, 4]).await;
+        assert_eq!(start_request(&handler, &mut session).await.0, Some(429));
+        drop(ctx);
+
+        let mut session = make_session([1, 2, 3, 4]).await;
+        assert_eq!(start_request(&handler, &mut session).await.0, None);
+        assert!(handler.in_flight.lock().unwrap().is_empty());
+    }
+}
@@ -0,0 +1,348 @@
+// Copyright 2024 Wladimir Palant
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! # Debug Tap Module for Pandora Web Server
+//!
+//! This crate helps debugging a chain of handlers by logging the request line and request
+//! headers as well as the response status and response headers as they pass through it. The
+//! following configuration options are available:
+//!
+//! * `debug_tap_enabled` (`--debug-tap-enabled` as command line flag): If `true`, requests and
+//!   responses will be logged.
+//! * `debug_tap_level`: Log level to use, one of `error`, `warn`, `info`, `debug` or `trace`.
+//!   `info` is the default.
+//! * `debug_tap_redact`: List of additional headers with values that should not be logged. The
+//!   values of `Authorization`, `Proxy-Authorization`, `Cookie` and `Set-Cookie` headers are
+//!   always redacted.
+//!
+//! For example:
+//!
+//! ```yaml
+//! debug_tap_enabled: true
+//! debug_tap_level: debug
+//! debug_tap_redact: [X-Api-Key]
+//! ```
+//!
+//! Redacted header values are replaced by `***` in the log, the header names are still logged.
+//!
+//! ## Using the module
+//!
+//! This module’s handler only logs the request and response as it sees them. For example, when
+//! it is called prior to any other handler, it logs the original request and the final
+//! response:
+//!
+//! ```rust
+//! use clap::Parser;
+//! use debug_tap_module::{DebugTapHandler, DebugTapOpt};
+//! use pandora_module_utils::{merge_conf, merge_opt, FromYaml, RequestFilter};
+//! use startup_module::{DefaultApp, StartupConf, StartupOpt};
+//! use static_files_module::{StaticFilesHandler, StaticFilesOpt};
+//!
+//! #[derive(Debug, RequestFilter)]
+//! struct Handler {
+//!     debug_tap: DebugTapHandler,
+//!     static_files: StaticFilesHandler,
+//! }
+//!
+//! #[merge_conf]
+//! struct Conf {
+//!     startup: StartupConf,
+//!     handler: <Handler as RequestFilter>::Conf,
+//! }
+//!
+//! #[merge_opt]
+//! struct Opt {
+//!     startup: StartupOpt,
+//!     debug_tap: DebugTapOpt,
+//!     static_files: StaticFilesOpt,
+//! }
+//!
+//! let opt = Opt::parse();
+//! let mut conf = Conf::load_from_files(opt.startup.conf.as_deref().unwrap_or(&[])).unwrap();
+//! conf.handler.debug_tap.merge_with_opt(opt.debug_tap);
+//! conf.handler.static_files.merge_with_opt(opt.static_files);
+//!
+//! let app = DefaultApp::<Handler>::from_conf(conf.handler)
+//!     .unwrap()
+//!     .with_startup_conf(&conf.startup);
+//! let server = conf.startup.into_server(app, Some(opt.startup)).unwrap();
+//!
+//! // Do something with the server here, e.g. call server.run_forever()
+//! ```
+
+use async_trait::async_trait;
+use clap::Parser;
+use http::header::{AUTHORIZATION, COOKIE, PROXY_AUTHORIZATION, SET_COOKIE};
+use http::{HeaderMap, HeaderName};
+use log::{log, Level};
+use pandora_module_utils::pingora::{
+    Error, ErrorType, RequestHeader, ResponseHeader, SessionWrapper,
+};
+use pandora_module_utils::{DeserializeMap, OneOrMany, RequestFilter, RequestFilterResult};
+use std::fmt::Write;
+use std::str::FromStr;
+
+/// Headers with values that are never logged
+const ALWAYS_REDACTED: [HeaderName; 4] = [AUTHORIZATION, PROXY_AUTHORIZATION, COOKIE, SET_COOKIE];
+
+/// Replacement logged instead of redacted header values
+const REDACTED: &str = "***";
+
+/// Command line options of the debug tap module
+#[derive(Debug, Parser)]
+pub struct DebugTapOpt {
+    /// Log request and response headers for debugging
+    #[clap(long)]
+    pub debug_tap_enabled: bool,
+}
+
+/// Debug tap configuration
+#[derive(Debug, Clone, PartialEq, Eq, DeserializeMap)]
+pub struct DebugTapConf {
+    /// If `true`, requests and responses will be logged.
+    pub debug_tap_enabled: bool,
+
+    /// Log level to use: `error`, `warn`, `info`, `debug` or `trace`.
+    pub debug_tap_level: String,
+
+    /// Additional headers with values that should not be logged.
+    pub debug_tap_redact: OneOrMany<String>,
+}
+
+impl Default for DebugTapConf {
+    fn default() -> Self {
+        Self {
+            debug_tap_enabled: false,
+            debug_tap_level: "info".to_owned(),
+            debug_tap_redact: Default::default(),
+        }
+    }
+}
+
+impl DebugTapConf {
+    /// Merges the command line options into the current configuration. Command line options
+    /// present overwrite existing settings.
+    pub fn merge_with_opt(&mut self, opt: DebugTapOpt) {
+        if opt.debug_tap_enabled {
+            self.debug_tap_enabled = true;
+        }
+    }
+}
+
+/// Handler logging requests and responses
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DebugTapHandler {
+    enabled: bool,
+    level: Level,
+    redact: Vec<HeaderName>,
+}
+
+impl DebugTapHandler {
+    fn is_redacted(&self, name: &HeaderName) -> bool {
+        ALWAYS_REDACTED.contains(name) || self.redact.contains(name)
+    }
+
+    fn format_headers(&self, headers: &HeaderMap, result: &mut String) {
+        for (name, value) in headers {
+            let _ = write!(result, "\n{name}: ");
+            if self.is_redacted(name) {
+                result.push_str(REDACTED);
+            } else {
+                result.push_str(&String::from_utf8_lossy(value.as_bytes()));
+            }
+        }
+    }
+
+    fn format_request(&self, header: &RequestHeader) -> String {
+        let mut result = format!("{} {} {:?}", header.method, header.uri, header.version);
+        self.format_headers(&header.headers, &mut result);
+        result
+    }
+
+    fn format_response(&self, header: &ResponseHeader) -> String {
+        let mut result = format!("{:?} {}", header.version, header.status);
+        self.format_headers(&header.headers, &mut result);
+        result
+    }
+}
+
+impl TryFrom<DebugTapConf> for DebugTapHandler {
+    type Error = Box<Error>;
+
+    fn try_from(conf: DebugTapConf) -> Result<Self, Self::Error> {
+        let level = Level::from_str(&conf.debug_tap_level).map_err(|err| {
+            Error::because(
+                ErrorType::InternalError,
+                format!(
+                    "Invalid log level {} in debug_tap_level",
+                    conf.debug_tap_level
+                ),
+                err,
+            )
+        })?;
+
+        let redact = conf
+            .debug_tap_redact
+            .iter()
+            .map(|name| {
+                HeaderName::from_str(name).map_err(|err| {
+                    Error::because(
+                        ErrorType::InternalError,
+                        format!("Invalid header name {name} in debug_tap_redact"),
+                        err,
+                    )
+                })
+            })
+            .collect::<Result<_, _>>()?;
+
+        Ok(Self {
+            enabled: conf.debug_tap_enabled,
+            level,
+            redact,
+        })
+    }
+}
+
+#[async_trait]
+impl RequestFilter for DebugTapHandler {
+    type Conf = DebugTapConf;
+
+    type CTX = ();
+
+    fn new_ctx() -> Self::CTX {}
+
+    async fn request_filter(
+        &self,
+        session: &mut impl SessionWrapper,
+        _ctx: &mut Self::CTX,
+    ) -> Result<RequestFilterResult, Box<Error>> {
+        if self.enabled {
+            log!(
+                self.level,
+                "request: {}",
+                self.format_request(session.req_header())
+            );
+        }
+        Ok(RequestFilterResult::Unhandled)
+    }
+
+    fn response_filter(
+        &self,
+        _session: &mut impl SessionWrapper,
+        response: &mut ResponseHeader,
+        _ctx: Option<&mut Self::CTX>,
+    ) {
+        if self.enabled {
+            log!(self.level, "response: {}", self.format_response(response));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use pandora_module_utils::pingora::TestSession;
+    use pandora_module_utils::FromYaml;
+    use test_log::test;
+
+    fn make_handler(conf: &str) -> DebugTapHandler {
+        <DebugTapHandler as RequestFilter>::Conf::from_yaml(conf)
+            .unwrap()
+            .try_into()
+            .unwrap()
+    }
+
+    fn make_request() -> RequestHeader {
+        let mut header = RequestHeader::build("GET", b"/file.txt?a=b", None).unwrap();
+        header.insert_header("Host", "localhost").unwrap();
+        header
+            .insert_header("Authorization", "Basic bWU6c2VjcmV0")
+            .unwrap();
+        header.insert_header("Cookie", "session=secret").unwrap();
+        header.insert_header("X-Api-Key", "secret-key").unwrap();
+        header
+    }
+
+    #[test(tokio::test)]
+    async fn passthrough() -> Result<(), Box<Error>> {
+        for conf in ["debug_tap_enabled: false", "debug_tap_enabled: true"] {
+            let handler = make_handler(conf);
+            let mut session = TestSession::from(make_request()).await;
+            assert_eq!(
+                handler.request_filter(&mut session, &mut ()).await?,
+                RequestFilterResult::Unhandled
+            );
+
+            let mut response = ResponseHeader::build(200, None)?;
+            response.insert_header("Content-Type", "text/plain")?;
+            handler.response_filter(&mut session, &mut response, None);
+            assert_eq!(response.status, 200);
+            assert_eq!(response.headers.len(), 1);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn request_redaction() {
+        let handler = make_handler("debug_tap_enabled: true");
+        let logged = handler.format_request(&make_request());
+        assert!(logged.starts_with("GET /file.txt?a=b HTTP/1.1\n"));
+        assert!(logged.contains("\nhost: localhost"));
+        assert!(logged.contains("\nauthorization: ***"));
+        assert!(logged.contains("\ncookie: ***"));
+        assert!(logged.contains("\nx-api-key: secret-key"));
+        assert!(!logged.contains("bWU6c2VjcmV0"));
+        assert!(!logged.contains("session=secret"));
+
+        let handler = make_handler(
+            r#"
+                debug_tap_enabled: true
+                debug_tap_redact: [X-Api-Key]
+            "#,
+        );
+        let logged = handler.format_request(&make_request());
+        assert!(logged.contains("\nauthorization: ***"));
+        assert!(logged.contains("\nx-api-key: ***"));
+        assert!(!logged.contains("secret-key"));
+    }
+
+    #[test]
+    fn response_redaction() -> Result<(), Box<Error>> {
+        let handler = make_handler("debug_tap_enabled: true");
+        let mut response = ResponseHeader::build(404, None)?;
+        response.insert_header("Content-Type", "text/html")?;
+        response.insert_header("Set-Cookie", "session=secret")?;
+
+        let logged = handler.format_response(&response);
+        assert!(logged.starts_with("HTTP/1.1 404 Not Found\n"));
+        assert!(logged.contains("\ncontent-type: text/html"));
+        assert!(logged.contains("\nset-cookie: ***"));
+        assert!(!logged.contains("session=secret"));
+        Ok(())
+    }
+
+    #[test]
+    fn configuration() -> Result<(), Box<Error>> {
+        assert_eq!(make_handler("debug_tap_enabled: true").level, Level::Info);
+        assert_eq!(make_handler("debug_tap_level: TRACE").level, Level::Trace);
+
+        let conf = DebugTapConf::from_yaml("debug_tap_level: verbose")?;
+        assert!(DebugTapHandler::try_from(conf).is_err());
+
+        let conf = DebugTapConf::from_yaml("debug_tap_redact: [\"in valid\"]")?;
+        assert!(DebugTapHandler::try_from(conf).is_err());
+        Ok(())
+    }
+}
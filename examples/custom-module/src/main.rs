@@ -91,9 +91,12 @@ fn main() {
     conf.handler.auth.merge_with_opt(opt.auth);
     conf.handler.web_app.merge_with_opt(opt.web_app);
 
-    let server = match DefaultApp::<Handler>::from_conf(conf.handler)
-        .and_then(|app| conf.startup.into_server(app, Some(opt.startup)))
-    {
+    let server = match DefaultApp::<Handler>::from_conf(conf.handler).and_then(|app| {
+        let app = app.with_startup_conf(&conf.startup);
+        conf.startup
+            .into_server(app, Some(opt.startup))
+            .map_err(Into::into)
+    }) {
         Ok(server) => server,
         Err(err) => {
             error!("{err}");
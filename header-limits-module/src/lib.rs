@@ -0,0 +1,199 @@
+// Copyright 2024 Wladimir Palant
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! # Header Limits Module for Pandora Web Server
+//!
+//! This crate rejects requests with an excessive number of headers or excessively large headers,
+//! responding with `431 Request Header Fields Too Large`. The following configuration options
+//! are available:
+//!
+//! * `header_limits_max_count`: Maximal number of request headers
+//! * `header_limits_max_size`: Maximal combined size of request headers in bytes. Each header is
+//!   counted as it would appear in an HTTP/1.1 request, as `name: value` followed by a line break.
+//!
+//! For example:
+//!
+//! ```yaml
+//! header_limits_max_count: 100
+//! header_limits_max_size: 8192
+//! ```
+//!
+//! No limit is enforced if the respective setting isn’t present.
+//!
+//! ## Using the module
+//!
+//! This module’s handler should be called prior to any handlers producing responses:
+//!
+//! ```rust
+//! use header_limits_module::HeaderLimitsHandler;
+//! use pandora_module_utils::{merge_conf, FromYaml, RequestFilter};
+//! use startup_module::{DefaultApp, StartupConf};
+//! use static_files_module::StaticFilesHandler;
+//!
+//! #[derive(Debug, RequestFilter)]
+//! struct Handler {
+//!     header_limits: HeaderLimitsHandler,
+//!     static_files: StaticFilesHandler,
+//! }
+//!
+//! #[merge_conf]
+//! struct Conf {
+//!     startup: StartupConf,
+//!     handler: <Handler as RequestFilter>::Conf,
+//! }
+//!
+//! let conf = Conf::from_yaml("header_limits_max_count: 100").unwrap();
+//!
+//! let app = DefaultApp::<Handler>::from_conf(conf.handler)
+//!     .unwrap()
+//!     .with_startup_conf(&conf.startup);
+//! let server = conf.startup.into_server(app, None).unwrap();
+//!
+//! // Do something with the server here, e.g. call server.run_forever()
+//! ```
+
+use async_trait::async_trait;
+use http::StatusCode;
+use pandora_module_utils::pingora::{Error, SessionWrapper};
+use pandora_module_utils::standard_response::error_response;
+use pandora_module_utils::{DeserializeMap, RequestFilter, RequestFilterResult};
+
+/// Header limits configuration
+#[derive(Debug, Default, Clone, PartialEq, Eq, DeserializeMap)]
+pub struct HeaderLimitsConf {
+    /// Maximal number of request headers
+    pub header_limits_max_count: Option<usize>,
+
+    /// Maximal combined size of request headers in bytes
+    pub header_limits_max_size: Option<usize>,
+}
+
+/// Handler for Pingora’s `request_filter` phase
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HeaderLimitsHandler {
+    conf: HeaderLimitsConf,
+}
+
+impl HeaderLimitsHandler {
+    fn exceeds_limits(&self, count: usize, size: usize) -> bool {
+        self.conf
+            .header_limits_max_count
+            .is_some_and(|max_count| count > max_count)
+            || self
+                .conf
+                .header_limits_max_size
+                .is_some_and(|max_size| size > max_size)
+    }
+}
+
+impl TryFrom<HeaderLimitsConf> for HeaderLimitsHandler {
+    type Error = Box<Error>;
+
+    fn try_from(conf: HeaderLimitsConf) -> Result<Self, Self::Error> {
+        Ok(Self { conf })
+    }
+}
+
+#[async_trait]
+impl RequestFilter for HeaderLimitsHandler {
+    type Conf = HeaderLimitsConf;
+
+    type CTX = ();
+
+    fn new_ctx() -> Self::CTX {}
+
+    async fn request_filter(
+        &self,
+        session: &mut impl SessionWrapper,
+        _ctx: &mut Self::CTX,
+    ) -> Result<RequestFilterResult, Box<Error>> {
+        let (count, size) = session.request_header_stats();
+        if !self.exceeds_limits(count, size) {
+            return Ok(RequestFilterResult::Unhandled);
+        }
+
+        session.set_keepalive(None);
+        error_response(session, StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE).await?;
+        Ok(RequestFilterResult::ResponseSent)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use pandora_module_utils::pingora::{RequestHeader, TestSession};
+    use pandora_module_utils::standard_response::response_text;
+    use pandora_module_utils::FromYaml;
+    use test_log::test;
+
+    fn make_handler(conf: &str) -> HeaderLimitsHandler {
+        HeaderLimitsHandler::try_from(HeaderLimitsConf::from_yaml(conf).unwrap()).unwrap()
+    }
+
+    async fn make_session(headers: &[(&'static str, &str)]) -> TestSession {
+        let mut header = RequestHeader::build("GET", b"/", None).unwrap();
+        for (name, value) in headers {
+            header.append_header(*name, *value).unwrap();
+        }
+        TestSession::from(header).await
+    }
+
+    async fn status(handler: &HeaderLimitsHandler, session: &mut TestSession) -> Option<u16> {
+        match handler.request_filter(session, &mut ()).await.unwrap() {
+            RequestFilterResult::ResponseSent => {
+                Some(session.response_written().unwrap().status.as_u16())
+            }
+            _ => None,
+        }
+    }
+
+    #[test(tokio::test)]
+    async fn unconfigured() {
+        let handler = make_handler("{}");
+        let headers = vec![("X-Test", "a"); 1000];
+        let mut session = make_session(&headers).await;
+        assert_eq!(status(&handler, &mut session).await, None);
+    }
+
+    #[test(tokio::test)]
+    async fn max_count() {
+        // TestSession adds Content-Length header, so there is always one header more
+        let handler = make_handler("header_limits_max_count: 3");
+
+        let mut session = make_session(&[("Host", "localhost"), ("X-Test", "a")]).await;
+        assert_eq!(status(&handler, &mut session).await, None);
+
+        let mut session =
+            make_session(&[("Host", "localhost"), ("X-Test", "a"), ("X-Test", "b")]).await;
+        assert_eq!(status(&handler, &mut session).await, Some(431));
+        assert_eq!(
+            session.response_body,
+            response_text(StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE)
+        );
+    }
+
+    #[test(tokio::test)]
+    async fn max_size() {
+        let handler = make_handler("header_limits_max_size: 100");
+
+        let mut session = make_session(&[("Host", "localhost")]).await;
+        assert_eq!(status(&handler, &mut session).await, None);
+
+        let long_value = "a".repeat(100);
+        let mut session =
+            make_session(&[("Host", "localhost"), ("X-Test", long_value.as_str())]).await;
+        assert_eq!(status(&handler, &mut session).await, Some(431));
+    }
+}
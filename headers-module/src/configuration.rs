@@ -248,6 +248,30 @@ macro_rules! impl_conf {
             HeaderValue::from_str(&$list.join("; ")).unwrap(),
         )]
     };
+
+    // Strict-Transport-Security types
+    (doc($header_name:literal, hsts Option<usize>)) => {
+        concat!("If set, ", $header_name, " directive will be sent")
+    };
+    (doc($header_name:literal, hsts bool)) => {
+        concat!("If `true`, ", $header_name, " directive will be sent")
+    };
+    (push($list:expr, $header_name:literal, $value:expr, hsts Option<usize>)) => {
+        if let Some(value) = $value {
+            $list.push(format!(concat!($header_name, "={}"), value).into());
+        }
+    };
+    (push($list:expr, $header_name:literal, $value:expr, hsts bool)) => {
+        if $value {
+            $list.push($header_name.into());
+        }
+    };
+    (finalize($list:expr, hsts)) => {
+        vec![(
+            header::STRICT_TRANSPORT_SECURITY,
+            HeaderValue::from_str(&$list.join("; ")).unwrap(),
+        )]
+    };
 }
 
 impl_conf! {cache_control:
@@ -301,6 +325,15 @@ impl_conf! {csp:
     }
 }
 
+impl_conf! {hsts:
+    /// Configuration for the Strict-Transport-Security header
+    pub struct StrictTransportSecurityConf {
+        max_age("max-age", Option<usize>),
+        include_subdomains("includeSubDomains", bool),
+        preload("preload", bool),
+    }
+}
+
 /// Custom headers configuration
 #[derive(Debug, Default, Clone, PartialEq, Eq)]
 pub struct CustomHeadersConf {
@@ -331,6 +364,10 @@ pub struct HeadersInnerConf {
     /// Content-Security-Policy header
     pub content_security_policy: OneOrMany<WithMatchRules<ContentSecurityPolicyConf>>,
 
+    /// Strict-Transport-Security header, only sent with HTTPS responses
+    #[pandora(alias = "hsts")]
+    pub strict_transport_security: OneOrMany<WithMatchRules<StrictTransportSecurityConf>>,
+
     /// Custom headers, headers configures as name => value map here
     pub custom: OneOrMany<WithMatchRules<CustomHeadersConf>>,
 }
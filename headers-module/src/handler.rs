@@ -13,7 +13,7 @@
 // limitations under the License.
 
 use async_trait::async_trait;
-use http::{HeaderName, HeaderValue};
+use http::{header, HeaderName, HeaderValue};
 use log::{debug, trace};
 use pandora_module_utils::merger::{Merger, StrictHostPathMatcher};
 use pandora_module_utils::pingora::{Error, ResponseHeader, SessionWrapper};
@@ -56,10 +56,12 @@ impl TryFrom<HeadersConf> for HeadersHandler {
 
         let cache_control = merge_rules(value.response_headers.cache_control);
         let content_security_policy = merge_rules(value.response_headers.content_security_policy);
+        let strict_transport_security =
+            merge_rules(value.response_headers.strict_transport_security);
         let custom = merge_rules(value.response_headers.custom);
 
         let mut merged = cache_control;
-        merged.extend([content_security_policy, custom]);
+        merged.extend([content_security_policy, strict_transport_security, custom]);
         trace!("Merged headers configuration into: {merged:#?}");
 
         let router = merged.merge(|values| {
@@ -125,8 +127,15 @@ impl RequestFilter for HeadersHandler {
         response: &mut ResponseHeader,
         _ctx: Option<&mut <Self as RequestFilter>::CTX>,
     ) {
+        // Strict-Transport-Security header must not be sent over plaintext connections
+        // https://datatracker.ietf.org/doc/html/rfc6797#section-7.2
+        let https = session.scheme(false) == "https";
         if let Some(HeadersList(list)) = session.extensions().get() {
             for (name, value) in list.iter() {
+                if name == header::STRICT_TRANSPORT_SECURITY && !https {
+                    continue;
+                }
+
                 // Conversion from HeaderName/HeaderValue is infallible, ignore errors.
                 let _ = response.insert_header(name, value);
             }
@@ -139,7 +148,6 @@ impl RequestFilter for HeadersHandler {
 mod tests {
     use super::*;
 
-    use http::header;
     use pandora_module_utils::pingora::{ProxyHttp, RequestHeader, TestSession};
     use pandora_module_utils::{DeserializeMap, FromYaml};
     use startup_module::DefaultApp;
@@ -611,4 +619,98 @@ mod tests {
 
         Ok(())
     }
+
+    fn make_hsts_app(send_response: bool) -> DefaultApp<Handler> {
+        DefaultApp::new(
+            <Handler as RequestFilter>::Conf::from_yaml(format!(
+                r#"
+                send_response: {send_response}
+                response_headers:
+                    hsts:
+                    -
+                        max-age: 31536000
+                        includeSubDomains: true
+                    -
+                        preload: true
+                        include: example.com
+                    -
+                        max-age: 300
+                        include: example.net
+            "#,
+            ))
+            .unwrap()
+            .try_into()
+            .unwrap(),
+        )
+    }
+
+    #[test(tokio::test)]
+    async fn strict_transport_security() -> Result<(), Box<Error>> {
+        let app = make_hsts_app(true);
+
+        for (path, expected) in [
+            (
+                "https://example.com/",
+                "max-age=31536000; includeSubDomains; preload",
+            ),
+            ("https://example.net/", "max-age=300; includeSubDomains"),
+            ("https://localhost/", "max-age=31536000; includeSubDomains"),
+        ] {
+            let mut session = make_session(path)
+                .await
+                .with_tls(Some("example.com"), "TLSv1.3");
+            assert!(app.request_filter(&mut session, &mut app.new_ctx()).await?);
+            assert_headers(
+                session.deref().response_written().unwrap(),
+                vec![
+                    ("X-Me", "none"),
+                    ("X-Test", "unchanged"),
+                    ("Strict-Transport-Security", expected),
+                ],
+            );
+        }
+
+        // Plaintext responses don’t get the header
+        let mut session = make_session("http://example.com/").await;
+        assert!(app.request_filter(&mut session, &mut app.new_ctx()).await?);
+        assert_headers(
+            session.deref().response_written().unwrap(),
+            vec![("X-Me", "none"), ("X-Test", "unchanged")],
+        );
+
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn strict_transport_security_upstream() -> Result<(), Box<Error>> {
+        let app = make_hsts_app(false);
+
+        let mut session = make_session("https://example.com/")
+            .await
+            .with_tls(Some("example.com"), "TLSv1.3");
+        let mut ctx = app.new_ctx();
+        assert!(!app.request_filter(&mut session, &mut ctx).await?);
+        let mut header = make_response_header()?;
+        app.upstream_response_filter(&mut session, &mut header, &mut ctx);
+        assert_headers(
+            &header,
+            vec![
+                ("X-Me", "none"),
+                ("X-Test", "unchanged"),
+                (
+                    "Strict-Transport-Security",
+                    "max-age=31536000; includeSubDomains; preload",
+                ),
+            ],
+        );
+
+        let mut session = make_session("http://example.com/").await;
+        let mut ctx = app.new_ctx();
+        assert!(!app.request_filter(&mut session, &mut ctx).await?);
+        let mut header = make_response_header()?;
+        app.upstream_response_filter(&mut session, &mut header, &mut ctx);
+        assert_headers(&header, vec![("X-Me", "none"), ("X-Test", "unchanged")]);
+
+        Ok(())
+    }
 }
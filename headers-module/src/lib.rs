@@ -124,6 +124,26 @@
 //! Finally, `upgrade-insecure-requests` directive is a boolean value. It should be set to `true`
 //! to enable this directive in the output. Setting it to `false` has no effect.
 //!
+//! ## `strict_transport_security` section
+//!
+//! The `strict_transport_security` section (also available under the shorter name `hsts`)
+//! composes the [`Strict-Transport-Security` header](https://developer.mozilla.org/en-US/docs/Web/HTTP/Headers/Strict-Transport-Security).
+//! The numeric `max-age` setting is required for the header to be valid. The boolean settings
+//! `includeSubDomains` and `preload` should be set to `true` to add the respective directives:
+//!
+//! ```yaml
+//! response_headers:
+//!     hsts:
+//!         max-age: 31536000
+//!         includeSubDomains: true
+//!         preload: true
+//!         include: example.com
+//! ```
+//!
+//! As required by the specification, this header is only sent with responses to HTTPS requests.
+//! Responses to plaintext HTTP requests never get it, regardless of the configuration. This also
+//! applies if a `Strict-Transport-Security` header is configured in the `custom` section.
+//!
 //! ## `custom` section
 //!
 //! The `custom` section maps header names to header values. These headers will be sent to the
@@ -190,7 +210,9 @@
 //! let mut conf = Conf::load_from_files(opt.startup.conf.as_deref().unwrap_or(&[])).unwrap();
 //! conf.handler.compression.merge_with_opt(opt.compression);
 //!
-//! let app = DefaultApp::<Handler>::from_conf(conf.handler).unwrap();
+//! let app = DefaultApp::<Handler>::from_conf(conf.handler)
+//!     .unwrap()
+//!     .with_startup_conf(&conf.startup);
 //! let server = conf.startup.into_server(app, Some(opt.startup)).unwrap();
 //!
 //! // Do something with the server here, e.g. call server.run_forever()
@@ -0,0 +1,351 @@
+// Copyright 2024 Wladimir Palant
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! # Health Module for Pandora Web Server
+//!
+//! This crate provides endpoints that container orchestration systems like Kubernetes can use to
+//! check the state of the web server. The following configuration options are available:
+//!
+//! * `health_liveness_path`: Path of the liveness endpoint, e.g. `/livez`. Requests to this path
+//!   always receive a `200 OK` response while the server is running.
+//! * `health_readiness_path`: Path of the readiness endpoint, e.g. `/readyz`. Requests to this
+//!   path receive a `200 OK` response if the server is ready to handle requests and
+//!   `503 Service Unavailable` otherwise.
+//!
+//! For example:
+//!
+//! ```yaml
+//! health_liveness_path: /livez
+//! health_readiness_path: /readyz
+//! ```
+//!
+//! Either endpoint is disabled if its path isn’t configured.
+//!
+//! ## Readiness state
+//!
+//! The server is considered ready while the shared [`Readiness`] flag is set. Initially, the
+//! server isn’t ready. The background service returned by [`readiness_service`] marks the server
+//! as ready once it starts up and as not ready when graceful shutdown starts. Alternatively, the
+//! application can call [`Readiness::set_ready`] on the flag returned by [`Readiness::global`]
+//! whenever it sees fit.
+//!
+//! ## Using the module
+//!
+//! This module’s handler should be called prior to any handlers producing responses. The
+//! background service has to be registered with the server:
+//!
+//! ```rust
+//! use health_module::{readiness_service, HealthHandler, Readiness};
+//! use pandora_module_utils::{merge_conf, FromYaml, RequestFilter};
+//! use startup_module::{DefaultApp, StartupConf};
+//! use static_files_module::StaticFilesHandler;
+//!
+//! #[derive(Debug, RequestFilter)]
+//! struct Handler {
+//!     health: HealthHandler,
+//!     static_files: StaticFilesHandler,
+//! }
+//!
+//! #[merge_conf]
+//! struct Conf {
+//!     startup: StartupConf,
+//!     handler: <Handler as RequestFilter>::Conf,
+//! }
+//!
+//! let conf = Conf::from_yaml("health_readiness_path: /readyz").unwrap();
+//!
+//! let app = DefaultApp::<Handler>::from_conf(conf.handler)
+//!     .unwrap()
+//!     .with_startup_conf(&conf.startup);
+//! let service = readiness_service(Readiness::global());
+//! let server = conf
+//!     .startup
+//!     .into_server_with_services(app, None, vec![Box::new(service)])
+//!     .unwrap();
+//!
+//! // Do something with the server here, e.g. call server.run_forever()
+//! ```
+
+use async_trait::async_trait;
+use http::{header, Method, StatusCode};
+use log::info;
+use pandora_module_utils::pingora::{
+    background_service, BackgroundService, Error, ResponseHeader, Service, SessionWrapper,
+    ShutdownWatch,
+};
+use pandora_module_utils::{DeserializeMap, RequestFilter, RequestFilterResult};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, OnceLock};
+
+/// Health endpoints configuration
+#[derive(Debug, Default, Clone, PartialEq, Eq, DeserializeMap)]
+pub struct HealthConf {
+    /// Path of the liveness endpoint, always responding with `200 OK`
+    pub health_liveness_path: Option<String>,
+
+    /// Path of the readiness endpoint, responding with `503 Service Unavailable` while the server
+    /// isn’t ready
+    pub health_readiness_path: Option<String>,
+}
+
+/// A flag indicating whether the server is ready to handle requests
+///
+/// Clones of this flag share the state, changing it via one copy affects all of them.
+#[derive(Debug, Clone, Default)]
+pub struct Readiness(Arc<AtomicBool>);
+
+impl Readiness {
+    /// Creates a new flag with the given initial state.
+    pub fn new(ready: bool) -> Self {
+        Self(Arc::new(AtomicBool::new(ready)))
+    }
+
+    /// Returns the process-wide flag, this is the one used by handlers created from
+    /// configuration. It is initially not set.
+    pub fn global() -> Self {
+        static GLOBAL: OnceLock<Readiness> = OnceLock::new();
+        GLOBAL.get_or_init(Default::default).clone()
+    }
+
+    /// Checks whether the server is ready.
+    pub fn is_ready(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+
+    /// Changes the readiness state.
+    pub fn set_ready(&self, ready: bool) {
+        self.0.store(ready, Ordering::Relaxed);
+    }
+}
+
+impl PartialEq for Readiness {
+    fn eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.0, &other.0)
+    }
+}
+
+impl Eq for Readiness {}
+
+/// Background service keeping track of the server’s readiness, see [`readiness_service`]
+#[derive(Debug)]
+pub struct ReadinessService {
+    readiness: Readiness,
+}
+
+#[async_trait]
+impl BackgroundService for ReadinessService {
+    async fn start(&self, mut shutdown: ShutdownWatch) {
+        self.readiness.set_ready(true);
+        while !*shutdown.borrow() {
+            if shutdown.changed().await.is_err() {
+                break;
+            }
+        }
+        info!("shutdown started, server is no longer ready");
+        self.readiness.set_ready(false);
+    }
+}
+
+/// Creates a background service that sets the readiness flag once the server starts and resets
+/// it when the server is shutting down. The service needs to be registered with the server, e.g.
+/// via `StartupConf::into_server_with_services`.
+pub fn readiness_service(readiness: Readiness) -> impl Service {
+    background_service("readiness", ReadinessService { readiness })
+}
+
+/// Handler for Pingora’s `request_filter` phase
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HealthHandler {
+    conf: HealthConf,
+    readiness: Readiness,
+}
+
+impl HealthHandler {
+    /// Makes the handler use a different readiness flag than [`Readiness::global`].
+    pub fn with_readiness(mut self, readiness: Readiness) -> Self {
+        self.readiness = readiness;
+        self
+    }
+}
+
+impl TryFrom<HealthConf> for HealthHandler {
+    type Error = Box<Error>;
+
+    fn try_from(conf: HealthConf) -> Result<Self, Self::Error> {
+        Ok(Self {
+            conf,
+            readiness: Readiness::global(),
+        })
+    }
+}
+
+async fn health_response(
+    session: &mut impl SessionWrapper,
+    status: StatusCode,
+) -> Result<(), Box<Error>> {
+    let text = status.canonical_reason().unwrap_or_default();
+
+    let mut header = ResponseHeader::build(status, Some(3))?;
+    header.append_header(header::CONTENT_LENGTH, text.len().to_string())?;
+    header.append_header(header::CONTENT_TYPE, "text/plain")?;
+    header.append_header(header::CACHE_CONTROL, "no-store")?;
+    session.write_response_header(Box::new(header)).await?;
+
+    if session.req_header().method != Method::HEAD {
+        session.write_response_body(text.into()).await?;
+    }
+
+    Ok(())
+}
+
+#[async_trait]
+impl RequestFilter for HealthHandler {
+    type Conf = HealthConf;
+
+    type CTX = ();
+
+    fn new_ctx() -> Self::CTX {}
+
+    async fn request_filter(
+        &self,
+        session: &mut impl SessionWrapper,
+        _ctx: &mut Self::CTX,
+    ) -> Result<RequestFilterResult, Box<Error>> {
+        let path = session.uri().path();
+        let status = if self.conf.health_liveness_path.as_deref() == Some(path) {
+            StatusCode::OK
+        } else if self.conf.health_readiness_path.as_deref() == Some(path) {
+            if self.readiness.is_ready() {
+                StatusCode::OK
+            } else {
+                StatusCode::SERVICE_UNAVAILABLE
+            }
+        } else {
+            return Ok(RequestFilterResult::Unhandled);
+        };
+
+        health_response(session, status).await?;
+        Ok(RequestFilterResult::ResponseSent)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use pandora_module_utils::pingora::{RequestHeader, TestSession};
+    use pandora_module_utils::FromYaml;
+    use test_log::test;
+
+    fn make_handler(conf: &str, readiness: &Readiness) -> HealthHandler {
+        HealthHandler::try_from(HealthConf::from_yaml(conf).unwrap())
+            .unwrap()
+            .with_readiness(readiness.clone())
+    }
+
+    async fn status(handler: &HealthHandler, path: &str) -> Result<Option<u16>, Box<Error>> {
+        let header = RequestHeader::build("GET", path.as_bytes(), None)?;
+        let mut session = TestSession::from(header).await;
+        match handler.request_filter(&mut session, &mut ()).await? {
+            RequestFilterResult::ResponseSent => {
+                Ok(Some(session.response_written().unwrap().status.as_u16()))
+            }
+            _ => Ok(None),
+        }
+    }
+
+    #[test(tokio::test)]
+    async fn unconfigured() -> Result<(), Box<Error>> {
+        let readiness = Readiness::new(true);
+        let handler = make_handler("{}", &readiness);
+        assert_eq!(status(&handler, "/livez").await?, None);
+        assert_eq!(status(&handler, "/readyz").await?, None);
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn readiness() -> Result<(), Box<Error>> {
+        let readiness = Readiness::new(false);
+        let handler = make_handler(
+            r#"
+                health_liveness_path: /livez
+                health_readiness_path: /readyz
+            "#,
+            &readiness,
+        );
+
+        assert_eq!(status(&handler, "/livez").await?, Some(200));
+        assert_eq!(status(&handler, "/readyz").await?, Some(503));
+        assert_eq!(status(&handler, "/other").await?, None);
+
+        readiness.set_ready(true);
+        assert_eq!(status(&handler, "/livez").await?, Some(200));
+        assert_eq!(status(&handler, "/readyz").await?, Some(200));
+
+        readiness.set_ready(false);
+        assert_eq!(status(&handler, "/livez").await?, Some(200));
+        assert_eq!(status(&handler, "/readyz").await?, Some(503));
+
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn response() -> Result<(), Box<Error>> {
+        let readiness = Readiness::new(false);
+        let handler = make_handler("health_readiness_path: /readyz", &readiness);
+
+        let header = RequestHeader::build("GET", b"/readyz", None)?;
+        let mut session = TestSession::from(header).await;
+        handler.request_filter(&mut session, &mut ()).await?;
+        let response = session.response_written().unwrap();
+        assert_eq!(response.status, 503);
+        assert_eq!(response.headers.get("Cache-Control").unwrap(), "no-store");
+        assert_eq!(session.response_body, "Service Unavailable");
+
+        let header = RequestHeader::build("HEAD", b"/readyz", None)?;
+        let mut session = TestSession::from(header).await;
+        handler.request_filter(&mut session, &mut ()).await?;
+        assert_eq!(session.response_written().unwrap().status, 503);
+        assert!(session.response_body.is_empty());
+
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn service() {
+        let readiness = Readiness::new(false);
+        let service = ReadinessService {
+            readiness: readiness.clone(),
+        };
+
+        let (sender, receiver) = tokio::sync::watch::channel(false);
+        let task = tokio::spawn(async move { service.start(receiver).await });
+        while !readiness.is_ready() {
+            tokio::task::yield_now().await;
+        }
+
+        sender.send(true).unwrap();
+        task.await.unwrap();
+        assert!(!readiness.is_ready());
+    }
+
+    #[test]
+    fn global() {
+        assert_eq!(Readiness::global(), Readiness::global());
+        assert_ne!(Readiness::global(), Readiness::new(false));
+
+        let handler = HealthHandler::try_from(HealthConf::default()).unwrap();
+        assert_eq!(handler.readiness, Readiness::global());
+    }
+}
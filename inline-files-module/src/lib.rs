@@ -0,0 +1,263 @@
+// Copyright 2024 Wladimir Palant
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! # Inline Files Module for Pandora Web Server
+//!
+//! This crate allows serving small files like `/robots.txt` or `/.well-known/security.txt`
+//! directly from the configuration, without having to manage actual files for each virtual host.
+//! The `inline_files` setting maps URI paths to the file contents:
+//!
+//! ```yaml
+//! inline_files:
+//!     /robots.txt:
+//!         content: |
+//!             User-agent: *
+//!             Disallow: /private/
+//!     /.well-known/security.txt:
+//!         content: |
+//!             Contact: mailto:security@example.com
+//!             Expires: 2030-01-01T00:00:00.000Z
+//!     /favicon.svg:
+//!         content: <svg xmlns="http://www.w3.org/2000/svg"/>
+//!         content_type: image/svg+xml
+//! ```
+//!
+//! The `content_type` setting is optional, `text/plain; charset=utf-8` is used by default. Paths
+//! have to match exactly. Requests for other paths are passed on to the next handler.
+//!
+//! ## Using the module
+//!
+//! This module’s handler should be called prior to handlers that would otherwise handle the
+//! configured paths, e.g. Static Files module:
+//!
+//! ```rust
+//! use inline_files_module::InlineFilesHandler;
+//! use pandora_module_utils::{merge_conf, FromYaml, RequestFilter};
+//! use startup_module::{DefaultApp, StartupConf};
+//! use static_files_module::StaticFilesHandler;
+//!
+//! #[derive(Debug, RequestFilter)]
+//! struct Handler {
+//!     inline_files: InlineFilesHandler,
+//!     static_files: StaticFilesHandler,
+//! }
+//!
+//! #[merge_conf]
+//! struct Conf {
+//!     startup: StartupConf,
+//!     handler: <Handler as RequestFilter>::Conf,
+//! }
+//!
+//! let conf = Conf::from_yaml(
+//!     r#"
+//!         inline_files:
+//!             /robots.txt:
+//!                 content: "User-agent: *\nDisallow: /\n"
+//!     "#,
+//! )
+//! .unwrap();
+//!
+//! let app = DefaultApp::<Handler>::from_conf(conf.handler)
+//!     .unwrap()
+//!     .with_startup_conf(&conf.startup);
+//! let server = conf.startup.into_server(app, None).unwrap();
+//!
+//! // Do something with the server here, e.g. call server.run_forever()
+//! ```
+
+use async_trait::async_trait;
+use http::{header, Method, StatusCode};
+use pandora_module_utils::pingora::{Error, ResponseHeader, SessionWrapper};
+use pandora_module_utils::standard_response::error_response;
+use pandora_module_utils::{DeserializeMap, RequestFilter, RequestFilterResult};
+use std::collections::HashMap;
+
+/// Default MIME type of inline files
+const DEFAULT_CONTENT_TYPE: &str = "text/plain; charset=utf-8";
+
+/// Contents of a single file
+#[derive(Debug, Default, Clone, PartialEq, Eq, DeserializeMap)]
+pub struct InlineFile {
+    /// File contents
+    pub content: String,
+
+    /// MIME type of the file, `text/plain; charset=utf-8` if not set
+    pub content_type: Option<String>,
+}
+
+/// Inline files configuration
+#[derive(Debug, Default, Clone, PartialEq, Eq, DeserializeMap)]
+pub struct InlineFilesConf {
+    /// Maps URI paths like `/robots.txt` to the files to be served
+    pub inline_files: HashMap<String, InlineFile>,
+}
+
+/// Handler for Pingora’s `request_filter` phase
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InlineFilesHandler {
+    conf: InlineFilesConf,
+}
+
+impl TryFrom<InlineFilesConf> for InlineFilesHandler {
+    type Error = Box<Error>;
+
+    fn try_from(conf: InlineFilesConf) -> Result<Self, Self::Error> {
+        Ok(Self { conf })
+    }
+}
+
+#[async_trait]
+impl RequestFilter for InlineFilesHandler {
+    type Conf = InlineFilesConf;
+
+    type CTX = ();
+
+    fn new_ctx() -> Self::CTX {}
+
+    async fn request_filter(
+        &self,
+        session: &mut impl SessionWrapper,
+        _ctx: &mut Self::CTX,
+    ) -> Result<RequestFilterResult, Box<Error>> {
+        let file = if let Some(file) = self.conf.inline_files.get(session.uri().path()) {
+            file
+        } else {
+            return Ok(RequestFilterResult::Unhandled);
+        };
+
+        let method = &session.req_header().method;
+        if method != Method::GET && method != Method::HEAD {
+            error_response(session, StatusCode::METHOD_NOT_ALLOWED).await?;
+            return Ok(RequestFilterResult::ResponseSent);
+        }
+
+        let content_type = file.content_type.as_deref().unwrap_or(DEFAULT_CONTENT_TYPE);
+        let mut header = ResponseHeader::build(StatusCode::OK, Some(2))?;
+        header.append_header(header::CONTENT_LENGTH, file.content.len().to_string())?;
+        header.append_header(header::CONTENT_TYPE, content_type)?;
+        session.write_response_header(Box::new(header)).await?;
+
+        if session.req_header().method != Method::HEAD {
+            session
+                .write_response_body(file.content.clone().into())
+                .await?;
+        }
+
+        Ok(RequestFilterResult::ResponseSent)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use pandora_module_utils::pingora::{RequestHeader, TestSession};
+    use pandora_module_utils::FromYaml;
+    use test_log::test;
+
+    fn make_handler() -> InlineFilesHandler {
+        InlineFilesConf::from_yaml(
+            r#"
+                inline_files:
+                    /robots.txt:
+                        content: "User-agent: *\nDisallow: /\n"
+                    /favicon.svg:
+                        content: <svg/>
+                        content_type: image/svg+xml
+            "#,
+        )
+        .unwrap()
+        .try_into()
+        .unwrap()
+    }
+
+    async fn make_session(method: &str, path: &str) -> TestSession {
+        let header = RequestHeader::build(method, path.as_bytes(), None).unwrap();
+        TestSession::from(header).await
+    }
+
+    #[test(tokio::test)]
+    async fn inline_file() -> Result<(), Box<Error>> {
+        let handler = make_handler();
+
+        let mut session = make_session("GET", "/robots.txt").await;
+        assert_eq!(
+            handler.request_filter(&mut session, &mut ()).await?,
+            RequestFilterResult::ResponseSent
+        );
+        let response = session.response_written().unwrap();
+        assert_eq!(response.status, 200);
+        assert_eq!(
+            response.headers.get("Content-Type").unwrap(),
+            "text/plain; charset=utf-8"
+        );
+        assert_eq!(response.headers.get("Content-Length").unwrap(), "26");
+        assert_eq!(session.response_body, "User-agent: *\nDisallow: /\n");
+
+        let mut session = make_session("GET", "/favicon.svg").await;
+        assert_eq!(
+            handler.request_filter(&mut session, &mut ()).await?,
+            RequestFilterResult::ResponseSent
+        );
+        let response = session.response_written().unwrap();
+        assert_eq!(response.status, 200);
+        assert_eq!(
+            response.headers.get("Content-Type").unwrap(),
+            "image/svg+xml"
+        );
+        assert_eq!(session.response_body, "<svg/>");
+
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn methods() -> Result<(), Box<Error>> {
+        let handler = make_handler();
+
+        let mut session = make_session("HEAD", "/robots.txt").await;
+        assert_eq!(
+            handler.request_filter(&mut session, &mut ()).await?,
+            RequestFilterResult::ResponseSent
+        );
+        let response = session.response_written().unwrap();
+        assert_eq!(response.status, 200);
+        assert_eq!(response.headers.get("Content-Length").unwrap(), "26");
+        assert!(session.response_body.is_empty());
+
+        let mut session = make_session("POST", "/robots.txt").await;
+        assert_eq!(
+            handler.request_filter(&mut session, &mut ()).await?,
+            RequestFilterResult::ResponseSent
+        );
+        assert_eq!(session.response_written().unwrap().status, 405);
+
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn unconfigured_path() -> Result<(), Box<Error>> {
+        let handler = make_handler();
+
+        for path in ["/", "/robots.txt/", "/ROBOTS.TXT", "/index.html"] {
+            let mut session = make_session("GET", path).await;
+            assert_eq!(
+                handler.request_filter(&mut session, &mut ()).await?,
+                RequestFilterResult::Unhandled
+            );
+            assert!(session.response_written().is_none());
+        }
+
+        Ok(())
+    }
+}
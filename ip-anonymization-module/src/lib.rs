@@ -70,7 +70,9 @@
 //! conf.handler.anonymization.merge_with_opt(opt.anonymization);
 //! conf.handler.static_files.merge_with_opt(opt.static_files);
 //!
-//! let app = DefaultApp::<Handler>::from_conf(conf.handler).unwrap();
+//! let app = DefaultApp::<Handler>::from_conf(conf.handler)
+//!     .unwrap()
+//!     .with_startup_conf(&conf.startup);
 //! let server = conf.startup.into_server(app, Some(opt.startup)).unwrap();
 //!
 //! // Do something with the server here, e.g. call server.run_forever()
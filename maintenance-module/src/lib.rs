@@ -0,0 +1,393 @@
+// Copyright 2024 Wladimir Palant
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! # Maintenance Module for Pandora Web Server
+//!
+//! This crate allows putting the web server into maintenance mode, e.g. during deployments. In
+//! maintenance mode all requests receive a `503 Service Unavailable` response. The following
+//! configuration options are available:
+//!
+//! * `maintenance_enabled` (`--maintenance-enabled` as command line flag): If `true`, maintenance
+//!   mode is always on.
+//! * `maintenance_file`: Path of a sentinel file. Maintenance mode is on while this file exists.
+//!   This allows toggling maintenance mode without restarting the server.
+//! * `maintenance_page`: Path of an HTML file to be sent with the `503 Service Unavailable`
+//!   response. A standard error page is sent if omitted.
+//! * `maintenance_retry_after`: Number of seconds to be sent to the clients in the `Retry-After`
+//!   header.
+//! * `maintenance_allow`: List of IP addresses that aren’t affected by maintenance mode.
+//!
+//! For example:
+//!
+//! ```yaml
+//! maintenance_file: /var/run/pandora/maintenance
+//! maintenance_page: /var/www/maintenance.html
+//! maintenance_retry_after: 300
+//! maintenance_allow:
+//! - 127.0.0.1
+//! - ::1
+//! ```
+//!
+//! *Note*: IP addresses are matched against the client address as seen by the module. If the IP
+//! Anonymization Module runs first, the allowed addresses have to be anonymized as well.
+//!
+//! ## Using the module
+//!
+//! This module’s handler should be called prior to any handlers producing responses:
+//!
+//! ```rust
+//! use clap::Parser;
+//! use maintenance_module::{MaintenanceHandler, MaintenanceOpt};
+//! use pandora_module_utils::{merge_conf, merge_opt, FromYaml, RequestFilter};
+//! use startup_module::{DefaultApp, StartupConf, StartupOpt};
+//! use static_files_module::{StaticFilesHandler, StaticFilesOpt};
+//!
+//! #[derive(Debug, RequestFilter)]
+//! struct Handler {
+//!     maintenance: MaintenanceHandler,
+//!     static_files: StaticFilesHandler,
+//! }
+//!
+//! #[merge_conf]
+//! struct Conf {
+//!     startup: StartupConf,
+//!     handler: <Handler as RequestFilter>::Conf,
+//! }
+//!
+//! #[merge_opt]
+//! struct Opt {
+//!     startup: StartupOpt,
+//!     maintenance: MaintenanceOpt,
+//!     static_files: StaticFilesOpt,
+//! }
+//!
+//! let opt = Opt::parse();
+//! let mut conf = Conf::load_from_files(opt.startup.conf.as_deref().unwrap_or(&[])).unwrap();
+//! conf.handler.maintenance.merge_with_opt(opt.maintenance);
+//! conf.handler.static_files.merge_with_opt(opt.static_files);
+//!
+//! let app = DefaultApp::<Handler>::from_conf(conf.handler)
+//!     .unwrap()
+//!     .with_startup_conf(&conf.startup);
+//! let server = conf.startup.into_server(app, Some(opt.startup)).unwrap();
+//!
+//! // Do something with the server here, e.g. call server.run_forever()
+//! ```
+
+use std::net::IpAddr;
+use std::path::PathBuf;
+
+use async_trait::async_trait;
+use clap::Parser;
+use http::{header, Method, StatusCode};
+use log::debug;
+use pandora_module_utils::pingora::{Error, ErrorType, ResponseHeader, SessionWrapper, SocketAddr};
+use pandora_module_utils::standard_response::response_text;
+use pandora_module_utils::{DeserializeMap, OneOrMany, RequestFilter, RequestFilterResult};
+
+/// Command line options of the maintenance module
+#[derive(Debug, Parser)]
+pub struct MaintenanceOpt {
+    /// Enables maintenance mode, all requests will receive a 503 Service Unavailable response
+    #[clap(long)]
+    pub maintenance_enabled: bool,
+}
+
+/// Maintenance mode configuration
+#[derive(Debug, Default, Clone, PartialEq, Eq, DeserializeMap)]
+pub struct MaintenanceConf {
+    /// If `true`, maintenance mode is always on.
+    pub maintenance_enabled: bool,
+
+    /// Path of a sentinel file, maintenance mode is on while this file exists.
+    pub maintenance_file: Option<PathBuf>,
+
+    /// Path of an HTML file to be sent with the `503 Service Unavailable` response. A standard
+    /// error page will be used if omitted.
+    pub maintenance_page: Option<PathBuf>,
+
+    /// Number of seconds to be sent in the `Retry-After` header.
+    pub maintenance_retry_after: Option<u64>,
+
+    /// IP addresses that should be allowed to access the server in maintenance mode.
+    pub maintenance_allow: OneOrMany<IpAddr>,
+}
+
+impl MaintenanceConf {
+    /// Merges the command line options into the current configuration. Command line options
+    /// present overwrite existing settings.
+    pub fn merge_with_opt(&mut self, opt: MaintenanceOpt) {
+        if opt.maintenance_enabled {
+            self.maintenance_enabled = true;
+        }
+    }
+}
+
+/// Handler for Pingora’s `request_filter` phase
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MaintenanceHandler {
+    conf: MaintenanceConf,
+    page: String,
+}
+
+impl TryFrom<MaintenanceConf> for MaintenanceHandler {
+    type Error = Box<Error>;
+
+    fn try_from(conf: MaintenanceConf) -> Result<Self, Self::Error> {
+        let page = if let Some(path) = &conf.maintenance_page {
+            std::fs::read_to_string(path).map_err(|err| {
+                Error::because(
+                    ErrorType::FileReadError,
+                    format!("failed reading maintenance page {path:?}"),
+                    err,
+                )
+            })?
+        } else {
+            response_text(StatusCode::SERVICE_UNAVAILABLE)
+        };
+        Ok(Self { conf, page })
+    }
+}
+
+impl MaintenanceHandler {
+    /// Checks whether maintenance mode is currently on.
+    fn is_active(&self) -> bool {
+        self.conf.maintenance_enabled
+            || self
+                .conf
+                .maintenance_file
+                .as_ref()
+                .is_some_and(|path| path.exists())
+    }
+
+    /// Checks whether the client address is allowed to bypass maintenance mode.
+    fn is_allowed(&self, addr: Option<&SocketAddr>) -> bool {
+        let ip = if let Some(SocketAddr::Inet(addr)) = addr {
+            addr.ip()
+        } else {
+            return false;
+        };
+
+        // Treat IPv4 addresses in disguise like regular IPv4 addresses
+        let ip = match ip {
+            IpAddr::V6(addr) => addr.to_ipv4_mapped().map_or(ip, IpAddr::V4),
+            ip => ip,
+        };
+        self.conf.maintenance_allow.contains(&ip)
+    }
+}
+
+#[async_trait]
+impl RequestFilter for MaintenanceHandler {
+    type Conf = MaintenanceConf;
+
+    type CTX = ();
+
+    fn new_ctx() -> Self::CTX {}
+
+    async fn request_filter(
+        &self,
+        session: &mut impl SessionWrapper,
+        _ctx: &mut Self::CTX,
+    ) -> Result<RequestFilterResult, Box<Error>> {
+        if !self.is_active() {
+            return Ok(RequestFilterResult::Unhandled);
+        }
+
+        if self.is_allowed(session.client_addr()) {
+            debug!("maintenance mode is on but client address is allowed");
+            return Ok(RequestFilterResult::Unhandled);
+        }
+
+        let mut header = ResponseHeader::build(StatusCode::SERVICE_UNAVAILABLE, Some(4))?;
+        header.append_header(header::CONTENT_LENGTH, self.page.len().to_string())?;
+        header.append_header(header::CONTENT_TYPE, "text/html; charset=utf-8")?;
+        if let Some(retry_after) = self.conf.maintenance_retry_after {
+            header.append_header(header::RETRY_AFTER, retry_after.to_string())?;
+        }
+        session.write_response_header(Box::new(header)).await?;
+
+        if session.req_header().method != Method::HEAD {
+            session
+                .write_response_body(self.page.clone().into())
+                .await?;
+        }
+
+        Ok(RequestFilterResult::ResponseSent)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use pandora_module_utils::pingora::{RequestHeader, TestSession};
+    use pandora_module_utils::FromYaml;
+    use std::path::Path;
+    use test_log::test;
+
+    fn testdata(name: &str) -> PathBuf {
+        Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("testdata")
+            .join(name)
+    }
+
+    fn make_handler(conf: &str) -> MaintenanceHandler {
+        <MaintenanceHandler as RequestFilter>::Conf::from_yaml(conf)
+            .unwrap()
+            .try_into()
+            .unwrap()
+    }
+
+    async fn make_session(addr: [u8; 4]) -> TestSession {
+        let header = RequestHeader::build("GET", b"/", None).unwrap();
+        TestSession::from(header)
+            .await
+            .with_client_addr(SocketAddr::Inet((addr, 8000).into()))
+    }
+
+    #[test(tokio::test)]
+    async fn maintenance_off() -> Result<(), Box<Error>> {
+        let handler = make_handler("maintenance_enabled: false");
+
+        let mut session = make_session([1, 2, 3, 4]).await;
+        assert_eq!(
+            handler.request_filter(&mut session, &mut ()).await?,
+            RequestFilterResult::Unhandled
+        );
+        assert!(session.response_header.is_none());
+
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn maintenance_on() -> Result<(), Box<Error>> {
+        let handler = make_handler(&format!(
+            r#"
+                maintenance_enabled: true
+                maintenance_page: {}
+                maintenance_retry_after: 300
+            "#,
+            testdata("maintenance.html").to_str().unwrap()
+        ));
+
+        let mut session = make_session([1, 2, 3, 4]).await;
+        assert_eq!(
+            handler.request_filter(&mut session, &mut ()).await?,
+            RequestFilterResult::ResponseSent
+        );
+        let response = session.response_header.as_ref().unwrap();
+        assert_eq!(response.status, 503);
+        assert_eq!(response.headers.get("Retry-After").unwrap(), "300");
+        assert_eq!(
+            response.headers.get("Content-Type").unwrap(),
+            "text/html; charset=utf-8"
+        );
+        assert_eq!(
+            session.response_body,
+            std::fs::read_to_string(testdata("maintenance.html")).unwrap()
+        );
+
+        // Default page, no Retry-After header
+        let handler = make_handler("maintenance_enabled: true");
+        let mut session = make_session([1, 2, 3, 4]).await;
+        assert_eq!(
+            handler.request_filter(&mut session, &mut ()).await?,
+            RequestFilterResult::ResponseSent
+        );
+        let response = session.response_header.as_ref().unwrap();
+        assert_eq!(response.status, 503);
+        assert!(response.headers.get("Retry-After").is_none());
+        assert_eq!(
+            session.response_body,
+            response_text(StatusCode::SERVICE_UNAVAILABLE)
+        );
+
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn allowed_address() -> Result<(), Box<Error>> {
+        let handler = make_handler(
+            r#"
+                maintenance_enabled: true
+                maintenance_allow: [127.0.0.1, "::1"]
+            "#,
+        );
+
+        let mut session = make_session([127, 0, 0, 1]).await;
+        assert_eq!(
+            handler.request_filter(&mut session, &mut ()).await?,
+            RequestFilterResult::Unhandled
+        );
+        assert!(session.response_header.is_none());
+
+        let mut session = make_session([127, 0, 0, 1]).await;
+        let ip: IpAddr = "::ffff:127.0.0.1".parse().unwrap();
+        session.set_client_addr(SocketAddr::Inet((ip, 8000).into()));
+        assert_eq!(
+            handler.request_filter(&mut session, &mut ()).await?,
+            RequestFilterResult::Unhandled
+        );
+
+        let mut session = make_session([127, 0, 0, 2]).await;
+        assert_eq!(
+            handler.request_filter(&mut session, &mut ()).await?,
+            RequestFilterResult::ResponseSent
+        );
+        assert_eq!(session.response_header.as_ref().unwrap().status, 503);
+
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn sentinel_file() -> Result<(), Box<Error>> {
+        let mut path = std::env::temp_dir();
+        path.push(format!("pandora-maintenance-{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+
+        let handler = make_handler(&format!("maintenance_file: {}", path.to_str().unwrap()));
+
+        let mut session = make_session([1, 2, 3, 4]).await;
+        assert_eq!(
+            handler.request_filter(&mut session, &mut ()).await?,
+            RequestFilterResult::Unhandled
+        );
+
+        std::fs::write(&path, "").unwrap();
+        let mut session = make_session([1, 2, 3, 4]).await;
+        let result = handler.request_filter(&mut session, &mut ()).await;
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(result?, RequestFilterResult::ResponseSent);
+        assert_eq!(session.response_header.as_ref().unwrap().status, 503);
+
+        let mut session = make_session([1, 2, 3, 4]).await;
+        assert_eq!(
+            handler.request_filter(&mut session, &mut ()).await?,
+            RequestFilterResult::Unhandled
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn missing_page() {
+        let conf = MaintenanceConf::from_yaml(format!(
+            "maintenance_page: {}",
+            testdata("missing.html").to_str().unwrap()
+        ))
+        .unwrap();
+        assert!(MaintenanceHandler::try_from(conf).is_err());
+    }
+}
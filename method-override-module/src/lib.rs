@@ -0,0 +1,336 @@
+// Copyright 2024 Wladimir Palant
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! # Method Override Module for Pandora Web Server
+//!
+//! Some clients can only send `GET` and `POST` requests. This crate allows these clients to
+//! specify the actual request method via the `X-HTTP-Method-Override` header of a `POST` request.
+//! The following configuration options are available:
+//!
+//! * `method_override_enabled` (`--method-override-enabled` as command line flag): If `true`,
+//!   the method of `POST` requests will be replaced by the one given in the
+//!   `X-HTTP-Method-Override` header.
+//! * `method_override_allow`: List of methods that a request can be changed to. By default, these
+//!   are `PUT`, `PATCH` and `DELETE`. `CONNECT` and `TRACE` methods cannot be allowed.
+//!
+//! For example:
+//!
+//! ```yaml
+//! method_override_enabled: true
+//! method_override_allow: [PUT, DELETE]
+//! ```
+//!
+//! The method name in the header is case-insensitive. Override headers of other requests than
+//! `POST` or with methods that aren’t allowed are ignored. Either way, the header is removed from
+//! the request so that later handlers or upstream servers won’t act on it.
+//!
+//! ## Using the module
+//!
+//! This module’s handler should be called prior to any other handler for the `request_filter`
+//! phase, so that routing and all other handlers already see the overridden method:
+//!
+//! ```rust
+//! use clap::Parser;
+//! use method_override_module::{MethodOverrideHandler, MethodOverrideOpt};
+//! use pandora_module_utils::{merge_conf, merge_opt, FromYaml, RequestFilter};
+//! use startup_module::{DefaultApp, StartupConf, StartupOpt};
+//! use static_files_module::{StaticFilesHandler, StaticFilesOpt};
+//!
+//! #[derive(Debug, RequestFilter)]
+//! struct Handler {
+//!     method_override: MethodOverrideHandler,
+//!     static_files: StaticFilesHandler,
+//! }
+//!
+//! #[merge_conf]
+//! struct Conf {
+//!     startup: StartupConf,
+//!     handler: <Handler as RequestFilter>::Conf,
+//! }
+//!
+//! #[merge_opt]
+//! struct Opt {
+//!     startup: StartupOpt,
+//!     method_override: MethodOverrideOpt,
+//!     static_files: StaticFilesOpt,
+//! }
+//!
+//! let opt = Opt::parse();
+//! let mut conf = Conf::load_from_files(opt.startup.conf.as_deref().unwrap_or(&[])).unwrap();
+//! conf.handler.method_override.merge_with_opt(opt.method_override);
+//! conf.handler.static_files.merge_with_opt(opt.static_files);
+//!
+//! let app = DefaultApp::<Handler>::from_conf(conf.handler)
+//!     .unwrap()
+//!     .with_startup_conf(&conf.startup);
+//! let server = conf.startup.into_server(app, Some(opt.startup)).unwrap();
+//!
+//! // Do something with the server here, e.g. call server.run_forever()
+//! ```
+
+use async_trait::async_trait;
+use clap::Parser;
+use http::Method;
+use log::debug;
+use pandora_module_utils::pingora::{Error, ErrorType, SessionWrapper};
+use pandora_module_utils::{DeserializeMap, OneOrMany, RequestFilter, RequestFilterResult};
+
+/// Name of the header containing the actual request method
+const OVERRIDE_HEADER: &str = "X-HTTP-Method-Override";
+
+/// Command line options of the method override module
+#[derive(Debug, Parser)]
+pub struct MethodOverrideOpt {
+    /// Allows POST requests to specify the actual request method via X-HTTP-Method-Override header
+    #[clap(long)]
+    pub method_override_enabled: bool,
+}
+
+/// Method override configuration
+#[derive(Debug, Default, Clone, PartialEq, Eq, DeserializeMap)]
+pub struct MethodOverrideConf {
+    /// If `true`, the method of `POST` requests will be replaced by the one specified in the
+    /// `X-HTTP-Method-Override` header.
+    pub method_override_enabled: bool,
+
+    /// Methods that a request can be changed to. If empty, `PUT`, `PATCH` and `DELETE` are
+    /// allowed.
+    pub method_override_allow: OneOrMany<String>,
+}
+
+impl MethodOverrideConf {
+    /// Merges the command line options into the current configuration. Command line options
+    /// present overwrite existing settings.
+    pub fn merge_with_opt(&mut self, opt: MethodOverrideOpt) {
+        if opt.method_override_enabled {
+            self.method_override_enabled = true;
+        }
+    }
+}
+
+/// Handler for Pingora’s `request_filter` phase
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MethodOverrideHandler {
+    enabled: bool,
+    allowed: Vec<Method>,
+}
+
+impl TryFrom<MethodOverrideConf> for MethodOverrideHandler {
+    type Error = Box<Error>;
+
+    fn try_from(conf: MethodOverrideConf) -> Result<Self, Self::Error> {
+        let allowed = if conf.method_override_allow.is_empty() {
+            vec![Method::PUT, Method::PATCH, Method::DELETE]
+        } else {
+            conf.method_override_allow
+                .iter()
+                .map(|method| {
+                    let method = Method::from_bytes(method.to_ascii_uppercase().as_bytes())
+                        .map_err(|err| {
+                            Error::because(
+                                ErrorType::InternalError,
+                                format!("Invalid method {method} in method_override_allow"),
+                                err,
+                            )
+                        })?;
+                    if method == Method::CONNECT || method == Method::TRACE {
+                        Err(Error::explain(
+                            ErrorType::InternalError,
+                            format!("Method {method} cannot be allowed in method_override_allow"),
+                        ))
+                    } else {
+                        Ok(method)
+                    }
+                })
+                .collect::<Result<_, _>>()?
+        };
+
+        Ok(Self {
+            enabled: conf.method_override_enabled,
+            allowed,
+        })
+    }
+}
+
+#[async_trait]
+impl RequestFilter for MethodOverrideHandler {
+    type Conf = MethodOverrideConf;
+
+    type CTX = ();
+
+    fn new_ctx() -> Self::CTX {}
+
+    async fn request_filter(
+        &self,
+        session: &mut impl SessionWrapper,
+        _ctx: &mut Self::CTX,
+    ) -> Result<RequestFilterResult, Box<Error>> {
+        if !self.enabled {
+            return Ok(RequestFilterResult::Unhandled);
+        }
+
+        let value = if let Some(value) = session.req_header_mut().remove_header(OVERRIDE_HEADER) {
+            value
+        } else {
+            return Ok(RequestFilterResult::Unhandled);
+        };
+
+        if session.req_header().method != Method::POST {
+            debug!(
+                "ignoring method override for {} request",
+                session.req_header().method
+            );
+            return Ok(RequestFilterResult::Unhandled);
+        }
+
+        match Method::from_bytes(&value.as_bytes().to_ascii_uppercase()) {
+            Ok(method) if self.allowed.contains(&method) => {
+                debug!("overriding request method with {method}");
+                session.req_header_mut().set_method(method);
+            }
+            _ => debug!("ignoring invalid method override {value:?}"),
+        }
+
+        Ok(RequestFilterResult::Unhandled)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use pandora_module_utils::pingora::{RequestHeader, TestSession};
+    use pandora_module_utils::FromYaml;
+    use test_log::test;
+
+    fn make_handler(conf: &str) -> MethodOverrideHandler {
+        <MethodOverrideHandler as RequestFilter>::Conf::from_yaml(conf)
+            .unwrap()
+            .try_into()
+            .unwrap()
+    }
+
+    async fn make_session(method: &str, method_override: Option<&str>) -> TestSession {
+        let mut header = RequestHeader::build(method, b"/", None).unwrap();
+        if let Some(method_override) = method_override {
+            header
+                .insert_header(OVERRIDE_HEADER, method_override)
+                .unwrap();
+        }
+        TestSession::from(header).await
+    }
+
+    async fn resulting_method(
+        handler: &MethodOverrideHandler,
+        method: &str,
+        method_override: Option<&str>,
+    ) -> Result<Method, Box<Error>> {
+        let mut session = make_session(method, method_override).await;
+        assert_eq!(
+            handler.request_filter(&mut session, &mut ()).await?,
+            RequestFilterResult::Unhandled
+        );
+        if handler.enabled {
+            assert!(!session.req_header().headers.contains_key(OVERRIDE_HEADER));
+        }
+        Ok(session.req_header().method.clone())
+    }
+
+    #[test(tokio::test)]
+    async fn unconfigured() -> Result<(), Box<Error>> {
+        let handler = make_handler("method_override_enabled: false");
+        assert_eq!(
+            resulting_method(&handler, "POST", Some("PUT")).await?,
+            Method::POST
+        );
+
+        let mut session = make_session("POST", Some("PUT")).await;
+        handler.request_filter(&mut session, &mut ()).await?;
+        assert!(session.req_header().headers.contains_key(OVERRIDE_HEADER));
+
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn override_method() -> Result<(), Box<Error>> {
+        let handler = make_handler("method_override_enabled: true");
+        assert_eq!(
+            resulting_method(&handler, "POST", Some("PUT")).await?,
+            Method::PUT
+        );
+        assert_eq!(
+            resulting_method(&handler, "POST", Some("delete")).await?,
+            Method::DELETE
+        );
+        assert_eq!(
+            resulting_method(&handler, "POST", Some("Patch")).await?,
+            Method::PATCH
+        );
+        assert_eq!(
+            resulting_method(&handler, "POST", None).await?,
+            Method::POST
+        );
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn invalid_override() -> Result<(), Box<Error>> {
+        let handler = make_handler("method_override_enabled: true");
+        for method_override in ["CONNECT", "TRACE", "PROPFIND", "GET", "PUT PUT", ""] {
+            assert_eq!(
+                resulting_method(&handler, "POST", Some(method_override)).await?,
+                Method::POST
+            );
+        }
+
+        // Only POST requests can be overridden
+        assert_eq!(
+            resulting_method(&handler, "GET", Some("DELETE")).await?,
+            Method::GET
+        );
+        assert_eq!(
+            resulting_method(&handler, "PUT", Some("DELETE")).await?,
+            Method::PUT
+        );
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn allowed_methods() -> Result<(), Box<Error>> {
+        let handler = make_handler(
+            r#"
+                method_override_enabled: true
+                method_override_allow: [delete, PROPFIND]
+            "#,
+        );
+        assert_eq!(
+            resulting_method(&handler, "POST", Some("PROPFIND")).await?,
+            Method::from_bytes(b"PROPFIND").unwrap()
+        );
+        assert_eq!(
+            resulting_method(&handler, "POST", Some("DELETE")).await?,
+            Method::DELETE
+        );
+        assert_eq!(
+            resulting_method(&handler, "POST", Some("PUT")).await?,
+            Method::POST
+        );
+
+        for allow in ["CONNECT", "trace", "IN VALID"] {
+            let conf = MethodOverrideConf::from_yaml(format!("method_override_allow: {allow}"))?;
+            assert!(MethodOverrideHandler::try_from(conf).is_err());
+        }
+        Ok(())
+    }
+}
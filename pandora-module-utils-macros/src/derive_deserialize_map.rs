@@ -24,6 +24,7 @@ use crate::utils::{generics_with_de, get_fields, type_name_short, where_clause};
 struct ContainerAttributes {
     rename_all: RenameRule,
     crate_path: Path,
+    shorthand: Option<LitStr>,
 }
 
 impl TryFrom<&DeriveInput> for ContainerAttributes {
@@ -32,6 +33,7 @@ impl TryFrom<&DeriveInput> for ContainerAttributes {
     fn try_from(value: &DeriveInput) -> Result<Self, Self::Error> {
         let mut rename_all = RenameRule::None;
         let mut crate_path = None;
+        let mut shorthand = None;
 
         for attr in &value.attrs {
             if !attr.path().is_ident("pandora") {
@@ -66,6 +68,12 @@ impl TryFrom<&DeriveInput> for ContainerAttributes {
                     let lit: LitStr = meta.value()?.parse()?;
                     crate_path = Some(lit.parse()?);
                     Ok(())
+                } else if meta.path.is_ident("shorthand") {
+                    if shorthand.is_some() {
+                        return Err(Error::new_spanned(meta.path, "duplicate shorthand"));
+                    }
+                    shorthand = Some(meta.value()?.parse()?);
+                    Ok(())
                 } else {
                     Err(Error::new_spanned(meta.path, "unexpected parameter"))
                 }
@@ -81,6 +89,7 @@ impl TryFrom<&DeriveInput> for ContainerAttributes {
         Ok(Self {
             rename_all,
             crate_path,
+            shorthand,
         })
     }
 }
@@ -443,6 +452,25 @@ fn generate_deserialize_impl(
         syn::parse2(quote! {#struct_name: #crate_path::DeserializeMap<#de>}).unwrap(),
     );
 
+    // With a shorthand field, a string value is accepted as the value of that field.
+    let (deserialize_method, visit_shorthand) = if let Some(field) = &container_attrs.shorthand {
+        (
+            quote! {deserialize_any},
+            quote! {
+                fn visit_str<E>(self, v: &::std::primitive::str)
+                    -> ::std::result::Result<Self::Value, E>
+                where
+                    E: #crate_path::serde::de::Error
+                {
+                    let deserializer = #crate_path::_private::ShorthandDeserializer::<E>::new(v);
+                    self.inner.visit_field(#field, deserializer)?.finalize()
+                }
+            },
+        )
+    } else {
+        (quote! {deserialize_map}, quote! {})
+    };
+
     quote! {
         impl<#generics> #crate_path::serde::Deserialize<#de> for #struct_name #where_clause {
             fn deserialize<D>(deserializer: D) -> ::std::result::Result<Self, D::Error>
@@ -511,12 +539,14 @@ fn generate_deserialize_impl(
                         }
                         self.inner.finalize()
                     }
+
+                    #visit_shorthand
                 }
 
                 let visitor = __Visitor {
                     inner: self.visitor(),
                 };
-                deserializer.deserialize_map(visitor)
+                deserializer.#deserialize_method(visitor)
             }
         }
     }
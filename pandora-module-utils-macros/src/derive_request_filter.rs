@@ -137,6 +137,19 @@ fn generate_request_filter_impl(
                     Ok(None)
                 }
 
+                async fn upstream_request_filter(
+                    &self,
+                    _session: &mut impl ::pandora_module_utils::pingora::SessionWrapper,
+                    _upstream_request: &mut ::pandora_module_utils::pingora::RequestHeader,
+                    _ctx: &mut Self::CTX,
+                ) -> ::std::result::Result<(), ::std::boxed::Box<::pandora_module_utils::pingora::Error>>
+                {
+                    #(
+                        self.#field_name.upstream_request_filter(_session, _upstream_request, &mut _ctx.#field_name).await?;
+                    )*
+                    Ok(())
+                }
+
                 fn response_filter(
                     &self,
                     _session: &mut impl ::pandora_module_utils::pingora::SessionWrapper,
@@ -148,6 +161,18 @@ fn generate_request_filter_impl(
                     )*
                 }
 
+                fn response_body_filter(
+                    &self,
+                    _session: &mut impl ::pandora_module_utils::pingora::SessionWrapper,
+                    _body: &mut ::std::option::Option<::pandora_module_utils::pingora::Bytes>,
+                    _end_of_stream: bool,
+                    _ctx: &mut Self::CTX,
+                ) {
+                    #(
+                        self.#field_name.response_body_filter(_session, _body, _end_of_stream, &mut _ctx.#field_name);
+                    )*
+                }
+
                 async fn logging(
                     &self,
                     _session: &mut impl ::pandora_module_utils::pingora::SessionWrapper,
@@ -92,7 +92,7 @@ pub fn merge_opt(_args: TokenStream, input: TokenStream) -> TokenStream {
 /// "#).unwrap();
 /// assert!(conf.app.roll_over);
 /// assert_eq!(conf.startup.listen, vec!["127.0.0.1:8080".into()].into());
-/// assert_eq!(conf.static_files.root, Some(PathBuf::from(".")));
+/// assert_eq!(conf.static_files.root, Some(vec![PathBuf::from(".")].into()));
 /// ```
 ///
 /// Unknown fields will cause an error during deserialization:
@@ -240,6 +240,11 @@ pub fn derive_request_filter(input: TokenStream) -> TokenStream {
 ///   Specify a path to the `pandora_module_utils` crate instance to use when referring to APIs
 ///   from generated code. This is normally only applicable when `pandora_module_utils` isn’t
 ///   accessible under its usual name but only as a re-exported name from a different crate.
+/// * `#[pandora(shorthand = "name")]`
+///
+///   Accept a string value in place of the entire structure. This value is then deserialized as
+///   the value of the given field, any other fields are left unchanged. The field can also belong
+///   to a flattened structure. There is no Serde analog to this attribute.
 ///
 /// Unknown fields will cause a deserialization error, missing fields will be left at their initial
 /// value. This is similar to the behavior of
@@ -60,6 +60,16 @@ impl RequestFilter for Handler1 {
             RequestFilterResult::Unhandled
         })
     }
+
+    async fn upstream_request_filter(
+        &self,
+        _session: &mut (impl SessionWrapper),
+        upstream_request: &mut RequestHeader,
+        _ctx: &mut Self::CTX,
+    ) -> Result<(), Box<Error>> {
+        upstream_request.append_header("X-Handler", "handler1")?;
+        Ok(())
+    }
 }
 
 #[derive(Debug, Clone, PartialEq, Eq, DeserializeMap)]
@@ -120,6 +130,16 @@ impl<T: Default + Sync, U: Default + Sync> RequestFilter for Handler2<T, U> {
         ctx.value1 = self.conf.value3;
         Ok(RequestFilterResult::Unhandled)
     }
+
+    async fn upstream_request_filter(
+        &self,
+        _session: &mut (impl SessionWrapper),
+        upstream_request: &mut RequestHeader,
+        ctx: &mut Self::CTX,
+    ) -> Result<(), Box<Error>> {
+        upstream_request.append_header("X-Handler", ctx.value2.clone())?;
+        Ok(())
+    }
 }
 
 #[derive(Debug, Clone, PartialEq, Eq, RequestFilter)]
@@ -185,6 +205,18 @@ async fn handler() -> Result<(), Box<Error>> {
         RequestFilterResult::Handled
     );
 
+    // All handlers get to modify the upstream request, in order
+    let mut upstream_request = RequestHeader::build("GET", "/".as_bytes(), None)?;
+    handler
+        .upstream_request_filter(&mut session, &mut upstream_request, &mut ctx)
+        .await?;
+    let values = upstream_request
+        .headers
+        .get_all("X-Handler")
+        .iter()
+        .collect::<Vec<_>>();
+    assert_eq!(values, vec!["Hi!", "handler1"]);
+
     Ok(())
 }
 
@@ -231,6 +263,41 @@ fn container_attributes() {
     assert_eq!(&conf.value, "1");
     assert_eq!(&conf.string_value, "2");
     assert_eq!(&conf.string_value2, "3");
+
+    #[derive(Debug, Default, Clone, PartialEq, Eq, DeserializeMap)]
+    #[pandora(shorthand = "value")]
+    struct Conf3 {
+        enabled: bool,
+        #[pandora(flatten)]
+        inner: Conf1,
+    }
+
+    let conf = Conf3::from_yaml("shortened").unwrap();
+    assert!(!conf.enabled);
+    assert_eq!(&conf.inner.value, "shortened");
+    assert_eq!(conf, Conf3::from_yaml("value: shortened").unwrap());
+
+    let conf = Conf3::from_yaml(
+        r#"
+            enabled: true
+            value: "1"
+        "#,
+    )
+    .unwrap();
+    assert!(conf.enabled);
+    assert_eq!(&conf.inner.value, "1");
+
+    Conf3::from_yaml("12").unwrap_err();
+    Conf2::from_yaml("shortened").unwrap_err();
+
+    #[derive(Debug, Default, Clone, PartialEq, Eq, DeserializeMap)]
+    #[pandora(shorthand = "value")]
+    struct Conf4 {
+        value: Option<String>,
+    }
+
+    let conf = Conf4::from_yaml("shortened").unwrap();
+    assert_eq!(conf.value.as_deref(), Some("shortened"));
 }
 
 #[test]
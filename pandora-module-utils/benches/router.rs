@@ -0,0 +1,95 @@
+// Copyright 2024 Wladimir Palant
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Compares router lookup performance against a naive linear scan over all routes.
+//!
+//! Run with `cargo bench -p pandora-module-utils`.
+
+use pandora_module_utils::router::{Path, Router};
+use std::hint::black_box;
+use std::time::Instant;
+
+const HOSTS: usize = 1000;
+const PATHS_PER_HOST: usize = 10;
+
+/// The naive approach: check every route, keep the longest matching one.
+struct LinearRouter {
+    routes: Vec<(String, Path, usize)>,
+}
+
+impl LinearRouter {
+    fn lookup(&self, host: &str, path: &str) -> Option<usize> {
+        let path = Path::new(path);
+        self.routes
+            .iter()
+            .filter(|(route_host, route_path, _)| {
+                route_host == host && route_path.is_prefix_of(&path)
+            })
+            .max_by_key(|(_, route_path, _)| route_path.len())
+            .map(|(_, _, value)| *value)
+    }
+}
+
+fn measure(
+    name: &str,
+    iterations: u32,
+    queries: &[(String, String)],
+    mut lookup: impl FnMut(&str, &str),
+) {
+    let start = Instant::now();
+    for i in 0..iterations as usize {
+        let (host, path) = &queries[i % queries.len()];
+        lookup(host, path);
+    }
+    println!("{name}: {:?} per lookup", start.elapsed() / iterations);
+}
+
+fn main() {
+    let mut builder = Router::builder();
+    let mut linear = LinearRouter { routes: Vec::new() };
+    for host in 0..HOSTS {
+        let host = format!("host{host}.example.com");
+        for path in 0..PATHS_PER_HOST {
+            let path = format!("/dir{path}/subdir/");
+            let value = linear.routes.len();
+            builder.push(&host, &path, value, Some(value));
+            linear.routes.push((host.clone(), Path::new(&path), value));
+        }
+    }
+    let router = builder.build();
+    println!("{} routes", linear.routes.len());
+
+    let queries = (0..HOSTS)
+        .map(|i| {
+            (
+                format!("host{}.example.com", (i * 7919) % HOSTS),
+                format!("/dir{}/subdir/file.txt", i % PATHS_PER_HOST),
+            )
+        })
+        .collect::<Vec<_>>();
+
+    for (host, path) in &queries {
+        assert_eq!(
+            router.lookup(host, path).map(|result| *result),
+            linear.lookup(host, path)
+        );
+    }
+
+    measure("router", 1_000_000, &queries, |host, path| {
+        black_box(router.lookup(black_box(host), black_box(path)));
+    });
+    measure("linear scan", 1_000, &queries, |host, path| {
+        black_box(linear.lookup(black_box(host), black_box(path)));
+    });
+}
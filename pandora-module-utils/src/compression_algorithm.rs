@@ -0,0 +1,379 @@
+// Copyright 2024 Wladimir Palant
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Handles various compression algorithms allowed in `Accept-Encoding` and `Content-Encoding` HTTP
+//! headers.
+
+use crate::pingora::AcceptedEncodings;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::fmt::Display;
+use std::str::FromStr;
+
+/// Represents a compression algorithm choice.
+///
+/// In configuration files the algorithm is identified by its file extension, e.g. `br`. Parsing
+/// is case-insensitive, a leading dot is ignored and some aliases like `brotli` are accepted as
+/// well. Serialization always produces the canonical file extension.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum CompressionAlgorithm {
+    /// gzip compression
+    Gzip,
+    /// deflate (zlib) compression
+    Deflate,
+    /// compress compression
+    Compress,
+    /// Brotli compression
+    Brotli,
+    /// Zstandard compression
+    Zstandard,
+}
+
+impl CompressionAlgorithm {
+    /// Returns the file extension corresponding to the algorithm, without the leading dot.
+    pub fn extension(&self) -> &'static str {
+        match self {
+            Self::Gzip => "gz",
+            Self::Deflate => "zz",
+            Self::Compress => "z",
+            Self::Brotli => "br",
+            Self::Zstandard => "zst",
+        }
+    }
+
+    /// Returns the file extension corresponding to the algorithm.
+    ///
+    /// This is an alias for [`CompressionAlgorithm::extension`].
+    pub fn ext(&self) -> &'static str {
+        self.extension()
+    }
+
+    /// Determines the algorithm corresponding to the file extension if any.
+    pub fn from_ext(ext: &str) -> Option<Self> {
+        match ext {
+            "gz" => Some(Self::Gzip),
+            "zz" => Some(Self::Deflate),
+            "z" => Some(Self::Compress),
+            "br" => Some(Self::Brotli),
+            "zst" => Some(Self::Zstandard),
+            _ => None,
+        }
+    }
+
+    /// Returns the token identifying the algorithm in `Accept-Encoding` and `Content-Encoding`
+    /// HTTP headers.
+    pub fn encoding_token(&self) -> &'static str {
+        match self {
+            Self::Gzip => "gzip",
+            Self::Deflate => "deflate",
+            Self::Compress => "compress",
+            Self::Brotli => "br",
+            Self::Zstandard => "zstd",
+        }
+    }
+
+    /// Returns the algorithm name as used in `Accept-Encoding` HTTP header.
+    ///
+    /// This is an alias for [`CompressionAlgorithm::encoding_token`].
+    pub fn name(&self) -> &'static str {
+        self.encoding_token()
+    }
+
+    /// Determines the algorithm corresponding to a name from `Accept-Encoding` HTTP header.
+    ///
+    /// Names are case-insensitive, the legacy `x-gzip` and `x-compress` aliases are recognized.
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name.to_ascii_lowercase().as_str() {
+            "gzip" | "x-gzip" => Some(Self::Gzip),
+            "deflate" => Some(Self::Deflate),
+            "compress" | "x-compress" => Some(Self::Compress),
+            "br" => Some(Self::Brotli),
+            "zstd" => Some(Self::Zstandard),
+            _ => None,
+        }
+    }
+}
+
+impl FromStr for CompressionAlgorithm {
+    type Err = UnsupportedCompressionAlgorithm;
+
+    /// Coverts a file extension into a compression algorithm.
+    ///
+    /// Unlike [`CompressionAlgorithm::from_ext`], this is case-insensitive, ignores a leading dot
+    /// and accepts aliases like `brotli` or `gzip`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let ext = s.strip_prefix('.').unwrap_or(s).to_ascii_lowercase();
+        CompressionAlgorithm::from_ext(&ext)
+            .or_else(|| match ext.as_str() {
+                "gzip" => Some(Self::Gzip),
+                "deflate" | "zlib" => Some(Self::Deflate),
+                "compress" => Some(Self::Compress),
+                "brotli" => Some(Self::Brotli),
+                "zstd" | "zstandard" => Some(Self::Zstandard),
+                _ => None,
+            })
+            .ok_or(UnsupportedCompressionAlgorithm(s.to_owned()))
+    }
+}
+
+impl<'de> Deserialize<'de> for CompressionAlgorithm {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        use serde::de::Error;
+
+        let s = String::deserialize(deserializer)?;
+        s.parse().map_err(D::Error::custom)
+    }
+}
+
+impl Serialize for CompressionAlgorithm {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        serializer.serialize_str(self.extension())
+    }
+}
+
+impl Display for CompressionAlgorithm {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> Result<(), std::fmt::Error> {
+        write!(f, "{}", self.encoding_token())
+    }
+}
+
+/// The error type returned by `CompressionAlgorithm::from_str()`
+#[derive(Debug, PartialEq, Eq)]
+pub struct UnsupportedCompressionAlgorithm(String);
+
+impl Display for UnsupportedCompressionAlgorithm {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> Result<(), std::fmt::Error> {
+        write!(f, "Unsupported compression algorithm: {}", self.0)
+    }
+}
+
+impl std::error::Error for UnsupportedCompressionAlgorithm {}
+
+/// Compares the encodings accepted by the client with a list of supported algorithms and returns
+/// any matches, sorted by the respective quality value.
+pub fn find_matches(
+    accepted: &AcceptedEncodings,
+    supported: &[CompressionAlgorithm],
+) -> Vec<CompressionAlgorithm> {
+    let mut result = Vec::new();
+    for encoding in accepted.acceptable() {
+        if encoding.coding == "*" {
+            for algorithm in supported {
+                if accepted.accepts(algorithm.encoding_token()) && !result.contains(algorithm) {
+                    result.push(*algorithm);
+                }
+            }
+            break;
+        } else if let Some(algorithm) = CompressionAlgorithm::from_name(&encoding.coding) {
+            if supported.contains(&algorithm) && !result.contains(&algorithm) {
+                result.push(algorithm);
+            }
+        }
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn find_matches(
+        requested: &str,
+        supported: &[CompressionAlgorithm],
+    ) -> Vec<CompressionAlgorithm> {
+        super::find_matches(&AcceptedEncodings::parse([requested]), supported)
+    }
+
+    #[test]
+    fn test_from_name() {
+        assert_eq!(
+            CompressionAlgorithm::from_name("compress"),
+            Some(CompressionAlgorithm::Compress)
+        );
+        assert_eq!(
+            CompressionAlgorithm::from_name("X-Compress"),
+            Some(CompressionAlgorithm::Compress)
+        );
+        assert_eq!(
+            CompressionAlgorithm::from_name("x-gzip"),
+            Some(CompressionAlgorithm::Gzip)
+        );
+        assert_eq!(CompressionAlgorithm::from_name("z"), None);
+    }
+
+    #[test]
+    fn test_extension_and_token() {
+        let expected = [
+            (CompressionAlgorithm::Gzip, "gz", "gzip"),
+            (CompressionAlgorithm::Deflate, "zz", "deflate"),
+            (CompressionAlgorithm::Compress, "z", "compress"),
+            (CompressionAlgorithm::Brotli, "br", "br"),
+            (CompressionAlgorithm::Zstandard, "zst", "zstd"),
+        ];
+        for (algorithm, extension, token) in expected {
+            assert_eq!(algorithm.extension(), extension);
+            assert_eq!(algorithm.encoding_token(), token);
+            assert_eq!(CompressionAlgorithm::from_ext(extension), Some(algorithm));
+            assert_eq!(CompressionAlgorithm::from_name(token), Some(algorithm));
+            assert_eq!(algorithm.to_string(), token);
+        }
+    }
+
+    #[test]
+    fn test_from_str() {
+        assert_eq!("br".parse(), Ok(CompressionAlgorithm::Brotli));
+        assert_eq!("BR".parse(), Ok(CompressionAlgorithm::Brotli));
+        assert_eq!(".br".parse(), Ok(CompressionAlgorithm::Brotli));
+        assert_eq!("brotli".parse(), Ok(CompressionAlgorithm::Brotli));
+        assert_eq!(".Brotli".parse(), Ok(CompressionAlgorithm::Brotli));
+        assert_eq!("GZ".parse(), Ok(CompressionAlgorithm::Gzip));
+        assert_eq!("gzip".parse(), Ok(CompressionAlgorithm::Gzip));
+        assert_eq!("zstd".parse(), Ok(CompressionAlgorithm::Zstandard));
+
+        assert_eq!(
+            "rar".parse::<CompressionAlgorithm>(),
+            Err(UnsupportedCompressionAlgorithm("rar".to_owned()))
+        );
+        assert_eq!(
+            "".parse::<CompressionAlgorithm>(),
+            Err(UnsupportedCompressionAlgorithm("".to_owned()))
+        );
+        assert_eq!(
+            "..br".parse::<CompressionAlgorithm>(),
+            Err(UnsupportedCompressionAlgorithm("..br".to_owned()))
+        );
+    }
+
+    #[test]
+    fn test_serde() {
+        assert_eq!(
+            serde_yaml::from_str::<Vec<CompressionAlgorithm>>("[BR, .gz, brotli]").unwrap(),
+            vec![
+                CompressionAlgorithm::Brotli,
+                CompressionAlgorithm::Gzip,
+                CompressionAlgorithm::Brotli
+            ]
+        );
+        assert!(serde_yaml::from_str::<CompressionAlgorithm>("rar").is_err());
+
+        assert_eq!(
+            serde_yaml::to_string(&CompressionAlgorithm::Brotli)
+                .unwrap()
+                .trim_start_matches("---")
+                .trim(),
+            "br"
+        );
+    }
+
+    #[test]
+    fn test_find_matches() {
+        assert_eq!(
+            find_matches(
+                "",
+                &[CompressionAlgorithm::Gzip, CompressionAlgorithm::Brotli]
+            ),
+            Vec::new()
+        );
+
+        assert_eq!(
+            find_matches(
+                "identity",
+                &[CompressionAlgorithm::Gzip, CompressionAlgorithm::Brotli]
+            ),
+            Vec::new()
+        );
+
+        assert_eq!(
+            find_matches(
+                "*",
+                &[CompressionAlgorithm::Gzip, CompressionAlgorithm::Brotli]
+            ),
+            vec![CompressionAlgorithm::Gzip, CompressionAlgorithm::Brotli]
+        );
+
+        assert_eq!(
+            find_matches(
+                "br, *",
+                &[CompressionAlgorithm::Gzip, CompressionAlgorithm::Brotli]
+            ),
+            vec![CompressionAlgorithm::Brotli, CompressionAlgorithm::Gzip]
+        );
+
+        assert_eq!(
+            find_matches(
+                "br;q=0.9, *",
+                &[CompressionAlgorithm::Gzip, CompressionAlgorithm::Brotli]
+            ),
+            vec![CompressionAlgorithm::Gzip, CompressionAlgorithm::Brotli]
+        );
+
+        assert_eq!(
+            find_matches(
+                "deflate;q=0.7, gzip;q=0.9, zstd;q=0.8, br;q=1.0, compress;q=0.5",
+                &[
+                    CompressionAlgorithm::Deflate,
+                    CompressionAlgorithm::Gzip,
+                    CompressionAlgorithm::Compress,
+                    CompressionAlgorithm::Brotli,
+                    CompressionAlgorithm::Zstandard,
+                ]
+            ),
+            vec![
+                CompressionAlgorithm::Brotli,
+                CompressionAlgorithm::Gzip,
+                CompressionAlgorithm::Zstandard,
+                CompressionAlgorithm::Deflate,
+                CompressionAlgorithm::Compress,
+            ]
+        );
+
+        assert_eq!(
+            find_matches(
+                "gzip;q=0, br;q=0.5, *",
+                &[CompressionAlgorithm::Gzip, CompressionAlgorithm::Brotli]
+            ),
+            vec![CompressionAlgorithm::Brotli]
+        );
+
+        assert_eq!(
+            find_matches(
+                "GZIP, br;q=0",
+                &[CompressionAlgorithm::Gzip, CompressionAlgorithm::Brotli]
+            ),
+            vec![CompressionAlgorithm::Gzip]
+        );
+
+        assert_eq!(
+            find_matches(
+                "deflate;q=0.7, zstd;q=0.8, br;q=1.0",
+                &[
+                    CompressionAlgorithm::Deflate,
+                    CompressionAlgorithm::Gzip,
+                    CompressionAlgorithm::Brotli,
+                    CompressionAlgorithm::Zstandard,
+                ]
+            ),
+            vec![
+                CompressionAlgorithm::Brotli,
+                CompressionAlgorithm::Zstandard,
+                CompressionAlgorithm::Deflate,
+            ]
+        );
+    }
+}
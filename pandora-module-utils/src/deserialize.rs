@@ -18,8 +18,10 @@
 use pingora::server::configuration::ServerConf;
 use serde::de::value::{MapAccessDeserializer, StrDeserializer, StringDeserializer};
 use serde::de::{Deserialize, DeserializeSeed, Deserializer, Error, SeqAccess, Visitor};
+use std::cell::RefCell;
 use std::fmt::Debug;
 use std::ops::{Deref, DerefMut};
+use std::path::{Path, PathBuf};
 
 /// Used to efficiently deserialize merged configurations
 pub trait DeserializeMap<'de>: Deserialize<'de> {
@@ -116,10 +118,6 @@ impl_deserialize_map!(ServerConf {
     error_log
     pid_file
     upgrade_sock
-    user
-    group
-    threads
-    work_stealing
     ca_file
     grace_period_seconds
     graceful_shutdown_timeout_seconds
@@ -130,6 +128,39 @@ impl_deserialize_map!(ServerConf {
     upstream_connect_offload_thread_per_pool
 });
 
+thread_local! {
+    static CONFIG_DIR: RefCell<Option<PathBuf>> = const { RefCell::new(None) };
+}
+
+/// Returns the directory of the configuration file currently being loaded.
+///
+/// This is only available while deserializing a configuration file, e.g. via
+/// [`FromYaml::load_from_files`](crate::FromYaml::load_from_files). It will be `None` when
+/// configuration is loaded from a string.
+pub fn config_dir() -> Option<PathBuf> {
+    CONFIG_DIR.with(|dir| dir.borrow().clone())
+}
+
+/// Sets the configuration file directory for the duration of its lifetime
+pub(crate) struct ConfigDirGuard {
+    previous: Option<PathBuf>,
+}
+
+impl ConfigDirGuard {
+    pub(crate) fn new(config_file: &Path) -> Self {
+        let dir = config_file.parent().map(|dir| dir.to_path_buf());
+        let previous = CONFIG_DIR.with(|current| current.replace(dir));
+        Self { previous }
+    }
+}
+
+impl Drop for ConfigDirGuard {
+    fn drop(&mut self) {
+        let previous = self.previous.take();
+        CONFIG_DIR.with(|current| current.replace(previous));
+    }
+}
+
 /// A wrapper around the `Vec` type allowing more comfortable deserialization.
 ///
 /// If a list is encountered in the configuration file, it is deserialized into `Vec` directly.
@@ -301,8 +332,8 @@ pub mod _private {
     //! <https://lukaskalbertodt.github.io/2019/12/05/generalized-autoref-based-specialization.html>
 
     use serde::{
-        de::{DeserializeSeed, MapAccess, Visitor},
-        Deserialize, Deserializer,
+        de::{value::StrDeserializer, DeserializeSeed, Error, MapAccess, Visitor},
+        forward_to_deserialize_any, Deserialize, Deserializer,
     };
     use std::{
         collections::{BTreeMap, HashMap},
@@ -311,6 +342,61 @@ pub mod _private {
         marker::PhantomData,
     };
 
+    /// Deserializer for shorthand string values. Unlike `StrDeserializer` it also supports
+    /// `Option` types, the string is considered the `Some` value then.
+    pub struct ShorthandDeserializer<'a, E> {
+        value: &'a str,
+        marker: PhantomData<E>,
+    }
+
+    impl<'a, E> ShorthandDeserializer<'a, E> {
+        pub fn new(value: &'a str) -> Self {
+            Self {
+                value,
+                marker: PhantomData,
+            }
+        }
+    }
+
+    impl<'de, E> Deserializer<'de> for ShorthandDeserializer<'_, E>
+    where
+        E: Error,
+    {
+        type Error = E;
+
+        fn deserialize_any<V>(self, visitor: V) -> Result<V::Value, Self::Error>
+        where
+            V: Visitor<'de>,
+        {
+            visitor.visit_str(self.value)
+        }
+
+        fn deserialize_option<V>(self, visitor: V) -> Result<V::Value, Self::Error>
+        where
+            V: Visitor<'de>,
+        {
+            visitor.visit_some(self)
+        }
+
+        fn deserialize_enum<V>(
+            self,
+            name: &'static str,
+            variants: &'static [&'static str],
+            visitor: V,
+        ) -> Result<V::Value, Self::Error>
+        where
+            V: Visitor<'de>,
+        {
+            StrDeserializer::new(self.value).deserialize_enum(name, variants, visitor)
+        }
+
+        forward_to_deserialize_any! {
+            bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string
+            bytes byte_buf unit unit_struct newtype_struct seq tuple
+            tuple_struct map struct identifier ignored_any
+        }
+    }
+
     pub trait DeserializeMerge<'de, T> {
         fn deserialize_merge<D>(&self, initial: T, deserializer: D) -> Result<T, D::Error>
         where
@@ -452,7 +538,23 @@ pub mod _private {
 
 #[cfg(test)]
 mod tests {
-    use crate::{DeserializeMap, FromYaml, OneOrMany};
+    use crate::{config_dir, DeserializeMap, FromYaml, OneOrMany};
+    use std::path::Path;
+
+    #[test]
+    fn config_dir_guard() {
+        assert_eq!(config_dir(), None);
+        {
+            let _guard = super::ConfigDirGuard::new(Path::new("/etc/pandora/conf.yaml"));
+            assert_eq!(config_dir().as_deref(), Some(Path::new("/etc/pandora")));
+            {
+                let _guard = super::ConfigDirGuard::new(Path::new("conf.d/other.yaml"));
+                assert_eq!(config_dir().as_deref(), Some(Path::new("conf.d")));
+            }
+            assert_eq!(config_dir().as_deref(), Some(Path::new("/etc/pandora")));
+        }
+        assert_eq!(config_dir(), None);
+    }
 
     #[test]
     fn one_or_many_strings() {
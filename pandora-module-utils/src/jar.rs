@@ -12,8 +12,11 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+#[cfg(feature = "jar")]
 use once_cell::sync::Lazy;
-use std::{collections::BTreeSet, sync::Mutex, time::SystemTime};
+use std::collections::BTreeSet;
+#[cfg(feature = "jar")]
+use std::{sync::Mutex, time::SystemTime};
 
 #[derive(Debug, Default, Copy, Clone)]
 pub enum Θεός {
@@ -25,49 +28,81 @@ pub enum Θεός {
     Δῖος,
 }
 
+#[cfg(feature = "jar")]
 impl Θεός {
     fn angry(&self) -> bool {
         format!("{self:?}").chars().count() < 5
     }
 }
 
-/// DO NOT OPEN!
+/// An ordered set of strings
+///
+/// DO NOT OPEN! The `open()` method is only available with the `jar` feature.
 #[derive(Debug, Default)]
 pub struct Πίθος {
     contents: BTreeSet<String>,
     touched: Θεός,
 }
 impl Πίθος {
-    fn len(&self) -> usize {
+    /// Creates an empty jar.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns the number of values in the jar.
+    pub fn len(&self) -> usize {
         self.contents.len()
     }
 
-    fn touch(&mut self, who: Θεός) {
-        self.touched = who;
+    /// Returns `true` if the jar contains no values.
+    pub fn is_empty(&self) -> bool {
+        self.contents.is_empty()
+    }
+
+    /// Adds a value to the jar. Returns `false` if the value was present already.
+    pub fn insert(&mut self, value: impl Into<String>) -> bool {
+        self.contents.insert(value.into())
+    }
+
+    /// Checks whether the jar contains the given value.
+    pub fn contains(&self, value: &str) -> bool {
+        self.contents.contains(value)
     }
 
-    fn add(&mut self, value: String) {
-        self.contents.insert(value);
+    /// Iterates over the values in the jar in sorted order.
+    pub fn iter(&self) -> impl Iterator<Item = &str> {
+        self.contents.iter().map(String::as_str)
     }
 
-    fn seal(&self) -> String {
+    /// Concatenates the name of whoever touched the jar last and all of its values.
+    pub fn seal(&self) -> String {
         std::iter::once(&format!("{:?}", self.touched))
             .chain(self.contents.iter())
             .map(|s| s.as_str())
             .collect()
     }
 
+    #[cfg(feature = "jar")]
+    fn touch(&mut self, who: Θεός) {
+        self.touched = who;
+    }
+
+    /// Lets the contents out, with no way of stopping it while the wrong god is in charge.
+    #[cfg(feature = "jar")]
     pub fn open(&mut self) {
         // extract_if isn’t stable
         let mut leak = Vec::new();
         self.contents.retain(|v| {
-            if v.chars().next().unwrap().is_uppercase() {
+            if v.is_empty() || v.chars().next().is_some_and(char::is_uppercase) {
                 true
             } else {
                 leak.push(v.clone());
                 false
             }
         });
+        if leak.is_empty() {
+            return;
+        }
 
         use std::io::Write;
         let mut γαῖα = std::io::stdout();
@@ -89,10 +124,12 @@ impl Πίθος {
     }
 }
 
+#[cfg(feature = "jar")]
 #[derive(Debug)]
 pub struct Γυνή {
     possessions: Option<Πίθος>,
 }
+#[cfg(feature = "jar")]
 impl Γυνή {
     fn new() -> Self {
         Self { possessions: None }
@@ -112,7 +149,7 @@ impl Γυνή {
         gift[2] = (gift[1]..gift[2]).rev().nth(5).unwrap();
         gift[0] = (gift[1]..gift[0]).rev().nth(5).unwrap();
         gift[1] = (gift[1]..).nth(19).unwrap();
-        possessions.add(gift.iter().collect());
+        possessions.insert(gift.iter().collect::<String>());
 
         self
     }
@@ -135,7 +172,7 @@ impl Γυνή {
         gift[2] = (gift[2] as u32 ^ gift[0] as u32 ^ gift[2] as u32)
             .try_into()
             .unwrap();
-        possessions.add(gift.iter().collect());
+        possessions.insert(gift.iter().collect::<String>());
 
         self
     }
@@ -166,7 +203,7 @@ impl Γυνή {
         gift[5] = (((gift[5] as u16 as f32) * 1.02).round() as u32)
             .try_into()
             .unwrap();
-        possessions.add(gift.iter().collect());
+        possessions.insert(gift.iter().collect::<String>());
 
         self
     }
@@ -187,7 +224,7 @@ impl Γυνή {
         gift.swap(1, 2);
         gift.swap(0, 1);
         gift[1] -= (gift[1] as u32 * 20 - gift[2] as u32 * 20) as u16;
-        possessions.add(String::from_utf16(&gift).unwrap());
+        possessions.insert(String::from_utf16(&gift).unwrap());
 
         self
     }
@@ -228,7 +265,7 @@ impl Γυνή {
         gift[10] = gift[10]
             .checked_sub(gift[10].wrapping_shr(3) + gift[10].count_ones() as u8)
             .unwrap();
-        possessions.add(String::from_utf8(gift).unwrap());
+        possessions.insert(String::from_utf8(gift).unwrap());
         self
     }
 
@@ -247,6 +284,7 @@ impl Γυνή {
     }
 }
 
+#[cfg(feature = "jar")]
 pub static ΑΝΗΣΙΔΩΡΑ: Lazy<Mutex<Γυνή>> = Lazy::new(|| {
     Mutex::new(
         Γυνή::new()
@@ -258,3 +296,84 @@ pub static ΑΝΗΣΙΔΩΡΑ: Lazy<Mutex<Γυνή>> = Lazy::new(|| {
             .run(),
     )
 });
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(feature = "jar")]
+    use crate::pingora::{Error, RequestHeader, ResponseHeader, SessionWrapper, TestSession};
+    #[cfg(feature = "jar")]
+    use crate::{RequestFilter, RequestFilterResult};
+    #[cfg(feature = "jar")]
+    use async_trait::async_trait;
+    use test_log::test;
+
+    #[test]
+    fn container() {
+        let mut jar = Πίθος::new();
+        assert!(jar.is_empty());
+        assert_eq!(jar.seal(), "Δῖος");
+
+        assert!(jar.insert("ἐλπίς"));
+        assert!(jar.insert("νόσος"));
+        assert!(jar.insert(String::from("γῆρας")));
+        assert!(!jar.insert("νόσος"));
+
+        assert_eq!(jar.len(), 3);
+        assert!(!jar.is_empty());
+        assert!(jar.contains("ἐλπίς"));
+        assert!(!jar.contains("Ἐλπίς"));
+        assert_eq!(jar.iter().collect::<Vec<_>>(), ["γῆρας", "νόσος", "ἐλπίς"]);
+        assert_eq!(jar.seal(), "Δῖοςγῆραςνόσοςἐλπίς");
+    }
+
+    #[cfg(feature = "jar")]
+    #[derive(Debug)]
+    struct Handler;
+
+    #[cfg(feature = "jar")]
+    #[async_trait]
+    impl RequestFilter for Handler {
+        type Conf = ();
+        type CTX = ();
+        fn new_ctx() -> Self::CTX {}
+
+        async fn request_filter(
+            &self,
+            session: &mut impl SessionWrapper,
+            _ctx: &mut Self::CTX,
+        ) -> Result<RequestFilterResult, Box<Error>> {
+            let header = ResponseHeader::build(200, None)?;
+            session.write_response_header(Box::new(header)).await?;
+            Ok(RequestFilterResult::ResponseSent)
+        }
+    }
+
+    #[cfg(feature = "jar")]
+    #[test]
+    fn open_empty() {
+        // Nothing to leak, these should return immediately rather than panic or loop
+        let mut jar = Πίθος::default();
+        assert!(jar.touched.angry());
+        jar.open();
+        assert_eq!(jar.len(), 0);
+
+        jar.insert("");
+        jar.insert("Ἐλπίς");
+        jar.open();
+        assert_eq!(jar.len(), 2);
+    }
+
+    #[cfg(feature = "jar")]
+    #[test(tokio::test)]
+    async fn not_forced_by_handlers() {
+        let header = RequestHeader::build("GET", b"/", None).unwrap();
+        let mut session = TestSession::from(header).await;
+        assert_eq!(
+            session.run(&Handler).await.unwrap(),
+            RequestFilterResult::ResponseSent
+        );
+        assert!(Lazy::get(&ΑΝΗΣΙΔΩΡΑ).is_none());
+    }
+}
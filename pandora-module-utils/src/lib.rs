@@ -18,24 +18,27 @@
 
 #![allow(non_ascii_idents)]
 
+pub mod compression_algorithm;
 mod deserialize;
 #[doc(hidden)]
 pub mod jar;
 pub mod merger;
+pub mod multipart;
 pub mod pingora;
 pub mod router;
 pub mod standard_response;
 mod trie;
 
 use log::{error, info, trace};
-use pingora::{Error, ErrorType, HttpPeer, ResponseHeader, SessionWrapper};
+use pingora::{Bytes, Error, ErrorType, HttpPeer, RequestHeader, ResponseHeader, SessionWrapper};
 use serde::{de::DeserializeSeed, Deserialize};
 use std::fmt::Debug;
 use std::fs::File;
 use std::io::BufReader;
 use std::path::Path;
 
-pub use deserialize::{DeserializeMap, MapVisitor, OneOrMany, _private};
+use deserialize::ConfigDirGuard;
+pub use deserialize::{_private, config_dir, DeserializeMap, MapVisitor, OneOrMany};
 pub use pandora_module_utils_macros::{merge_conf, merge_opt, DeserializeMap, RequestFilter};
 
 // Required for macros
@@ -64,6 +67,93 @@ pub enum RequestFilterResult {
     Unhandled,
 }
 
+impl RequestFilterResult {
+    /// Returns [`RequestFilterResult::ResponseSent`] if the condition is `true` and
+    /// [`RequestFilterResult::Unhandled`] otherwise.
+    pub fn response_sent_if(condition: bool) -> Self {
+        if condition {
+            Self::ResponseSent
+        } else {
+            Self::Unhandled
+        }
+    }
+
+    /// Returns [`RequestFilterResult::Handled`] if the condition is `true` and
+    /// [`RequestFilterResult::Unhandled`] otherwise.
+    pub fn handled_if(condition: bool) -> Self {
+        if condition {
+            Self::Handled
+        } else {
+            Self::Unhandled
+        }
+    }
+
+    /// Checks whether this result stops the request filter chain, meaning that it isn’t
+    /// [`RequestFilterResult::Unhandled`].
+    pub fn is_final(&self) -> bool {
+        *self != Self::Unhandled
+    }
+
+    /// Combines two results according to the rules of the request filter chain: the first result
+    /// that isn’t [`RequestFilterResult::Unhandled`] takes precedence.
+    pub fn or(self, other: Self) -> Self {
+        if self.is_final() {
+            self
+        } else {
+            other
+        }
+    }
+
+    /// Like [`RequestFilterResult::or`] but only calls `other` if its result is required.
+    pub fn or_else(self, other: impl FnOnce() -> Self) -> Self {
+        if self.is_final() {
+            self
+        } else {
+            other()
+        }
+    }
+}
+
+/// Converts `true` into [`RequestFilterResult::Handled`] and `false` into
+/// [`RequestFilterResult::Unhandled`]. Use [`RequestFilterResult::response_sent_if`] if a
+/// response has been sent already.
+impl From<bool> for RequestFilterResult {
+    fn from(value: bool) -> Self {
+        Self::handled_if(value)
+    }
+}
+
+/// Converts `None` into [`RequestFilterResult::Unhandled`], other values are unchanged.
+impl From<Option<RequestFilterResult>> for RequestFilterResult {
+    fn from(value: Option<RequestFilterResult>) -> Self {
+        value.unwrap_or_default()
+    }
+}
+
+/// Access log settings applying to the current request
+///
+/// Handlers routing requests, e.g. the Virtual Hosts module, can store these settings in session
+/// extensions. Access logging handlers like the Common Log module will respect them.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(default)]
+pub struct AccessLogConf {
+    /// If `false`, the request won’t be logged
+    pub enabled: bool,
+
+    /// List of fields to be logged instead of the logging handler’s own format, e.g.
+    /// `[remote_addr, request, status]`. The handler’s format is used if empty.
+    pub format: OneOrMany<String>,
+}
+
+impl Default for AccessLogConf {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            format: Default::default(),
+        }
+    }
+}
+
 /// Trait to be implemented by request filters.
 #[async_trait::async_trait]
 pub trait RequestFilter: Sized {
@@ -112,6 +202,19 @@ pub trait RequestFilter: Sized {
         Ok(None)
     }
 
+    /// Handler to run during Pingora’s `upstream_request_filter` phase, see
+    /// [`pingora::ProxyHttp::upstream_request_filter`]. The handler can modify the request
+    /// header before it is sent to the upstream server, e.g. add or remove headers. All handlers
+    /// in the chain will be called.
+    async fn upstream_request_filter(
+        &self,
+        _session: &mut impl SessionWrapper,
+        _upstream_request: &mut RequestHeader,
+        _ctx: &mut Self::CTX,
+    ) -> Result<(), Box<Error>> {
+        Ok(())
+    }
+
     /// Called when a response header is about to be sent, either from a request filter or an
     /// upstream response.
     ///
@@ -124,6 +227,21 @@ pub trait RequestFilter: Sized {
     ) {
     }
 
+    /// Called for each chunk of the upstream response body, see
+    /// [`pingora::ProxyHttp::upstream_response_body_filter`]. The handler can modify or replace
+    /// the body chunk.
+    ///
+    /// *Note*: This is only called for upstream responses, not for responses produced by request
+    /// filters.
+    fn response_body_filter(
+        &self,
+        _session: &mut impl SessionWrapper,
+        _body: &mut Option<Bytes>,
+        _end_of_stream: bool,
+        _ctx: &mut Self::CTX,
+    ) {
+    }
+
     /// Handler to run during Pingora’s `logging` phase, see [`pingora::ProxyHttp::logging`].
     async fn logging(
         &self,
@@ -216,6 +334,7 @@ where
         })?;
         let reader = BufReader::new(file);
 
+        let _guard = ConfigDirGuard::new(path);
         let conf = self
             .deserialize(serde_yaml::Deserializer::from_reader(reader))
             .map_err(|err| {
@@ -245,3 +364,70 @@ where
         Ok(conf)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn request_filter_result_conversions() {
+        assert_eq!(
+            RequestFilterResult::from(true),
+            RequestFilterResult::Handled
+        );
+        assert_eq!(
+            RequestFilterResult::from(false),
+            RequestFilterResult::Unhandled
+        );
+        assert_eq!(
+            RequestFilterResult::response_sent_if(true),
+            RequestFilterResult::ResponseSent
+        );
+        assert_eq!(
+            RequestFilterResult::response_sent_if(false),
+            RequestFilterResult::Unhandled
+        );
+        assert_eq!(
+            RequestFilterResult::handled_if(true),
+            RequestFilterResult::Handled
+        );
+        assert_eq!(
+            RequestFilterResult::handled_if(false),
+            RequestFilterResult::Unhandled
+        );
+
+        assert_eq!(
+            RequestFilterResult::from(Some(RequestFilterResult::ResponseSent)),
+            RequestFilterResult::ResponseSent
+        );
+        assert_eq!(
+            RequestFilterResult::from(None),
+            RequestFilterResult::Unhandled
+        );
+    }
+
+    #[test]
+    fn request_filter_result_combine() {
+        use RequestFilterResult::*;
+
+        assert_eq!(Unhandled.or(Unhandled), Unhandled);
+        assert_eq!(Unhandled.or(Handled), Handled);
+        assert_eq!(Unhandled.or(ResponseSent), ResponseSent);
+
+        // The first result stopping the chain wins
+        assert_eq!(Handled.or(ResponseSent), Handled);
+        assert_eq!(ResponseSent.or(Handled), ResponseSent);
+        assert_eq!(Handled.or(Unhandled), Handled);
+        assert_eq!(ResponseSent.or(Unhandled), ResponseSent);
+
+        assert_eq!(Unhandled.or_else(|| Handled), Handled);
+        assert_eq!(
+            ResponseSent.or_else(|| unreachable!("shouldn’t be called")),
+            ResponseSent
+        );
+
+        assert!(!Unhandled.is_final());
+        assert!(Handled.is_final());
+        assert!(ResponseSent.is_final());
+    }
+}
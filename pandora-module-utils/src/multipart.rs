@@ -0,0 +1,623 @@
+// Copyright 2024 Wladimir Palant
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Streaming reader for `multipart/form-data` request bodies
+//!
+//! The request body is read part by part, the content of each part is produced in chunks as it
+//! arrives from the client. Only the headers of the current part are kept in memory:
+//!
+//! ```rust
+//! use pandora_module_utils::multipart::MultipartReader;
+//! use pandora_module_utils::pingora::{Error, SessionWrapper};
+//!
+//! async fn upload(session: &mut impl SessionWrapper) -> Result<(), Box<Error>> {
+//!     let mut form = MultipartReader::new(session)?.with_part_limit(1024 * 1024);
+//!     while let Some(part) = form.next_part().await? {
+//!         if part.filename().is_some() {
+//!             while let Some(_chunk) = form.read_part_chunk().await? {
+//!                 // Write chunk to a file here
+//!             }
+//!         } else {
+//!             let value = form.read_part_to_end().await?;
+//!             println!("{}: {}", part.name(), String::from_utf8_lossy(&value));
+//!         }
+//!     }
+//!     Ok(())
+//! }
+//! ```
+//!
+//! Errors produced by the reader carry the appropriate HTTP status: `415 Unsupported Media Type`
+//! for requests that aren’t `multipart/form-data`, `413 Payload Too Large` for parts exceeding
+//! the size limit and `400 Bad Request` for a missing or malformed boundary and other malformed
+//! request bodies.
+
+use bytes::{Buf, BytesMut};
+use http::{header, HeaderMap, HeaderName, HeaderValue, StatusCode};
+
+use crate::pingora::{Bytes, Error, ErrorType, SessionWrapper};
+
+/// Default size limit of a single part’s content, 10 MiB
+pub const DEFAULT_PART_LIMIT: usize = 10 * 1024 * 1024;
+
+/// Maximal size of the header section of a part
+const MAX_HEADERS_LENGTH: usize = 8192;
+
+/// Maximal size of the data following the final boundary, longer data isn’t read
+const MAX_EPILOGUE_LENGTH: usize = 8192;
+
+/// Maximal length of a boundary, see RFC 2046 section 5.1.1
+const MAX_BOUNDARY_LENGTH: usize = 70;
+
+fn error(status: StatusCode, reason: impl Into<String>) -> Box<Error> {
+    Error::explain(ErrorType::HTTPStatus(status.as_u16()), reason.into())
+}
+
+fn malformed(reason: impl Into<String>) -> Box<Error> {
+    error(StatusCode::BAD_REQUEST, reason)
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack
+        .windows(needle.len())
+        .position(|window| window == needle)
+}
+
+/// Removes leading and trailing whitespace from a header value.
+fn trim(mut data: &[u8]) -> &[u8] {
+    while let [b' ' | b'\t', rest @ ..] = data {
+        data = rest;
+    }
+    while let [rest @ .., b' ' | b'\t'] = data {
+        data = rest;
+    }
+    data
+}
+
+/// Splits a header value like `form-data; name="field"` into the value itself and its
+/// parameters. Parameter names are converted to lower case.
+///
+/// Quoted strings end at the next quotation mark, backslashes have no special meaning. This is
+/// how browsers encode field and file names, quotation marks are percent-encoded there.
+fn parse_params(value: &str) -> (&str, Vec<(String, String)>) {
+    let (main, mut rest) = value.split_once(';').unwrap_or((value, ""));
+    let mut params = Vec::new();
+    loop {
+        rest = rest.trim_start_matches(|c: char| matches!(c, ' ' | '\t' | ';'));
+        if rest.is_empty() {
+            break;
+        }
+
+        let end = rest
+            .find(|c: char| matches!(c, '=' | ';'))
+            .unwrap_or(rest.len());
+        let name = rest[..end].trim().to_ascii_lowercase();
+        rest = &rest[end..];
+
+        let value = if let Some(value) = rest.strip_prefix('=') {
+            let value = value.trim_start();
+            if let Some(quoted) = value.strip_prefix('"') {
+                let end = quoted.find('"').unwrap_or(quoted.len());
+                rest = quoted.get(end + 1..).unwrap_or_default();
+                quoted[..end].to_owned()
+            } else {
+                let end = value.find(';').unwrap_or(value.len());
+                rest = &value[end..];
+                value[..end].trim().to_owned()
+            }
+        } else {
+            String::new()
+        };
+        params.push((name, value));
+    }
+    (main.trim(), params)
+}
+
+/// Checks whether a boundary is valid according to RFC 2046 section 5.1.1.
+fn is_boundary_valid(boundary: &str) -> bool {
+    !boundary.is_empty()
+        && boundary.len() <= MAX_BOUNDARY_LENGTH
+        && !boundary.ends_with(' ')
+        && boundary
+            .bytes()
+            .all(|c| c.is_ascii_alphanumeric() || b"'()+_,-./:=? ".contains(&c))
+}
+
+/// Headers of a part of a `multipart/form-data` request body
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MultipartPart {
+    name: String,
+    filename: Option<String>,
+    headers: HeaderMap,
+}
+
+impl MultipartPart {
+    fn parse(data: &[u8]) -> Result<Self, Box<Error>> {
+        let mut headers = HeaderMap::new();
+        for line in data.split(|c| *c == b'\n') {
+            let line = line.strip_suffix(b"\r").unwrap_or(line);
+            if line.is_empty() {
+                continue;
+            }
+
+            let colon = line
+                .iter()
+                .position(|c| *c == b':')
+                .ok_or_else(|| malformed("invalid header line in multipart body"))?;
+            let name = HeaderName::from_bytes(&line[..colon])
+                .map_err(|_| malformed("invalid header name in multipart body"))?;
+            let value = HeaderValue::from_bytes(trim(&line[colon + 1..]))
+                .map_err(|_| malformed("invalid header value in multipart body"))?;
+            headers.append(name, value);
+        }
+
+        // Browsers send file names as raw UTF-8, HeaderValue::to_str() would reject these.
+        let disposition = headers
+            .get(header::CONTENT_DISPOSITION)
+            .map(|value| String::from_utf8_lossy(value.as_bytes()))
+            .ok_or_else(|| malformed("multipart part without Content-Disposition header"))?;
+        let (kind, params) = parse_params(&disposition);
+        if !kind.eq_ignore_ascii_case("form-data") {
+            return Err(malformed(format!(
+                "unexpected Content-Disposition value in multipart part: {kind}"
+            )));
+        }
+
+        let mut name = None;
+        let mut filename = None;
+        for (param, value) in params {
+            match param.as_str() {
+                "name" => name = Some(value),
+                "filename" => filename = Some(value),
+                _ => {}
+            }
+        }
+
+        Ok(Self {
+            name: name.ok_or_else(|| malformed("multipart part without a name"))?,
+            filename,
+            headers,
+        })
+    }
+
+    /// Returns the name of the form field this part belongs to.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Returns the name of the uploaded file if this part is a file upload.
+    pub fn filename(&self) -> Option<&str> {
+        self.filename.as_deref()
+    }
+
+    /// Returns the value of the part’s `Content-Type` header if present.
+    pub fn content_type(&self) -> Option<&str> {
+        self.headers
+            .get(header::CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok())
+    }
+
+    /// Returns all headers of the part.
+    pub fn headers(&self) -> &HeaderMap {
+        &self.headers
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    /// Data before the first boundary
+    Preamble,
+    /// A boundary has been found, it is followed by part headers or the end of the body
+    Boundary,
+    /// Content of a part
+    Content,
+    /// Final boundary has been found
+    Done,
+}
+
+/// Reads a `multipart/form-data` request body part by part
+///
+/// See the [module documentation](self) for an example.
+#[derive(Debug)]
+pub struct MultipartReader<'a, S> {
+    session: &'a mut S,
+    delimiter: Vec<u8>,
+    buffer: BytesMut,
+    eof: bool,
+    state: State,
+    part_limit: usize,
+    part_length: usize,
+}
+
+impl<'a, S: SessionWrapper> MultipartReader<'a, S> {
+    /// Creates a reader for the session’s request body.
+    ///
+    /// This fails with `415 Unsupported Media Type` if the request isn’t `multipart/form-data`
+    /// and with `400 Bad Request` if the boundary is missing or malformed.
+    pub fn new(session: &'a mut S) -> Result<Self, Box<Error>> {
+        let content_type = session
+            .req_header()
+            .headers
+            .get(header::CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok())
+            .unwrap_or_default();
+        let (mime_type, params) = parse_params(content_type);
+        if !mime_type.eq_ignore_ascii_case("multipart/form-data") {
+            return Err(error(
+                StatusCode::UNSUPPORTED_MEDIA_TYPE,
+                format!("expected multipart/form-data request body, got {mime_type}"),
+            ));
+        }
+
+        let boundary = params
+            .into_iter()
+            .find_map(|(name, value)| (name == "boundary").then_some(value))
+            .ok_or_else(|| malformed("multipart/form-data request without boundary"))?;
+        if !is_boundary_valid(&boundary) {
+            return Err(malformed(format!(
+                "malformed multipart boundary {boundary}"
+            )));
+        }
+
+        let mut delimiter = b"\r\n--".to_vec();
+        delimiter.extend_from_slice(boundary.as_bytes());
+
+        // The first boundary isn’t necessarily preceded by a line break, add one so that all
+        // boundaries can be matched in the same way.
+        let mut buffer = BytesMut::new();
+        buffer.extend_from_slice(b"\r\n");
+
+        Ok(Self {
+            session,
+            delimiter,
+            buffer,
+            eof: false,
+            state: State::Preamble,
+            part_limit: DEFAULT_PART_LIMIT,
+            part_length: 0,
+        })
+    }
+
+    /// Sets the size limit for the content of a single part, [`DEFAULT_PART_LIMIT`] by default.
+    /// Reading a larger part fails with `413 Payload Too Large`.
+    pub fn with_part_limit(mut self, part_limit: usize) -> Self {
+        self.part_limit = part_limit;
+        self
+    }
+
+    /// Reads the next chunk of the request body into the buffer, returns `false` if the end of
+    /// the request body has been reached.
+    async fn fill(&mut self) -> Result<bool, Box<Error>> {
+        if self.eof {
+            return Ok(false);
+        }
+
+        self.session.send_continue().await?;
+        if let Some(chunk) = self.session.read_request_body().await? {
+            self.buffer.extend_from_slice(&chunk);
+        } else {
+            self.eof = true;
+        }
+        Ok(!self.eof)
+    }
+
+    /// Reads until the buffer contains `needle`, returns its position. Fails if `limit` bytes
+    /// have been buffered without finding it.
+    async fn fill_until(&mut self, needle: &[u8], limit: usize) -> Result<usize, Box<Error>> {
+        loop {
+            if let Some(pos) = find(&self.buffer, needle) {
+                return Ok(pos);
+            }
+            if self.buffer.len() > limit {
+                return Err(malformed("multipart headers too long"));
+            }
+            if !self.fill().await? {
+                return Err(malformed("unexpected end of multipart body"));
+            }
+        }
+    }
+
+    /// Returns the headers of the next part or `None` if all parts have been read. Any unread
+    /// content of the current part is skipped.
+    pub async fn next_part(&mut self) -> Result<Option<MultipartPart>, Box<Error>> {
+        match self.state {
+            State::Preamble => self.skip_preamble().await?,
+            State::Content => while self.read_part_chunk().await?.is_some() {},
+            State::Boundary => {}
+            State::Done => return Ok(None),
+        }
+
+        while self.buffer.len() < 2 {
+            if !self.fill().await? {
+                return Err(malformed("unexpected end of multipart body"));
+            }
+        }
+        if self.buffer.starts_with(b"--") {
+            // Final boundary, ignore whatever follows it
+            self.state = State::Done;
+            self.buffer.clear();
+            self.session.drain_request_body(MAX_EPILOGUE_LENGTH).await;
+            return Ok(None);
+        }
+
+        // Boundary line might contain trailing whitespace
+        let pos = self.fill_until(b"\r\n", MAX_HEADERS_LENGTH).await?;
+        if !self.buffer[..pos].iter().all(|c| matches!(c, b' ' | b'\t')) {
+            return Err(malformed("unexpected data after multipart boundary"));
+        }
+        self.buffer.advance(pos);
+
+        // Buffer starts with the line break now, so an empty header section is found as well
+        let pos = self.fill_until(b"\r\n\r\n", MAX_HEADERS_LENGTH).await?;
+        let part = MultipartPart::parse(self.buffer.get(2..pos).unwrap_or_default())?;
+        self.buffer.advance(pos + 4);
+
+        self.state = State::Content;
+        self.part_length = 0;
+        Ok(Some(part))
+    }
+
+    /// Discards everything up to and including the first boundary.
+    async fn skip_preamble(&mut self) -> Result<(), Box<Error>> {
+        loop {
+            if let Some(pos) = find(&self.buffer, &self.delimiter) {
+                self.buffer.advance(pos + self.delimiter.len());
+                self.state = State::Boundary;
+                return Ok(());
+            }
+
+            // Keep only the data that might be the start of a boundary
+            let keep = self.delimiter.len() - 1;
+            if self.buffer.len() > keep {
+                self.buffer.advance(self.buffer.len() - keep);
+            }
+
+            if !self.fill().await? {
+                return Err(malformed("no boundary found in multipart body"));
+            }
+        }
+    }
+
+    /// Returns the next chunk of the current part’s content or `None` once the part’s content
+    /// has been read completely.
+    pub async fn read_part_chunk(&mut self) -> Result<Option<Bytes>, Box<Error>> {
+        if self.state != State::Content {
+            return Ok(None);
+        }
+
+        let chunk = loop {
+            if let Some(pos) = find(&self.buffer, &self.delimiter) {
+                let chunk = self.buffer.split_to(pos);
+                self.buffer.advance(self.delimiter.len());
+                self.state = State::Boundary;
+                break chunk;
+            }
+
+            // Data that might be the start of a boundary has to stay in the buffer
+            let available = self.buffer.len().saturating_sub(self.delimiter.len() - 1);
+            if available > 0 {
+                break self.buffer.split_to(available);
+            }
+
+            if !self.fill().await? {
+                return Err(malformed("unexpected end of multipart body"));
+            }
+        };
+
+        self.part_length += chunk.len();
+        if self.part_length > self.part_limit {
+            return Err(error(
+                StatusCode::PAYLOAD_TOO_LARGE,
+                format!("multipart part exceeds {} bytes", self.part_limit),
+            ));
+        }
+
+        Ok(if chunk.is_empty() {
+            None
+        } else {
+            Some(chunk.freeze())
+        })
+    }
+
+    /// Reads the remaining content of the current part.
+    ///
+    /// The content is kept in memory, only use this for parts that are expected to be small.
+    pub async fn read_part_to_end(&mut self) -> Result<Bytes, Box<Error>> {
+        let mut content = BytesMut::new();
+        while let Some(chunk) = self.read_part_chunk().await? {
+            content.extend_from_slice(&chunk);
+        }
+        Ok(content.freeze())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::pingora::{RequestHeader, TestSession};
+    use test_log::test;
+
+    async fn make_session(content_type: &str, body: &str) -> TestSession {
+        let mut header = RequestHeader::build("POST", b"/upload", None).unwrap();
+        header
+            .insert_header(header::CONTENT_TYPE, content_type)
+            .unwrap();
+        TestSession::with_body(header, body.replace('\n', "\r\n")).await
+    }
+
+    fn status(err: Box<Error>) -> Option<u16> {
+        match err.etype() {
+            ErrorType::HTTPStatus(status) => Some(*status),
+            _ => None,
+        }
+    }
+
+    #[test(tokio::test)]
+    async fn two_parts() -> Result<(), Box<Error>> {
+        let mut session = make_session(
+            "multipart/form-data; boundary=\"----boundary\"",
+            r#"This is the preamble
+------boundary
+Content-Disposition: form-data; name="title"
+
+Hello, world!
+------boundary
+Content-Disposition: form-data; name="file"; filename="tëst.txt"
+Content-Type: text/plain
+
+First line
+------boundar
+------boundary--
+This is the epilogue
+"#,
+        )
+        .await;
+
+        let mut form = MultipartReader::new(&mut session)?;
+        let part = form.next_part().await?.unwrap();
+        assert_eq!(part.name(), "title");
+        assert_eq!(part.filename(), None);
+        assert_eq!(part.content_type(), None);
+        assert_eq!(form.read_part_to_end().await?, "Hello, world!");
+
+        let part = form.next_part().await?.unwrap();
+        assert_eq!(part.name(), "file");
+        assert_eq!(part.filename(), Some("tëst.txt"));
+        assert_eq!(part.content_type(), Some("text/plain"));
+        assert_eq!(part.headers().len(), 2);
+        assert_eq!(
+            form.read_part_to_end().await?,
+            "First line\r\n------boundar"
+        );
+        assert_eq!(form.read_part_chunk().await?, None);
+
+        assert_eq!(form.next_part().await?, None);
+        assert_eq!(form.next_part().await?, None);
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn skip_content() -> Result<(), Box<Error>> {
+        let mut session = make_session(
+            "Multipart/Form-Data; charset=utf-8; boundary=xyz",
+            r#"--xyz
+Content-Disposition: form-data; name=first
+
+Skipped
+--xyz
+Content-Disposition: form-data; name=second
+
+
+--xyz--"#,
+        )
+        .await;
+
+        let mut form = MultipartReader::new(&mut session)?;
+        assert_eq!(form.next_part().await?.unwrap().name(), "first");
+        let part = form.next_part().await?.unwrap();
+        assert_eq!(part.name(), "second");
+        assert_eq!(form.read_part_to_end().await?, "");
+        assert_eq!(form.next_part().await?, None);
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn content_type() {
+        for content_type in [
+            "multipart/form-data",
+            "multipart/form-data; charset=utf-8",
+            "multipart/form-data; boundary=",
+            "multipart/form-data; boundary=\"\"",
+            "multipart/form-data; boundary=\"trailing space \"",
+            "multipart/form-data; boundary=invalid*char",
+            format!("multipart/form-data; boundary={}", "x".repeat(71)).as_str(),
+        ] {
+            let mut session = make_session(content_type, "").await;
+            assert_eq!(
+                status(MultipartReader::new(&mut session).unwrap_err()),
+                Some(400),
+                "{content_type}"
+            );
+        }
+
+        for content_type in ["", "application/x-www-form-urlencoded", "multipart/mixed"] {
+            let mut session = make_session(content_type, "").await;
+            assert_eq!(
+                status(MultipartReader::new(&mut session).unwrap_err()),
+                Some(415),
+                "{content_type}"
+            );
+        }
+    }
+
+    #[test(tokio::test)]
+    async fn malformed_body() -> Result<(), Box<Error>> {
+        for body in [
+            "no boundary here",
+            "--xyz",
+            "--xyzabc\nContent-Disposition: form-data; name=field\n\n",
+            "--xyz\nContent-Disposition: form-data; name=field\n",
+            "--xyz\nContent-Disposition: attachment; name=field\n\n\n--xyz--",
+            "--xyz\nContent-Disposition: form-data\n\n\n--xyz--",
+            "--xyz\nContent-Type: text/plain\n\n\n--xyz--",
+            "--xyz\n\n\n--xyz--",
+        ] {
+            let mut session = make_session("multipart/form-data; boundary=xyz", body).await;
+            let mut form = MultipartReader::new(&mut session)?;
+            assert_eq!(
+                status(form.next_part().await.unwrap_err()),
+                Some(400),
+                "{body}"
+            );
+        }
+
+        // Body ends without final boundary
+        let mut session = make_session(
+            "multipart/form-data; boundary=xyz",
+            "--xyz\nContent-Disposition: form-data; name=field\n\nvalue",
+        )
+        .await;
+        let mut form = MultipartReader::new(&mut session)?;
+        form.next_part().await?;
+        assert_eq!(
+            status(form.read_part_to_end().await.unwrap_err()),
+            Some(400)
+        );
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn part_limit() -> Result<(), Box<Error>> {
+        let body = r#"--xyz
+Content-Disposition: form-data; name="small"
+
+12345
+--xyz
+Content-Disposition: form-data; name="large"
+
+1234567890
+--xyz--"#;
+
+        let mut session = make_session("multipart/form-data; boundary=xyz", body).await;
+        let mut form = MultipartReader::new(&mut session)?.with_part_limit(5);
+        form.next_part().await?;
+        assert_eq!(form.read_part_to_end().await?, "12345");
+        form.next_part().await?;
+        assert_eq!(
+            status(form.read_part_to_end().await.unwrap_err()),
+            Some(413)
+        );
+        Ok(())
+    }
+}
@@ -16,19 +16,30 @@
 //! longer need them as direct dependencies.
 
 use async_trait::async_trait;
-use bytes::{Bytes, BytesMut};
-use http::{header, Extensions, Uri};
+pub use bytes::Bytes;
+use bytes::BytesMut;
+use http::{header, uri::Authority, Extensions, Method, StatusCode, Uri, Version};
+use log::warn;
 pub use pingora::http::{IntoCaseHeaderName, RequestHeader, ResponseHeader};
 pub use pingora::protocols::http::HttpTask;
 pub use pingora::protocols::l4::socket::SocketAddr;
 pub use pingora::proxy::{http_proxy_service, ProxyHttp, Session};
 pub use pingora::server::configuration::{Opt as ServerOpt, ServerConf};
-pub use pingora::server::Server;
+pub use pingora::server::{Server, ShutdownWatch};
+pub use pingora::services::background::{background_service, BackgroundService};
+pub use pingora::services::Service;
 pub use pingora::upstreams::peer::HttpPeer;
 pub use pingora::{Error, ErrorType};
+use serde::Deserialize;
 use std::borrow::Cow;
-use std::io::{Cursor, Seek, SeekFrom, Write};
+use std::fs::{File, OpenOptions};
+use std::io::{Cursor, ErrorKind, Read, Seek, SeekFrom, Write};
+use std::net::Ipv6Addr;
 use std::ops::{Deref, DerefMut};
+use std::path::PathBuf;
+use std::time::Duration;
+
+use crate::{RequestFilter, RequestFilterResult};
 
 /// A trait implemented by wrappers around Pingora’s session
 ///
@@ -36,31 +47,65 @@ use std::ops::{Deref, DerefMut};
 #[async_trait]
 pub trait SessionWrapper: Send + Deref<Target = Session> + DerefMut {
     /// Attempts to determine the request host if one was specified.
+    ///
+    /// The host is taken from the `Host` header or the request URI. Any user info is removed,
+    /// the port is kept if present. IPv6 addresses are returned in brackets, e.g. `[::1]:8080`.
+    /// Invalid hosts are ignored.
     fn host(&self) -> Option<Cow<'_, str>>
     where
         Self: Sized,
     {
         fn host_from_header(session: &impl SessionWrapper) -> Option<Cow<'_, str>> {
             let host = session.get_header(header::HOST)?;
-            host.to_str().ok().map(|h| h.into())
+            normalize_host(host.to_str().ok()?).map(|h| h.into())
         }
 
         fn host_from_uri(session: &impl SessionWrapper) -> Option<Cow<'_, str>> {
-            let uri = session.uri();
-            let host = uri.host()?;
-            if let Some(port) = uri.port() {
-                let mut host = host.to_owned();
-                host.push(':');
-                host.push_str(port.as_str());
-                Some(host.into())
-            } else {
-                Some(host.into())
-            }
+            let authority = session.uri().authority()?;
+            normalize_host(authority.as_str()).map(|h| h.into())
         }
 
         host_from_header(self).or_else(|| host_from_uri(self))
     }
 
+    /// Returns the scheme of the request, either `http` or `https`.
+    ///
+    /// If `trust_forwarded` is `false`, this reflects whether the connection to the client is
+    /// encrypted. Otherwise the `X-Forwarded-Proto` header set by a proxy will be taken into
+    /// account if present. Only enable this if the server is behind a trusted proxy, the header
+    /// can be set by any client.
+    fn scheme(&self, trust_forwarded: bool) -> &str {
+        if trust_forwarded {
+            let forwarded = self
+                .get_header("X-Forwarded-Proto")
+                .and_then(|value| value.to_str().ok())
+                .and_then(|value| value.split(',').next())
+                .map(|value| value.trim());
+            if let Some(forwarded) = forwarded {
+                if forwarded.eq_ignore_ascii_case("https") {
+                    return "https";
+                } else if forwarded.eq_ignore_ascii_case("http") {
+                    return "http";
+                }
+            }
+        }
+
+        if self.tls_version().is_some() {
+            "https"
+        } else {
+            "http"
+        }
+    }
+
+    /// Returns the TLS version negotiated with the client, e.g. `TLSv1.3`. This will be `None`
+    /// for connections not using TLS.
+    fn tls_version(&self) -> Option<&str> {
+        self.digest()?
+            .ssl_digest
+            .as_ref()
+            .map(|digest| digest.version)
+    }
+
     /// Return the client (peer) address of the connection.
     ///
     /// Unlike the identical method of the Pingora session, this value can be overwritten.
@@ -110,6 +155,52 @@ pub trait SessionWrapper: Send + Deref<Target = Session> + DerefMut {
         }
     }
 
+    /// Returns the percent-decoded request path, see [`percent_decode_path`].
+    ///
+    /// `None` is returned if the path cannot be decoded safely: it contains invalid
+    /// percent-encoded sequences, an encoded null byte (`%00`) or an encoded slash (`%2F`), or
+    /// the decoded path isn’t valid UTF-8. Encoded slashes are rejected because the decoded path
+    /// would be indistinguishable from one with actual path separators.
+    fn decoded_path(&self) -> Option<Cow<'_, str>> {
+        let path = self.uri().path();
+        if path.contains("%2F") || path.contains("%2f") {
+            return None;
+        }
+
+        match percent_decode_path(path)? {
+            Cow::Borrowed(_) => Some(Cow::Borrowed(path)),
+            Cow::Owned(decoded) => String::from_utf8(decoded).ok().map(Cow::Owned),
+        }
+    }
+
+    /// Parses the `Accept-Encoding` request header into a list of content codings sorted by
+    /// their quality value, highest first.
+    ///
+    /// Codings explicitly rejected by the client (`q=0`) are kept at the end of the list. Use
+    /// [`AcceptedEncodings::quality`] to determine whether a particular coding is acceptable.
+    fn accepted_encodings(&self) -> AcceptedEncodings {
+        AcceptedEncodings::parse(
+            self.req_header()
+                .headers
+                .get_all(header::ACCEPT_ENCODING)
+                .iter()
+                .filter_map(|value| value.to_str().ok()),
+        )
+    }
+
+    /// Returns the number of request headers and their combined size in bytes.
+    ///
+    /// Each header is counted as it would appear in an HTTP/1.1 request, `name: value` followed
+    /// by a line break. The request line isn’t included.
+    fn request_header_stats(&self) -> (usize, usize) {
+        let headers = &self.req_header().headers;
+        let size = headers
+            .iter()
+            .map(|(name, value)| name.as_str().len() + value.len() + 4)
+            .sum();
+        (headers.len(), size)
+    }
+
     /// Returns the name of the authorized user if any
     fn remote_user(&self) -> Option<&str> {
         if let Some(RemoteUser(remote_user)) = self.extensions().get() {
@@ -124,14 +215,41 @@ pub trait SessionWrapper: Send + Deref<Target = Session> + DerefMut {
         self.extensions_mut().insert(RemoteUser(remote_user));
     }
 
+    /// Returns the policy applied to cookies produced by [`SessionWrapper::format_cookie`]
+    fn cookie_policy(&self) -> CookiePolicy {
+        self.extensions().get().copied().unwrap_or_default()
+    }
+
+    /// Changes the policy applied to cookies produced by [`SessionWrapper::format_cookie`]
+    fn set_cookie_policy(&mut self, policy: CookiePolicy) {
+        self.extensions_mut().insert(policy);
+    }
+
+    /// Produces a `Set-Cookie` header value for the given cookie, see [`CookiePolicy::format`].
+    ///
+    /// The `Secure` attribute is added by default if the connection to the client is encrypted.
+    fn format_cookie(&self, name: &str, value: &str, attributes: &str) -> String {
+        self.cookie_policy()
+            .format(self.tls_version().is_some(), name, value, attributes)
+    }
+
     /// See [`Session::write_response_header`](pingora::protocols::http::server::Session::write_response_header)
-    async fn write_response_header(&mut self, resp: Box<ResponseHeader>) -> Result<(), Box<Error>> {
+    ///
+    /// Unlike Pingora’s method, this will disable keep-alive if the response body can only be
+    /// delimited by closing the connection. Chunked encoding is removed from responses to
+    /// HTTP/1.0 requests as these clients don’t support it.
+    async fn write_response_header(
+        &mut self,
+        mut resp: Box<ResponseHeader>,
+    ) -> Result<(), Box<Error>> {
+        let expects_continue = self.expects_continue();
+        prepare_keepalive(self.deref_mut(), &mut resp, expects_continue);
         self.deref_mut().write_response_header(resp).await
     }
 
     /// See [`Session::write_response_header_ref`](pingora::protocols::http::server::Session::write_response_header_ref)
     async fn write_response_header_ref(&mut self, resp: &ResponseHeader) -> Result<(), Box<Error>> {
-        self.deref_mut().write_response_header_ref(resp).await
+        self.write_response_header(Box::new(resp.clone())).await
     }
 
     /// See [`Session::response_written`](pingora::protocols::http::server::Session::response_written)
@@ -143,6 +261,514 @@ pub trait SessionWrapper: Send + Deref<Target = Session> + DerefMut {
     async fn write_response_body(&mut self, data: Bytes) -> Result<(), Box<Error>> {
         self.deref_mut().write_response_body(data).await
     }
+
+    /// Checks whether a response has been sent already, either by the current handler or by
+    /// another handler earlier in the chain. No further response should be written in this case.
+    fn response_started(&self) -> bool {
+        self.response_written().is_some()
+    }
+
+    /// Returns the number of response bytes sent to the client so far, see
+    /// [`Session::body_bytes_sent`](pingora::protocols::http::server::Session::body_bytes_sent)
+    ///
+    /// Despite the name of the Pingora method, for HTTP/1 connections this includes the
+    /// serialized response header as well as any informational (1xx) responses. For HTTP/2
+    /// connections only response body bytes are counted.
+    fn bytes_sent(&self) -> usize {
+        self.deref().body_bytes_sent()
+    }
+
+    /// Returns the number of request body bytes received from the client so far, see
+    /// [`Session::body_bytes_read`](pingora::protocols::http::server::Session::body_bytes_read)
+    fn bytes_received(&self) -> usize {
+        self.deref().body_bytes_read()
+    }
+
+    /// Checks whether the client sent an `Expect: 100-continue` request header and is still
+    /// waiting for a `100 Continue` response before sending the request body. This is only
+    /// supported for HTTP/1.1 requests.
+    fn expects_continue(&self) -> bool {
+        let request = self.req_header();
+        request.version == Version::HTTP_11
+            && self.extensions().get::<ContinueSent>().is_none()
+            && request
+                .headers
+                .get(header::EXPECT)
+                .is_some_and(|value| value.as_bytes().eq_ignore_ascii_case(b"100-continue"))
+    }
+
+    /// Sends a `100 Continue` response if the client is waiting for one, see
+    /// [`SessionWrapper::expects_continue`]. Does nothing otherwise.
+    ///
+    /// Handlers accepting the request body should call this before reading it, otherwise the
+    /// client will only send the body after a delay. Handlers rejecting the request should
+    /// produce their response without calling this method.
+    async fn send_continue(&mut self) -> Result<(), Box<Error>> {
+        if !self.expects_continue() || self.response_written().is_some() {
+            return Ok(());
+        }
+
+        self.extensions_mut().insert(ContinueSent);
+        let header = ResponseHeader::build(StatusCode::CONTINUE, Some(0))?;
+        self.write_response_header(Box::new(header)).await
+    }
+
+    /// Reads and discards the remaining request body.
+    ///
+    /// Handlers producing a response without processing the request body should call this
+    /// before writing the response, otherwise the connection cannot be reused for further
+    /// requests. If the body exceeds `limit` bytes or reading it fails, keep-alive is disabled
+    /// instead and the connection will be closed after the response. The same happens if the
+    /// client is still waiting for `100 Continue` and didn’t send the body yet.
+    ///
+    /// Returns `true` if the request body has been consumed completely.
+    async fn drain_request_body(&mut self, limit: usize) -> bool {
+        if self.expects_continue() {
+            self.set_keepalive(None);
+            return false;
+        }
+
+        let mut drained = 0;
+        while let Ok(chunk) = self.read_request_body().await {
+            match chunk {
+                Some(chunk) => {
+                    drained += chunk.len();
+                    if drained > limit {
+                        break;
+                    }
+                }
+                None => return true,
+            }
+        }
+
+        self.set_keepalive(None);
+        false
+    }
+
+    /// Reads the complete request body, keeping up to `memory_limit` bytes in memory.
+    ///
+    /// Larger request bodies are written to a temporary file instead. Either way, the returned
+    /// handle allows reading the body back. The temporary file is removed when the handle is
+    /// dropped. If the client is waiting for `100 Continue`, it is sent before reading.
+    ///
+    /// Reading is aborted with a `413 Payload Too Large` error as soon as the body exceeds
+    /// `max_size` bytes.
+    async fn buffer_request_body(
+        &mut self,
+        memory_limit: usize,
+        max_size: usize,
+    ) -> Result<BufferedBody, Box<Error>> {
+        self.send_continue().await?;
+
+        let mut body = BytesMut::new();
+        let mut file: Option<TempFile> = None;
+        let mut size = 0;
+        while let Some(chunk) = self.read_request_body().await? {
+            size += chunk.len();
+            if size > max_size {
+                return Err(Error::explain(
+                    ErrorType::HTTPStatus(413),
+                    format!("request body exceeds {max_size} bytes"),
+                ));
+            }
+
+            if let Some(file) = &mut file {
+                file.write(&chunk)?;
+            } else if body.len() + chunk.len() > memory_limit {
+                let mut temp = TempFile::create()?;
+                temp.write(&body)?;
+                temp.write(&chunk)?;
+                body.clear();
+                file = Some(temp);
+            } else {
+                body.extend_from_slice(&chunk);
+            }
+        }
+
+        Ok(if let Some(mut file) = file {
+            let len = file.rewind()?;
+            BufferedBody {
+                inner: BufferedBodyInner::File(file),
+                len,
+            }
+        } else {
+            let len = body.len();
+            BufferedBody {
+                inner: BufferedBodyInner::Memory(Cursor::new(body.freeze())),
+                len,
+            }
+        })
+    }
+}
+
+/// Marks a session where `100 Continue` has been sent already
+#[derive(Debug, Clone, Copy)]
+struct ContinueSent;
+
+/// Disables keep-alive for responses that are terminated by closing the connection: responses
+/// without `Content-Length` header and without chunked encoding. For HTTP/1.0 requests, chunked
+/// encoding is removed from the response as well.
+///
+/// Keep-alive is also disabled if the client is still waiting for `100 Continue`: it might send
+/// the request body after receiving the response or not.
+fn prepare_keepalive(session: &mut Session, response: &mut ResponseHeader, expects_continue: bool) {
+    if response.status.is_informational() {
+        return;
+    }
+
+    if expects_continue {
+        session.set_keepalive(None);
+        return;
+    }
+
+    let request = session.req_header();
+    if request.method == Method::HEAD
+        || matches!(
+            response.status,
+            StatusCode::NO_CONTENT | StatusCode::NOT_MODIFIED
+        )
+    {
+        return;
+    }
+
+    let http10 = request.version == Version::HTTP_10;
+    let chunked = response
+        .headers
+        .get(header::TRANSFER_ENCODING)
+        .is_some_and(|value| value.as_bytes().eq_ignore_ascii_case(b"chunked"));
+    if chunked && http10 {
+        response.remove_header(&header::TRANSFER_ENCODING);
+    } else if chunked || response.headers.contains_key(header::CONTENT_LENGTH) {
+        return;
+    }
+
+    session.set_keepalive(None);
+}
+
+/// A content coding listed in the `Accept-Encoding` request header
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AcceptedEncoding {
+    /// Name of the coding in lower case, e.g. `gzip`, `identity` or `*`
+    pub coding: String,
+    /// Quality value multiplied by 1000, `0` means that the coding is not acceptable
+    pub quality: u16,
+}
+
+/// Parsed `Accept-Encoding` request header, as returned by
+/// [`SessionWrapper::accepted_encodings`]
+///
+/// The entries are sorted by quality value, entries with identical quality values keep their
+/// original order.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct AcceptedEncodings(Vec<AcceptedEncoding>);
+
+impl AcceptedEncodings {
+    /// Parses the values of one or multiple `Accept-Encoding` headers.
+    ///
+    /// Invalid quality values are ignored, such entries are considered to have the default
+    /// quality `1`.
+    pub fn parse<'a>(values: impl IntoIterator<Item = &'a str>) -> Self {
+        let mut encodings = values
+            .into_iter()
+            .flat_map(|value| value.split(','))
+            .filter_map(|entry| {
+                let mut params = entry.split(';');
+                let coding = params.next()?.trim();
+                if coding.is_empty() {
+                    return None;
+                }
+
+                let mut quality = 1000;
+                for param in params {
+                    if let Some((name, value)) = param.split_once('=') {
+                        if name.trim().eq_ignore_ascii_case("q") {
+                            if let Ok(value) = value.trim().parse::<f32>() {
+                                quality = (value.clamp(0.0, 1.0) * 1000.0).round() as u16;
+                            }
+                        }
+                    }
+                }
+
+                Some(AcceptedEncoding {
+                    coding: coding.to_ascii_lowercase(),
+                    quality,
+                })
+            })
+            .collect::<Vec<_>>();
+        encodings.sort_by_key(|encoding| std::cmp::Reverse(encoding.quality));
+        Self(encodings)
+    }
+
+    /// Returns the quality value (multiplied by 1000) that the client assigned to a coding, `0`
+    /// if the coding is not acceptable.
+    ///
+    /// An explicit entry for the coding takes precedence, otherwise the `*` entry applies if
+    /// present. The `identity` coding is acceptable unless rejected explicitly or via `*;q=0`.
+    pub fn quality(&self, coding: &str) -> u16 {
+        let find = |coding: &str| {
+            self.0
+                .iter()
+                .find(|encoding| encoding.coding.eq_ignore_ascii_case(coding))
+                .map(|encoding| encoding.quality)
+        };
+
+        if let Some(quality) = find(coding).or_else(|| find("*")) {
+            quality
+        } else if coding.eq_ignore_ascii_case("identity") {
+            1000
+        } else {
+            0
+        }
+    }
+
+    /// Checks whether the client accepts the given coding.
+    pub fn accepts(&self, coding: &str) -> bool {
+        self.quality(coding) > 0
+    }
+
+    /// Returns an iterator over the codings acceptable to the client, sorted by quality value.
+    pub fn acceptable(&self) -> impl Iterator<Item = &AcceptedEncoding> {
+        self.0.iter().filter(|encoding| encoding.quality > 0)
+    }
+}
+
+impl Deref for AcceptedEncodings {
+    type Target = [AcceptedEncoding];
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+/// Value of the `SameSite` cookie attribute
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+pub enum SameSite {
+    /// Cookie is only sent with same-site requests
+    Strict,
+    /// Cookie is also sent with top-level navigation from other sites
+    #[default]
+    Lax,
+    /// Cookie is sent with all requests, this requires the `Secure` attribute
+    None,
+}
+
+impl SameSite {
+    /// Returns the attribute value as used in the `Set-Cookie` header.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Strict => "Strict",
+            Self::Lax => "Lax",
+            Self::None => "None",
+        }
+    }
+}
+
+/// Defaults applied to cookies set by modules, see [`SessionWrapper::cookie_policy`]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CookiePolicy {
+    /// Determines whether the `Secure` attribute should be added. If `None`, the attribute is
+    /// added for HTTPS connections.
+    pub secure: Option<bool>,
+
+    /// Value of the `SameSite` attribute, `Lax` by default
+    pub same_site: SameSite,
+}
+
+impl CookiePolicy {
+    /// Produces a `Set-Cookie` header value for the given cookie.
+    ///
+    /// `attributes` are added verbatim, e.g. `Max-Age=3600; HttpOnly`. Unless already present
+    /// there, `SameSite` and `Secure` attributes are added according to the policy. `https`
+    /// indicates whether the connection to the client is encrypted. `SameSite=None` is only
+    /// accepted by browsers for secure cookies, so `Secure` is always added then.
+    pub fn format(&self, https: bool, name: &str, value: &str, attributes: &str) -> String {
+        let attributes = attributes.trim();
+        let mut cookie = format!("{name}={value}");
+        if !attributes.is_empty() {
+            cookie.push_str("; ");
+            cookie.push_str(attributes);
+        }
+
+        let same_site_none = match cookie_attribute(attributes, "SameSite") {
+            Some(same_site) => same_site.eq_ignore_ascii_case(SameSite::None.as_str()),
+            None => {
+                cookie.push_str("; SameSite=");
+                cookie.push_str(self.same_site.as_str());
+                self.same_site == SameSite::None
+            }
+        };
+
+        if cookie_attribute(attributes, "Secure").is_none()
+            && (self.secure.unwrap_or(https) || same_site_none)
+        {
+            cookie.push_str("; Secure");
+        }
+        cookie
+    }
+}
+
+/// Looks up a cookie attribute by name, returns its value (empty for attributes without a value).
+fn cookie_attribute<'a>(attributes: &'a str, name: &str) -> Option<&'a str> {
+    attributes.split(';').find_map(|entry| {
+        let (attribute, value) = entry.split_once('=').unwrap_or((entry, ""));
+        if attribute.trim().eq_ignore_ascii_case(name) {
+            Some(value.trim())
+        } else {
+            None
+        }
+    })
+}
+
+/// Number of attempts to find an unused name for a temporary file
+const TEMP_FILE_ATTEMPTS: usize = 16;
+
+/// A temporary file that is removed when dropped
+#[derive(Debug)]
+struct TempFile {
+    file: File,
+    path: PathBuf,
+}
+
+impl TempFile {
+    /// Creates a new empty file with a random name in the system’s temporary directory.
+    fn create() -> Result<Self, Box<Error>> {
+        let mut attempt = 0;
+        loop {
+            let mut suffix = [0u8; 16];
+            getrandom::getrandom(&mut suffix).map_err(|err| {
+                Error::because(
+                    ErrorType::FileCreateError,
+                    "failed generating temporary file name",
+                    err,
+                )
+            })?;
+            let suffix: String = suffix.iter().map(|byte| format!("{byte:02x}")).collect();
+            let path = std::env::temp_dir().join(format!("pandora-request-body-{suffix}"));
+
+            attempt += 1;
+            match OpenOptions::new()
+                .read(true)
+                .write(true)
+                .create_new(true)
+                .open(&path)
+            {
+                Ok(file) => return Ok(Self { file, path }),
+                Err(err)
+                    if err.kind() == ErrorKind::AlreadyExists && attempt < TEMP_FILE_ATTEMPTS =>
+                {
+                    continue
+                }
+                Err(err) => {
+                    return Err(Error::because(
+                        ErrorType::FileCreateError,
+                        format!("failed creating temporary file {}", path.display()),
+                        err,
+                    ))
+                }
+            }
+        }
+    }
+
+    /// Appends data to the file.
+    fn write(&mut self, data: &[u8]) -> Result<(), Box<Error>> {
+        self.file.write_all(data).map_err(|err| {
+            Error::because(
+                ErrorType::FileWriteError,
+                format!("failed writing temporary file {}", self.path.display()),
+                err,
+            )
+        })
+    }
+
+    /// Moves back to the start of the file, returns the file size.
+    fn rewind(&mut self) -> Result<usize, Box<Error>> {
+        let len = self.file.stream_position().and_then(|len| {
+            self.file.rewind()?;
+            Ok(len)
+        });
+        len.map(|len| len as usize).map_err(|err| {
+            Error::because(
+                ErrorType::FileReadError,
+                format!("failed seeking temporary file {}", self.path.display()),
+                err,
+            )
+        })
+    }
+}
+
+impl Drop for TempFile {
+    fn drop(&mut self) {
+        if let Err(err) = std::fs::remove_file(&self.path) {
+            warn!(
+                "failed removing temporary file {}: {err}",
+                self.path.display()
+            );
+        }
+    }
+}
+
+#[derive(Debug)]
+enum BufferedBodyInner {
+    Memory(Cursor<Bytes>),
+    File(TempFile),
+}
+
+/// A request body read by [`SessionWrapper::buffer_request_body`]
+///
+/// The body is read back via the [`Read`] trait. It is kept either in memory or in a temporary
+/// file, the latter is removed once this handle is dropped.
+#[derive(Debug)]
+pub struct BufferedBody {
+    inner: BufferedBodyInner,
+    len: usize,
+}
+
+impl BufferedBody {
+    /// Returns the size of the request body in bytes.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Checks whether the request body is empty.
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Checks whether the request body is kept in memory rather than a temporary file.
+    pub fn in_memory(&self) -> bool {
+        matches!(self.inner, BufferedBodyInner::Memory(_))
+    }
+}
+
+impl Read for BufferedBody {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        match &mut self.inner {
+            BufferedBodyInner::Memory(cursor) => cursor.read(buf),
+            BufferedBodyInner::File(file) => file.file.read(buf),
+        }
+    }
+}
+
+impl Seek for BufferedBody {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        match &mut self.inner {
+            BufferedBodyInner::Memory(cursor) => cursor.seek(pos),
+            BufferedBodyInner::File(file) => file.file.seek(pos),
+        }
+    }
+}
+
+/// Calculates the size of a response header as Pingora serializes it for HTTP/1 connections
+fn serialized_header_size(header: &ResponseHeader) -> usize {
+    let mut headers = BytesMut::new();
+    header.header_to_h1_wire(&mut headers);
+
+    // Status line like `HTTP/1.1 200 OK\r\n`, headers and an empty line
+    "HTTP/1.1 200 ".len()
+        + header.get_reason_phrase().map_or(0, str::len)
+        + "\r\n".len()
+        + headers.len()
+        + "\r\n".len()
 }
 
 /// Type used to store remote user’s name in `SessionWrapper::extensions`
@@ -153,10 +779,19 @@ struct RemoteUser(String);
 #[derive(Debug, Clone)]
 struct OriginalUri(Uri);
 
+/// Maximal time [`TestSession::read_body_to_end`] will wait for the request body
+const TEST_BODY_TIMEOUT: Duration = Duration::from_secs(5);
+
 /// A `SessionWrapper` implementation used for tests.
 pub struct TestSession {
     inner: Session,
     extensions: Extensions,
+    sni: Option<String>,
+    tls_version: Option<&'static str>,
+
+    /// Informational (1xx) response headers written before the final response header, e.g.
+    /// `103 Early Hints`
+    pub informational_headers: Vec<ResponseHeader>,
 
     /// The response header written if any
     pub response_header: Option<ResponseHeader>,
@@ -172,10 +807,24 @@ impl TestSession {
     }
 
     /// Creates a new test session based with the given header and request body.
+    ///
+    /// The complete body is available to the session right away and the `Content-Length` header
+    /// is set to its size. So reading the request body never waits for more data: once all of it
+    /// has been read, `end_of_stream` is reached and `read_request_body` returns `None`.
     pub async fn with_body(mut header: RequestHeader, body: impl AsRef<[u8]>) -> Self {
+        // Pingora determines keep-alive status while parsing the request, so the version and
+        // Connection header of the parsed request have to match the actual header.
         let mut cursor = Cursor::new(Vec::<u8>::new());
-        let _ = cursor.write(b"POST / HTTP/1.1\r\n");
-        let _ = cursor.write(b"Connection: close\r\n");
+        if header.version == Version::HTTP_10 {
+            let _ = cursor.write(b"POST / HTTP/1.0\r\n");
+        } else {
+            let _ = cursor.write(b"POST / HTTP/1.1\r\n");
+        }
+        if let Some(connection) = header.headers.get(header::CONNECTION) {
+            let _ = cursor.write(b"Connection: ");
+            let _ = cursor.write(connection.as_bytes());
+            let _ = cursor.write(b"\r\n");
+        }
         let _ = cursor.write(b"\r\n");
         let _ = cursor.write(body.as_ref());
         let _ = cursor.seek(SeekFrom::Start(0));
@@ -189,10 +838,103 @@ impl TestSession {
         Self {
             inner,
             extensions: Extensions::new(),
+            sni: None,
+            tls_version: None,
+            informational_headers: Vec::new(),
             response_header: None,
             response_body: BytesMut::new(),
         }
     }
+
+    /// Reads the complete request body, failing if it is larger than `limit` bytes.
+    ///
+    /// Reading is aborted with an error as soon as the limit is exceeded. Should reading the body
+    /// stall for whatever reason, a `ReadTimedout` error is produced after five seconds rather
+    /// than blocking the test indefinitely.
+    pub async fn read_body_to_end(&mut self, limit: usize) -> Result<Bytes, Box<Error>> {
+        let read = async {
+            let mut body = BytesMut::new();
+            while let Some(chunk) = self.read_request_body().await? {
+                if body.len() + chunk.len() > limit {
+                    return Err(Error::explain(
+                        ErrorType::HTTPStatus(413),
+                        format!("request body exceeds {limit} bytes"),
+                    ));
+                }
+                body.extend_from_slice(&chunk);
+            }
+            Ok(body.freeze())
+        };
+
+        tokio::time::timeout(TEST_BODY_TIMEOUT, read)
+            .await
+            .unwrap_or_else(|_| {
+                Err(Error::explain(
+                    ErrorType::ReadTimedout,
+                    format!("reading request body, timeout: {TEST_BODY_TIMEOUT:?}"),
+                ))
+            })
+    }
+
+    /// Checks whether the connection would be kept alive after the response. Pingora would send
+    /// this as `Connection` header, the test session leaves the response header unchanged.
+    pub fn will_keepalive(&self) -> bool {
+        self.inner
+            .as_http1()
+            .is_some_and(|session| session.will_keepalive())
+    }
+
+    /// Makes the session report the given client address.
+    pub fn with_client_addr(mut self, addr: SocketAddr) -> Self {
+        self.set_client_addr(addr);
+        self
+    }
+
+    /// Makes the session behave like a TLS connection with the given server name (SNI) and TLS
+    /// version, e.g. `TLSv1.3`.
+    pub fn with_tls(mut self, sni: Option<&str>, version: &'static str) -> Self {
+        self.sni = sni.map(|sni| sni.to_owned());
+        self.tls_version = Some(version);
+        self
+    }
+
+    /// Returns the server name (SNI) set via [`TestSession::with_tls`].
+    ///
+    /// Pingora doesn’t expose the server name requested by the client, so this is only available
+    /// for test sessions and not part of [`SessionWrapper`].
+    pub fn sni(&self) -> Option<&str> {
+        self.sni.as_deref()
+    }
+
+    /// Runs the request through the handler’s phases in the order the server would call them:
+    /// `request_filter` first, then `response_filter` for the response written by the request
+    /// filter (without a context), and `logging` last. If `request_filter` fails, `logging` is
+    /// called with the error and the error is returned.
+    ///
+    /// Upstream phases are not run, the result of `request_filter` is returned instead. The
+    /// response is available via `response_header` and `response_body` fields afterwards.
+    pub async fn run<H>(&mut self, handler: &H) -> Result<RequestFilterResult, Box<Error>>
+    where
+        H: RequestFilter + Sync,
+        H::CTX: Send,
+    {
+        let mut ctx = H::new_ctx();
+        let result = match handler.request_filter(self, &mut ctx).await {
+            Ok(result) => result,
+            Err(err) => {
+                handler.logging(self, Some(&*err), &mut ctx).await;
+                return Err(err);
+            }
+        };
+
+        if let Some(mut response) = self.response_header.take() {
+            handler.response_filter(self, &mut response, None);
+            self.response_header = Some(response);
+        }
+
+        handler.logging(self, None, &mut ctx).await;
+        Ok(result)
+    }
 }
 
 #[async_trait]
@@ -205,7 +947,23 @@ impl SessionWrapper for TestSession {
         &mut self.extensions
     }
 
-    async fn write_response_header(&mut self, resp: Box<ResponseHeader>) -> Result<(), Box<Error>> {
+    fn tls_version(&self) -> Option<&str> {
+        self.tls_version
+    }
+
+    async fn write_response_header(
+        &mut self,
+        mut resp: Box<ResponseHeader>,
+    ) -> Result<(), Box<Error>> {
+        // Like with Pingora, informational headers can be written multiple times
+        if resp.status.is_informational() && resp.status != StatusCode::SWITCHING_PROTOCOLS {
+            self.informational_headers.push(*resp);
+            return Ok(());
+        }
+
+        let expects_continue = self.expects_continue();
+        prepare_keepalive(&mut self.inner, &mut resp, expects_continue);
+
         self.response_header = Some(*resp);
         Ok(())
     }
@@ -222,6 +980,20 @@ impl SessionWrapper for TestSession {
         self.response_body.extend(std::iter::once(data));
         Ok(())
     }
+
+    fn response_started(&self) -> bool {
+        self.response_header.is_some() || !self.response_body.is_empty()
+    }
+
+    fn bytes_sent(&self) -> usize {
+        // Count headers like Pingora does for HTTP/1 connections
+        self.informational_headers
+            .iter()
+            .chain(self.response_header.as_ref())
+            .map(serialized_header_size)
+            .sum::<usize>()
+            + self.response_body.len()
+    }
 }
 
 impl Deref for TestSession {
@@ -243,3 +1015,918 @@ impl std::fmt::Debug for TestSession {
         f.debug_struct("TestSession").finish()
     }
 }
+
+/// Percent-decodes a URI path or a path component.
+///
+/// `None` is returned if the input contains invalid percent-encoded sequences (`%` not followed by
+/// two hexadecimal digits) or an encoded null byte (`%00`). The input is returned unchanged if it
+/// doesn’t contain any percent-encoded sequences.
+pub fn percent_decode_path(path: &str) -> Option<Cow<'_, [u8]>> {
+    fn hex_value(digit: u8) -> Option<u8> {
+        (digit as char).to_digit(16).map(|value| value as u8)
+    }
+
+    let bytes = path.as_bytes();
+    if !bytes.contains(&b'%') {
+        return Some(Cow::Borrowed(bytes));
+    }
+
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut iter = bytes.iter();
+    while let Some(&byte) = iter.next() {
+        if byte == b'%' {
+            let high = hex_value(*iter.next()?)?;
+            let low = hex_value(*iter.next()?)?;
+            let value = (high << 4) | low;
+            if value == 0 {
+                return None;
+            }
+            decoded.push(value);
+        } else {
+            decoded.push(byte);
+        }
+    }
+    Some(Cow::Owned(decoded))
+}
+
+/// Validates a host name with optional user info and port, returns host and port only.
+fn normalize_host(value: &str) -> Option<&str> {
+    let authority = Authority::try_from(value).ok()?;
+    let host = authority.host();
+    if host.is_empty() {
+        return None;
+    }
+
+    if let Some(address) = host.strip_prefix('[') {
+        address.strip_suffix(']')?.parse::<Ipv6Addr>().ok()?;
+    }
+
+    // Strip user info, this is everything up to the last @ character
+    let host_port = &value[value.rfind('@').map_or(0, |i| i + 1)..];
+    match &host_port[host.len()..] {
+        "" | ":" => Some(&host_port[..host.len()]),
+        _ if authority.port().is_some() => Some(host_port),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use test_log::test;
+
+    async fn make_session(forwarded_proto: Option<&str>) -> TestSession {
+        let mut header = RequestHeader::build("GET", b"/", None).unwrap();
+        if let Some(forwarded_proto) = forwarded_proto {
+            header
+                .insert_header("X-Forwarded-Proto", forwarded_proto)
+                .unwrap();
+        }
+        TestSession::from(header).await
+    }
+
+    /// Session wrapper relying on default implementations of all methods
+    struct DefaultWrapper<'a> {
+        inner: &'a mut Session,
+        extensions: Extensions,
+    }
+
+    #[async_trait]
+    impl SessionWrapper for DefaultWrapper<'_> {
+        fn extensions(&self) -> &Extensions {
+            &self.extensions
+        }
+
+        fn extensions_mut(&mut self) -> &mut Extensions {
+            &mut self.extensions
+        }
+    }
+
+    impl Deref for DefaultWrapper<'_> {
+        type Target = Session;
+
+        fn deref(&self) -> &Self::Target {
+            self.inner
+        }
+    }
+
+    impl DerefMut for DefaultWrapper<'_> {
+        fn deref_mut(&mut self) -> &mut Self::Target {
+            self.inner
+        }
+    }
+
+    async fn make_host_session(host: &str) -> TestSession {
+        let mut header = RequestHeader::build("GET", b"/", None).unwrap();
+        header.insert_header(header::HOST, host).unwrap();
+        TestSession::from(header).await
+    }
+
+    #[test(tokio::test)]
+    async fn host() {
+        for (host, expected) in [
+            ("example.com", Some("example.com")),
+            ("example.com:8080", Some("example.com:8080")),
+            ("example.com:", Some("example.com")),
+            ("user@example.com", Some("example.com")),
+            ("user:password@example.com:8080", Some("example.com:8080")),
+            ("127.0.0.1:8080", Some("127.0.0.1:8080")),
+            ("[::1]", Some("[::1]")),
+            ("[::1]:8080", Some("[::1]:8080")),
+            ("user@[::1]:8080", Some("[::1]:8080")),
+            ("", None),
+            ("[::1", None),
+            ("[::1]x", None),
+            ("[example.com]", None),
+            ("example.com:port", None),
+            ("example.com:99999", None),
+            ("example.com/path", None),
+            ("example .com", None),
+        ] {
+            let session = make_host_session(host).await;
+            assert_eq!(session.host().as_deref(), expected, "Host: {host}");
+        }
+    }
+
+    #[test(tokio::test)]
+    async fn host_from_uri() {
+        let mut header = RequestHeader::build("GET", b"/", None).unwrap();
+        header.set_uri("http://user@[::1]:8080/".parse().unwrap());
+        let session = TestSession::from(header).await;
+        assert_eq!(session.host().as_deref(), Some("[::1]:8080"));
+
+        let header = RequestHeader::build("GET", b"/", None).unwrap();
+        let session = TestSession::from(header).await;
+        assert_eq!(session.host(), None);
+    }
+
+    #[test(tokio::test)]
+    async fn response_started() -> Result<(), Box<Error>> {
+        let mut session = make_session(None).await;
+        assert!(!session.response_started());
+
+        let header = ResponseHeader::build(200, None)?;
+        session.write_response_header(Box::new(header)).await?;
+        assert!(session.response_started());
+        assert_eq!(
+            session.response_written().map(|r| r.status.as_u16()),
+            Some(200)
+        );
+
+        let mut session = make_session(None).await;
+        session.write_response_body("Hi!".into()).await?;
+        assert!(session.response_started());
+
+        // Default implementation relying on the Pingora session
+        let mut session = make_session(None).await;
+        let mut session = DefaultWrapper {
+            inner: &mut session.inner,
+            extensions: Extensions::new(),
+        };
+        assert!(!session.response_started());
+        let header = ResponseHeader::build(200, None)?;
+        session.write_response_header(Box::new(header)).await?;
+        assert!(session.response_started());
+
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn byte_counts() -> Result<(), Box<Error>> {
+        let mut session = make_session(None).await;
+        assert_eq!(session.bytes_sent(), 0);
+        session.write_response_body("Hello, ".into()).await?;
+        session.write_response_body("world!".into()).await?;
+        assert_eq!(session.bytes_sent(), 13);
+
+        // Response headers are counted as well
+        let mut session = make_session(None).await;
+        let header = ResponseHeader::build(103, None)?;
+        session.write_response_header(Box::new(header)).await?;
+        let mut header = ResponseHeader::build(200, None)?;
+        header.insert_header("Content-Length", "13")?;
+        session.write_response_header(Box::new(header)).await?;
+        session.write_response_body("Hello, world!".into()).await?;
+        assert_eq!(
+            session.bytes_sent(),
+            "HTTP/1.1 103 Early Hints\r\n\r\n".len()
+                + "HTTP/1.1 200 OK\r\nContent-Length: 13\r\n\r\n".len()
+                + 13
+        );
+
+        let header = RequestHeader::build("POST", b"/", None)?;
+        let mut session = TestSession::with_body(header, "request body").await;
+        assert_eq!(session.bytes_received(), 0);
+        while session.read_request_body().await?.is_some() {}
+        assert_eq!(session.bytes_received(), 12);
+
+        // Default implementation relying on the Pingora session
+        let mut session = make_session(None).await;
+        let mut session = DefaultWrapper {
+            inner: &mut session.inner,
+            extensions: Extensions::new(),
+        };
+        let header = ResponseHeader::build(200, None)?;
+        session.write_response_header(Box::new(header)).await?;
+        session.write_response_body("Hello, world!".into()).await?;
+        let header_size = serialized_header_size(session.response_written().unwrap());
+        assert_eq!(session.bytes_sent(), header_size + 13);
+
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn request_header_stats() {
+        // TestSession always adds Content-Length header
+        let session = make_session(None).await;
+        assert_eq!(session.request_header_stats(), (1, 19));
+
+        let mut header = RequestHeader::build("GET", b"/", None).unwrap();
+        header.append_header("Host", "localhost").unwrap();
+        header.append_header("X-Test", "a").unwrap();
+        header.append_header("X-Test", "bc").unwrap();
+        let session = TestSession::from(header).await;
+        assert_eq!(session.request_header_stats(), (4, 19 + 17 + 11 + 12));
+    }
+
+    #[test(tokio::test)]
+    async fn accepted_encodings() {
+        let codings = |value: &str| {
+            AcceptedEncodings::parse([value])
+                .iter()
+                .map(|encoding| (encoding.coding.clone(), encoding.quality))
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(codings(""), vec![]);
+        assert_eq!(
+            codings("gzip;q=0.5, BR, zstd;q=0.8, deflate;q=invalid"),
+            vec![
+                ("br".to_owned(), 1000),
+                ("deflate".to_owned(), 1000),
+                ("zstd".to_owned(), 800),
+                ("gzip".to_owned(), 500),
+            ]
+        );
+        assert_eq!(
+            codings("gzip; Q = 0.25 , , compress;q=2, br;q=-1"),
+            vec![
+                ("compress".to_owned(), 1000),
+                ("gzip".to_owned(), 250),
+                ("br".to_owned(), 0),
+            ]
+        );
+
+        let mut header = RequestHeader::build("GET", b"/", None).unwrap();
+        header
+            .append_header(header::ACCEPT_ENCODING, "gzip;q=0.5")
+            .unwrap();
+        header.append_header(header::ACCEPT_ENCODING, "br").unwrap();
+        let session = TestSession::from(header).await;
+        let encodings = session.accepted_encodings();
+        assert_eq!(encodings.len(), 2);
+        assert_eq!(encodings[0].coding, "br");
+        assert_eq!(encodings.quality("gzip"), 500);
+        assert_eq!(encodings.quality("GZIP"), 500);
+        assert_eq!(encodings.quality("zstd"), 0);
+        assert!(encodings.accepts("identity"));
+
+        let session = make_session(None).await;
+        let encodings = session.accepted_encodings();
+        assert!(encodings.is_empty());
+        assert!(encodings.accepts("identity"));
+        assert!(!encodings.accepts("gzip"));
+    }
+
+    #[test]
+    fn accepted_encodings_identity() {
+        let encodings = AcceptedEncodings::parse(["gzip, identity;q=0"]);
+        assert!(encodings.accepts("gzip"));
+        assert!(!encodings.accepts("identity"));
+        assert_eq!(
+            encodings
+                .acceptable()
+                .map(|encoding| encoding.coding.as_str())
+                .collect::<Vec<_>>(),
+            vec!["gzip"]
+        );
+
+        let encodings = AcceptedEncodings::parse(["*;q=0"]);
+        assert!(!encodings.accepts("identity"));
+        assert!(!encodings.accepts("gzip"));
+
+        let encodings = AcceptedEncodings::parse(["*;q=0, identity"]);
+        assert!(encodings.accepts("identity"));
+        assert!(!encodings.accepts("br"));
+    }
+
+    #[test]
+    fn accepted_encodings_wildcard() {
+        let encodings = AcceptedEncodings::parse(["br;q=0.9, *;q=0.1"]);
+        assert_eq!(encodings.quality("br"), 900);
+        assert_eq!(encodings.quality("gzip"), 100);
+        assert_eq!(encodings.quality("identity"), 100);
+
+        let encodings = AcceptedEncodings::parse(["gzip;q=0, *"]);
+        assert!(!encodings.accepts("gzip"));
+        assert!(encodings.accepts("br"));
+        assert_eq!(encodings.acceptable().count(), 1);
+    }
+
+    #[test(tokio::test)]
+    async fn read_body_to_end() -> Result<(), Box<Error>> {
+        let body = "0123456789".repeat(10000);
+
+        let header = RequestHeader::build("POST", b"/", None)?;
+        let mut session = TestSession::with_body(header, &body).await;
+        assert_eq!(session.read_body_to_end(body.len()).await?, body.as_bytes());
+        assert_eq!(session.read_body_to_end(body.len()).await?, "");
+
+        let header = RequestHeader::build("POST", b"/", None)?;
+        let mut session = TestSession::from(header).await;
+        assert_eq!(session.read_body_to_end(0).await?, "");
+
+        let header = RequestHeader::build("POST", b"/", None)?;
+        let mut session = TestSession::with_body(header, &body).await;
+        let start = std::time::Instant::now();
+        let err = session.read_body_to_end(1000).await.unwrap_err();
+        assert_eq!(err.etype(), &ErrorType::HTTPStatus(413));
+        assert!(start.elapsed() < TEST_BODY_TIMEOUT);
+
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn drain_request_body() -> Result<(), Box<Error>> {
+        let body = "0123456789".repeat(10000);
+
+        // Body within the limit is drained, connection stays reusable
+        let header = RequestHeader::build("POST", b"/", None)?;
+        let mut session = TestSession::with_body(header, &body).await;
+        assert!(session.drain_request_body(body.len()).await);
+        assert!(session.is_body_done());
+        assert_eq!(session.bytes_received(), body.len());
+        session
+            .write_response_header(make_keepalive_response(Some(13), false))
+            .await?;
+        assert!(session.will_keepalive());
+
+        // Requests without a body
+        let mut session = make_session(None).await;
+        assert!(session.drain_request_body(0).await);
+
+        // Body exceeding the limit results in the connection being closed
+        let header = RequestHeader::build("POST", b"/", None)?;
+        let mut session = TestSession::with_body(header, &body).await;
+        assert!(!session.drain_request_body(1000).await);
+        session
+            .write_response_header(make_keepalive_response(Some(13), false))
+            .await?;
+        assert!(!session.will_keepalive());
+
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn buffer_request_body() -> Result<(), Box<Error>> {
+        let body = "0123456789".repeat(10000);
+
+        // Small body is kept in memory
+        let header = RequestHeader::build("POST", b"/", None)?;
+        let mut session = TestSession::with_body(header, &body).await;
+        let mut buffered = session.buffer_request_body(body.len(), body.len()).await?;
+        assert!(buffered.in_memory());
+        assert_eq!(buffered.len(), body.len());
+        let mut contents = String::new();
+        buffered.read_to_string(&mut contents).unwrap();
+        assert_eq!(contents, body);
+
+        // Large body spills to disk
+        let header = RequestHeader::build("POST", b"/", None)?;
+        let mut session = TestSession::with_body(header, &body).await;
+        let mut buffered = session.buffer_request_body(1000, body.len()).await?;
+        assert!(!buffered.in_memory());
+        assert_eq!(buffered.len(), body.len());
+        let mut contents = String::new();
+        buffered.read_to_string(&mut contents).unwrap();
+        assert_eq!(contents, body);
+
+        // Reading can be repeated
+        buffered.rewind().unwrap();
+        let mut contents = String::new();
+        buffered.read_to_string(&mut contents).unwrap();
+        assert_eq!(contents, body);
+
+        // Temporary file is removed on drop
+        let path = match &buffered.inner {
+            BufferedBodyInner::File(file) => file.path.clone(),
+            BufferedBodyInner::Memory(_) => unreachable!(),
+        };
+        assert!(path.exists());
+        drop(buffered);
+        assert!(!path.exists());
+
+        // Empty body
+        let mut session = make_session(None).await;
+        let buffered = session.buffer_request_body(0, 0).await?;
+        assert!(buffered.in_memory());
+        assert!(buffered.is_empty());
+
+        // Body exceeding the size limit is rejected, whether in memory or on disk
+        for memory_limit in [body.len(), 1000] {
+            let header = RequestHeader::build("POST", b"/", None)?;
+            let mut session = TestSession::with_body(header, &body).await;
+            let err = session
+                .buffer_request_body(memory_limit, body.len() - 1)
+                .await
+                .unwrap_err();
+            assert_eq!(err.etype(), &ErrorType::HTTPStatus(413));
+        }
+
+        // Temporary files get unique names
+        let first = TempFile::create()?;
+        let second = TempFile::create()?;
+        assert_ne!(first.path, second.path);
+
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn expect_continue() -> Result<(), Box<Error>> {
+        let make_session = |expect: &'static str| async move {
+            let mut header = RequestHeader::build("POST", b"/", None).unwrap();
+            header.insert_header(header::EXPECT, expect).unwrap();
+            TestSession::with_body(header, "0123456789").await
+        };
+
+        // Accepted request receives 100 Continue once
+        let mut session = make_session("100-Continue").await;
+        assert!(session.expects_continue());
+        let body = session.buffer_request_body(100, 100).await?;
+        assert_eq!(body.len(), 10);
+        assert!(!session.expects_continue());
+        session.send_continue().await?;
+        assert_eq!(session.informational_headers.len(), 1);
+        assert_eq!(session.informational_headers[0].status, 100);
+        session
+            .write_response_header(make_keepalive_response(Some(13), false))
+            .await?;
+        assert!(session.will_keepalive());
+
+        // Rejected request gets the final response without 100 Continue, body isn’t drained
+        let mut session = make_session("100-continue").await;
+        assert!(!session.drain_request_body(100).await);
+        assert_eq!(session.bytes_received(), 0);
+        session
+            .write_response_header(make_keepalive_response(Some(13), false))
+            .await?;
+        assert!(session.informational_headers.is_empty());
+        assert!(!session.will_keepalive());
+
+        // 100 Continue isn’t sent after the final response
+        session.send_continue().await?;
+        assert!(session.informational_headers.is_empty());
+
+        // Other expectations and HTTP/1.0 requests are ignored
+        let session = make_session("something-else").await;
+        assert!(!session.expects_continue());
+
+        let mut header = RequestHeader::build("POST", b"/", None)?;
+        header.set_version(Version::HTTP_10);
+        header.insert_header(header::EXPECT, "100-continue")?;
+        let session = TestSession::from(header).await;
+        assert!(!session.expects_continue());
+
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn format_cookie() {
+        let mut session = make_session(None).await;
+        assert_eq!(
+            session.format_cookie("name", "value", "Max-Age=60; HttpOnly"),
+            "name=value; Max-Age=60; HttpOnly; SameSite=Lax"
+        );
+
+        session.set_cookie_policy(CookiePolicy {
+            secure: None,
+            same_site: SameSite::Strict,
+        });
+        assert_eq!(
+            session.format_cookie("name", "value", ""),
+            "name=value; SameSite=Strict"
+        );
+
+        // SameSite=None requires the Secure attribute
+        session.set_cookie_policy(CookiePolicy {
+            secure: Some(false),
+            same_site: SameSite::None,
+        });
+        assert_eq!(
+            session.format_cookie("name", "value", ""),
+            "name=value; SameSite=None; Secure"
+        );
+
+        // Explicit attributes take precedence
+        session.set_cookie_policy(CookiePolicy {
+            secure: Some(true),
+            same_site: SameSite::Strict,
+        });
+        assert_eq!(
+            session.format_cookie("name", "value", "samesite=lax; secure"),
+            "name=value; samesite=lax; secure"
+        );
+    }
+
+    #[test(tokio::test)]
+    async fn format_cookie_https() {
+        let session = make_session(None).await.with_tls(None, "TLSv1.3");
+        assert_eq!(
+            session.format_cookie("name", "value", "HttpOnly"),
+            "name=value; HttpOnly; SameSite=Lax; Secure"
+        );
+
+        let mut session = make_session(None).await.with_tls(None, "TLSv1.3");
+        session.set_cookie_policy(CookiePolicy {
+            secure: Some(false),
+            same_site: SameSite::Lax,
+        });
+        assert_eq!(
+            session.format_cookie("name", "value", "HttpOnly"),
+            "name=value; HttpOnly; SameSite=Lax"
+        );
+    }
+
+    async fn make_keepalive_session(version: Version, connection: Option<&str>) -> TestSession {
+        let mut header = RequestHeader::build("GET", b"/", None).unwrap();
+        header.set_version(version);
+        if let Some(connection) = connection {
+            header
+                .insert_header(header::CONNECTION, connection)
+                .unwrap();
+        }
+        TestSession::from(header).await
+    }
+
+    fn make_keepalive_response(length: Option<usize>, chunked: bool) -> Box<ResponseHeader> {
+        let mut header = ResponseHeader::build(200, None).unwrap();
+        if let Some(length) = length {
+            header
+                .insert_header(header::CONTENT_LENGTH, length)
+                .unwrap();
+        }
+        if chunked {
+            header
+                .insert_header(header::TRANSFER_ENCODING, "chunked")
+                .unwrap();
+        }
+        Box::new(header)
+    }
+
+    #[test(tokio::test)]
+    async fn keepalive() -> Result<(), Box<Error>> {
+        for (version, connection, expected) in [
+            (Version::HTTP_10, None, false),
+            (Version::HTTP_10, Some("keep-alive"), true),
+            (Version::HTTP_10, Some("Keep-Alive"), true),
+            (Version::HTTP_10, Some("close"), false),
+            (Version::HTTP_11, None, true),
+            (Version::HTTP_11, Some("keep-alive"), true),
+            (Version::HTTP_11, Some("close"), false),
+            (Version::HTTP_11, Some("Close"), false),
+        ] {
+            let mut session = make_keepalive_session(version, connection).await;
+            session
+                .write_response_header(make_keepalive_response(Some(13), false))
+                .await?;
+            assert_eq!(
+                session.will_keepalive(),
+                expected,
+                "{version:?} with Connection: {connection:?}"
+            );
+
+            // Default implementation relying on the Pingora session
+            let mut session = make_keepalive_session(version, connection).await;
+            let mut session = DefaultWrapper {
+                inner: &mut session.inner,
+                extensions: Extensions::new(),
+            };
+            session
+                .write_response_header(make_keepalive_response(Some(13), false))
+                .await?;
+            assert_eq!(
+                session.as_http1().unwrap().will_keepalive(),
+                expected,
+                "{version:?} with Connection: {connection:?}"
+            );
+        }
+
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn keepalive_body_length() -> Result<(), Box<Error>> {
+        // Without Content-Length or chunked encoding the connection has to be closed
+        for version in [Version::HTTP_10, Version::HTTP_11] {
+            let mut session = make_keepalive_session(version, Some("keep-alive")).await;
+            session
+                .write_response_header(make_keepalive_response(None, false))
+                .await?;
+            assert!(!session.will_keepalive());
+        }
+
+        // HTTP/1.1 clients support chunked encoding
+        let mut session = make_keepalive_session(Version::HTTP_11, None).await;
+        session
+            .write_response_header(make_keepalive_response(None, true))
+            .await?;
+        assert!(session.will_keepalive());
+        let response = session.response_header.as_ref().unwrap();
+        assert_eq!(
+            response.headers.get(header::TRANSFER_ENCODING).unwrap(),
+            "chunked"
+        );
+
+        // HTTP/1.0 clients don’t, the connection is closed instead
+        let mut session = make_keepalive_session(Version::HTTP_10, Some("keep-alive")).await;
+        session
+            .write_response_header(make_keepalive_response(None, true))
+            .await?;
+        assert!(!session.will_keepalive());
+        let response = session.response_header.as_ref().unwrap();
+        assert!(response.headers.get(header::TRANSFER_ENCODING).is_none());
+
+        // Responses without a body don’t affect keep-alive
+        let mut session = make_keepalive_session(Version::HTTP_11, None).await;
+        session
+            .write_response_header(Box::new(ResponseHeader::build(304, None)?))
+            .await?;
+        assert!(session.will_keepalive());
+
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn scheme_plaintext() {
+        let session = make_session(None).await;
+        assert_eq!(session.scheme(false), "http");
+        assert_eq!(session.scheme(true), "http");
+    }
+
+    #[test(tokio::test)]
+    async fn scheme_forwarded() {
+        let session = make_session(Some("https")).await;
+        assert_eq!(session.scheme(false), "http");
+        assert_eq!(session.scheme(true), "https");
+
+        let session = make_session(Some("HTTPS, http")).await;
+        assert_eq!(session.scheme(true), "https");
+
+        let session = make_session(Some("gopher")).await;
+        assert_eq!(session.scheme(true), "http");
+    }
+
+    #[test(tokio::test)]
+    async fn scheme_tls() {
+        let session = make_session(None)
+            .await
+            .with_tls(Some("example.com"), "TLSv1.3");
+        assert_eq!(session.scheme(false), "https");
+
+        let session = make_session(Some("http"))
+            .await
+            .with_tls(Some("example.com"), "TLSv1.3");
+        assert_eq!(session.scheme(false), "https");
+        assert_eq!(session.scheme(true), "http");
+    }
+
+    #[test(tokio::test)]
+    async fn test_session_client_addr() {
+        let session = make_session(None).await;
+        assert_eq!(session.client_addr(), None);
+
+        let addr: SocketAddr = "192.168.1.2:1234".parse().unwrap();
+        let session = make_session(None).await.with_client_addr(addr.clone());
+        assert_eq!(session.client_addr(), Some(&addr));
+    }
+
+    #[test(tokio::test)]
+    async fn test_session_tls() {
+        let session = make_session(None).await;
+        assert_eq!(session.sni(), None);
+        assert_eq!(session.tls_version(), None);
+
+        let session = make_session(None)
+            .await
+            .with_tls(Some("example.com"), "TLSv1.3");
+        assert_eq!(session.sni(), Some("example.com"));
+        assert_eq!(session.tls_version(), Some("TLSv1.3"));
+
+        let session = make_session(None).await.with_tls(None, "TLSv1.2");
+        assert_eq!(session.sni(), None);
+        assert_eq!(session.tls_version(), Some("TLSv1.2"));
+    }
+
+    #[test]
+    fn percent_decode() {
+        let decode = |path| percent_decode_path(path).map(|decoded| decoded.into_owned());
+
+        assert!(matches!(
+            percent_decode_path("/file.txt"),
+            Some(Cow::Borrowed(b"/file.txt"))
+        ));
+        assert_eq!(decode("/file%2Etxt"), Some(b"/file.txt".to_vec()));
+        assert_eq!(decode("/caf%c3%A9"), Some("/café".as_bytes().to_vec()));
+        assert_eq!(decode("/a%2Fb"), Some(b"/a/b".to_vec()));
+        assert_eq!(decode("/%FF"), Some(b"/\xFF".to_vec()));
+        assert_eq!(decode("/a%00b"), None);
+        assert_eq!(decode("/a%2"), None);
+        assert_eq!(decode("/a%"), None);
+        assert_eq!(decode("/a%zzb"), None);
+        assert_eq!(decode("/a%%41"), None);
+    }
+
+    #[test(tokio::test)]
+    async fn decoded_path() {
+        async fn decoded(path: &str) -> Option<String> {
+            let header = RequestHeader::build("GET", path.as_bytes(), None).unwrap();
+            let session = TestSession::from(header).await;
+            session.decoded_path().map(|path| path.into_owned())
+        }
+
+        // Plain paths
+        assert_eq!(decoded("/").await.as_deref(), Some("/"));
+        assert_eq!(
+            decoded("/dir/file.txt").await.as_deref(),
+            Some("/dir/file.txt")
+        );
+
+        // Encoded characters
+        assert_eq!(decoded("/file%2Etxt").await.as_deref(), Some("/file.txt"));
+        assert_eq!(
+            decoded("/%D1%84%D0%B0%D0%B9%D0%BB%20x").await.as_deref(),
+            Some("/файл x")
+        );
+        assert_eq!(decoded("/%252F").await.as_deref(), Some("/%2F"));
+
+        // Encoded slashes
+        assert_eq!(decoded("/dir%2Ffile.txt").await, None);
+        assert_eq!(decoded("/dir%2ffile.txt").await, None);
+
+        // Invalid sequences
+        assert_eq!(decoded("/file%00.txt").await, None);
+        assert_eq!(decoded("/file%2").await, None);
+        assert_eq!(decoded("/file%g0").await, None);
+        assert_eq!(decoded("/file%FF").await, None);
+    }
+
+    #[test(tokio::test)]
+    async fn run_handler() {
+        use std::sync::Mutex;
+
+        /// Handler recording the phases it has been called for
+        #[derive(Debug, Default)]
+        struct Handler {
+            phases: Mutex<Vec<&'static str>>,
+        }
+
+        impl Handler {
+            fn record(&self, phase: &'static str) {
+                self.phases.lock().unwrap().push(phase);
+            }
+
+            fn take_phases(&self) -> Vec<&'static str> {
+                std::mem::take(&mut *self.phases.lock().unwrap())
+            }
+        }
+
+        #[async_trait]
+        impl RequestFilter for Handler {
+            type Conf = ();
+            type CTX = ();
+            fn new_ctx() -> Self::CTX {}
+
+            async fn request_filter(
+                &self,
+                session: &mut impl SessionWrapper,
+                _ctx: &mut Self::CTX,
+            ) -> Result<RequestFilterResult, Box<Error>> {
+                self.record("request_filter");
+                match session.uri().path() {
+                    "/respond" => {
+                        let header = ResponseHeader::build(StatusCode::OK, None)?;
+                        session.write_response_header(Box::new(header)).await?;
+                        session
+                            .write_response_body(Bytes::from_static(b"Hi!"))
+                            .await?;
+                        Ok(RequestFilterResult::ResponseSent)
+                    }
+                    "/fail" => Err(Error::new(ErrorType::InternalError)),
+                    _ => Ok(RequestFilterResult::Unhandled),
+                }
+            }
+
+            fn response_filter(
+                &self,
+                _session: &mut impl SessionWrapper,
+                response: &mut ResponseHeader,
+                _ctx: Option<&mut Self::CTX>,
+            ) {
+                self.record("response_filter");
+                response.insert_header("X-Filtered", "yes").unwrap();
+            }
+
+            async fn logging(
+                &self,
+                _session: &mut impl SessionWrapper,
+                e: Option<&Error>,
+                _ctx: &mut Self::CTX,
+            ) {
+                self.record(if e.is_some() {
+                    "logging with error"
+                } else {
+                    "logging"
+                });
+            }
+        }
+
+        let handler = Handler::default();
+
+        let header = RequestHeader::build("GET", b"/respond", None).unwrap();
+        let mut session = TestSession::from(header).await;
+        assert_eq!(
+            session.run(&handler).await.unwrap(),
+            RequestFilterResult::ResponseSent
+        );
+        assert_eq!(
+            handler.take_phases(),
+            vec!["request_filter", "response_filter", "logging"]
+        );
+        let response = session.response_header.as_ref().unwrap();
+        assert_eq!(response.status, 200);
+        assert_eq!(response.headers.get("X-Filtered").unwrap(), "yes");
+        assert_eq!(session.response_body, "Hi!");
+
+        let header = RequestHeader::build("GET", b"/other", None).unwrap();
+        let mut session = TestSession::from(header).await;
+        assert_eq!(
+            session.run(&handler).await.unwrap(),
+            RequestFilterResult::Unhandled
+        );
+        assert_eq!(handler.take_phases(), vec!["request_filter", "logging"]);
+        assert!(session.response_header.is_none());
+
+        let header = RequestHeader::build("GET", b"/fail", None).unwrap();
+        let mut session = TestSession::from(header).await;
+        assert!(session.run(&handler).await.is_err());
+        assert_eq!(
+            handler.take_phases(),
+            vec!["request_filter", "logging with error"]
+        );
+    }
+
+    #[test(tokio::test)]
+    async fn informational_headers() {
+        let mut session = make_session(None).await;
+        for link in ["</a.css>; rel=preload", "</b.js>; rel=preload"] {
+            let mut header = ResponseHeader::build(103, None).unwrap();
+            header.insert_header(header::LINK, link).unwrap();
+            session
+                .write_response_header(Box::new(header))
+                .await
+                .unwrap();
+        }
+        assert!(session.response_written().is_none());
+
+        let header = ResponseHeader::build(StatusCode::OK, None).unwrap();
+        session
+            .write_response_header(Box::new(header))
+            .await
+            .unwrap();
+
+        let informational = session
+            .informational_headers
+            .iter()
+            .map(|response| {
+                (
+                    response.status.as_u16(),
+                    response
+                        .headers
+                        .get(header::LINK)
+                        .unwrap()
+                        .to_str()
+                        .unwrap(),
+                )
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            informational,
+            vec![
+                (103, "</a.css>; rel=preload"),
+                (103, "</b.js>; rel=preload")
+            ]
+        );
+        assert_eq!(session.response_written().unwrap().status, 200);
+    }
+}
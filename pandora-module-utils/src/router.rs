@@ -28,6 +28,11 @@
 //!
 //! Only the best match is returned. If rules exist for `/`, `/dir/` and `/dir/subdir/` for
 //! example, the path `/dir/subdir/file` will match `/dir/subdir/`.
+//!
+//! Routes can be given an explicit priority (default is 0) to override this. The matching route
+//! with the highest priority wins, the longest match rule only applies to routes with identical
+//! priorities. A fallback host route will also take precedence over a matching route for the
+//! specific host if its priority is higher.
 
 use std::collections::HashMap;
 use std::fmt::Debug;
@@ -150,6 +155,25 @@ impl Deref for Path {
 /// assert_eq!(*router.lookup("localhost", "/dir/file").unwrap(), "Within localhost");
 /// assert_eq!(*router.lookup("example.com", "/dir/file").unwrap(), "Within website subdirectory");
 /// ```
+///
+/// A built router cannot be modified. In order to add or remove routes, e.g. when reloading
+/// configuration, keep the builder around. It retains all rules and can build a modified router
+/// while the existing one stays in use:
+///
+/// ```rust
+/// use pandora_module_utils::router::Router;
+///
+/// let mut builder = Router::builder();
+/// builder.push("localhost", "/", "Localhost root", None);
+/// let router = builder.build();
+///
+/// builder.remove("localhost", "/");
+/// builder.push("localhost", "/dir/", "Localhost subdirectory", None);
+/// let new_router = builder.build();
+/// assert!(new_router.lookup("localhost", "/").is_none());
+/// assert_eq!(*new_router.lookup("localhost", "/dir").unwrap(), "Localhost subdirectory");
+/// assert_eq!(*router.lookup("localhost", "/").unwrap(), "Localhost root");
+/// ```
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct Router<Value> {
     trie: Trie<Value>,
@@ -166,8 +190,7 @@ impl<Value> Router<Value> {
         Value: Clone + Eq,
     {
         RouterBuilder {
-            entries: Default::default(),
-            fallbacks: Default::default(),
+            routes: Default::default(),
         }
     }
 
@@ -177,17 +200,35 @@ impl<Value> Router<Value> {
         host: &(impl AsRef<[u8]> + ?Sized),
         path: &(impl AsRef<[u8]> + ?Sized),
     ) -> Option<LookupResult<'_, Value>> {
-        if !host.as_ref().is_empty() {
+        let result = if !host.as_ref().is_empty() {
             self.trie.lookup(make_key(host, path))
         } else {
             None
+        };
+
+        // Shift fallback indexes so that these don’t overlap with the main trie
+        let fallback = self
+            .fallback
+            .lookup(make_key("", path))
+            .map(|result| result.offset_index(self.trie.value_count()));
+
+        match (result, fallback) {
+            (Some(result), Some(fallback)) if fallback.priority() > result.priority() => {
+                Some(fallback)
+            }
+            (Some(result), _) => Some(result),
+            (None, fallback) => fallback,
         }
-        .or_else(|| self.fallback.lookup(make_key("", path)))
     }
 
     /// Retrieves the value from a previous lookup by its index
     pub fn retrieve(&self, index: usize) -> Option<&Value> {
-        self.trie.retrieve(index)
+        let count = self.trie.value_count();
+        if index < count {
+            self.trie.retrieve(index)
+        } else {
+            self.fallback.retrieve(index - count)
+        }
     }
 }
 
@@ -208,27 +249,38 @@ fn make_key<'a>(
     }
 }
 
+/// A rule as added to the router builder
+#[derive(Debug, Clone)]
+struct Route<Value> {
+    host: Vec<u8>,
+    path: Path,
+    value_exact: (Value, i32),
+    value_prefix: Option<(Value, i32)>,
+}
+
 /// Intermediate entry stored in the router prior to merging
 #[derive(Debug)]
 struct RouterEntry<Value> {
     path: Path,
-    value_exact: Value,
-    value_prefix: Option<Value>,
+    value_exact: (Value, i32),
+    value_prefix: Option<(Value, i32)>,
 }
 
 /// The router builder used to set up a [`Router`] instance
-#[derive(Debug)]
+///
+/// The builder keeps all rules in their original order, so that it can be modified and used to
+/// build new routers after [`RouterBuilder::build`] has been called.
+#[derive(Debug, Clone)]
 pub struct RouterBuilder<Value> {
-    entries: HashMap<Vec<u8>, Vec<RouterEntry<Value>>>,
-    fallbacks: Vec<RouterEntry<Value>>,
+    routes: Vec<Route<Value>>,
 }
 
 impl<Value: Clone + Eq> RouterBuilder<Value> {
     fn merge_value(
         existing: &mut Vec<RouterEntry<Value>>,
         path: Path,
-        value_exact: Value,
-        mut value_prefix: Option<Value>,
+        value_exact: (Value, i32),
+        mut value_prefix: Option<(Value, i32)>,
     ) {
         match existing.binary_search_by_key(&path.as_slice(), |entry| entry.path.as_slice()) {
             Ok(index) => {
@@ -272,35 +324,88 @@ impl<Value: Clone + Eq> RouterBuilder<Value> {
         value_exact: Value,
         value_prefix: Option<Value>,
     ) {
-        let path = Path::new(path);
+        self.push_with_priority(host, path, value_exact, value_prefix, 0);
+    }
 
-        let existing = if host.as_ref().is_empty() {
-            &mut self.fallbacks
-        } else {
-            self.entries.entry(host.as_ref().to_vec()).or_default()
-        };
+    /// Adds a host/path combination with the respective values and an explicit priority to the
+    /// routing table.
+    ///
+    /// This works like [`RouterBuilder::push`], routes added via that method have priority 0.
+    /// During lookup, the matching route with the highest priority is selected even if a more
+    /// specific route matches as well.
+    pub fn push_with_priority(
+        &mut self,
+        host: impl AsRef<[u8]>,
+        path: impl AsRef<[u8]>,
+        value_exact: Value,
+        value_prefix: Option<Value>,
+        priority: i32,
+    ) {
+        self.routes.push(Route {
+            host: host.as_ref().to_vec(),
+            path: Path::new(path),
+            value_exact: (value_exact, priority),
+            value_prefix: value_prefix.map(|value| (value, priority)),
+        });
+    }
 
-        Self::merge_value(existing, path, value_exact, value_prefix);
+    /// Removes all rules previously added for a host/path combination. Returns `true` if any
+    /// rules have been removed.
+    ///
+    /// Values that other rules inherited from the removed rules are removed as well, as if the
+    /// removed rules had never been added.
+    pub fn remove(&mut self, host: impl AsRef<[u8]>, path: impl AsRef<[u8]>) -> bool {
+        let host = host.as_ref();
+        let path = Path::new(path);
+        let count = self.routes.len();
+        self.routes
+            .retain(|route| route.host != host || route.path != path);
+        self.routes.len() != count
     }
 
     /// Translates all rules into a router instance while also merging values if multiple apply to
     /// the same location.
-    pub fn build(self) -> Router<Value> {
+    ///
+    /// The builder is retained, so that rules can be changed and the router built again. This
+    /// comes at a cost however: each call clones all values and rebuilds the router from scratch,
+    /// for large routing tables with expensive values this should be done sparingly.
+    pub fn build(&self) -> Router<Value> {
+        let mut entries: HashMap<Vec<u8>, Vec<RouterEntry<Value>>> = HashMap::new();
+        let mut fallbacks = Vec::new();
+        for route in &self.routes {
+            let existing = if route.host.is_empty() {
+                &mut fallbacks
+            } else {
+                entries.entry(route.host.clone()).or_default()
+            };
+
+            Self::merge_value(
+                existing,
+                route.path.clone(),
+                route.value_exact.clone(),
+                route.value_prefix.clone(),
+            );
+        }
+
         let mut builder = Trie::builder();
-        for (host, entries) in self.entries {
+        for (host, entries) in entries {
             for entry in entries {
                 let mut key = host.clone();
                 if !entry.path.is_empty() {
                     key.push(SEPARATOR);
                     key.extend_from_slice(&entry.path);
                 }
-                builder.push(key, entry.value_exact, entry.value_prefix);
+                builder.push_with_priority(key, entry.value_exact, entry.value_prefix);
             }
         }
 
         let mut fallback_builder = Trie::builder();
-        for entry in self.fallbacks {
-            fallback_builder.push(entry.path.path, entry.value_exact, entry.value_prefix);
+        for entry in fallbacks {
+            fallback_builder.push_with_priority(
+                entry.path.path,
+                entry.value_exact,
+                entry.value_prefix,
+            );
         }
 
         Router {
@@ -357,6 +462,146 @@ mod tests {
         );
     }
 
+    #[test]
+    fn priority() {
+        let mut builder = Router::builder();
+        builder.push("localhost", "/", "Root", Some("Within root"));
+        builder.push("localhost", "/dir/", "Dir", Some("Within dir"));
+        builder.push("localhost", "/dir/subdir/", "Subdir", Some("Within subdir"));
+        builder.push_with_priority("localhost", "/api/", "API", Some("Within API"), 1);
+        builder.push("localhost", "/api/v1/", "API v1", Some("Within API v1"));
+        builder.push("localhost", "/api/v1/exact", "API v1 exact", None);
+        builder.push("example.com", "/", "Example", Some("Within example"));
+        builder.push(
+            "example.com",
+            "/.well-known/",
+            "Known",
+            Some("Within known"),
+        );
+        builder.push_with_priority("", "/.well-known/", "Global", Some("Within global"), 10);
+        let router = builder.build();
+
+        // Default priority, longest match wins
+        assert_eq!(
+            *router.lookup("localhost", "/dir/subdir/x").unwrap(),
+            "Within subdir"
+        );
+        assert_eq!(*router.lookup("localhost", "/dir/x").unwrap(), "Within dir");
+
+        // Less specific route with higher priority wins
+        assert_eq!(*router.lookup("localhost", "/api/").unwrap(), "API");
+        assert_eq!(
+            *router.lookup("localhost", "/api/v1/").unwrap(),
+            "Within API"
+        );
+        assert_eq!(
+            *router.lookup("localhost", "/api/v1/x").unwrap(),
+            "Within API"
+        );
+        assert_eq!(
+            *router.lookup("localhost", "/api/v1/exact").unwrap(),
+            "Within API"
+        );
+        assert_eq!(
+            router.lookup("localhost", "/api/v1/x").unwrap().priority(),
+            1
+        );
+
+        // Fallback route with higher priority wins over host routes
+        assert_eq!(
+            *router.lookup("example.com", "/.well-known/x").unwrap(),
+            "Within global"
+        );
+        assert_eq!(
+            *router.lookup("localhost", "/.well-known/").unwrap(),
+            "Global"
+        );
+        assert_eq!(
+            *router.lookup("example.com", "/x").unwrap(),
+            "Within example"
+        );
+
+        for (host, path) in [
+            ("localhost", "/api/v1/x"),
+            ("example.com", "/.well-known/x"),
+            ("example.com", "/x"),
+        ] {
+            let result = router.lookup(host, path).unwrap();
+            assert_eq!(router.retrieve(result.index()), Some(result.as_value()));
+        }
+    }
+
+    #[test]
+    fn retrieve_fallback() {
+        let mut builder = Router::builder();
+        builder.push("localhost", "/", "Localhost root", Some("Within localhost"));
+        builder.push("", "/", "Fallback root", Some("Within fallback"));
+        builder.push("", "/dir/", "Fallback dir", Some("Within fallback dir"));
+        let router = builder.build();
+
+        for (host, path, expected) in [
+            ("localhost", "/", "Localhost root"),
+            ("localhost", "/file", "Within localhost"),
+            ("example.com", "/", "Fallback root"),
+            ("example.com", "/file", "Within fallback"),
+            ("example.com", "/dir/file", "Within fallback dir"),
+            ("", "/dir/", "Fallback dir"),
+        ] {
+            let result = router.lookup(host, path).unwrap();
+            assert_eq!(*result, expected);
+            assert_eq!(router.retrieve(result.index()), Some(&expected));
+        }
+    }
+
+    #[test]
+    fn rebuild() {
+        let mut builder = Router::builder();
+        builder.push("localhost", "/", "Localhost root", Some("Within localhost"));
+        builder.push("localhost", "/dir/", "Localhost dir", None);
+        builder.push("", "/", "Fallback root", None);
+        let router = builder.build();
+
+        let lookup = |router: &Router<&'static str>, host: &str, path: &str| {
+            router.lookup(host, path).map(|result| *result)
+        };
+
+        builder.push("example.com", "/", "Website root", None);
+        builder.push("localhost", "/other/", "Localhost other", None);
+        let rebuilt = builder.build();
+        assert_eq!(lookup(&rebuilt, "example.com", "/"), Some("Website root"));
+        assert_eq!(
+            lookup(&rebuilt, "localhost", "/other"),
+            Some("Localhost other")
+        );
+        assert_eq!(
+            lookup(&rebuilt, "localhost", "/dir/file"),
+            Some("Within localhost")
+        );
+
+        // Original router is unchanged
+        assert_eq!(lookup(&router, "example.com", "/"), Some("Fallback root"));
+        assert_eq!(
+            lookup(&router, "localhost", "/other"),
+            Some("Within localhost")
+        );
+
+        assert!(builder.remove("localhost", "//"));
+        assert!(builder.remove("example.com", "/"));
+        assert!(!builder.remove("example.com", "/"));
+        let rebuilt = builder.build();
+        assert_eq!(lookup(&rebuilt, "example.com", "/"), Some("Fallback root"));
+        assert_eq!(lookup(&rebuilt, "localhost", "/"), Some("Fallback root"));
+        assert_eq!(lookup(&rebuilt, "localhost", "/file"), None);
+        assert_eq!(lookup(&rebuilt, "localhost", "/dir"), Some("Localhost dir"));
+        assert_eq!(
+            lookup(&rebuilt, "localhost", "/other/"),
+            Some("Localhost other")
+        );
+
+        // Inherited values disappear along with the removed rule
+        assert_eq!(lookup(&rebuilt, "localhost", "/dir/file"), None);
+    }
+
     #[test]
     fn host_path_matcher_parsing() {
         assert_eq!(
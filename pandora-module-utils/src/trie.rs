@@ -20,6 +20,7 @@
 //! * The labels are segmented with a separator character (forward slash) and only full segment
 //!   matches are accepted.
 //! * Different value returned for exact and prefix matches
+//! * Values can have a priority overriding the longest match rule
 //! * When the same value is used multiple times, only one copy is stored
 
 use std::ops::{Deref, Range};
@@ -51,12 +52,17 @@ pub(crate) fn common_prefix_length(a: &[u8], b: &[u8]) -> usize {
     length
 }
 
+/// Returns the first segment of a label, this identifies a child node among its siblings.
+fn first_segment(label: &[u8]) -> &[u8] {
+    label.split(|b| *b == SEPARATOR).next().unwrap_or(label)
+}
+
 /// A trie data structure
 ///
 /// To use memory more efficiently and to improve locality, this stores all data in three vectors.
 /// One lists all nodes, ordered in such a way that children of one node are always stored
-/// consecutively and sorted by their label. A node stores an index range referring to its
-/// children.
+/// consecutively and sorted by the first segment of their label. A node stores an index range
+/// referring to its children, so that the matching child can be located via binary search.
 ///
 /// Since values are optional and potentially rather large, existing values are stored in a
 /// separate vector. The node stores an optional index of its value, not the value itself.
@@ -76,11 +82,16 @@ pub(crate) struct Trie<Value> {
 pub struct LookupResult<'a, Value> {
     value: &'a Value,
     index: usize,
+    priority: i32,
 }
 
 impl<'a, Value> LookupResult<'a, Value> {
-    fn new(value: &'a Value, index: usize) -> Self {
-        Self { value, index }
+    fn new(value: &'a Value, index: usize, priority: i32) -> Self {
+        Self {
+            value,
+            index,
+            priority,
+        }
     }
 
     /// The index of the referenced value, allows retrieving it again without going through another
@@ -89,12 +100,23 @@ impl<'a, Value> LookupResult<'a, Value> {
         self.index
     }
 
+    /// The priority of the matching route
+    pub fn priority(&self) -> i32 {
+        self.priority
+    }
+
     /// Retrieves the inner value
     ///
     /// Unlike dereferencing, this propagates lifetimes properly
     pub fn as_value(&self) -> &'a Value {
         self.value
     }
+
+    /// Shifts the index by the given offset, used when combining multiple tries
+    pub(crate) fn offset_index(mut self, offset: usize) -> Self {
+        self.index += offset;
+        self
+    }
 }
 
 impl<Value> Deref for LookupResult<'_, Value> {
@@ -121,6 +143,8 @@ struct Node {
     label: Range<usize>,
     value_exact: Option<usize>,
     value_prefix: Option<usize>,
+    priority_exact: i32,
+    priority_prefix: i32,
     children: Range<usize>,
 }
 
@@ -136,11 +160,21 @@ impl<Value> Trie<Value> {
         TrieBuilder::<Value>::new()
     }
 
-    /// Converts a value index into a lookup result
-    fn to_lookup_result(&self, result: Option<usize>) -> Option<LookupResult<'_, Value>> {
-        result
-            .and_then(|index| Some((self.values.get(index)?, index)))
-            .map(|(value, index)| (LookupResult::new(value, index)))
+    /// Converts a value index and priority into a lookup result
+    fn to_lookup_result(&self, result: Option<(usize, i32)>) -> Option<LookupResult<'_, Value>> {
+        let (index, priority) = result?;
+        Some(LookupResult::new(self.values.get(index)?, index, priority))
+    }
+
+    /// Replaces the current result if the candidate has at least the same priority. Candidates
+    /// are considered in the order of increasing specificity, so this implements the longest
+    /// match rule for identical priorities.
+    fn consider(result: &mut Option<(usize, i32)>, value: Option<usize>, priority: i32) {
+        if let Some(value) = value {
+            if result.map_or(true, |(_, current)| priority >= current) {
+                *result = Some((value, priority));
+            }
+        }
     }
 
     /// Looks up a particular label in the trie.
@@ -148,68 +182,62 @@ impl<Value> Trie<Value> {
     /// The label is identified by an iterator producing segments. The segments are expected to be
     /// normalized: no empty segments exist and no segments contain the separator character.
     ///
-    /// This will return the value corresponding to the longest matching path if any.
+    /// This will return the value with the highest priority among all matches. If multiple values
+    /// have the same priority, the one corresponding to the longest matching path wins.
     pub(crate) fn lookup<'a, L>(&self, mut label: L) -> Option<LookupResult<'_, Value>>
     where
         L: Iterator<Item = &'a [u8]>,
     {
-        let mut result_exact;
         let mut result_prefix = None;
         let mut current = self.nodes.get(Self::ROOT)?;
         loop {
-            result_exact = current.value_exact;
-            if current.value_prefix.is_some() {
-                result_prefix = current.value_prefix;
-            }
+            Self::consider(
+                &mut result_prefix,
+                current.value_prefix,
+                current.priority_prefix,
+            );
 
             let segment = if let Some(segment) = label.next() {
                 segment
             } else {
-                // End of label, return either exact or prefix result
-                return self.to_lookup_result(result_exact.or(result_prefix));
+                // End of label, exact result takes precedence for identical priorities
+                let mut result = result_prefix;
+                Self::consider(&mut result, current.value_exact, current.priority_exact);
+                return self.to_lookup_result(result);
             };
 
-            // TODO: Binary search might be more efficient here
-            let mut found_match = false;
-            for child in current.children.start..current.children.end {
-                let child = self.nodes.get(child)?;
-                let mut label_start = child.label.start;
-                let label_end = child.label.end;
+            let children = &self.nodes[current.children.clone()];
+            let child = match children.binary_search_by(|child| {
+                first_segment(&self.labels[child.label.clone()]).cmp(segment)
+            }) {
+                Ok(index) => &children[index],
+                Err(_) => return self.to_lookup_result(result_prefix),
+            };
+
+            // First segment matched, keep matching more segments until there is no more label left
+            let mut label_start = child.label.start + segment.len();
+            let label_end = child.label.end;
+            while label_end > label_start {
+                // Skip separator character
+                label_start += 1;
+
+                let segment = if let Some(segment) = label.next() {
+                    segment
+                } else {
+                    // End of label, return whatever we’ve got
+                    return self.to_lookup_result(result_prefix);
+                };
+
                 let length = common_prefix_length(segment, &self.labels[label_start..label_end]);
                 if length > 0 {
                     label_start += length;
-
-                    // Keep matching more segments until there is no more label left
-                    while label_end > label_start {
-                        // Skip separator character
-                        label_start += 1;
-
-                        let segment = if let Some(segment) = label.next() {
-                            segment
-                        } else {
-                            // End of label, return whatever we’ve got
-                            return self.to_lookup_result(result_prefix);
-                        };
-
-                        let length =
-                            common_prefix_length(segment, &self.labels[label_start..label_end]);
-                        if length > 0 {
-                            label_start += length;
-                        } else {
-                            // Got only a partial match
-                            return self.to_lookup_result(result_prefix);
-                        }
-                    }
-
-                    found_match = true;
-                    current = child;
-                    break;
+                } else {
+                    // Got only a partial match
+                    return self.to_lookup_result(result_prefix);
                 }
             }
 
-            if !found_match {
-                return self.to_lookup_result(result_prefix);
-            }
+            current = child;
         }
     }
 
@@ -217,6 +245,11 @@ impl<Value> Trie<Value> {
     pub(crate) fn retrieve(&self, index: usize) -> Option<&Value> {
         self.values.get(index)
     }
+
+    /// Returns the number of distinct values stored in the trie
+    pub(crate) fn value_count(&self) -> usize {
+        self.values.len()
+    }
 }
 
 /// A trie builder used to set up a `Trie` instance
@@ -237,8 +270,8 @@ pub(crate) struct TrieBuilder<Value> {
 struct BuilderNode<Value> {
     label: Vec<u8>,
     children: Vec<BuilderNode<Value>>,
-    value_exact: Option<Value>,
-    value_prefix: Option<Value>,
+    value_exact: Option<(Value, i32)>,
+    value_prefix: Option<(Value, i32)>,
 }
 
 impl<Value: Eq> TrieBuilder<Value> {
@@ -271,43 +304,47 @@ impl<Value: Eq> TrieBuilder<Value> {
         labels: &mut usize,
         label: &mut Vec<u8>,
     ) -> &'a mut BuilderNode<Value> {
-        let mut match_ = None;
-        for (i, node) in current.children.iter_mut().enumerate() {
-            let length = common_prefix_length(&node.label, label);
-            if length > 0 {
-                label.drain(..std::cmp::min(length + 1, label.len()));
-                if length < node.label.len() {
-                    // Partial match, insert a new node and make the original its child
-                    let mut head: Vec<_> = node.label.drain(..length + 1).collect();
-
-                    // Remove separator
-                    head.pop();
-
-                    *nodes += 1;
-
-                    // Splitting the node label in two results in one character less (separator)
-                    *labels -= 1;
-
-                    let mut new_node = BuilderNode {
-                        label: head,
-                        children: Vec::new(),
-                        value_exact: None,
-                        value_prefix: None,
-                    };
-
-                    std::mem::swap(node, &mut new_node);
-                    node.children.push(new_node);
-                };
+        let index = match Self::find_child(current, label) {
+            Ok(index) => index,
+            Err(_) => return current,
+        };
 
-                match_ = Some(i);
-                break;
-            }
-        }
+        let node = &mut current.children[index];
+        let length = common_prefix_length(&node.label, label);
+        label.drain(..std::cmp::min(length + 1, label.len()));
+        if length < node.label.len() {
+            // Partial match, insert a new node and make the original its child
+            let mut head: Vec<_> = node.label.drain(..length + 1).collect();
+
+            // Remove separator
+            head.pop();
 
-        return match match_ {
-            Some(i) => Self::find_insertion_point(&mut current.children[i], nodes, labels, label),
-            None => current,
+            *nodes += 1;
+
+            // Splitting the node label in two results in one character less (separator)
+            *labels -= 1;
+
+            let mut new_node = BuilderNode {
+                label: head,
+                children: Vec::new(),
+                value_exact: None,
+                value_prefix: None,
+            };
+
+            std::mem::swap(node, &mut new_node);
+            node.children.push(new_node);
         };
+
+        Self::find_insertion_point(&mut current.children[index], nodes, labels, label)
+    }
+
+    /// Locates the child node sharing the first segment with the given label. Children are kept
+    /// sorted, so that the error value is the position where a new child should be inserted.
+    fn find_child(current: &BuilderNode<Value>, label: &[u8]) -> Result<usize, usize> {
+        let segment = first_segment(label);
+        current
+            .children
+            .binary_search_by(|child| first_segment(&child.label).cmp(segment))
     }
 
     /// Adds a value for the given label. Will return `true` if an existing value was overwritten.
@@ -319,9 +356,24 @@ impl<Value: Eq> TrieBuilder<Value> {
     /// always only one separator character used to separate segments.
     pub(crate) fn push(
         &mut self,
-        mut label: Vec<u8>,
+        label: Vec<u8>,
         value_exact: Value,
         value_prefix: Option<Value>,
+    ) -> bool {
+        self.push_with_priority(
+            label,
+            (value_exact, 0),
+            value_prefix.map(|value| (value, 0)),
+        )
+    }
+
+    /// Adds a value for the given label, like [`TrieBuilder::push`]. The values are accompanied
+    /// by their respective priorities however.
+    pub(crate) fn push_with_priority(
+        &mut self,
+        mut label: Vec<u8>,
+        value_exact: (Value, i32),
+        value_prefix: Option<(Value, i32)>,
     ) -> bool {
         let node = Self::find_insertion_point(
             &mut self.root,
@@ -340,12 +392,16 @@ impl<Value: Eq> TrieBuilder<Value> {
             // Insert new node as child of the current one
             self.nodes += 1;
             self.labels += label.len();
-            node.children.push(BuilderNode {
-                label,
-                children: Vec::new(),
-                value_exact: Some(value_exact),
-                value_prefix,
-            });
+            let index = Self::find_child(node, &label).unwrap_or_else(|index| index);
+            node.children.insert(
+                index,
+                BuilderNode {
+                    label,
+                    children: Vec::new(),
+                    value_exact: Some(value_exact),
+                    value_prefix,
+                },
+            );
             false
         }
     }
@@ -359,6 +415,8 @@ impl<Value: Eq> TrieBuilder<Value> {
             label: 0..0,
             value_exact: None,
             value_prefix: None,
+            priority_exact: 0,
+            priority_prefix: 0,
             children: 0..0,
         });
     }
@@ -389,15 +447,16 @@ impl<Value: Eq> TrieBuilder<Value> {
         nodes[index].label = labels.len()..labels.len() + current.label.len();
         labels.append(&mut current.label);
 
-        if let Some(value) = current.value_exact {
+        if let Some((value, priority)) = current.value_exact {
             nodes[index].value_exact = Some(Self::add_value(value, values));
+            nodes[index].priority_exact = priority;
         }
-        if let Some(value) = current.value_prefix {
+        if let Some((value, priority)) = current.value_prefix {
             nodes[index].value_prefix = Some(Self::add_value(value, values));
+            nodes[index].priority_prefix = priority;
         }
 
-        current.children.sort_by(|a, b| a.label.cmp(&b.label));
-
+        // Children are already sorted by `find_child`, lookup relies on it.
         let mut child_index = nodes.len();
         nodes[index].children = child_index..child_index + current.children.len();
         for _ in &current.children {
@@ -525,6 +584,65 @@ mod tests {
         assert_eq!(trie.lookup(make_key("a/bc/de/h")).as_deref(), Some(&16));
     }
 
+    #[test]
+    fn lookup_with_priority() {
+        let mut builder = Trie::builder();
+        for (label, value_exact, value_prefix) in [
+            ("", (1, 0), (11, 0)),
+            ("a", (2, 1), (12, 1)),
+            ("a/bc", (3, 0), (13, 0)),
+            ("a/bc/de", (4, 2), (14, 2)),
+            ("x", (5, -1), (15, -1)),
+        ] {
+            assert!(!builder.push_with_priority(
+                label.as_bytes().to_vec(),
+                value_exact,
+                Some(value_prefix)
+            ));
+        }
+        let trie = builder.build();
+
+        assert_eq!(trie.lookup(make_key("")).as_deref(), Some(&1));
+        assert_eq!(trie.lookup(make_key("a")).as_deref(), Some(&2));
+        assert_eq!(trie.lookup(make_key("a/b")).as_deref(), Some(&12));
+        assert_eq!(trie.lookup(make_key("a/bc")).as_deref(), Some(&12));
+        assert_eq!(trie.lookup(make_key("a/bc/d")).as_deref(), Some(&12));
+        assert_eq!(trie.lookup(make_key("a/bc/de")).as_deref(), Some(&4));
+        assert_eq!(trie.lookup(make_key("a/bc/de/f")).as_deref(), Some(&14));
+        assert_eq!(trie.lookup(make_key("x")).as_deref(), Some(&11));
+        assert_eq!(trie.lookup(make_key("x/y")).as_deref(), Some(&11));
+
+        assert_eq!(trie.lookup(make_key("a/bc")).unwrap().priority(), 1);
+        assert_eq!(trie.lookup(make_key("a/bc/de/f")).unwrap().priority(), 2);
+        assert_eq!(trie.lookup(make_key("x")).unwrap().priority(), 0);
+    }
+
+    #[test]
+    fn lookup_many_siblings() {
+        let mut builder = Trie::builder();
+        for i in 0..1000 {
+            let label = format!("host{i}");
+            builder.push(label.as_bytes().to_vec(), i, Some(i + 1000));
+        }
+        builder.push(b"a-c".to_vec(), 2000, None);
+        builder.push(b"a/b".to_vec(), 2001, None);
+        builder.push(b"a.b/c".to_vec(), 2002, None);
+        let trie = builder.build();
+
+        for i in 0..1000 {
+            let label = format!("host{i}");
+            assert_eq!(trie.lookup(make_key(&label)).as_deref(), Some(&i));
+            let label = format!("host{i}/file");
+            assert_eq!(trie.lookup(make_key(&label)).as_deref(), Some(&(i + 1000)));
+        }
+        assert_eq!(trie.lookup(make_key("host1000")).as_deref(), None);
+        assert_eq!(trie.lookup(make_key("a-c")).as_deref(), Some(&2000));
+        assert_eq!(trie.lookup(make_key("a/b")).as_deref(), Some(&2001));
+        assert_eq!(trie.lookup(make_key("a.b/c")).as_deref(), Some(&2002));
+        assert_eq!(trie.lookup(make_key("a.b")).as_deref(), None);
+        assert_eq!(trie.lookup(make_key("a")).as_deref(), None);
+    }
+
     #[test]
     fn value_compacting() {
         let mut builder = Trie::builder();
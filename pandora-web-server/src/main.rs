@@ -26,6 +26,8 @@
 //!
 //! ### Modules
 //!
+//! * **ACME Challenge**: Answers ACME HTTP-01 challenges from memory, allowing certificates to
+//!   be obtained without writing challenge files.
 //! * **Auth**: Puts parts of the webspace behind an authentication wall. Supports page-based
 //!   logins (recommended) and HTTP Basic authentication.
 //! * **Common Log**: Access logging using [Common Log
@@ -33,15 +35,29 @@
 //!   configurable.
 //! * **Compression**: Dynamic compression of server responses and (if necessary) decompression of
 //!   upstream responses.
+//! * **Debug Tap**: Logs request and response headers passing through the handler chain, helpful
+//!   for debugging. Sensitive header values are redacted.
+//! * **Header Limits**: Rejects requests with too many or too large headers, responding with
+//!   `431 Request Header Fields Too Large`.
 //! * **Headers**: Structured configuration of `Cache-Control` and `Content-Security-Policy`
 //!   headers, supports adding custom response headers.
+//! * **Health**: Liveness and readiness endpoints for container orchestration systems like
+//!   Kubernetes.
+//! * **Inline Files**: Serves small files like `robots.txt` or `security.txt` directly from the
+//!   configuration.
 //! * **IP Anonymization**: Removes part of the IP address, making sure no personal data is
 //!   collected here.
+//! * **Maintenance**: Responds with `503 Service Unavailable` while maintenance mode is on, e.g.
+//!   during deployments.
+//! * **Method Override**: Allows clients limited to `GET` and `POST` requests to specify the actual
+//!   request method via `X-HTTP-Method-Override` header.
 //! * **Rewrite**: Flexible rules allowing internal or external redirection of requests.
 //! * **Static Files**: Serves static files from a directory, supports pre-compressed files.
 //! * **Startup**: Listening on any number of IP addresses/ports, TLS support, automatic
 //!   redirecting from HTTP to HTTPS.
 //! * **Upstream**: Delegates the request to an upstream HTTP server.
+//! * **URL Signing**: Restricts access to URLs signed with a shared secret, e.g. for expiring
+//!   download links.
 //! * **Virtual Hosts**: Separate configurations per host name and (optionally) subpaths within a
 //!   host.
 //!
@@ -110,6 +126,9 @@
 //! You can find more information on the `RUST_LOG` environment variable in the [documentation of
 //! the `env_logger` crate](https://docs.rs/env_logger/latest/env_logger/).
 //!
+//! Log level and output file can also be set via the `log` setting in the configuration file, see
+//! the [Startup Module documentation](https://docs.rs/startup-module/latest/startup_module/).
+//!
 //! ## Selecting other features
 //!
 //! In additions to the default features, the preset `default-single-host` is also available. It
@@ -125,16 +144,26 @@
 //! Features of this crate also allow selecting for each module whether it should be used at the
 //! top level or in a per-host configuration:
 //!
-//! | Module            | Top-level feature             | Per-host feature              |
-//! |-------------------|-------------------------------|-------------------------------|
-//! | Auth              | `auth-top-level`              | `auth-per-host`               |
-//! | Common Log        | `common-log-top-level`        | `common-log-per-host`         |
-//! | Compression       | `compression-top-level`       | `compression-per-host`        |
-//! | Headers           | `headers-top-level`           | `headers-per-host`            |
-//! | IP Anonymization  | `ip-anonymization-top-level`  | `ip-anonymization-per-host`   |
-//! | Rewrite           | `rewrite-top-level`           | `rewrite-per-host`            |
-//! | Static Files      | `static-files-top-level`      | `static-files-per-host`       |
-//! | Upstream          | `upstream-top-level`          | `upstream-per-host`           |
+//! | Module             | Top-level feature              | Per-host feature              |
+//! |--------------------|--------------------------------|-------------------------------|
+//! | ACME Challenge     | `acme-challenge-top-level`     | `acme-challenge-per-host`     |
+//! | Auth               | `auth-top-level`               | `auth-per-host`               |
+//! | Cache              | `cache-top-level`              | `cache-per-host`              |
+//! | Common Log         | `common-log-top-level`         | `common-log-per-host`         |
+//! | Compression        | `compression-top-level`        | `compression-per-host`        |
+//! | Concurrency Limits | `concurrency-limits-top-level` | `concurrency-limits-per-host` |
+//! | Debug Tap          | `debug-tap-top-level`          | `debug-tap-per-host`          |
+//! | Header Limits      | `header-limits-top-level`      | `header-limits-per-host`      |
+//! | Headers            | `headers-top-level`            | `headers-per-host`            |
+//! | Health             | `health-top-level`             | `health-per-host`             |
+//! | Inline Files       | `inline-files-top-level`       | `inline-files-per-host`       |
+//! | IP Anonymization   | `ip-anonymization-top-level`   | `ip-anonymization-per-host`   |
+//! | Maintenance        | `maintenance-top-level`        | `maintenance-per-host`        |
+//! | Method Override    | `method-override-top-level`    | `method-override-per-host`    |
+//! | Rewrite            | `rewrite-top-level`            | `rewrite-per-host`            |
+//! | Static Files       | `static-files-top-level`       | `static-files-per-host`       |
+//! | Upstream           | `upstream-top-level`           | `upstream-per-host`           |
+//! | URL Signing        | `url-signing-top-level`        | `url-signing-per-host`        |
 //!
 //! For example, if your server only needs to serve static files and write access logs, you can
 //! build it with the following command:
@@ -144,7 +173,8 @@
 //! ```
 //!
 //! The Startup module is always present at the top level, and the Virtual Hosts module is added
-//! automatically if any per-host feature is enabled.
+//! automatically if any per-host feature is enabled: each of these enables the `virtual-hosts`
+//! feature.
 //!
 //! *Note*: It is technically possible to include a module both at the top and per-host level. It
 //! will be configurable on both levels then. Whether this approach makes sense and how the two
@@ -153,54 +183,86 @@
 
 use clap::Parser;
 use log::error;
+use pandora_module_utils::pingora::Service;
 use pandora_module_utils::{merge_conf, merge_opt, FromYaml, RequestFilter};
 use startup_module::{DefaultApp, StartupConf, StartupOpt};
 
 #[derive(Debug, Clone, PartialEq, Eq, RequestFilter)]
 struct Handler {
+    #[cfg(feature = "debug-tap-top-level")]
+    debug_tap: debug_tap_module::DebugTapHandler,
+    #[cfg(feature = "header-limits-top-level")]
+    header_limits: header_limits_module::HeaderLimitsHandler,
+    #[cfg(feature = "concurrency-limits-top-level")]
+    concurrency_limits: concurrency_limits_module::ConcurrencyLimitsHandler,
+    #[cfg(feature = "health-top-level")]
+    health: health_module::HealthHandler,
+    #[cfg(feature = "acme-challenge-top-level")]
+    acme_challenge: acme_challenge_module::AcmeChallengeHandler,
     #[cfg(feature = "ip-anonymization-top-level")]
     anonymization: ip_anonymization_module::IPAnonymizationHandler,
+    #[cfg(feature = "method-override-top-level")]
+    method_override: method_override_module::MethodOverrideHandler,
     #[cfg(feature = "common-log-top-level")]
     log: common_log_module::CommonLogHandler,
     #[cfg(feature = "compression-top-level")]
     compression: compression_module::CompressionHandler,
     #[cfg(feature = "headers-top-level")]
     headers: headers_module::HeadersHandler,
+    #[cfg(feature = "maintenance-top-level")]
+    maintenance: maintenance_module::MaintenanceHandler,
+    #[cfg(feature = "inline-files-top-level")]
+    inline_files: inline_files_module::InlineFilesHandler,
     #[cfg(feature = "auth-top-level")]
     auth: auth_module::AuthHandler,
+    #[cfg(feature = "url-signing-top-level")]
+    url_signing: url_signing_module::UrlSigningHandler,
     #[cfg(feature = "rewrite-top-level")]
     rewrite: rewrite_module::RewriteHandler,
+    #[cfg(feature = "cache-top-level")]
+    cache: cache_module::CacheHandler,
     #[cfg(feature = "upstream-top-level")]
     upstream: upstream_module::UpstreamHandler,
     #[cfg(feature = "static-files-top-level")]
     static_files: static_files_module::StaticFilesHandler,
-    #[cfg(any(
-        feature = "auth-per-host",
-        feature = "common-log-per-host",
-        feature = "compression-per-host",
-        feature = "headers-per-host",
-        feature = "ip-anonymization-per-host",
-        feature = "rewrite-per-host",
-        feature = "static-files-per-host",
-        feature = "upstream-per-host"
-    ))]
+    #[cfg(feature = "virtual-hosts")]
     virtual_hosts: virtual_hosts_module::VirtualHostsHandler<HostHandler>,
 }
 
 #[derive(Debug, Clone, PartialEq, Eq, RequestFilter)]
 struct HostHandler {
+    #[cfg(feature = "debug-tap-per-host")]
+    debug_tap: debug_tap_module::DebugTapHandler,
+    #[cfg(feature = "header-limits-per-host")]
+    header_limits: header_limits_module::HeaderLimitsHandler,
+    #[cfg(feature = "concurrency-limits-per-host")]
+    concurrency_limits: concurrency_limits_module::ConcurrencyLimitsHandler,
+    #[cfg(feature = "health-per-host")]
+    health: health_module::HealthHandler,
+    #[cfg(feature = "acme-challenge-per-host")]
+    acme_challenge: acme_challenge_module::AcmeChallengeHandler,
     #[cfg(feature = "ip-anonymization-per-host")]
     anonymization: ip_anonymization_module::IPAnonymizationHandler,
+    #[cfg(feature = "method-override-per-host")]
+    method_override: method_override_module::MethodOverrideHandler,
     #[cfg(feature = "common-log-per-host")]
     log: common_log_module::CommonLogHandler,
     #[cfg(feature = "compression-per-host")]
     compression: compression_module::CompressionHandler,
     #[cfg(feature = "headers-per-host")]
     headers: headers_module::HeadersHandler,
+    #[cfg(feature = "maintenance-per-host")]
+    maintenance: maintenance_module::MaintenanceHandler,
+    #[cfg(feature = "inline-files-per-host")]
+    inline_files: inline_files_module::InlineFilesHandler,
     #[cfg(feature = "auth-per-host")]
     auth: auth_module::AuthHandler,
+    #[cfg(feature = "url-signing-per-host")]
+    url_signing: url_signing_module::UrlSigningHandler,
     #[cfg(feature = "rewrite-per-host")]
     rewrite: rewrite_module::RewriteHandler,
+    #[cfg(feature = "cache-per-host")]
+    cache: cache_module::CacheHandler,
     #[cfg(feature = "upstream-per-host")]
     upstream: upstream_module::UpstreamHandler,
     #[cfg(feature = "static-files-per-host")]
@@ -221,6 +283,14 @@ struct Opt {
     auth: auth_module::AuthOpt,
     #[cfg(feature = "compression-top-level")]
     static_files: static_files_module::StaticFilesOpt,
+    #[cfg(feature = "cache-top-level")]
+    cache: cache_module::CacheOpt,
+    #[cfg(feature = "maintenance-top-level")]
+    maintenance: maintenance_module::MaintenanceOpt,
+    #[cfg(feature = "method-override-top-level")]
+    method_override: method_override_module::MethodOverrideOpt,
+    #[cfg(feature = "debug-tap-top-level")]
+    debug_tap: debug_tap_module::DebugTapOpt,
 }
 
 /// The configuration of Pandora Web Server
@@ -231,12 +301,19 @@ struct Conf {
 }
 
 fn main() {
-    env_logger::init();
-
     let opt = Opt::parse();
 
+    let conf = Conf::load_from_files(opt.startup.conf.as_deref().unwrap_or(&[]));
+    if !conf
+        .as_ref()
+        .is_ok_and(|conf| conf.startup.log.is_configured())
+    {
+        // Logging hasn’t been configured, fall back to RUST_LOG environment variable
+        env_logger::init();
+    }
+
     #[allow(unused_mut)]
-    let mut conf = match Conf::load_from_files(opt.startup.conf.as_deref().unwrap_or(&[])) {
+    let mut conf = match conf {
         Ok(conf) => conf,
         Err(err) => {
             error!("{err}");
@@ -252,12 +329,43 @@ fn main() {
     conf.handler.compression.merge_with_opt(opt.compression);
     #[cfg(feature = "auth-top-level")]
     conf.handler.auth.merge_with_opt(opt.auth);
+    #[cfg(feature = "cache-top-level")]
+    conf.handler.cache.merge_with_opt(opt.cache);
+    #[cfg(feature = "maintenance-top-level")]
+    conf.handler.maintenance.merge_with_opt(opt.maintenance);
+    #[cfg(feature = "method-override-top-level")]
+    conf.handler
+        .method_override
+        .merge_with_opt(opt.method_override);
+    #[cfg(feature = "debug-tap-top-level")]
+    conf.handler.debug_tap.merge_with_opt(opt.debug_tap);
     #[cfg(feature = "static-files-top-level")]
     conf.handler.static_files.merge_with_opt(opt.static_files);
 
-    let server = match DefaultApp::<Handler>::from_conf(conf.handler)
-        .and_then(|app| conf.startup.into_server(app, Some(opt.startup)))
+    #[allow(unused_mut)]
+    let mut services: Vec<Box<dyn Service>> = Vec::new();
+    #[cfg(any(feature = "health-top-level", feature = "health-per-host"))]
+    services.push(Box::new(health_module::readiness_service(
+        health_module::Readiness::global(),
+    )));
+
+    #[cfg(feature = "virtual-hosts")]
+    if let Err(err) = conf
+        .handler
+        .virtual_hosts
+        .tls_server_names()
+        .and_then(|server_names| conf.startup.tls.add_server_names(server_names))
     {
+        error!("{err}");
+        return;
+    }
+
+    let server = match DefaultApp::<Handler>::from_conf(conf.handler).and_then(|app| {
+        let app = app.with_startup_conf(&conf.startup);
+        conf.startup
+            .into_server_with_services(app, Some(opt.startup), services)
+            .map_err(Into::into)
+    }) {
         Ok(server) => server,
         Err(err) => {
             error!("{err}");
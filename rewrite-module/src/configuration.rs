@@ -16,7 +16,7 @@
 
 use pandora_module_utils::merger::PathMatcher;
 use pandora_module_utils::{DeserializeMap, OneOrMany};
-use regex::Regex;
+use regex::{Captures, Regex};
 use serde::Deserialize;
 use std::default::Default;
 
@@ -126,6 +126,11 @@ pub enum RewriteType {
     Redirect,
     /// A 308 Permanent Redirect response
     Permanent,
+    /// A 302 Found response, a temporary redirect that allows changing request method to `GET`
+    Found,
+    /// A 301 Moved Permanently response, a permanent redirect that allows changing request method
+    /// to `GET`
+    Moved,
 }
 
 /// A parsed representation of a field like `from_regex` of the rewrite rule
@@ -148,6 +153,16 @@ impl RegexMatch {
             result
         }
     }
+
+    /// Returns capture groups if the given value is matched. Negated regular expressions never
+    /// produce capture groups.
+    pub(crate) fn captures<'a>(&self, value: &'a str) -> Option<Captures<'a>> {
+        if self.negate {
+            None
+        } else {
+            self.regex.captures(value)
+        }
+    }
 }
 
 impl PartialEq for RegexMatch {
@@ -222,9 +237,16 @@ pub struct RewriteRule {
     ///   `/file.txt?a=b` will be rewritten into `/file.html?a=b`.
     /// * `${http_<header>}`: This allows inserting arbitrary HTTP headers into the redirect
     ///   target.
+    /// * `${1}`, `${2}` etc.: Only valid if `from_regex` is set. These will be replaced by the
+    ///   corresponding capture group of the regular expression, `${0}` by the entire match. For
+    ///   example, if `from_regex` is `^/old/(.*)$` and `to` is `/new/${1}` then a request to
+    ///   `/old/file.txt` will be rewritten into `/new/file.txt`.
+    /// * `${<name>}`: Only valid if `from_regex` is set. This will be replaced by a named capture
+    ///   group like `(?<name>.*)` of the regular expression. The variables above take precedence
+    ///   over capture groups with the same name.
     pub to: VariableInterpolation,
 
-    /// Rewriting type, one of `internal` (default), `redirect` or `permanent`
+    /// Rewriting type, one of `internal` (default), `redirect`, `permanent`, `found` or `moved`
     pub r#type: RewriteType,
 }
 
@@ -301,6 +323,19 @@ mod tests {
         );
     }
 
+    #[test]
+    fn regex_captures() {
+        let regex_match = RegexMatch::try_from("^/old/(.*)$").unwrap();
+        let captures = regex_match.captures("/old/file.txt").unwrap();
+        assert_eq!(&captures[0], "/old/file.txt");
+        assert_eq!(&captures[1], "file.txt");
+        assert!(regex_match.captures("/new/file.txt").is_none());
+
+        let regex_match = RegexMatch::try_from("!^/old/(.*)$").unwrap();
+        assert!(regex_match.captures("/old/file.txt").is_none());
+        assert!(regex_match.captures("/new/file.txt").is_none());
+    }
+
     #[test]
     fn regex_match() {
         let regex_match = RegexMatch::try_from("abc").unwrap();
@@ -22,6 +22,7 @@ use pandora_module_utils::pingora::{Error, SessionWrapper};
 use pandora_module_utils::router::{Path, Router};
 use pandora_module_utils::standard_response::redirect_response;
 use pandora_module_utils::{RequestFilter, RequestFilterResult};
+use regex::Captures;
 
 use crate::configuration::{RegexMatch, RewriteConf, RewriteType, VariableInterpolation};
 
@@ -72,6 +73,30 @@ impl TryFrom<RewriteConf> for RewriteHandler {
     }
 }
 
+/// Looks up a capture group by its index or name. Groups that didn't participate in the match
+/// resolve to an empty value.
+fn capture_group<'a>(
+    regex_match: &RegexMatch,
+    captures: &Captures<'a>,
+    name: &str,
+) -> Option<&'a [u8]> {
+    let index = name.parse::<usize>().ok().or_else(|| {
+        regex_match
+            .regex
+            .capture_names()
+            .position(|group| group == Some(name))
+    })?;
+    if index < captures.len() {
+        Some(
+            captures
+                .get(index)
+                .map_or(&b""[..], |group| group.as_str().as_bytes()),
+        )
+    } else {
+        None
+    }
+}
+
 #[async_trait]
 impl RequestFilter for RewriteHandler {
     type Conf = RewriteConf;
@@ -99,11 +124,14 @@ impl RequestFilter for RewriteHandler {
 
         // Iterate in reverse order, merging puts rules in reverse order of precedence.
         for (rule_path, rule) in list.iter().rev() {
-            if let Some(from_regex) = &rule.from_regex {
-                if !from_regex.matches(session.uri().path()) {
+            let captures = if let Some(from_regex) = &rule.from_regex {
+                if !from_regex.matches(path) {
                     continue;
                 }
-            }
+                from_regex.captures(path)
+            } else {
+                None
+            };
 
             if let Some(query_regex) = &rule.query_regex {
                 if !query_regex.matches(session.uri().query().unwrap_or("")) {
@@ -133,7 +161,7 @@ impl RequestFilter for RewriteHandler {
                                 .unwrap_or(b""),
                         )
                     } else {
-                        None
+                        capture_group(rule.from_regex.as_ref()?, captures.as_ref()?, name)
                     }
                 }
             });
@@ -150,7 +178,10 @@ impl RequestFilter for RewriteHandler {
                     session.set_uri(uri);
                     break;
                 }
-                RewriteType::Redirect | RewriteType::Permanent => {
+                RewriteType::Redirect
+                | RewriteType::Permanent
+                | RewriteType::Found
+                | RewriteType::Moved => {
                     let location = match String::from_utf8(target) {
                         Ok(location) => location,
                         Err(err) => {
@@ -158,10 +189,11 @@ impl RequestFilter for RewriteHandler {
                             return Ok(RequestFilterResult::Unhandled);
                         }
                     };
-                    let status = if rule.r#type == RewriteType::Redirect {
-                        StatusCode::TEMPORARY_REDIRECT
-                    } else {
-                        StatusCode::PERMANENT_REDIRECT
+                    let status = match rule.r#type {
+                        RewriteType::Permanent => StatusCode::PERMANENT_REDIRECT,
+                        RewriteType::Found => StatusCode::FOUND,
+                        RewriteType::Moved => StatusCode::MOVED_PERMANENTLY,
+                        _ => StatusCode::TEMPORARY_REDIRECT,
                     };
                     redirect_response(session, status, &location).await?;
                     return Ok(RequestFilterResult::ResponseSent);
@@ -435,6 +467,151 @@ mod tests {
         Ok(())
     }
 
+    #[test(tokio::test)]
+    async fn capture_groups() -> Result<(), Box<Error>> {
+        let handler = make_handler(
+            r#"
+                rewrite_rules:
+                -
+                    from: /old/*
+                    from_regex: "^/old/(.*)$"
+                    to: /new/${1}?${query}
+                -
+                    from: /user/*
+                    from_regex: "^/user/(?<name>[^/]+)(/(?<page>.*))?$"
+                    to: /profile?user=${name}&page=${page}&${2}
+                -
+                    from: /moved/*
+                    from_regex: "^/moved/(.*)\\.html$"
+                    to: https://example.com/${1}/
+                    type: moved
+                -
+                    from: /not-html/*
+                    from_regex: "!^/not-html/(.*)\\.html$"
+                    to: /plain${tail}${1}${name}
+                    type: redirect
+            "#,
+        );
+
+        let mut session = make_session("/old/dir/file.txt?a=b").await;
+        assert_eq!(
+            handler
+                .request_filter(&mut session, &mut RewriteHandler::new_ctx())
+                .await?,
+            RequestFilterResult::Unhandled
+        );
+        assert_eq!(session.uri(), "/new/dir/file.txt?a=b");
+        assert_eq!(session.original_uri(), "/old/dir/file.txt?a=b");
+
+        let mut session = make_session("/user/me/settings").await;
+        assert_eq!(
+            handler
+                .request_filter(&mut session, &mut RewriteHandler::new_ctx())
+                .await?,
+            RequestFilterResult::Unhandled
+        );
+        assert_eq!(session.uri(), "/profile?user=me&page=settings&/settings");
+
+        let mut session = make_session("/user/me").await;
+        assert_eq!(
+            handler
+                .request_filter(&mut session, &mut RewriteHandler::new_ctx())
+                .await?,
+            RequestFilterResult::Unhandled
+        );
+        assert_eq!(session.uri(), "/profile?user=me&page=&");
+
+        let mut session = make_session("/moved/dir/page.html").await;
+        assert_eq!(
+            handler
+                .request_filter(&mut session, &mut RewriteHandler::new_ctx())
+                .await?,
+            RequestFilterResult::ResponseSent
+        );
+        assert_eq!(
+            session.response_written().map(|r| r.status),
+            Some(StatusCode::MOVED_PERMANENTLY)
+        );
+        assert_eq!(
+            session
+                .response_written()
+                .and_then(|r| r.headers.get("Location"))
+                .map(|h| h.to_str().unwrap()),
+            Some("https://example.com/dir/page/")
+        );
+
+        let mut session = make_session("/moved/dir/page.txt").await;
+        assert_eq!(
+            handler
+                .request_filter(&mut session, &mut RewriteHandler::new_ctx())
+                .await?,
+            RequestFilterResult::Unhandled
+        );
+        assert_eq!(session.uri(), "/moved/dir/page.txt");
+        assert!(session.response_written().is_none());
+
+        // Negated regular expressions don't produce capture groups
+        let mut session = make_session("/not-html/file.txt").await;
+        assert_eq!(
+            handler
+                .request_filter(&mut session, &mut RewriteHandler::new_ctx())
+                .await?,
+            RequestFilterResult::ResponseSent
+        );
+        assert_eq!(
+            session
+                .response_written()
+                .and_then(|r| r.headers.get("Location"))
+                .map(|h| h.to_str().unwrap()),
+            Some("/plain/file.txt${1}${name}")
+        );
+
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn redirect_types() -> Result<(), Box<Error>> {
+        let handler = make_handler(
+            r#"
+                rewrite_rules:
+                -
+                    from: /redirect
+                    to: /target
+                    type: redirect
+                -
+                    from: /permanent
+                    to: /target
+                    type: permanent
+                -
+                    from: /found
+                    to: /target
+                    type: found
+                -
+                    from: /moved
+                    to: /target
+                    type: moved
+            "#,
+        );
+
+        for (path, status) in [
+            ("/redirect", StatusCode::TEMPORARY_REDIRECT),
+            ("/permanent", StatusCode::PERMANENT_REDIRECT),
+            ("/found", StatusCode::FOUND),
+            ("/moved", StatusCode::MOVED_PERMANENTLY),
+        ] {
+            let mut session = make_session(path).await;
+            assert_eq!(
+                handler
+                    .request_filter(&mut session, &mut RewriteHandler::new_ctx())
+                    .await?,
+                RequestFilterResult::ResponseSent
+            );
+            assert_eq!(session.response_written().map(|r| r.status), Some(status));
+        }
+
+        Ok(())
+    }
+
     #[test(tokio::test)]
     async fn rule_order() -> Result<(), Box<Error>> {
         let handler = make_handler(
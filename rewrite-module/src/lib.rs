@@ -31,6 +31,10 @@
 //!   from_regex: "\\.jpg$"
 //!   to: https://example.com${tail}
 //!   type: redirect
+//! - from: /old/*
+//!   from_regex: "^/old/(.*)\\.html$"
+//!   to: /new/${1}/
+//!   type: moved
 //! ```
 //!
 //! ## Rewrite rules
@@ -50,8 +54,14 @@
 //!   * `${query}`: The original query string
 //!   * `${http_<header>}`: The value of an HTTP header, e.g. `${http_host}` will be replaced by
 //!     the value of the `Host` header
+//!   * `${1}`, `${2}` etc.: The value of a capture group of the `from_regex` regular expression,
+//!     `${0}` being the entire match
+//!   * `${<name>}`: The value of a named capture group like `(?<name>.*)` of the `from_regex`
+//!     regular expression
 //! * `type` is the rewrite type, one of `internal` (default, internal redirect), `redirect`
-//!   (temporary redirect) or `permanent` (permanent redirect)
+//!   (307 temporary redirect), `permanent` (308 permanent redirect), `found` (302 temporary
+//!   redirect) or `moved` (301 permanent redirect). Unlike `redirect` and `permanent`, the
+//!   latter two allow clients to change the request method to `GET`.
 //!
 //! If multiple rules potentially apply to a particular request, the rule with the longer path in
 //! the `from` field is applied. If multiple rules with the same path in `from` exist, exact
@@ -93,7 +103,9 @@
 //! let mut conf = Conf::load_from_files(opt.startup.conf.as_deref().unwrap_or(&[])).unwrap();
 //! conf.handler.static_files.merge_with_opt(opt.static_files);
 //!
-//! let app = DefaultApp::<Handler>::from_conf(conf.handler).unwrap();
+//! let app = DefaultApp::<Handler>::from_conf(conf.handler)
+//!     .unwrap()
+//!     .with_startup_conf(&conf.startup);
 //! let server = conf.startup.into_server(app, Some(opt.startup)).unwrap();
 //!
 //! // Do something with the server here, e.g. call server.run_forever()
@@ -14,29 +14,81 @@
 
 use async_trait::async_trait;
 use clap::Parser;
+use env_logger::{Builder, Target};
+use http::{header, HeaderName, HeaderValue};
+use log::{debug, LevelFilter};
 use pandora_module_utils::pingora::{
-    http_proxy_service, Error, ErrorType, ProxyHttp, Server, ServerConf, ServerOpt,
+    http_proxy_service, Error, ProxyHttp, ResponseHeader, Server, ServerConf, ServerOpt,
 };
 use pandora_module_utils::{DeserializeMap, OneOrMany};
 use pingora::listeners::{TcpSocketOptions, TlsAccept, TlsSettings};
+#[cfg(unix)]
+use pingora::services::background::background_service;
 use pingora::services::Service;
 use pingora::tls::ext::ssl_add_chain_cert;
 use pingora::tls::{
     ext::{ssl_use_certificate, ssl_use_private_key},
     pkey::PKey,
-    ssl::{NameType, SslRef},
+    ssl::{select_next_proto, AlpnError, NameType, SslAcceptorBuilder, SslRef},
     x509::X509,
 };
 use pingora::utils::CertKey;
-use serde::de::{Deserialize, Deserializer, MapAccess, Visitor};
+use serde::de::{Deserializer, MapAccess, Unexpected, Visitor};
+use serde::Deserialize;
 use std::collections::HashMap;
-use std::fs::read;
+use std::fs::{read, OpenOptions};
+use std::net::{SocketAddr, ToSocketAddrs};
 use std::path::{Path, PathBuf};
+use std::str::FromStr;
 use std::sync::Arc;
+#[cfg(unix)]
+use tokio::sync::watch;
 
+use crate::error::{StartupError, TLS_CONF_ERR};
+#[cfg(unix)]
+use crate::privileges::{Privileges, PrivilegesGate, PrivilegesService};
 use crate::redirector::create_redirector;
 
-pub(crate) const TLS_CONF_ERR: ErrorType = ErrorType::Custom("TLSConfigError");
+fn validate_positive<T>(setting: &str, value: Option<T>) -> Result<(), StartupError>
+where
+    T: Default + PartialEq,
+{
+    if value == Some(T::default()) {
+        Err(StartupError::InvalidConfig(format!(
+            "{setting} setting has to be at least 1"
+        )))
+    } else {
+        Ok(())
+    }
+}
+
+fn deserialize_level<'de, D>(deserializer: D) -> Result<Option<LevelFilter>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    use serde::de::Error as _;
+
+    let level = String::deserialize(deserializer)?;
+    let level = LevelFilter::from_str(&level).map_err(|_| {
+        D::Error::invalid_value(
+            Unexpected::Str(&level),
+            &"one of off, error, warn, info, debug, trace",
+        )
+    })?;
+    Ok(Some(level))
+}
+
+fn deserialize_header_name<'de, D>(deserializer: D) -> Result<Option<HeaderName>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    use serde::de::Error as _;
+
+    let name = String::deserialize(deserializer)?;
+    let name = HeaderName::from_str(&name)
+        .map_err(|_| D::Error::invalid_value(Unexpected::Str(&name), &"a valid header name"))?;
+    Ok(Some(name))
+}
 
 /// Run a web server
 #[derive(Debug, Default, Parser)]
@@ -57,10 +109,34 @@ pub struct StartupOpt {
     pub conf: Option<Vec<String>>,
 }
 
+/// HTTP protocol version a listening address can accept
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+pub enum HttpVersion {
+    /// HTTP/1.x, configured as `http/1.1`
+    #[serde(rename = "http/1.1")]
+    Http1,
+    /// HTTP/2, configured as `h2`, only available with TLS
+    #[serde(rename = "h2")]
+    Http2,
+}
+
+impl HttpVersion {
+    /// Returns the protocol identifier used in TLS Application-Layer Protocol Negotiation (ALPN).
+    fn alpn_id(&self) -> &'static [u8] {
+        match self {
+            Self::Http1 => b"http/1.1",
+            Self::Http2 => b"h2",
+        }
+    }
+}
+
 /// Address for the server to listen on
 #[derive(Debug, Default, Clone, PartialEq, Eq)]
 pub struct ListenAddr {
     /// IP address and port combination, e.g. `127.0.0.1:8080` or `[::1]:8080`
+    ///
+    /// A host name can be used instead of the IP address, e.g. `localhost:8080`. It will be
+    /// resolved once at startup, and the server will listen on all resulting IP addresses.
     pub addr: String,
 
     /// If `true`, TLS will be enabled for this address.
@@ -73,9 +149,112 @@ pub struct ListenAddr {
     /// If set, the IPV6_V6ONLY flag will be set accordingly for the socket. Otherwise the system
     /// default will be used.
     pub ipv6_only: Option<bool>,
+
+    /// Server name to choose the certificate for if the client doesn’t send a server name
+    /// indicator (SNI)
+    ///
+    /// This has to be one of the names listed under `server_names` in TLS configuration. If not
+    /// set, the default certificate will be used for such clients.
+    pub default_server_name: Option<String>,
+
+    /// HTTP versions accepted on this address in the order of preference, e.g. `[h2, http/1.1]`
+    ///
+    /// If empty, Pingora’s default applies: only HTTP/1.x is accepted. HTTP/2 can only be enabled
+    /// for TLS addresses.
+    pub versions: OneOrMany<HttpVersion>,
 }
 
 impl ListenAddr {
+    /// Resolves the address into IP address/port combinations
+    ///
+    /// Addresses containing an IP address are returned unchanged. For addresses containing a host
+    /// name, one entry is returned for each IP address the host name resolves to. Resolving
+    /// fails if the host name is unknown or the address doesn’t contain a port.
+    pub fn resolve(&self) -> Result<Vec<Self>, StartupError> {
+        if self.addr.parse::<SocketAddr>().is_ok() {
+            return Ok(vec![self.clone()]);
+        }
+
+        let addrs = self
+            .addr
+            .to_socket_addrs()
+            .map_err(|err| self.error(format!("failed resolving: {err}")))?;
+
+        let mut result: Vec<Self> = Vec::new();
+        for addr in addrs {
+            let addr = addr.to_string();
+            if !result.iter().any(|existing| existing.addr == addr) {
+                result.push(Self {
+                    addr,
+                    ..self.clone()
+                });
+            }
+        }
+
+        if result.is_empty() {
+            Err(self.error("didn’t resolve to any IP addresses"))
+        } else {
+            debug!("resolved listen address {} into {result:?}", self.addr);
+            Ok(result)
+        }
+    }
+
+    fn error(&self, reason: impl Into<String>) -> StartupError {
+        StartupError::InvalidListenAddr {
+            addr: self.addr.clone(),
+            reason: reason.into(),
+        }
+    }
+
+    fn resolve_all(list: OneOrMany<Self>) -> Result<OneOrMany<Self>, StartupError> {
+        let mut result = Vec::with_capacity(list.len());
+        for addr in &list {
+            result.extend(addr.resolve()?);
+        }
+        Ok(result.into())
+    }
+
+    /// Produces the list of allowed protocols in ALPN wire format, `None` if the default
+    /// should be used.
+    fn alpn_protocols(&self) -> Result<Option<Vec<u8>>, StartupError> {
+        if self.versions.is_empty() {
+            return Ok(None);
+        }
+
+        // Pingora only supports HTTP/2 negotiated via TLS, not h2c
+        if !self.tls && self.versions.contains(&HttpVersion::Http2) {
+            return Err(self.error("HTTP/2 requires TLS and cannot be enabled"));
+        }
+
+        let mut protocols = Vec::new();
+        for version in &self.versions {
+            let id = version.alpn_id();
+            protocols.push(id.len() as u8);
+            protocols.extend_from_slice(id);
+        }
+        Ok(Some(protocols))
+    }
+
+    /// Validates the `versions` setting for a plaintext address. No protocol negotiation happens
+    /// here, only HTTP/1.x is possible.
+    pub(crate) fn validate_versions(&self) -> Result<(), StartupError> {
+        self.alpn_protocols().map(|_| ())
+    }
+
+    /// Restricts the protocols negotiated during TLS handshake according to the `versions`
+    /// setting.
+    ///
+    /// Unlike Pingora’s own ALPN handling, the handshake fails if the client offers none of the
+    /// allowed protocols. Clients not using ALPN at all are always handled as HTTP/1.x however.
+    pub(crate) fn set_alpn(&self, builder: &mut SslAcceptorBuilder) -> Result<(), StartupError> {
+        if let Some(protocols) = self.alpn_protocols()? {
+            builder.set_alpn_select_callback(move |_ssl, offered| {
+                select_next_proto(&protocols, offered).ok_or(AlpnError::ALERT_FATAL)
+            });
+        }
+        Ok(())
+    }
+
     pub(crate) fn to_socket_options(&self) -> Option<TcpSocketOptions> {
         self.ipv6_only
             .map(|ipv6_only| TcpSocketOptions { ipv6_only })
@@ -88,6 +267,8 @@ impl From<String> for ListenAddr {
             addr: value,
             tls: false,
             ipv6_only: None,
+            default_server_name: None,
+            versions: Default::default(),
         }
     }
 }
@@ -140,12 +321,16 @@ impl<'de> Deserialize<'de> for ListenAddr {
                 use serde::de::Error as _;
 
                 const ADDR_FIELD: &str = "addr";
+                const DEFAULT_SERVER_NAME_FIELD: &str = "default_server_name";
                 const IPV6_ONLY_FIELD: &str = "ipv6_only";
                 const TLS_FIELD: &str = "tls";
+                const VERSIONS_FIELD: &str = "versions";
 
                 let mut addr = None;
                 let mut tls = None;
                 let mut ipv6_only = None;
+                let mut default_server_name = None;
+                let mut versions = None;
                 while let Some(key) = map.next_key::<String>()? {
                     match key.as_str() {
                         ADDR_FIELD => {
@@ -154,6 +339,12 @@ impl<'de> Deserialize<'de> for ListenAddr {
                             }
                             addr = Some(map.next_value()?);
                         }
+                        DEFAULT_SERVER_NAME_FIELD => {
+                            if default_server_name.is_some() {
+                                return Err(A::Error::duplicate_field(DEFAULT_SERVER_NAME_FIELD));
+                            }
+                            default_server_name = Some(map.next_value()?);
+                        }
                         IPV6_ONLY_FIELD => {
                             if ipv6_only.is_some() {
                                 return Err(A::Error::duplicate_field(IPV6_ONLY_FIELD));
@@ -166,10 +357,22 @@ impl<'de> Deserialize<'de> for ListenAddr {
                             }
                             tls = Some(map.next_value()?);
                         }
+                        VERSIONS_FIELD => {
+                            if versions.is_some() {
+                                return Err(A::Error::duplicate_field(VERSIONS_FIELD));
+                            }
+                            versions = Some(map.next_value()?);
+                        }
                         other => {
                             return Err(A::Error::unknown_field(
                                 other,
-                                &[ADDR_FIELD, IPV6_ONLY_FIELD, TLS_FIELD],
+                                &[
+                                    ADDR_FIELD,
+                                    DEFAULT_SERVER_NAME_FIELD,
+                                    IPV6_ONLY_FIELD,
+                                    TLS_FIELD,
+                                    VERSIONS_FIELD,
+                                ],
                             ))
                         }
                     }
@@ -181,6 +384,8 @@ impl<'de> Deserialize<'de> for ListenAddr {
                         addr,
                         ipv6_only,
                         tls,
+                        default_server_name,
+                        versions: versions.unwrap_or_default(),
                     })
                 } else {
                     Err(A::Error::missing_field(ADDR_FIELD))
@@ -204,22 +409,30 @@ pub struct CertKeyConf {
 }
 
 impl CertKeyConf {
-    fn read_file<P: AsRef<Path>>(path: P) -> Result<Vec<u8>, Box<Error>> {
-        let path = path.as_ref();
-        read(path).map_err(|err| {
-            Error::because(
-                TLS_CONF_ERR,
-                format!("failed reading file {}", path.display()),
-                err,
-            )
+    fn read_file(path: &Path) -> Result<Vec<u8>, StartupError> {
+        read(path).map_err(|err| StartupError::InvalidCertificate {
+            path: path.to_path_buf(),
+            reason: format!("failed reading file: {err}"),
         })
     }
 
-    fn into_certificate(self) -> Result<CertKey, Box<Error>> {
+    /// Converts the certificate/key combinations configured for a particular purpose, `name`
+    /// describes it in error messages.
+    fn into_certificates(list: OneOrMany<Self>, name: &str) -> Result<Vec<CertKey>, StartupError> {
+        if list.is_empty() {
+            return Err(StartupError::MissingCertificate(name.to_owned()));
+        }
+
+        list.into_iter()
+            .map(|conf| conf.into_certificate(name))
+            .collect()
+    }
+
+    fn into_certificate(self, name: &str) -> Result<CertKey, StartupError> {
         if let (Some(cert_path), Some(key_path)) = (self.cert_path, self.key_path) {
             const END_MARKER: &[u8] = b"-----END CERTIFICATE-----";
             let mut certs = Vec::new();
-            let cert_data = Self::read_file(cert_path)?;
+            let cert_data = Self::read_file(&cert_path)?;
             let mut start = 0;
             while start != cert_data.len() {
                 if cert_data[start..].iter().all(|b| b.is_ascii_whitespace()) {
@@ -232,27 +445,32 @@ impl CertKeyConf {
                     .map(|pos| start + pos + END_MARKER.len())
                     .unwrap_or(cert_data.len());
                 certs.push(X509::from_pem(&cert_data[start..end]).map_err(|err| {
-                    Error::because(TLS_CONF_ERR, "failed parsing certificate", err)
+                    StartupError::InvalidCertificate {
+                        path: cert_path.clone(),
+                        reason: format!("failed parsing certificate: {err}"),
+                    }
                 })?);
                 start = end;
             }
 
             if certs.is_empty() {
-                return Err(Error::explain(
-                    TLS_CONF_ERR,
-                    "certificate chain shouldn't be empty",
-                ));
+                return Err(StartupError::InvalidCertificate {
+                    path: cert_path,
+                    reason: "certificate chain shouldn't be empty".to_owned(),
+                });
             }
 
-            let key = PKey::private_key_from_pem(&Self::read_file(key_path)?)
-                .map_err(|err| Error::because(TLS_CONF_ERR, "failed parsing private key", err))?;
+            let key = PKey::private_key_from_pem(&Self::read_file(&key_path)?).map_err(|err| {
+                StartupError::InvalidCertificate {
+                    path: key_path,
+                    reason: format!("failed parsing private key: {err}"),
+                }
+            })?;
 
             Ok(CertKey::new(certs, key))
         } else {
-            Err(Error::explain(
-                TLS_CONF_ERR,
-                "both `cert_path` and `key_path` settings must be present",
-            ))
+            // Both `cert_path` and `key_path` settings must be present
+            Err(StartupError::MissingCertificate(name.to_owned()))
         }
     }
 }
@@ -276,17 +494,29 @@ pub struct TlsRedirectorConf {
     /// If the requested name is not found in the list or the request didn’t contain a server name,
     /// the default redirect target will be used.
     pub redirect_by_name: HashMap<String, String>,
+
+    /// Number of worker threads for the redirector service
+    ///
+    /// If not set, the top-level `threads` setting applies.
+    pub threads: Option<usize>,
 }
 
 impl TlsRedirectorConf {
     fn to_redirector(
         &self,
         server_conf: &Arc<ServerConf>,
-    ) -> Result<Option<impl Service + 'static>, Box<Error>> {
+        #[cfg(unix)] privileges_dropped: Option<watch::Receiver<bool>>,
+    ) -> Result<Option<impl Service + 'static>, StartupError> {
         if self.listen.is_empty() {
             Ok(None)
         } else {
-            create_redirector(self, server_conf).map(Some)
+            create_redirector(
+                self,
+                server_conf,
+                #[cfg(unix)]
+                privileges_dropped,
+            )
+            .map(Some)
         }
     }
 }
@@ -298,51 +528,106 @@ pub struct TlsConf {
     #[pandora(flatten)]
     pub default: CertKeyConf,
 
+    /// Additional default certificate/key combinations
+    ///
+    /// This allows using certificates with different key types, e.g. ECDSA and RSA. The TLS
+    /// implementation will choose the certificate supported by the client.
+    pub additional_certificates: OneOrMany<CertKeyConf>,
+
     /// Certificate/key combinations for particular server names
-    pub server_names: HashMap<String, CertKeyConf>,
+    ///
+    /// Multiple certificate/key combinations with different key types can be listed for a server
+    /// name.
+    pub server_names: HashMap<String, OneOrMany<CertKeyConf>>,
 
     /// HTTP to HTTPS redirector settings
     pub redirector: TlsRedirectorConf,
 }
 
 impl TlsConf {
-    fn into_callbacks(self) -> Result<TlsAcceptCallbacks, Box<Error>> {
+    /// Adds certificate/key combinations for server names declared elsewhere, e.g. in virtual
+    /// host configuration.
+    ///
+    /// Fails if a server name is listed under `server_names` already with a different
+    /// configuration.
+    pub fn add_server_names(
+        &mut self,
+        server_names: impl IntoIterator<Item = (String, OneOrMany<CertKeyConf>)>,
+    ) -> Result<(), Box<Error>> {
+        for (name, conf) in server_names {
+            match self.server_names.get(&name) {
+                Some(existing) if *existing != conf => {
+                    return Err(Error::explain(
+                        TLS_CONF_ERR,
+                        format!("conflicting certificate/key configuration for server name {name}"),
+                    ));
+                }
+                Some(_) => {}
+                None => {
+                    self.server_names.insert(name, conf);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn into_callbacks(self) -> Result<TlsAcceptCallbacks, StartupError> {
         let mut certificates = HashMap::with_capacity(self.server_names.len() + 1);
         for (name, conf) in self.server_names.into_iter() {
-            let cert = conf.into_certificate().map_err(|err| {
-                Error::because(
-                    TLS_CONF_ERR,
-                    format!("failed setting up certificate/key for server name {name}"),
-                    err,
-                )
-            })?;
-            certificates.insert(name, cert);
+            let certs = CertKeyConf::into_certificates(conf, &format!("server name {name}"))?;
+            certificates.insert(name, certs);
         }
-        let cert = self.default.into_certificate().map_err(|err| {
-            Error::because(
-                TLS_CONF_ERR,
-                "failed setting up default certificate/key",
-                err,
-            )
-        })?;
-        certificates.insert(String::new(), cert);
-        Ok(TlsAcceptCallbacks { certificates })
+
+        let mut default = self.additional_certificates;
+        default.insert(0, self.default);
+        let certs = CertKeyConf::into_certificates(default, "default server")?;
+        certificates.insert(String::new(), certs);
+        Ok(TlsAcceptCallbacks {
+            certificates,
+            default_server_name: None,
+        })
     }
 }
 
 #[derive(Debug, Clone)]
 struct TlsAcceptCallbacks {
-    certificates: HashMap<String, CertKey>,
+    certificates: HashMap<String, Vec<CertKey>>,
+    default_server_name: Option<String>,
+}
+
+impl TlsAcceptCallbacks {
+    /// Produces callbacks for a particular listening address, taking its `default_server_name`
+    /// setting into account.
+    fn for_listener(&self, addr: &ListenAddr) -> Result<Self, StartupError> {
+        if let Some(name) = &addr.default_server_name {
+            if name.is_empty() || !self.certificates.contains_key(name) {
+                return Err(StartupError::MissingCertificate(format!(
+                    "default server name {name} of listen address {}",
+                    addr.addr
+                )));
+            }
+        }
+
+        Ok(Self {
+            certificates: self.certificates.clone(),
+            default_server_name: addr.default_server_name.clone(),
+        })
+    }
 }
 
 #[async_trait]
 impl TlsAccept for TlsAcceptCallbacks {
     async fn certificate_callback(&self, ssl: &mut SslRef) {
-        let cert = ssl
+        let name = ssl
             .servername(NameType::HOST_NAME)
+            .or(self.default_server_name.as_deref());
+        let certs = name
             .and_then(|name| self.certificates.get(name))
             .or_else(|| self.certificates.get(""));
-        if let Some(cert) = cert {
+
+        // OpenSSL keeps one certificate per key type, setting multiple certificates with different
+        // key types lets it choose the one supported by the client.
+        for cert in certs.into_iter().flatten() {
             // Errors are unexpected here, these should only occur if a certificate has been set
             // already or private key and certificate don’t match. Ok to panic then.
             ssl_use_certificate(ssl, cert.leaf()).unwrap();
@@ -354,7 +639,181 @@ impl TlsAccept for TlsAcceptCallbacks {
     }
 }
 
+/// Logging settings for the server’s own diagnostic messages
+#[derive(Debug, Default, Clone, PartialEq, Eq, DeserializeMap)]
+pub struct LogConf {
+    /// Maximal level of messages to be logged: `off`, `error`, `warn`, `info`, `debug` or `trace`
+    ///
+    /// If not set, only errors will be logged. The `RUST_LOG` environment variable takes
+    /// precedence over this setting.
+    #[pandora(deserialize_with = "deserialize_level")]
+    pub level: Option<LevelFilter>,
+
+    /// File to append log messages to
+    ///
+    /// If not set, log messages will be written to stderr.
+    pub file: Option<PathBuf>,
+}
+
+impl LogConf {
+    /// Checks whether any logging settings are present
+    ///
+    /// If this returns `false`, [`StartupConf::into_server`] won’t install a logger.
+    pub fn is_configured(&self) -> bool {
+        self.level.is_some() || self.file.is_some()
+    }
+
+    fn level(&self) -> LevelFilter {
+        self.level.unwrap_or(LevelFilter::Error)
+    }
+
+    fn target(&self) -> Result<Target, StartupError> {
+        if let Some(path) = &self.file {
+            let file = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)
+                .map_err(|err| {
+                    StartupError::InvalidConfig(format!(
+                        "failed opening log file {}: {err}",
+                        path.display()
+                    ))
+                })?;
+            Ok(Target::Pipe(Box::new(file)))
+        } else {
+            Ok(Target::Stderr)
+        }
+    }
+
+    fn init_logger(&self) -> Result<(), StartupError> {
+        if !self.is_configured() {
+            return Ok(());
+        }
+
+        let mut builder = Builder::new();
+        builder.filter_level(self.level());
+        if let Ok(filters) = std::env::var("RUST_LOG") {
+            builder.parse_filters(&filters);
+        }
+        builder.target(self.target()?);
+
+        if builder.try_init().is_err() {
+            debug!("a logger is installed already, ignoring log settings");
+        }
+        Ok(())
+    }
+}
+
+/// Handling of the `Server` response header
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub enum ServerHeader {
+    /// The header is sent the way handlers or the upstream server produced it
+    #[default]
+    Preserve,
+    /// The header is removed from all responses, configured as `none`
+    Remove,
+    /// The header is set to the given value in all responses
+    Value(HeaderValue),
+}
+
+impl ServerHeader {
+    /// Sets or removes the `Server` header in the response.
+    pub fn apply(&self, response: &mut ResponseHeader) {
+        match self {
+            Self::Preserve => {}
+            Self::Remove => {
+                response.remove_header(&header::SERVER);
+            }
+            Self::Value(value) => {
+                // Header value is validated, this won’t fail
+                let _ = response.insert_header(header::SERVER, value.clone());
+            }
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for ServerHeader {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        use serde::de::Error as _;
+
+        let value = String::deserialize(deserializer)?;
+        if value == "none" {
+            Ok(Self::Remove)
+        } else {
+            HeaderValue::from_str(&value).map(Self::Value).map_err(|_| {
+                D::Error::invalid_value(Unexpected::Str(&value), &"a valid header value")
+            })
+        }
+    }
+}
+
+/// Letter case of header names in HTTP/1.x responses
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum HeaderCase {
+    /// Header names are sent the way handlers or the upstream server produced them
+    #[default]
+    Preserve,
+    /// Header names are sent title-cased, e.g. `Content-Type` or `X-My-Header`
+    Title,
+    /// Header names are sent in lower case, e.g. `content-type` or `x-my-header`
+    Lower,
+}
+
+impl HeaderCase {
+    fn convert(&self, name: &str) -> String {
+        match self {
+            Self::Preserve => name.to_owned(),
+            Self::Title => {
+                let mut capitalize = true;
+                name.chars()
+                    .map(|c| {
+                        let result = if capitalize {
+                            c.to_ascii_uppercase()
+                        } else {
+                            c.to_ascii_lowercase()
+                        };
+                        capitalize = c == '-';
+                        result
+                    })
+                    .collect()
+            }
+            Self::Lower => name.to_ascii_lowercase(),
+        }
+    }
+
+    /// Changes the case of all header names in the response.
+    ///
+    /// Header order is kept, multiple values of the same header stay together. This only affects
+    /// HTTP/1.x responses, HTTP/2 header names are always lower case.
+    pub fn apply(&self, response: &mut ResponseHeader) {
+        if *self == Self::Preserve {
+            return;
+        }
+
+        let headers = response
+            .headers
+            .iter()
+            .map(|(name, value)| (name.clone(), value.clone()))
+            .collect::<Vec<_>>();
+        for (name, _) in &headers {
+            response.remove_header(name);
+        }
+        for (name, value) in headers {
+            // Changing case of a valid header name produces a valid header name, this won’t fail
+            let _ = response.append_header(self.convert(name.as_str()), value);
+        }
+    }
+}
+
 /// Configuration settings of the startup module
+///
+/// Settings affecting request processing like `header_case` aren’t applied by the server itself,
+/// these have to be passed on to the app via
+/// [`DefaultApp::with_startup_conf`](crate::DefaultApp::with_startup_conf).
 #[derive(Debug, Default, PartialEq, Eq, DeserializeMap)]
 pub struct StartupConf {
     /// List of address/port combinations to listen on, e.g. "127.0.0.1:8080"
@@ -363,6 +822,73 @@ pub struct StartupConf {
     /// TLS configuration for the server
     pub tls: TlsConf,
 
+    /// Number of worker threads for each service
+    ///
+    /// Each service gets its own threads, these aren’t shared. If not set, Pingora’s default of
+    /// one thread per service applies.
+    pub threads: Option<usize>,
+
+    /// Determines whether threads of the same service can steal work from each other
+    ///
+    /// If not set, Pingora’s default (work stealing enabled) applies.
+    pub work_stealing: Option<bool>,
+
+    /// User name or ID to switch to once the server is listening (Unix only)
+    ///
+    /// This allows binding to privileged ports as root without running the server with root
+    /// privileges.
+    pub user: Option<String>,
+
+    /// Group name or ID to switch to once the server is listening (Unix only)
+    ///
+    /// If not set but `user` is set, the primary group of the user will be used.
+    pub group: Option<String>,
+
+    /// Logging settings for the server’s own diagnostic messages
+    ///
+    /// These don’t affect access logs.
+    pub log: LogConf,
+
+    /// Letter case of header names in HTTP/1.x responses: `preserve`, `title` or `lower`
+    pub header_case: HeaderCase,
+
+    /// Name of the request ID header, e.g. `X-Request-Id`
+    ///
+    /// If set, each request gets an ID which is sent to the upstream server and returned with the
+    /// response. A request ID sent by the client is reused.
+    #[pandora(deserialize_with = "deserialize_header_name")]
+    pub request_id_header: Option<HeaderName>,
+
+    /// Value of the `Server` response header, `none` to remove it from responses
+    ///
+    /// If not set, the header is sent as produced by handlers or the upstream server.
+    pub server_header: ServerHeader,
+
+    /// If `true`, requests with a malformed `Host` header are passed on to the handler rather than
+    /// rejected with `400 Bad Request`
+    pub allow_invalid_host: bool,
+
+    /// Maximal length of the request URI in bytes, longer URIs are rejected with
+    /// `414 URI Too Long`
+    ///
+    /// If not set, the default limit of 8192 bytes applies.
+    pub max_uri_length: Option<usize>,
+
+    /// If `true`, `X-Forwarded-For`, `X-Forwarded-Proto` and `X-Forwarded-Host` headers are
+    /// added to requests sent to the upstream server
+    pub forwarded_headers: bool,
+
+    /// If `true`, `X-Forwarded-*` headers sent by the client are kept and the client address is
+    /// appended to `X-Forwarded-For`, otherwise these are replaced. Only enable this if the server
+    /// is behind a trusted proxy.
+    pub trust_forwarded_headers: bool,
+
+    /// Time in seconds after which clients should close idle connections
+    ///
+    /// This is only announced via the `Keep-Alive` response header, Pingora 0.2 doesn’t allow
+    /// enforcing it. If not set, no timeout is announced.
+    pub downstream_keepalive_timeout: Option<u64>,
+
     /// Pingora’s default server configuration options
     #[pandora(flatten)]
     pub server: ServerConf,
@@ -370,13 +896,87 @@ pub struct StartupConf {
 
 impl StartupConf {
     /// Sets up a server with the given configuration and command line options
-    pub fn into_server<SV>(self, app: SV, opt: Option<StartupOpt>) -> Result<Server, Box<Error>>
+    pub fn into_server<SV>(self, app: SV, opt: Option<StartupOpt>) -> Result<Server, StartupError>
+    where
+        SV: ProxyHttp + Send + Sync + 'static,
+        <SV as ProxyHttp>::CTX: Send + Sync,
+    {
+        self.into_server_with_services(app, opt, Vec::new())
+    }
+
+    /// Sets up a server like [`StartupConf::into_server`] and registers additional services with
+    /// it, e.g. background services created via `background_service()`
+    pub fn into_server_with_services<SV>(
+        self,
+        app: SV,
+        opt: Option<StartupOpt>,
+        services: Vec<Box<dyn Service>>,
+    ) -> Result<Server, StartupError>
+    where
+        SV: ProxyHttp + Send + Sync + 'static,
+        <SV as ProxyHttp>::CTX: Send + Sync,
+    {
+        self.build_server(app, opt, services, |_| {})
+    }
+
+    /// Sets up a server like [`StartupConf::into_server`] but calls `configure` before the server
+    /// is bootstrapped
+    ///
+    /// This allows adjusting Pingora configuration options after all configuration files and
+    /// command line options have been applied.
+    pub fn into_server_with<SV, F>(
+        self,
+        app: SV,
+        opt: Option<StartupOpt>,
+        configure: F,
+    ) -> Result<Server, StartupError>
+    where
+        SV: ProxyHttp + Send + Sync + 'static,
+        <SV as ProxyHttp>::CTX: Send + Sync,
+        F: FnOnce(&mut ServerConf),
+    {
+        self.build_server(app, opt, Vec::new(), configure)
+    }
+
+    fn build_server<SV, F>(
+        mut self,
+        app: SV,
+        opt: Option<StartupOpt>,
+        services: Vec<Box<dyn Service>>,
+        configure: F,
+    ) -> Result<Server, StartupError>
     where
         SV: ProxyHttp + Send + Sync + 'static,
         <SV as ProxyHttp>::CTX: Send + Sync,
+        F: FnOnce(&mut ServerConf),
     {
         let opt = opt.unwrap_or_default();
 
+        self.log.init_logger()?;
+
+        validate_positive("threads", self.threads)?;
+        validate_positive("tls.redirector.threads", self.tls.redirector.threads)?;
+        validate_positive(
+            "downstream_keepalive_timeout",
+            self.downstream_keepalive_timeout,
+        )?;
+        validate_positive("max_uri_length", self.max_uri_length)?;
+        if let Some(threads) = self.threads {
+            self.server.threads = threads;
+        }
+        if let Some(work_stealing) = self.work_stealing {
+            self.server.work_stealing = work_stealing;
+        }
+
+        #[cfg(unix)]
+        let privileges = Privileges::resolve(self.user.as_deref(), self.group.as_deref())?;
+        #[cfg(not(unix))]
+        if self.user.is_some() || self.group.is_some() {
+            return Err(StartupError::InvalidConfig(
+                "user and group settings are only supported on Unix".to_owned(),
+            ));
+        }
+
         let mut listen = opt.listen.map(|l| l.into()).unwrap_or(self.listen);
         if listen.is_empty() {
             // Make certain we have a listening address
@@ -384,6 +984,34 @@ impl StartupConf {
             listen.push("[::1]:8080".into());
         }
 
+        // Host names are resolved only once, at startup
+        let listen = ListenAddr::resolve_all(listen)?;
+        self.tls.redirector.listen = ListenAddr::resolve_all(self.tls.redirector.listen)?;
+
+        #[cfg(unix)]
+        let (privileges_service, privileges_dropped) = match privileges {
+            Some(privileges) => {
+                let mut addrs = listen
+                    .iter()
+                    .map(|addr| addr.addr.as_str())
+                    .collect::<Vec<_>>();
+                if listen.iter().any(|addr| addr.tls) {
+                    addrs.extend(
+                        self.tls
+                            .redirector
+                            .listen
+                            .iter()
+                            .map(|addr| addr.addr.as_str()),
+                    );
+                }
+                let (service, dropped) = PrivilegesService::new(privileges, addrs.into_iter())?;
+                (Some(service), Some(dropped))
+            }
+            None => (None, None),
+        };
+
+        configure(&mut self.server);
+
         let mut server = Server::new_with_opt_and_conf(
             ServerOpt {
                 daemon: opt.daemon,
@@ -396,12 +1024,17 @@ impl StartupConf {
         );
         server.bootstrap();
 
+        // Requests shouldn’t be processed before privileges are dropped
+        #[cfg(unix)]
+        let app = PrivilegesGate::new(app, privileges_dropped.clone());
+
         let mut service = http_proxy_service(&server.configuration, app);
         for addr in &listen {
             if addr.tls {
                 continue;
             }
 
+            addr.validate_versions()?;
             if let Some(socket_options) = addr.to_socket_options() {
                 service.add_tcp_with_settings(&addr.addr, socket_options);
             } else {
@@ -410,7 +1043,11 @@ impl StartupConf {
         }
 
         if listen.iter().any(|addr| addr.tls) {
-            if let Some(redirector) = self.tls.redirector.to_redirector(&server.configuration)? {
+            if let Some(redirector) = self.tls.redirector.to_redirector(
+                &server.configuration,
+                #[cfg(unix)]
+                privileges_dropped,
+            )? {
                 server.add_service(redirector);
             }
 
@@ -420,15 +1057,608 @@ impl StartupConf {
                     continue;
                 }
 
-                service.add_tls_with_settings(
-                    &addr.addr,
-                    addr.to_socket_options(),
-                    TlsSettings::with_callbacks(Box::new(tls_callbacks.clone()))?,
-                );
+                let mut settings =
+                    TlsSettings::with_callbacks(Box::new(tls_callbacks.for_listener(addr)?))
+                        .map_err(StartupError::Server)?;
+                addr.set_alpn(&mut settings)?;
+                service.add_tls_with_settings(&addr.addr, addr.to_socket_options(), settings);
             }
         }
         server.add_service(service);
+        server.add_services(services);
+
+        #[cfg(unix)]
+        if let Some(privileges_service) = privileges_service {
+            server.add_service(background_service("drop privileges", privileges_service));
+        }
 
         Ok(server)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use pandora_module_utils::pingora::{ErrorType, HttpPeer, Session};
+    use pandora_module_utils::FromYaml;
+    use test_log::test;
+
+    struct App;
+
+    #[async_trait]
+    impl ProxyHttp for App {
+        type CTX = ();
+        fn new_ctx(&self) -> Self::CTX {}
+
+        async fn upstream_peer(
+            &self,
+            _session: &mut Session,
+            _ctx: &mut Self::CTX,
+        ) -> Result<Box<HttpPeer>, Box<Error>> {
+            Err(Error::new(ErrorType::HTTPStatus(404)))
+        }
+    }
+
+    #[test]
+    fn dual_certificates() {
+        use pingora::tls::pkey::Id;
+
+        let conf = StartupConf::from_yaml(
+            r#"
+                tls:
+                    cert_path: testdata/rsa.cert.pem
+                    key_path: testdata/rsa.key.pem
+                    additional_certificates:
+                        cert_path: testdata/ecdsa.cert.pem
+                        key_path: testdata/ecdsa.key.pem
+                    server_names:
+                        example.com:
+                        - cert_path: testdata/ecdsa.cert.pem
+                          key_path: testdata/ecdsa.key.pem
+                        - cert_path: testdata/rsa.cert.pem
+                          key_path: testdata/rsa.key.pem
+                        example.net:
+                            cert_path: testdata/rsa.cert.pem
+                            key_path: testdata/rsa.key.pem
+            "#,
+        )
+        .unwrap();
+        assert_eq!(conf.tls.additional_certificates.len(), 1);
+        assert_eq!(conf.tls.server_names["example.com"].len(), 2);
+        assert_eq!(conf.tls.server_names["example.net"].len(), 1);
+
+        let key_types =
+            |certs: &Vec<CertKey>| certs.iter().map(|cert| cert.key().id()).collect::<Vec<_>>();
+
+        let callbacks = conf.tls.into_callbacks().unwrap();
+        assert_eq!(
+            key_types(&callbacks.certificates[""]),
+            vec![Id::RSA, Id::EC]
+        );
+        assert_eq!(
+            key_types(&callbacks.certificates["example.com"]),
+            vec![Id::EC, Id::RSA]
+        );
+        assert_eq!(
+            key_types(&callbacks.certificates["example.net"]),
+            vec![Id::RSA]
+        );
+
+        let conf = StartupConf::from_yaml(
+            r#"
+                tls:
+                    cert_path: testdata/rsa.cert.pem
+                    key_path: testdata/rsa.key.pem
+                    server_names:
+                        example.com: []
+            "#,
+        )
+        .unwrap();
+        assert!(conf.tls.into_callbacks().is_err());
+    }
+
+    #[test(tokio::test)]
+    async fn add_server_names() {
+        use pingora::tls::pkey::Id;
+        use pingora::tls::ssl::{Ssl, SslContext, SslMethod};
+
+        let mut conf = StartupConf::from_yaml(
+            r#"
+                tls:
+                    cert_path: testdata/rsa.cert.pem
+                    key_path: testdata/rsa.key.pem
+                    server_names:
+                        example.com:
+                            cert_path: testdata/rsa.cert.pem
+                            key_path: testdata/rsa.key.pem
+            "#,
+        )
+        .unwrap();
+
+        let cert_key = |name: &str| CertKeyConf {
+            cert_path: Some(format!("testdata/{name}.cert.pem").into()),
+            key_path: Some(format!("testdata/{name}.key.pem").into()),
+        };
+
+        // Same configuration listed twice is fine
+        conf.tls
+            .add_server_names([
+                ("example.com".to_owned(), vec![cert_key("rsa")].into()),
+                ("example.net".to_owned(), vec![cert_key("ecdsa")].into()),
+            ])
+            .unwrap();
+        assert_eq!(conf.tls.server_names.len(), 2);
+
+        // Conflicting configuration is rejected
+        assert!(conf
+            .tls
+            .add_server_names([("example.com".to_owned(), vec![cert_key("ecdsa")].into())])
+            .is_err());
+
+        // Simulates a connection with the given SNI, returns the key type of the chosen certificate
+        async fn key_type(callbacks: &TlsAcceptCallbacks, name: &str) -> Id {
+            let context = SslContext::builder(SslMethod::tls()).unwrap().build();
+            let mut ssl = Ssl::new(&context).unwrap();
+            ssl.set_hostname(name).unwrap();
+            callbacks.certificate_callback(&mut ssl).await;
+            ssl.certificate().unwrap().public_key().unwrap().id()
+        }
+
+        let callbacks = conf.tls.into_callbacks().unwrap();
+        assert_eq!(key_type(&callbacks, "example.com").await, Id::RSA);
+        assert_eq!(key_type(&callbacks, "example.net").await, Id::EC);
+        assert_eq!(key_type(&callbacks, "example.org").await, Id::RSA);
+    }
+
+    #[test(tokio::test)]
+    async fn default_server_name() {
+        use pingora::tls::pkey::Id;
+        use pingora::tls::ssl::{Ssl, SslContext, SslMethod};
+
+        let conf = StartupConf::from_yaml(
+            r#"
+                listen:
+                - {addr: 127.0.0.1:8443, tls: true}
+                - {addr: 127.0.0.1:8444, tls: true, default_server_name: example.com}
+                tls:
+                    cert_path: testdata/rsa.cert.pem
+                    key_path: testdata/rsa.key.pem
+                    server_names:
+                        example.com:
+                            cert_path: testdata/ecdsa.cert.pem
+                            key_path: testdata/ecdsa.key.pem
+            "#,
+        )
+        .unwrap();
+        assert_eq!(conf.listen[0].default_server_name, None);
+        assert_eq!(
+            conf.listen[1].default_server_name.as_deref(),
+            Some("example.com")
+        );
+
+        // Simulates a connection without SNI, returns the key type of the chosen certificate
+        async fn key_type(callbacks: TlsAcceptCallbacks) -> Id {
+            let context = SslContext::builder(SslMethod::tls()).unwrap().build();
+            let mut ssl = Ssl::new(&context).unwrap();
+            callbacks.certificate_callback(&mut ssl).await;
+            ssl.certificate().unwrap().public_key().unwrap().id()
+        }
+
+        let callbacks = conf.tls.clone().into_callbacks().unwrap();
+        assert_eq!(
+            key_type(callbacks.for_listener(&conf.listen[0]).unwrap()).await,
+            Id::RSA
+        );
+        assert_eq!(
+            key_type(callbacks.for_listener(&conf.listen[1]).unwrap()).await,
+            Id::EC
+        );
+
+        // Unknown server names are rejected
+        let addr = ListenAddr {
+            default_server_name: Some("example.net".to_owned()),
+            ..ListenAddr::from("127.0.0.1:8444")
+        };
+        assert!(callbacks.for_listener(&addr).is_err());
+
+        let mut conf = conf;
+        conf.listen[1].default_server_name = Some("example.net".to_owned());
+        assert!(conf.into_server(App, None).is_err());
+    }
+
+    #[test]
+    fn http_versions() {
+        use pingora::tls::ssl::{SslAcceptor, SslConnector, SslFiletype, SslMethod, SslVerifyMode};
+        use std::net::{TcpListener, TcpStream};
+
+        let conf = StartupConf::from_yaml(
+            r#"
+                listen:
+                - 127.0.0.1:8080
+                - {addr: 127.0.0.1:8443, tls: true, versions: http/1.1}
+                - {addr: 127.0.0.1:8444, tls: true, versions: [h2, http/1.1]}
+            "#,
+        )
+        .unwrap();
+        assert!(conf.listen[0].versions.is_empty());
+        assert_eq!(*conf.listen[1].versions, vec![HttpVersion::Http1]);
+        assert_eq!(
+            *conf.listen[2].versions,
+            vec![HttpVersion::Http2, HttpVersion::Http1]
+        );
+        assert!(StartupConf::from_yaml("listen: {addr: 127.0.0.1:8080, versions: h3}").is_err());
+
+        // HTTP/2 requires TLS
+        let conf =
+            StartupConf::from_yaml("listen: {addr: 127.0.0.1:8080, versions: http/1.1}").unwrap();
+        assert!(conf.into_server(App, None).is_ok());
+        let conf =
+            StartupConf::from_yaml("listen: {addr: 127.0.0.1:8080, versions: [h2, http/1.1]}")
+                .unwrap();
+        assert!(conf.into_server(App, None).is_err());
+
+        // Performs a TLS handshake, returns the negotiated protocol or `None` on failure
+        fn handshake(addr: &ListenAddr, offered: &[u8]) -> Option<Vec<u8>> {
+            let mut builder = SslAcceptor::mozilla_intermediate_v5(SslMethod::tls()).unwrap();
+            builder
+                .set_certificate_chain_file("testdata/rsa.cert.pem")
+                .unwrap();
+            builder
+                .set_private_key_file("testdata/rsa.key.pem", SslFiletype::PEM)
+                .unwrap();
+            addr.set_alpn(&mut builder).unwrap();
+            let acceptor = builder.build();
+
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            let local_addr = listener.local_addr().unwrap();
+            let server = std::thread::spawn(move || {
+                let (stream, _) = listener.accept().unwrap();
+                let _ = acceptor.accept(stream);
+            });
+
+            let mut connector = SslConnector::builder(SslMethod::tls()).unwrap();
+            connector.set_verify(SslVerifyMode::NONE);
+            if !offered.is_empty() {
+                connector.set_alpn_protos(offered).unwrap();
+            }
+            let stream = TcpStream::connect(local_addr).unwrap();
+            let result = connector
+                .build()
+                .connect("localhost", stream)
+                .ok()
+                .map(|stream| {
+                    stream
+                        .ssl()
+                        .selected_alpn_protocol()
+                        .unwrap_or_default()
+                        .to_vec()
+                });
+            server.join().unwrap();
+            result
+        }
+
+        let http1_only = ListenAddr {
+            tls: true,
+            versions: vec![HttpVersion::Http1].into(),
+            ..ListenAddr::from("127.0.0.1:8443")
+        };
+        let h2_preferred = ListenAddr {
+            tls: true,
+            versions: vec![HttpVersion::Http2, HttpVersion::Http1].into(),
+            ..ListenAddr::from("127.0.0.1:8444")
+        };
+
+        // An HTTP/2 attempt on an HTTP/1.1-only listener is rejected
+        assert_eq!(handshake(&http1_only, b"\x02h2"), None);
+        assert_eq!(
+            handshake(&http1_only, b"\x02h2\x08http/1.1"),
+            Some(b"http/1.1".to_vec())
+        );
+        assert_eq!(handshake(&http1_only, b""), Some(Vec::new()));
+
+        assert_eq!(
+            handshake(&h2_preferred, b"\x08http/1.1\x02h2"),
+            Some(b"h2".to_vec())
+        );
+        assert_eq!(
+            handshake(&h2_preferred, b"\x08http/1.1"),
+            Some(b"http/1.1".to_vec())
+        );
+    }
+
+    #[test]
+    fn log_level() {
+        let conf = StartupConf::default();
+        assert!(!conf.log.is_configured());
+        assert_eq!(conf.log.level(), LevelFilter::Error);
+
+        for (value, expected) in [
+            ("off", LevelFilter::Off),
+            ("error", LevelFilter::Error),
+            ("warn", LevelFilter::Warn),
+            ("info", LevelFilter::Info),
+            ("Debug", LevelFilter::Debug),
+            ("TRACE", LevelFilter::Trace),
+        ] {
+            let conf = StartupConf::from_yaml(format!("log:\n    level: {value}")).unwrap();
+            assert!(conf.log.is_configured());
+            assert_eq!(conf.log.level, Some(expected));
+            assert_eq!(conf.log.level(), expected);
+        }
+
+        assert!(StartupConf::from_yaml("log:\n    level: verbose").is_err());
+    }
+
+    #[test]
+    fn log_target() {
+        let conf = StartupConf::default();
+        assert!(matches!(conf.log.target().unwrap(), Target::Stderr));
+
+        let path = std::env::temp_dir().join("startup-module-log-target.log");
+        let _ = std::fs::remove_file(&path);
+        let conf = StartupConf::from_yaml(format!("log:\n    file: {}", path.display())).unwrap();
+        assert!(conf.log.is_configured());
+        assert_eq!(conf.log.file.as_ref(), Some(&path));
+        assert!(matches!(conf.log.target().unwrap(), Target::Pipe(_)));
+        assert!(path.exists());
+        let _ = std::fs::remove_file(&path);
+
+        let conf = StartupConf::from_yaml("log:\n    file: nonexisting/dir/server.log").unwrap();
+        assert!(conf.log.target().is_err());
+        assert!(conf.into_server(App, None).is_err());
+
+        // A logger is installed by test_log already, this shouldn’t fail
+        let conf = StartupConf::from_yaml("log:\n    level: debug").unwrap();
+        assert!(conf.into_server(App, None).is_ok());
+    }
+
+    #[test]
+    fn header_case() {
+        assert_eq!(StartupConf::default().header_case, HeaderCase::Preserve);
+        let conf = StartupConf::from_yaml("header_case: title").unwrap();
+        assert_eq!(conf.header_case, HeaderCase::Title);
+        assert!(StartupConf::from_yaml("header_case: upper").is_err());
+
+        assert_eq!(HeaderCase::Title.convert("x-my-header"), "X-My-Header");
+        assert_eq!(
+            HeaderCase::Title.convert("WWW-AUTHENTICATE"),
+            "Www-Authenticate"
+        );
+        assert_eq!(HeaderCase::Lower.convert("X-My-Header"), "x-my-header");
+    }
+
+    #[test]
+    fn request_id_header() {
+        assert_eq!(StartupConf::default().request_id_header, None);
+        let conf = StartupConf::from_yaml("request_id_header: X-Request-Id").unwrap();
+        assert_eq!(
+            conf.request_id_header,
+            Some(HeaderName::from_static("x-request-id"))
+        );
+        assert!(StartupConf::from_yaml("request_id_header: in valid").is_err());
+    }
+
+    #[test]
+    fn server_header() {
+        assert_eq!(StartupConf::default().server_header, ServerHeader::Preserve);
+        let conf = StartupConf::from_yaml("server_header: My Server").unwrap();
+        assert_eq!(
+            conf.server_header,
+            ServerHeader::Value(HeaderValue::from_static("My Server"))
+        );
+        let conf = StartupConf::from_yaml("server_header: none").unwrap();
+        assert_eq!(conf.server_header, ServerHeader::Remove);
+        assert!(StartupConf::from_yaml("server_header: \"in\\nvalid\"").is_err());
+    }
+
+    #[test]
+    fn allow_invalid_host() {
+        assert!(!StartupConf::default().allow_invalid_host);
+        let conf = StartupConf::from_yaml("allow_invalid_host: true").unwrap();
+        assert!(conf.allow_invalid_host);
+    }
+
+    #[test]
+    fn thread_settings() {
+        let conf = StartupConf::from_yaml(
+            r#"
+                threads: 4
+                work_stealing: false
+                tls:
+                    redirector:
+                        threads: 2
+            "#,
+        )
+        .unwrap();
+        assert_eq!(conf.threads, Some(4));
+        assert_eq!(conf.work_stealing, Some(false));
+        assert_eq!(conf.tls.redirector.threads, Some(2));
+
+        let server = conf.into_server(App, None).unwrap();
+        assert_eq!(server.configuration.threads, 4);
+        assert!(!server.configuration.work_stealing);
+    }
+
+    #[test]
+    fn thread_defaults() {
+        let server = StartupConf::default().into_server(App, None).unwrap();
+        assert_eq!(server.configuration.threads, 1);
+        assert!(server.configuration.work_stealing);
+    }
+
+    #[test]
+    fn thread_validation() {
+        let conf = StartupConf::from_yaml("threads: 0").unwrap();
+        assert!(conf.into_server(App, None).is_err());
+
+        let conf = StartupConf::from_yaml(
+            r#"
+                tls:
+                    redirector:
+                        threads: 0
+            "#,
+        )
+        .unwrap();
+        assert!(conf.into_server(App, None).is_err());
+    }
+
+    #[test]
+    fn max_uri_length_validation() {
+        let conf = StartupConf::from_yaml("max_uri_length: 2048").unwrap();
+        assert_eq!(conf.max_uri_length, Some(2048));
+        assert!(conf.into_server(App, None).is_ok());
+
+        let conf = StartupConf::from_yaml("max_uri_length: 0").unwrap();
+        let err = conf.into_server(App, None).unwrap_err();
+        assert!(matches!(err, StartupError::InvalidConfig(_)), "{err:?}");
+    }
+
+    #[test]
+    fn keepalive_timeout_validation() {
+        let conf = StartupConf::from_yaml("downstream_keepalive_timeout: 30").unwrap();
+        assert_eq!(conf.downstream_keepalive_timeout, Some(30));
+        assert!(conf.into_server(App, None).is_ok());
+
+        let conf = StartupConf::from_yaml("downstream_keepalive_timeout: 0").unwrap();
+        let err = conf.into_server(App, None).unwrap_err();
+        assert!(matches!(err, StartupError::InvalidConfig(_)), "{err:?}");
+
+        assert!(StartupConf::from_yaml("downstream_keepalive_timeout: -1").is_err());
+    }
+
+    #[test]
+    fn error_variants() {
+        let err = StartupConf::from_yaml("threads: many")
+            .map_err(StartupError::Config)
+            .unwrap_err();
+        assert!(matches!(err, StartupError::Config(_)), "{err:?}");
+
+        let conf = StartupConf::from_yaml("threads: 0").unwrap();
+        let err = conf.into_server(App, None).unwrap_err();
+        assert!(matches!(err, StartupError::InvalidConfig(_)), "{err:?}");
+
+        let conf = StartupConf::from_yaml("listen: nonexistent.invalid:8080").unwrap();
+        let err = conf.into_server(App, None).unwrap_err();
+        assert!(
+            matches!(&err, StartupError::InvalidListenAddr { addr, .. } if addr == "nonexistent.invalid:8080"),
+            "{err:?}"
+        );
+
+        let conf = StartupConf::from_yaml("listen: {addr: 127.0.0.1:0, versions: h2}").unwrap();
+        let err = conf.into_server(App, None).unwrap_err();
+        assert!(
+            matches!(err, StartupError::InvalidListenAddr { .. }),
+            "{err:?}"
+        );
+
+        let conf = StartupConf::from_yaml("listen: {addr: 127.0.0.1:0, tls: true}").unwrap();
+        let err = conf.into_server(App, None).unwrap_err();
+        assert!(
+            matches!(err, StartupError::MissingCertificate(_)),
+            "{err:?}"
+        );
+
+        let conf = StartupConf::from_yaml(
+            r#"
+                listen: {addr: 127.0.0.1:0, tls: true}
+                tls:
+                    cert_path: testdata/nonexistent.cert.pem
+                    key_path: testdata/rsa.key.pem
+            "#,
+        )
+        .unwrap();
+        let err = conf.into_server(App, None).unwrap_err();
+        assert!(
+            matches!(&err, StartupError::InvalidCertificate { path, .. } if path == Path::new("testdata/nonexistent.cert.pem")),
+            "{err:?}"
+        );
+
+        // Conversion to Pingora errors keeps the error category
+        let err: Box<Error> = err.into();
+        assert_eq!(err.etype(), &TLS_CONF_ERR);
+    }
+
+    #[test]
+    fn listen_resolve() {
+        let addr = ListenAddr::from("127.0.0.1:8080");
+        assert_eq!(addr.resolve().unwrap(), vec![addr.clone()]);
+
+        let addr = ListenAddr {
+            addr: "localhost:8080".to_owned(),
+            tls: true,
+            ipv6_only: Some(true),
+            default_server_name: None,
+            versions: Default::default(),
+        };
+        let resolved = addr.resolve().unwrap();
+        assert!(!resolved.is_empty());
+        for entry in &resolved {
+            let socket_addr: SocketAddr = entry.addr.parse().unwrap();
+            assert!(socket_addr.ip().is_loopback());
+            assert_eq!(socket_addr.port(), 8080);
+            assert!(entry.tls);
+            assert_eq!(entry.ipv6_only, Some(true));
+        }
+
+        assert!(ListenAddr::from("localhost").resolve().is_err());
+        assert!(ListenAddr::from("nonexistent.invalid:8080")
+            .resolve()
+            .is_err());
+
+        let conf = StartupConf::from_yaml("listen: localhost:8080").unwrap();
+        assert!(conf.into_server(App, None).is_ok());
+
+        let conf = StartupConf::from_yaml("listen: nonexistent.invalid:8080").unwrap();
+        assert!(conf.into_server(App, None).is_err());
+    }
+
+    #[test]
+    fn configure_hook() {
+        let conf = StartupConf::from_yaml("threads: 4").unwrap();
+        let server = conf
+            .into_server_with(App, None, |server_conf| {
+                // Configuration files have been applied already
+                assert_eq!(server_conf.threads, 4);
+
+                server_conf.threads = 3;
+                server_conf.upstream_keepalive_pool_size = 42;
+            })
+            .unwrap();
+        assert_eq!(server.configuration.threads, 3);
+        assert_eq!(server.configuration.upstream_keepalive_pool_size, 42);
+    }
+
+    #[test]
+    fn additional_services() {
+        use pandora_module_utils::pingora::{background_service, BackgroundService, ShutdownWatch};
+
+        struct Job(Arc<()>);
+
+        #[async_trait]
+        impl BackgroundService for Job {
+            async fn start(&self, _shutdown: ShutdownWatch) {}
+        }
+
+        let marker = Arc::new(());
+        let service = background_service("job", Job(marker.clone()));
+        assert_eq!(service.name(), "BG job");
+
+        let server = StartupConf::default()
+            .into_server_with_services(App, None, vec![Box::new(service)])
+            .unwrap();
+        // The service is kept by the server
+        assert_eq!(Arc::strong_count(&marker), 2);
+
+        drop(server);
+        assert_eq!(Arc::strong_count(&marker), 1);
+
+        // Errors are still reported, and the services are dropped then
+        let conf = StartupConf::from_yaml("threads: 0").unwrap();
+        let service = background_service("job", Job(marker.clone()));
+        assert!(conf
+            .into_server_with_services(App, None, vec![Box::new(service)])
+            .is_err());
+        assert_eq!(Arc::strong_count(&marker), 1);
+    }
+}
@@ -0,0 +1,105 @@
+// Copyright 2024 Wladimir Palant
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Errors produced when setting up the server
+
+use pandora_module_utils::pingora::{Error, ErrorType};
+use std::fmt::{Display, Formatter};
+use std::path::PathBuf;
+
+pub(crate) const TLS_CONF_ERR: ErrorType = ErrorType::Custom("TLSConfigError");
+pub(crate) const STARTUP_CONF_ERR: ErrorType = ErrorType::Custom("StartupConfigError");
+
+/// Error setting up the server from its configuration
+///
+/// This can be converted into Pingora’s `Box<Error>`, so that the `?` operator can be used with
+/// functions returning `Result<_, Box<Error>>`.
+#[derive(Debug)]
+pub enum StartupError {
+    /// Configuration files couldn’t be loaded, e.g. an error returned by
+    /// [`FromYaml::load_from_files`](pandora_module_utils::FromYaml::load_from_files)
+    Config(Box<Error>),
+
+    /// A setting has an invalid value
+    InvalidConfig(String),
+
+    /// A listen address is invalid or couldn’t be resolved
+    InvalidListenAddr {
+        /// The listen address as configured
+        addr: String,
+        /// Description of the problem
+        reason: String,
+    },
+
+    /// Certificate/key combination is missing for a TLS configuration, e.g. for the default
+    /// server name
+    MissingCertificate(String),
+
+    /// A certificate or private key file couldn’t be read or parsed
+    InvalidCertificate {
+        /// Path of the file
+        path: PathBuf,
+        /// Description of the problem
+        reason: String,
+    },
+
+    /// Pingora failed setting up a service
+    Server(Box<Error>),
+}
+
+impl Display for StartupError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Config(err) => write!(f, "failed loading configuration: {err}"),
+            Self::InvalidConfig(reason) => write!(f, "{reason}"),
+            Self::InvalidListenAddr { addr, reason } => {
+                write!(f, "invalid listen address {addr}: {reason}")
+            }
+            Self::MissingCertificate(name) => {
+                write!(f, "missing certificate/key combination for {name}")
+            }
+            Self::InvalidCertificate { path, reason } => {
+                write!(
+                    f,
+                    "invalid certificate/key file {}: {reason}",
+                    path.display()
+                )
+            }
+            Self::Server(err) => write!(f, "{err}"),
+        }
+    }
+}
+
+impl std::error::Error for StartupError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Config(err) | Self::Server(err) => Some(err.as_ref()),
+            _ => None,
+        }
+    }
+}
+
+impl From<StartupError> for Box<Error> {
+    fn from(err: StartupError) -> Self {
+        match err {
+            StartupError::Config(err) | StartupError::Server(err) => err,
+            StartupError::MissingCertificate(_) | StartupError::InvalidCertificate { .. } => {
+                Error::explain(TLS_CONF_ERR, err.to_string())
+            }
+            StartupError::InvalidConfig(_) | StartupError::InvalidListenAddr { .. } => {
+                Error::explain(STARTUP_CONF_ERR, err.to_string())
+            }
+        }
+    }
+}
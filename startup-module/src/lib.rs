@@ -47,6 +47,11 @@
 //! With the configuration above the server will still listen on all IPv6 addresses, yet IPv4
 //! connections will only be accepted if configured explicitly.
 //!
+//! A host name can be given instead of an IP address, e.g. `localhost:8080`. Host names are
+//! resolved once at startup, and the server will listen on all IP addresses found. Startup fails
+//! if a host name cannot be resolved. Changes to DNS records won’t be noticed until the server is
+//! restarted.
+//!
 //! The `listen` configuration option is also available as `--listen` command line option. Flags
 //! cannot be specified via the command line, only the address to listen on. This command line
 //! option can be specified multiple times to make the server listen on multiple addresses or ports.
@@ -54,6 +59,181 @@
 //! Other command line options are: `--conf` (configuration file or configuration files to load),
 //! `--daemon` (run process in background) and `--test` (test configuration and exit).
 //!
+//! If you need to adjust Pingora configuration options programmatically, e.g. to apply settings
+//! from another source, use `StartupConf::into_server_with` method instead of
+//! `StartupConf::into_server`. The closure passed in will be called with the final `ServerConf`
+//! just before the server is created.
+//!
+//! The settings described below up to and including `downstream_keepalive_timeout` affect request
+//! processing and are implemented by [`DefaultApp`] rather than the server. These only take effect
+//! if the configuration is passed on to the app via [`DefaultApp::with_startup_conf`].
+//!
+//! ## Response header case
+//!
+//! Header names are case-insensitive, yet some legacy HTTP/1.1 clients expect them in a particular
+//! case. The `header_case` setting allows changing the case of all response header names:
+//!
+//! ```yaml
+//! header_case: title
+//! ```
+//!
+//! Supported values are `preserve` (default, header names are sent as produced), `title`
+//! (`X-My-Header`) and `lower` (`x-my-header`). HTTP/2 responses always use lower case header
+//! names. Headers added by Pingora itself like `Date` and `Connection` are always title-cased.
+//!
+//! ## Request IDs
+//!
+//! In order to correlate log entries of the web server and upstream servers, request IDs can be
+//! enabled by configuring the header they should be sent in:
+//!
+//! ```yaml
+//! request_id_header: X-Request-Id
+//! ```
+//!
+//! With this setting, each request gets an ID which is added to the request forwarded to the
+//! upstream server and to the response. If the client sent a request ID in this header already,
+//! it is reused.
+//!
+//! ## `Server` response header
+//!
+//! By default, the `Server` header is sent as produced by handlers or the upstream server. The
+//! `server_header` setting allows replacing its value in all responses, `none` removes the header
+//! instead:
+//!
+//! ```yaml
+//! server_header: My Web Server
+//! ```
+//!
+//! This also affects error responses generated by Pingora itself.
+//!
+//! ## Malformed `Host` headers
+//!
+//! Requests with a `Host` header that isn’t a valid host name with an optional port are rejected
+//! by [`DefaultApp`] with `400 Bad Request`, before any handler is called. This applies to
+//! illegal characters like control characters or spaces, user info, invalid ports, host names
+//! longer than 253 characters and requests with multiple `Host` headers. Requests without a
+//! `Host` header aren’t affected.
+//!
+//! This check can be disabled with the `allow_invalid_host` setting, handlers will then receive
+//! such requests as before:
+//!
+//! ```yaml
+//! allow_invalid_host: true
+//! ```
+//!
+//! ## Overlong URIs
+//!
+//! Requests with a URI longer than 8192 bytes are rejected by [`DefaultApp`] with
+//! `414 URI Too Long`, before any handler is called. The limit can be changed with the
+//! `max_uri_length` setting:
+//!
+//! ```yaml
+//! max_uri_length: 2048
+//! ```
+//!
+//! ## Forwarding headers
+//!
+//! Upstream servers might need to know the original client address, scheme and host of a
+//! request. With the `forwarded_headers` setting, [`DefaultApp`] adds this information to the
+//! requests sent to the upstream server in the `X-Forwarded-For`, `X-Forwarded-Proto` and
+//! `X-Forwarded-Host` headers:
+//!
+//! ```yaml
+//! forwarded_headers: true
+//! trust_forwarded_headers: false
+//! ```
+//!
+//! The values are determined before any handler is called, apart from the client address which
+//! handlers can overwrite via [`SessionWrapper::set_client_addr`]. By default, any such headers
+//! sent by the client are replaced. If the server is behind another proxy,
+//! `trust_forwarded_headers` can be enabled: the client address is appended to an existing
+//! `X-Forwarded-For` header then, e.g. `X-Forwarded-For: 192.0.2.1, 198.51.100.2`. Existing
+//! `X-Forwarded-Proto` and `X-Forwarded-Host` headers are kept unchanged. Don’t enable this
+//! otherwise, these headers can be set by any client.
+//!
+//! ## Idle client connections
+//!
+//! By default, HTTP/1.x client connections are kept open between requests for as long as the
+//! client wants. The `downstream_keepalive_timeout` setting asks clients to close connections
+//! that have been idle for the given time (in seconds):
+//!
+//! ```yaml
+//! downstream_keepalive_timeout: 60
+//! ```
+//!
+//! This timeout is announced to the client via the `Keep-Alive: timeout=60` response header, so
+//! that clients close idle connections on their own. It is merely advisory however: Pingora 0.2
+//! provides no way to enforce an idle timeout between requests, connections of clients ignoring
+//! this header stay open. HTTP/2 connections aren’t affected.
+//!
+//! ## `OPTIONS *` requests
+//!
+//! Clients and proxies can send `OPTIONS *` requests to probe the capabilities of the server as a
+//! whole rather than a particular resource. [`DefaultApp`] answers these with `204 No Content`
+//! and an `Allow` header listing the usual request methods, without passing the request on to the
+//! handler. The header value can be changed or this behavior disabled via
+//! [`DefaultApp::with_options_allow`].
+//!
+//! ## `Expect: 100-continue` requests
+//!
+//! Clients uploading large request bodies can send an `Expect: 100-continue` header and wait for
+//! the server to confirm with `100 Continue` before sending the body. [`DefaultApp`] sends this
+//! confirmation once the handler passes the request on to the upstream server. A handler
+//! rejecting the request responds without `100 Continue`, the connection is closed afterwards
+//! since it is unknown whether the client will send the request body.
+//!
+//! Handlers processing the request body themselves should call
+//! [`SessionWrapper::send_continue`] before reading it,
+//! [`SessionWrapper::buffer_request_body`] does that automatically. This is only supported for
+//! HTTP/1.1 requests.
+//!
+//! ## Worker threads
+//!
+//! Each service gets its own worker threads. The `threads` setting determines how many, and
+//! `work_stealing` whether threads of the same service are allowed to take over each other’s
+//! tasks:
+//!
+//! ```yaml
+//! threads: 4
+//! work_stealing: false
+//! ```
+//!
+//! The TLS redirector (see below) is a separate service. It will use the same number of threads
+//! unless configured otherwise via its own `threads` setting.
+//!
+//! ## Dropping privileges
+//!
+//! Listening on ports below 1024 usually requires root privileges. In order to avoid running the
+//! server with root privileges, you can specify a user and group to switch to (Unix only):
+//!
+//! ```yaml
+//! user: www-data
+//! group: www-data
+//! ```
+//!
+//! Both user and group can be given as a name or a numeric ID. If only `user` is given, the
+//! primary group of that user is used. Unknown users or groups will make the server fail at
+//! startup. The switch happens once the server is listening on all configured addresses, so the
+//! server process needs to be started as root. Connections accepted before that are held back:
+//! no request is processed until the privileges have been dropped.
+//!
+//! ## Logging
+//!
+//! By default, the server’s own diagnostic messages are configured via the `RUST_LOG` environment
+//! variable. Alternatively, the `log` setting allows choosing the log level and a file to append
+//! messages to:
+//!
+//! ```yaml
+//! log:
+//!     level: info
+//!     file: /var/log/pandora/error.log
+//! ```
+//!
+//! Supported log levels are `off`, `error`, `warn`, `info`, `debug` and `trace`, the default is
+//! `error`. Without a `file` setting messages go to stderr. If present, the `RUST_LOG` environment
+//! variable overrides the configured log level. These settings have no effect if the application
+//! installed a logger already. Access logs aren’t affected by them.
+//!
 //! ## TLS configuration
 //!
 //! You can enable TLS for some or all addresses the server listens on by specifying the `tls`
@@ -94,6 +274,53 @@
 //! the corresponding certificate will be used. Otherwise the default certificate will be used as
 //! fallback.
 //!
+//! Some old clients don’t send a server name indicator at all. For these, the default certificate
+//! is used unless the listening address specifies a different server name via
+//! `default_server_name`. This server name has to be listed under `server_names`:
+//!
+//! ```yaml
+//! listen:
+//! - {addr: 0.0.0.0:443, tls: true}
+//! - {addr: 0.0.0.0:8443, tls: true, default_server_name: example.net}
+//! ```
+//!
+//! Multiple certificates with different key types can be configured for the same server name,
+//! e.g. an ECDSA certificate for modern clients and an RSA certificate for legacy ones. The TLS
+//! implementation will choose the certificate supported by the client:
+//!
+//! ```yaml
+//! tls:
+//!     cert_path: cert.ecdsa.pem
+//!     key_path: key.ecdsa.pem
+//!     additional_certificates:
+//!     - cert_path: cert.rsa.pem
+//!       key_path: key.rsa.pem
+//!     server_names:
+//!         example.com:
+//!         - cert_path: cert.example.com.ecdsa.pem
+//!           key_path: key.example.com.ecdsa.pem
+//!         - cert_path: cert.example.com.rsa.pem
+//!           key_path: key.example.com.rsa.pem
+//! ```
+//!
+//! ## HTTP versions
+//!
+//! By default, the server only speaks HTTP/1.x. The `versions` flag of a listening address
+//! restricts the HTTP versions accepted on it, listed in the order of preference. Supported
+//! values are `http/1.1` and `h2`:
+//!
+//! ```yaml
+//! listen:
+//! - {addr: 0.0.0.0:443, tls: true, versions: [h2, http/1.1]}
+//! - {addr: 0.0.0.0:8443, tls: true, versions: h2}
+//! ```
+//!
+//! The versions are negotiated via ALPN during the TLS handshake. If a client offers none of the
+//! allowed versions, the handshake fails. Clients not using ALPN are always handled as HTTP/1.x.
+//!
+//! HTTP/2 requires TLS, listing `h2` for a plaintext address is a configuration error. Upgrading
+//! plaintext connections to HTTP/2 (h2c) isn’t supported.
+//!
 //! ## TLS Redirector configuration
 //!
 //! In order to simplify TLS setup, automatic redirection of non-HTTPS ports to TLS is supported.
@@ -142,6 +369,70 @@
 //!
 //! The incoming server name in the `redirect_by_name` setting does not depend on the port.
 //!
+//! The redirector gets as many worker threads as the main service by default. Since its job is
+//! very simple, you might want to give it fewer threads:
+//!
+//! ```yaml
+//! tls:
+//!     redirector:
+//!         threads: 1
+//! ```
+//!
+//! ## Additional services
+//!
+//! If your application needs to run additional Pingora services, e.g. periodic jobs implemented
+//! as background services, you can pass these to `StartupConf::into_server_with_services` method
+//! instead of `StartupConf::into_server`. These services will be registered with the server
+//! along with the main service, so that `run_forever()` starts all of them. The
+//! `background_service()` helper, `BackgroundService` and `Service` traits are re-exported by
+//! `pandora_module_utils::pingora`.
+//!
+//! ## Error handling
+//!
+//! `StartupConf::into_server` and its variants produce a [`StartupError`] if the configuration is
+//! invalid. This allows distinguishing e.g. invalid listen addresses
+//! ([`StartupError::InvalidListenAddr`]) from missing or broken certificates
+//! ([`StartupError::MissingCertificate`], [`StartupError::InvalidCertificate`]). Errors loading
+//! the configuration files can be wrapped into [`StartupError::Config`] for uniform handling:
+//!
+//! ```rust
+//! use pandora_module_utils::pingora::{ProxyHttp, Server};
+//! use pandora_module_utils::FromYaml;
+//! use startup_module::{StartupConf, StartupError};
+//!
+//! fn create_server<SV>(app: SV) -> Option<Server>
+//! where
+//!     SV: ProxyHttp + Send + Sync + 'static,
+//!     SV::CTX: Send + Sync,
+//! {
+//!     let result = StartupConf::load_from_files(["/etc/pandora/*.yaml"])
+//!         .map_err(StartupError::Config)
+//!         .and_then(|conf| conf.into_server(app, None));
+//!     match result {
+//!         Ok(server) => Some(server),
+//!         Err(StartupError::InvalidListenAddr { addr, reason }) => {
+//!             eprintln!("Please check listen address {addr}: {reason}");
+//!             None
+//!         }
+//!         Err(err) => {
+//!             eprintln!("{err}");
+//!             None
+//!         }
+//!     }
+//! }
+//! ```
+//!
+//! `StartupError` converts into Pingora’s `Box<Error>`, so the `?` operator works in functions
+//! returning the latter.
+//!
+//! ## Known limitations
+//!
+//! There is currently no timeout for receiving request headers, so clients sending their headers
+//! very slowly (“slow loris” attack) can keep connections open. Pingora reads request headers
+//! before any application code is invoked and doesn’t provide a way to limit the time spent doing
+//! so. Until this is addressed in Pingora, you might want to put a reverse proxy with such a
+//! timeout in front of the server if this is a concern.
+//!
 //! ## Code example
 //!
 //! ```rust
@@ -175,33 +466,410 @@
 //! ```
 
 mod configuration;
+mod error;
+#[cfg(unix)]
+mod privileges;
 mod redirector;
 
 use async_trait::async_trait;
 pub use configuration::{
-    CertKeyConf, ListenAddr, StartupConf, StartupOpt, TlsConf, TlsRedirectorConf,
+    CertKeyConf, HeaderCase, HttpVersion, ListenAddr, LogConf, ServerHeader, StartupConf,
+    StartupOpt, TlsConf, TlsRedirectorConf,
 };
-use http::Extensions;
+pub use error::StartupError;
+use http::{header, uri::Authority, Extensions, HeaderName, Method, StatusCode, Uri};
+use log::error;
 use pandora_module_utils::pingora::{
-    Error, HttpPeer, ProxyHttp, ResponseHeader, Session, SessionWrapper,
+    Bytes, Error, HttpPeer, ProxyHttp, RequestHeader, ResponseHeader, Session, SessionWrapper,
 };
+use pandora_module_utils::standard_response::error_response;
 use pandora_module_utils::{RequestFilter, RequestFilterResult};
-use pingora::ErrorType;
+use pingora::protocols::http::error_resp::gen_error_response;
+use pingora::{ErrorSource, ErrorType};
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
 use std::ops::{Deref, DerefMut};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::OnceLock;
+use std::time::{Duration, SystemTime};
+
+/// Default value of the `Allow` header in responses to `OPTIONS *` requests
+pub const DEFAULT_OPTIONS_ALLOW: &str = "GET, HEAD, POST, PUT, PATCH, DELETE, OPTIONS";
+
+/// Default maximal length of the request URI in bytes
+pub const DEFAULT_MAX_URI_LENGTH: usize = 8192;
+
+/// Maximal length of a request ID sent by the client for it to be reused
+const MAX_REQUEST_ID_LENGTH: usize = 200;
+
+/// Headers that only apply to a single connection and must not be forwarded, see RFC 9110
+/// section 7.6.1
+const HOP_BY_HOP_HEADERS: [&str; 7] = [
+    "connection",
+    "keep-alive",
+    "proxy-connection",
+    "proxy-authorization",
+    "te",
+    "trailer",
+    "upgrade",
+];
+
+/// Sets a forwarding header of the upstream request, appending the value to the existing ones if
+/// `append` is `true`. A `None` value removes the header unless existing values are kept.
+fn set_forwarded_header(
+    request: &mut RequestHeader,
+    name: &'static str,
+    value: Option<&str>,
+    append: bool,
+) -> Result<(), Box<Error>> {
+    let mut values = Vec::new();
+    if append {
+        values.extend(
+            request
+                .headers
+                .get_all(name)
+                .iter()
+                .filter_map(|value| value.to_str().ok())
+                .map(str::trim)
+                .filter(|value| !value.is_empty())
+                .map(str::to_owned),
+        );
+    }
+    values.extend(value.map(str::to_owned));
+
+    if values.is_empty() {
+        request.remove_header(name);
+    } else {
+        request.insert_header(name, values.join(", "))?;
+    }
+    Ok(())
+}
+
+/// Adds `X-Forwarded-For`, `X-Forwarded-Proto` and `X-Forwarded-Host` headers to a request that
+/// is about to be forwarded.
+fn add_forwarded_headers(
+    session: &impl SessionWrapper,
+    request: &mut RequestHeader,
+    host: Option<&str>,
+    trusted: bool,
+) -> Result<(), Box<Error>> {
+    let client_ip = session
+        .client_addr()
+        .and_then(|addr| addr.as_inet())
+        .map(|addr| addr.ip().to_string());
+    set_forwarded_header(request, "X-Forwarded-For", client_ip.as_deref(), trusted)?;
+
+    // These headers hold a single value, a trusted proxy’s value is kept as is
+    for (name, value) in [
+        ("X-Forwarded-Proto", Some(session.scheme(false))),
+        ("X-Forwarded-Host", host),
+    ] {
+        let existing = request
+            .headers
+            .get(name)
+            .is_some_and(|value| !value.is_empty());
+        if !trusted || !existing {
+            set_forwarded_header(request, name, value, false)?;
+        }
+    }
+    Ok(())
+}
+
+/// Removes hop-by-hop headers from a request that is about to be forwarded.
+///
+/// `Transfer-Encoding` and `Content-Length` are kept even if listed in the `Connection` header,
+/// Pingora needs these to determine how the request body is to be sent upstream. For protocol
+/// upgrade requests (e.g. WebSocket) the `Upgrade` header and `Connection: upgrade` are kept.
+fn strip_hop_by_hop_headers(request: &mut RequestHeader) {
+    let connection_tokens = request
+        .headers
+        .get_all(header::CONNECTION)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(|token| token.trim().to_ascii_lowercase())
+        .filter(|token| !token.is_empty())
+        .collect::<Vec<_>>();
+    let upgrade = request
+        .headers
+        .get(header::UPGRADE)
+        .filter(|_| connection_tokens.iter().any(|token| token == "upgrade"))
+        .cloned();
+
+    let names = HOP_BY_HOP_HEADERS
+        .iter()
+        .copied()
+        .chain(connection_tokens.iter().map(String::as_str))
+        .filter(|name| {
+            *name != header::TRANSFER_ENCODING.as_str() && *name != header::CONTENT_LENGTH.as_str()
+        })
+        .collect::<Vec<_>>();
+    for name in names {
+        request.remove_header(name);
+    }
+
+    if let Some(upgrade) = upgrade {
+        // Header names and values are valid, this won’t fail
+        let _ = request.insert_header(header::CONNECTION, "upgrade");
+        let _ = request.insert_header(header::UPGRADE, upgrade);
+    }
+}
+
+/// ID of the current request
+///
+/// If request IDs are enabled via [`DefaultApp::with_request_id_header`], handlers can retrieve
+/// it from session extensions: `session.extensions().get::<RequestId>()`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RequestId(pub String);
+
+impl RequestId {
+    /// Generates a new request ID, unique for this process and unlikely to repeat across
+    /// processes.
+    fn generate() -> Self {
+        static PREFIX: OnceLock<u64> = OnceLock::new();
+        static COUNTER: AtomicU64 = AtomicU64::new(0);
+
+        let prefix = PREFIX.get_or_init(|| {
+            let mut hasher = RandomState::new().build_hasher();
+            if let Ok(time) = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH) {
+                hasher.write_u128(time.as_nanos());
+            }
+            hasher.finish()
+        });
+        let counter = COUNTER.fetch_add(1, Ordering::Relaxed);
+        Self(format!("{prefix:016x}{counter:016x}"))
+    }
+
+    /// Takes over a request ID sent by the client if it is reasonably short and contains only
+    /// visible ASCII characters.
+    fn from_client(value: &[u8]) -> Option<Self> {
+        if !value.is_empty()
+            && value.len() <= MAX_REQUEST_ID_LENGTH
+            && value.iter().all(|b| b.is_ascii_graphic())
+        {
+            Some(Self(String::from_utf8_lossy(value).into_owned()))
+        } else {
+            None
+        }
+    }
+}
+
+/// Error type used to abort responses with an upstream body not matching its `Content-Length`
+const CONTENT_LENGTH_ERR: ErrorType = ErrorType::Custom("ContentLengthMismatch");
+
+/// Determines the body length the upstream server declared for its response, `None` if the
+/// response has no body or its length isn’t known in advance.
+fn declared_body_length(request: &RequestHeader, response: &ResponseHeader) -> Option<u64> {
+    if request.method == Method::HEAD
+        || response.status.is_informational()
+        || response.status == StatusCode::NO_CONTENT
+        || response.status == StatusCode::NOT_MODIFIED
+        || response.headers.contains_key(header::TRANSFER_ENCODING)
+    {
+        return None;
+    }
+
+    response
+        .headers
+        .get(header::CONTENT_LENGTH)?
+        .to_str()
+        .ok()?
+        .trim()
+        .parse()
+        .ok()
+}
 
 /// A basic Pingora app implementation, to be passed to [`StartupConf::into_server`]
 ///
-/// This app will only handle the `request_filter`, `upstream_peer`, `upstream_response_filter` and
-/// `logging` phases. All processing will be delegated to the respective `RequestFilter` methods.
+/// This app will only handle the `request_filter`, `upstream_peer`, `upstream_request_filter`,
+/// `upstream_response_filter`, `upstream_response_body_filter` and `logging` phases. All
+/// processing will be delegated to the respective `RequestFilter` methods.
+///
+/// If the upstream server declares a `Content-Length` for its response but sends a different
+/// number of bytes, an error is logged and the response is aborted rather than forwarded. The
+/// handler’s `response_body_filter` won’t see any further body chunks then, so that handlers
+/// like the Cache module don’t process the malformed body.
+///
+/// Hop-by-hop headers like `Connection`, `Keep-Alive` or headers listed in the `Connection`
+/// header are removed from requests before these are forwarded to the upstream server and before
+/// the handler’s `upstream_request_filter` is called.
+///
+/// If the handler doesn’t produce an upstream peer for a request, a 502 Bad Gateway response is
+/// sent. By default this is the standard error page, a custom message can be set via
+/// [`DefaultApp::with_no_peer_message`].
+///
+/// Response header names are sent as produced by the handler or upstream server, this can be
+/// changed via [`DefaultApp::with_header_case`]. Request IDs can be enabled via
+/// [`DefaultApp::with_request_id_header`], the `Server` header can be changed via
+/// [`DefaultApp::with_server_header`].
+///
+/// Requests with a malformed `Host` header are rejected with `400 Bad Request` unless allowed via
+/// [`DefaultApp::with_allow_invalid_host`]. Requests with a URI longer than
+/// [`DEFAULT_MAX_URI_LENGTH`] are rejected with `414 URI Too Long`, the limit can be changed via
+/// [`DefaultApp::with_max_uri_length`].
+///
+/// `OPTIONS *` requests (asterisk-form request target) are answered with a server-wide
+/// `204 No Content` response without calling the handler, see [`DefaultApp::with_options_allow`].
+///
+/// An idle timeout for HTTP/1.x client connections can be announced via
+/// [`DefaultApp::with_downstream_keepalive_timeout`].
+///
+/// [`DefaultApp::with_startup_conf`] applies all of these settings from the startup
+/// configuration at once.
 #[derive(Debug)]
 pub struct DefaultApp<H> {
     handler: H,
+    no_peer_message: Option<String>,
+    header_case: HeaderCase,
+    request_id_header: Option<HeaderName>,
+    server_header: ServerHeader,
+    allow_invalid_host: bool,
+    max_uri_length: usize,
+    forwarded_headers: bool,
+    trust_forwarded_headers: bool,
+    options_allow: Option<String>,
+    downstream_keepalive_timeout: Option<u64>,
 }
 
 impl<H> DefaultApp<H> {
     /// Creates a new app from a [`RequestFilter`] instance.
     pub fn new(handler: H) -> Self {
-        Self { handler }
+        Self {
+            handler,
+            no_peer_message: None,
+            header_case: HeaderCase::Preserve,
+            request_id_header: None,
+            server_header: ServerHeader::Preserve,
+            allow_invalid_host: false,
+            max_uri_length: DEFAULT_MAX_URI_LENGTH,
+            forwarded_headers: false,
+            trust_forwarded_headers: false,
+            options_allow: Some(DEFAULT_OPTIONS_ALLOW.to_owned()),
+            downstream_keepalive_timeout: None,
+        }
+    }
+
+    /// Applies the request processing settings of the startup configuration: `header_case`,
+    /// `request_id_header`, `server_header`, `allow_invalid_host`, `max_uri_length`,
+    /// `forwarded_headers`, `trust_forwarded_headers` and `downstream_keepalive_timeout`.
+    ///
+    /// These settings are implemented by the app rather than the server, so
+    /// [`StartupConf::into_server`] cannot apply them.
+    pub fn with_startup_conf(self, conf: &StartupConf) -> Self {
+        self.with_header_case(conf.header_case)
+            .with_request_id_header(conf.request_id_header.clone())
+            .with_server_header(conf.server_header.clone())
+            .with_allow_invalid_host(conf.allow_invalid_host)
+            .with_max_uri_length(conf.max_uri_length)
+            .with_forwarded_headers(conf.forwarded_headers)
+            .with_trust_forwarded_headers(conf.trust_forwarded_headers)
+            .with_downstream_keepalive_timeout(conf.downstream_keepalive_timeout)
+    }
+
+    /// Sets the plain text message to be sent with the 502 Bad Gateway response if the handler
+    /// doesn’t produce an upstream peer.
+    pub fn with_no_peer_message(mut self, message: impl Into<String>) -> Self {
+        self.no_peer_message = Some(message.into());
+        self
+    }
+
+    /// Sets the letter case of header names in HTTP/1.x responses, usually the value of the
+    /// `header_case` setting in [`StartupConf`].
+    pub fn with_header_case(mut self, header_case: HeaderCase) -> Self {
+        self.header_case = header_case;
+        self
+    }
+
+    /// Sets the name of the request ID header, usually the value of the `request_id_header`
+    /// setting in [`StartupConf`]. `None` disables request IDs.
+    ///
+    /// If enabled, the request ID sent by the client in this header is reused, otherwise a new
+    /// one is generated. The ID is added to the request sent to the upstream server and to the
+    /// response. Handlers can access it as [`RequestId`] in session extensions.
+    pub fn with_request_id_header(mut self, request_id_header: Option<HeaderName>) -> Self {
+        self.request_id_header = request_id_header;
+        self
+    }
+
+    /// Sets the handling of the `Server` response header, usually the value of the
+    /// `server_header` setting in [`StartupConf`].
+    pub fn with_server_header(mut self, server_header: ServerHeader) -> Self {
+        self.server_header = server_header;
+        self
+    }
+
+    /// Determines whether requests with a malformed `Host` header are passed on to the handler,
+    /// usually the value of the `allow_invalid_host` setting in [`StartupConf`]. Such requests
+    /// are rejected with `400 Bad Request` by default.
+    pub fn with_allow_invalid_host(mut self, allow_invalid_host: bool) -> Self {
+        self.allow_invalid_host = allow_invalid_host;
+        self
+    }
+
+    /// Sets the maximal length of the request URI in bytes, usually the value of the
+    /// `max_uri_length` setting in [`StartupConf`]. Requests with longer URIs are rejected with
+    /// `414 URI Too Long`. `None` keeps the default limit of [`DEFAULT_MAX_URI_LENGTH`].
+    pub fn with_max_uri_length(mut self, max_uri_length: Option<usize>) -> Self {
+        self.max_uri_length = max_uri_length.unwrap_or(DEFAULT_MAX_URI_LENGTH);
+        self
+    }
+
+    /// Determines whether `X-Forwarded-For`, `X-Forwarded-Proto` and `X-Forwarded-Host` headers
+    /// are added to requests sent to the upstream server, usually the value of the
+    /// `forwarded_headers` setting in [`StartupConf`].
+    pub fn with_forwarded_headers(mut self, forwarded_headers: bool) -> Self {
+        self.forwarded_headers = forwarded_headers;
+        self
+    }
+
+    /// Determines whether `X-Forwarded-*` headers sent by the client are kept (`X-Forwarded-For`
+    /// appended to) rather than replaced, usually the value of the `trust_forwarded_headers` setting in
+    /// [`StartupConf`]. Only enable this if the server is behind a trusted proxy.
+    pub fn with_trust_forwarded_headers(mut self, trust_forwarded_headers: bool) -> Self {
+        self.trust_forwarded_headers = trust_forwarded_headers;
+        self
+    }
+
+    /// Sets the value of the `Allow` header sent in response to `OPTIONS *` requests,
+    /// [`DEFAULT_OPTIONS_ALLOW`] by default. `None` disables special handling of these requests,
+    /// these will be passed on to the handler like any other request.
+    pub fn with_options_allow(mut self, options_allow: Option<String>) -> Self {
+        self.options_allow = options_allow;
+        self
+    }
+
+    /// Sets the time in seconds after which clients should close idle HTTP/1.x connections,
+    /// usually the value of the `downstream_keepalive_timeout` setting in [`StartupConf`]. The
+    /// timeout is only announced via the `Keep-Alive` response header, it isn’t enforced. `None`
+    /// means that no timeout is announced.
+    pub fn with_downstream_keepalive_timeout(mut self, timeout: Option<u64>) -> Self {
+        self.downstream_keepalive_timeout = timeout;
+        self
+    }
+
+    /// Adds the `Keep-Alive` header to a response if an idle timeout is configured and the
+    /// connection will be kept open.
+    fn add_keepalive(&self, session: &Session, response: &mut ResponseHeader) {
+        if let Some(timeout) = self.downstream_keepalive_timeout {
+            if !response.status.is_informational()
+                && session
+                    .as_http1()
+                    .is_some_and(|session| session.will_keepalive())
+            {
+                // This value is always valid, adding the header won’t fail
+                let _ = response.insert_header(header::KEEP_ALIVE, format!("timeout={timeout}"));
+            }
+        }
+    }
+
+    /// Adds the request ID header to a response if request IDs are enabled.
+    fn add_request_id(&self, extensions: &Extensions, response: &mut ResponseHeader) {
+        if let (Some(name), Some(RequestId(id))) =
+            (&self.request_id_header, extensions.get::<RequestId>())
+        {
+            // Request IDs are validated, adding the header won’t fail
+            let _ = response.insert_header(name.clone(), id);
+        }
     }
 
     /// Creates a new app from a [`RequestFilter`] configuration.
@@ -213,6 +881,124 @@ impl<H> DefaultApp<H> {
     {
         Ok(Self::new(conf.try_into()?))
     }
+
+    /// Rejects requests with a malformed `Host` header or an overlong URI before any handlers
+    /// see them.
+    fn check_request(&self, session: &Session) -> Result<(), Box<Error>> {
+        if !self.allow_invalid_host && !is_host_valid(session.req_header()) {
+            return Err(Error::explain(
+                ErrorType::HTTPStatus(StatusCode::BAD_REQUEST.as_u16()),
+                "malformed Host header",
+            ));
+        }
+
+        let length = uri_length(&session.req_header().uri);
+        if length > self.max_uri_length {
+            return Err(Error::explain(
+                ErrorType::HTTPStatus(StatusCode::URI_TOO_LONG.as_u16()),
+                format!(
+                    "request URI too long ({length} bytes, limit {})",
+                    self.max_uri_length
+                ),
+            ));
+        }
+        Ok(())
+    }
+
+    /// Sends the server-wide response to an `OPTIONS *` request if such responses are enabled.
+    /// Returns `true` if a response has been sent.
+    async fn options_response(
+        &self,
+        session: &mut impl SessionWrapper,
+    ) -> Result<bool, Box<Error>> {
+        let allow = match &self.options_allow {
+            Some(allow)
+                if session.req_header().method == Method::OPTIONS
+                    && session.req_header().uri.path() == "*" =>
+            {
+                allow
+            }
+            _ => return Ok(false),
+        };
+
+        let mut header = ResponseHeader::build(StatusCode::NO_CONTENT, Some(1))?;
+        header.append_header(header::ALLOW, allow)?;
+        session.write_response_header(Box::new(header)).await?;
+        Ok(true)
+    }
+
+    /// Sends the response for requests without an upstream peer.
+    async fn no_peer_response(&self, session: &mut impl SessionWrapper) -> Result<(), Box<Error>> {
+        let status = StatusCode::BAD_GATEWAY;
+        let text = if let Some(text) = &self.no_peer_message {
+            text
+        } else {
+            return error_response(session, status).await;
+        };
+
+        let mut header = ResponseHeader::build(status, Some(2))?;
+        header.append_header(header::CONTENT_LENGTH, text.len().to_string())?;
+        header.append_header(header::CONTENT_TYPE, "text/plain; charset=utf-8")?;
+        session.write_response_header(Box::new(header)).await?;
+
+        if session.req_header().method != Method::HEAD {
+            session.write_response_body(text.clone().into()).await?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Maximal length of the host name in the `Host` header, the limit for DNS names
+const MAX_HOST_LENGTH: usize = 253;
+
+/// Determines the length of the request URI as sent by the client.
+fn uri_length(uri: &Uri) -> usize {
+    uri.scheme_str()
+        .map_or(0, |scheme| scheme.len() + "://".len())
+        + uri
+            .authority()
+            .map_or(0, |authority| authority.as_str().len())
+        + uri.path_and_query().map_or(0, |path| path.as_str().len())
+}
+
+/// Checks whether the `Host` header of the request is a valid host name with an optional port.
+/// Requests without a `Host` header or with an empty one are considered valid, multiple `Host`
+/// headers are not.
+fn is_host_valid(request: &RequestHeader) -> bool {
+    let mut values = request.headers.get_all(header::HOST).iter();
+    let value = match (values.next(), values.next()) {
+        (None, _) => return true,
+        (Some(value), None) => value,
+        (Some(_), Some(_)) => return false,
+    };
+
+    // Empty value is allowed for requests without an authority
+    if value.is_empty() {
+        return true;
+    }
+
+    let authority = match value.to_str().map(Authority::try_from) {
+        Ok(Ok(authority)) => authority,
+        _ => return false,
+    };
+
+    let host = authority.host();
+    if host.is_empty() || host.len() > MAX_HOST_LENGTH || authority.as_str().contains('@') {
+        return false;
+    }
+
+    if let Some(address) = host.strip_prefix('[') {
+        if !address
+            .strip_suffix(']')
+            .is_some_and(|address| address.parse::<std::net::Ipv6Addr>().is_ok())
+        {
+            return false;
+        }
+    }
+
+    // Authority parsing doesn’t validate the port, an empty port is allowed however
+    authority.as_str().len() <= host.len() + 1 || authority.port_u16().is_some()
 }
 
 /// Context for the default app
@@ -220,6 +1006,11 @@ impl<H> DefaultApp<H> {
 pub struct DefaultCtx<C> {
     extensions: Extensions,
     handler: C,
+    no_peer: bool,
+    expected_body_length: Option<u64>,
+    received_body_length: u64,
+    body_length_mismatch: bool,
+    forwarded_host: Option<String>,
 }
 
 #[async_trait]
@@ -234,6 +1025,11 @@ where
         Self::CTX {
             extensions: Extensions::new(),
             handler: H::new_ctx(),
+            no_peer: false,
+            expected_body_length: None,
+            received_body_length: 0,
+            body_length_mismatch: false,
+            forwarded_host: None,
         }
     }
 
@@ -242,12 +1038,39 @@ where
         session: &mut Session,
         ctx: &mut Self::CTX,
     ) -> Result<bool, Box<Error>> {
-        let mut session = SessionWrapperImpl::new(session, &self.handler, &mut ctx.extensions);
-        Ok(self
+        self.check_request(session)?;
+
+        if let Some(name) = &self.request_id_header {
+            let id = session
+                .req_header()
+                .headers
+                .get(name)
+                .and_then(|value| RequestId::from_client(value.as_bytes()))
+                .unwrap_or_else(RequestId::generate);
+            ctx.extensions.insert(id);
+        }
+
+        let mut session = SessionWrapperImpl::new(session, self, &mut ctx.extensions);
+        if self.forwarded_headers {
+            // Handlers like the Upstream Module might change the Host header, keep the original.
+            ctx.forwarded_host = session.host().map(Into::into);
+        }
+
+        if self.options_response(&mut session).await? {
+            return Ok(true);
+        }
+
+        let result = self
             .handler
             .request_filter(&mut session, &mut ctx.handler)
-            .await?
-            == RequestFilterResult::ResponseSent)
+            .await?;
+        if result == RequestFilterResult::ResponseSent {
+            Ok(true)
+        } else {
+            // The request will be passed on upstream, let the client send the request body.
+            session.send_continue().await?;
+            Ok(false)
+        }
     }
 
     async fn upstream_peer(
@@ -255,7 +1078,7 @@ where
         session: &mut Session,
         ctx: &mut Self::CTX,
     ) -> Result<Box<HttpPeer>, Box<Error>> {
-        let mut session = SessionWrapperImpl::new(session, &self.handler, &mut ctx.extensions);
+        let mut session = SessionWrapperImpl::new(session, self, &mut ctx.extensions);
         let result = self
             .handler
             .upstream_peer(&mut session, &mut ctx.handler)
@@ -263,23 +1086,153 @@ where
         if let Some(result) = result {
             Ok(result)
         } else {
-            Err(Error::new(ErrorType::HTTPStatus(404)))
+            ctx.no_peer = true;
+            Err(Error::explain(
+                ErrorType::HTTPStatus(StatusCode::BAD_GATEWAY.as_u16()),
+                "handler did not produce an upstream peer",
+            ))
         }
     }
 
+    async fn upstream_request_filter(
+        &self,
+        session: &mut Session,
+        upstream_request: &mut RequestHeader,
+        ctx: &mut Self::CTX,
+    ) -> Result<(), Box<Error>> {
+        strip_hop_by_hop_headers(upstream_request);
+        if let (Some(name), Some(RequestId(id))) =
+            (&self.request_id_header, ctx.extensions.get::<RequestId>())
+        {
+            upstream_request.insert_header(name.clone(), id)?;
+        }
+
+        let mut session = SessionWrapperImpl::new(session, self, &mut ctx.extensions);
+        if self.forwarded_headers {
+            add_forwarded_headers(
+                &session,
+                upstream_request,
+                ctx.forwarded_host.as_deref(),
+                self.trust_forwarded_headers,
+            )?;
+        }
+
+        self.handler
+            .upstream_request_filter(&mut session, upstream_request, &mut ctx.handler)
+            .await
+    }
+
+    async fn fail_to_proxy(&self, session: &mut Session, e: &Error, ctx: &mut Self::CTX) -> u16
+    where
+        Self::CTX: Send + Sync,
+    {
+        if ctx.no_peer {
+            let mut session = SessionWrapperImpl::new(session, self, &mut ctx.extensions);
+            // The client might be gone already, nothing to be done about errors here.
+            let _ = self.no_peer_response(&mut session).await;
+            return StatusCode::BAD_GATEWAY.as_u16();
+        }
+
+        // Same logic as the default implementation
+        let code = match e.etype() {
+            ErrorType::HTTPStatus(code) => *code,
+            _ => match e.esource() {
+                ErrorSource::Upstream => 502,
+                ErrorSource::Downstream => match e.etype() {
+                    ErrorType::WriteError | ErrorType::ReadError | ErrorType::ConnectionClosed => 0,
+                    _ => 400,
+                },
+                ErrorSource::Internal | ErrorSource::Unset => 500,
+            },
+        };
+        if code > 0 {
+            // Same as Session::respond_error but with the Server header adjusted
+            let mut response = gen_error_response(code);
+            self.server_header.apply(&mut response);
+            let downstream = session.as_mut();
+            downstream.set_keepalive(None);
+            if let Err(err) = downstream.write_response_header(Box::new(response)).await {
+                error!("failed to send error response to downstream: {err}");
+            }
+        }
+        code
+    }
+
     fn upstream_response_filter(
         &self,
         session: &mut Session,
         response: &mut ResponseHeader,
         ctx: &mut Self::CTX,
     ) {
-        let mut session = SessionWrapperImpl::new(session, &self.handler, &mut ctx.extensions);
+        // Handlers might change Content-Length, the value sent by upstream is relevant here
+        ctx.expected_body_length = declared_body_length(session.req_header(), response);
+        ctx.received_body_length = 0;
+
+        let mut session = SessionWrapperImpl::new(session, self, &mut ctx.extensions);
+        self.handler
+            .response_filter(&mut session, response, Some(&mut ctx.handler));
+        self.add_request_id(&ctx.extensions, response);
+        self.add_keepalive(session, response);
+        self.server_header.apply(response);
+        self.header_case.apply(response);
+    }
+
+    fn upstream_response_body_filter(
+        &self,
+        session: &mut Session,
+        body: &mut Option<Bytes>,
+        end_of_stream: bool,
+        ctx: &mut Self::CTX,
+    ) {
+        if let Some(expected) = ctx.expected_body_length {
+            ctx.received_body_length += body.as_ref().map_or(0, |body| body.len() as u64);
+            if ctx.received_body_length > expected
+                || (end_of_stream && ctx.received_body_length < expected)
+            {
+                error!(
+                    "upstream response body length mismatch: Content-Length is {expected}, \
+                     received {} bytes{}",
+                    ctx.received_body_length,
+                    if end_of_stream { "" } else { " so far" }
+                );
+                ctx.expected_body_length = None;
+                ctx.body_length_mismatch = true;
+            }
+        }
+
+        if ctx.body_length_mismatch {
+            *body = None;
+            return;
+        }
+
+        let mut session = SessionWrapperImpl::new(session, self, &mut ctx.extensions);
         self.handler
-            .response_filter(&mut session, response, Some(&mut ctx.handler))
+            .response_body_filter(&mut session, body, end_of_stream, &mut ctx.handler)
+    }
+
+    fn response_body_filter(
+        &self,
+        _session: &mut Session,
+        _body: &mut Option<Bytes>,
+        _end_of_stream: bool,
+        ctx: &mut Self::CTX,
+    ) -> Result<Option<Duration>, Box<Error>>
+    where
+        Self::CTX: Send + Sync,
+    {
+        if ctx.body_length_mismatch {
+            Err(Error::explain(
+                CONTENT_LENGTH_ERR,
+                "upstream response body doesn’t match its Content-Length",
+            )
+            .into_up())
+        } else {
+            Ok(None)
+        }
     }
 
     async fn logging(&self, session: &mut Session, e: Option<&Error>, ctx: &mut Self::CTX) {
-        let mut session = SessionWrapperImpl::new(session, &self.handler, &mut ctx.extensions);
+        let mut session = SessionWrapperImpl::new(session, self, &mut ctx.extensions);
         self.handler
             .logging(&mut session, e, &mut ctx.handler)
             .await
@@ -288,19 +1241,19 @@ where
 
 struct SessionWrapperImpl<'a, H> {
     inner: &'a mut Session,
-    handler: &'a H,
+    app: &'a DefaultApp<H>,
     extensions: &'a mut Extensions,
 }
 
 impl<'a, H> SessionWrapperImpl<'a, H> {
     /// Creates a new session wrapper for the given Pingora session.
-    fn new(inner: &'a mut Session, handler: &'a H, extensions: &'a mut Extensions) -> Self
+    fn new(inner: &'a mut Session, app: &'a DefaultApp<H>, extensions: &'a mut Extensions) -> Self
     where
         H: RequestFilter,
     {
         Self {
             inner,
-            handler,
+            app,
             extensions,
         }
     }
@@ -310,7 +1263,7 @@ impl<'a, H> SessionWrapperImpl<'a, H> {
 impl<H> SessionWrapper for SessionWrapperImpl<'_, H>
 where
     H: RequestFilter,
-    for<'a> &'a H: Send,
+    for<'a> &'a DefaultApp<H>: Send,
 {
     fn extensions(&self) -> &Extensions {
         self.extensions
@@ -324,7 +1277,17 @@ where
         &mut self,
         mut resp: Box<ResponseHeader>,
     ) -> Result<(), Box<Error>> {
-        self.handler.response_filter(self, &mut resp, None);
+        if !resp.status.is_informational() && self.expects_continue() {
+            // The client might send the request body after this response or not, the
+            // connection cannot be reused.
+            self.set_keepalive(None);
+        }
+
+        self.app.handler.response_filter(self, &mut resp, None);
+        self.app.add_request_id(self.extensions, &mut resp);
+        self.app.add_keepalive(self.inner, &mut resp);
+        self.app.server_header.apply(&mut resp);
+        self.app.header_case.apply(&mut resp);
 
         self.deref_mut().write_response_header(resp).await
     }
@@ -347,3 +1310,769 @@ impl<H> DerefMut for SessionWrapperImpl<'_, H> {
         self.inner
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use pandora_module_utils::pingora::{SocketAddr, TestSession};
+    use pandora_module_utils::standard_response::response_text;
+    use pandora_module_utils::{DeserializeMap, FromYaml};
+    use std::ops::Deref;
+    use test_log::test;
+
+    #[derive(Debug, Default, Clone, PartialEq, Eq, DeserializeMap)]
+    struct TestConf {}
+
+    #[derive(Debug)]
+    struct TestHandler;
+
+    impl TryFrom<TestConf> for TestHandler {
+        type Error = Box<Error>;
+
+        fn try_from(_conf: TestConf) -> Result<Self, Self::Error> {
+            Ok(TestHandler)
+        }
+    }
+
+    #[async_trait]
+    impl RequestFilter for TestHandler {
+        type Conf = TestConf;
+        type CTX = ();
+        fn new_ctx() -> Self::CTX {}
+
+        async fn upstream_request_filter(
+            &self,
+            session: &mut impl SessionWrapper,
+            upstream_request: &mut RequestHeader,
+            _ctx: &mut Self::CTX,
+        ) -> Result<(), Box<Error>> {
+            if let Some(addr) = session.client_addr().and_then(|addr| addr.as_inet()) {
+                upstream_request.insert_header("X-Forwarded-For", addr.ip().to_string())?;
+            }
+            Ok(())
+        }
+    }
+
+    async fn make_session(method: &str) -> TestSession {
+        let header = RequestHeader::build(method, b"/", None).unwrap();
+        TestSession::from(header).await
+    }
+
+    #[test(tokio::test)]
+    async fn no_peer() {
+        let app = DefaultApp::new(TestHandler).with_no_peer_message("No backend here");
+        let mut session = make_session("GET").await;
+        let mut ctx = app.new_ctx();
+
+        let err = app.upstream_peer(&mut session, &mut ctx).await.unwrap_err();
+        assert_eq!(err.etype(), &ErrorType::HTTPStatus(502));
+
+        assert_eq!(app.fail_to_proxy(&mut session, &err, &mut ctx).await, 502);
+        let response = session.deref().response_written().unwrap();
+        assert_eq!(response.status, 502);
+        assert_eq!(response.headers.get(header::CONTENT_LENGTH).unwrap(), "15");
+        assert_eq!(
+            response.headers.get(header::CONTENT_TYPE).unwrap(),
+            "text/plain; charset=utf-8"
+        );
+
+        // Check response body as received by the client
+        let mut session = make_session("GET").await;
+        app.no_peer_response(&mut session).await.unwrap();
+        assert_eq!(session.response_header.unwrap().status, 502);
+        assert_eq!(session.response_body, "No backend here");
+
+        let mut session = make_session("HEAD").await;
+        app.no_peer_response(&mut session).await.unwrap();
+        assert_eq!(session.response_header.unwrap().status, 502);
+        assert!(session.response_body.is_empty());
+    }
+
+    #[test(tokio::test)]
+    async fn no_peer_default() {
+        let app = DefaultApp::new(TestHandler);
+        let mut session = make_session("GET").await;
+        let mut ctx = app.new_ctx();
+
+        let err = app.upstream_peer(&mut session, &mut ctx).await.unwrap_err();
+        assert_eq!(app.fail_to_proxy(&mut session, &err, &mut ctx).await, 502);
+
+        let mut session = make_session("GET").await;
+        app.no_peer_response(&mut session).await.unwrap();
+        let response = session.response_header.unwrap();
+        assert_eq!(response.status, 502);
+        assert_eq!(
+            response.headers.get(header::CONTENT_TYPE).unwrap(),
+            "text/html; charset=utf-8"
+        );
+        assert_eq!(
+            session.response_body,
+            response_text(StatusCode::BAD_GATEWAY)
+        );
+    }
+
+    #[test(tokio::test)]
+    async fn other_errors() {
+        let app = DefaultApp::new(TestHandler);
+        for (err, expected) in [
+            (Error::new(ErrorType::HTTPStatus(403)), 403),
+            (Error::new(ErrorType::ConnectTimedout).into_up(), 502),
+            (Error::new(ErrorType::InvalidHTTPHeader).into_down(), 400),
+            (Error::new(ErrorType::ReadError).into_down(), 0),
+            (Error::new(ErrorType::InternalError), 500),
+        ] {
+            let mut session = make_session("GET").await;
+            let mut ctx = app.new_ctx();
+            assert_eq!(
+                app.fail_to_proxy(&mut session, &err, &mut ctx).await,
+                expected
+            );
+
+            let status = session
+                .deref()
+                .response_written()
+                .map(|response| response.status.as_u16());
+            assert_eq!(status, Some(expected).filter(|code| *code > 0));
+        }
+    }
+
+    #[test(tokio::test)]
+    async fn content_length_mismatch() {
+        let app = DefaultApp::new(TestHandler);
+
+        // Returns the result of the response body filter for each chunk
+        let send_body = |chunks: &[&str], content_length: Option<&str>| {
+            let chunks = chunks
+                .iter()
+                .map(|chunk| Bytes::copy_from_slice(chunk.as_bytes()))
+                .collect::<Vec<_>>();
+            let content_length = content_length.map(str::to_owned);
+            let app = &app;
+            async move {
+                let mut session = make_session("GET").await;
+                let mut ctx = app.new_ctx();
+
+                let mut response = ResponseHeader::build(200, None).unwrap();
+                if let Some(content_length) = content_length {
+                    response
+                        .insert_header(header::CONTENT_LENGTH, content_length)
+                        .unwrap();
+                }
+                app.upstream_response_filter(&mut session, &mut response, &mut ctx);
+
+                let mut results = Vec::new();
+                let count = chunks.len();
+                for (i, chunk) in chunks.into_iter().enumerate() {
+                    let mut body = Some(chunk);
+                    let end_of_stream = i == count - 1;
+                    app.upstream_response_body_filter(
+                        &mut session,
+                        &mut body,
+                        end_of_stream,
+                        &mut ctx,
+                    );
+                    results.push(
+                        app.response_body_filter(&mut session, &mut body, end_of_stream, &mut ctx)
+                            .is_ok(),
+                    );
+                }
+                results
+            }
+        };
+
+        assert_eq!(send_body(&["abc", "def"], Some("6")).await, [true, true]);
+        assert_eq!(send_body(&["abc", "def"], None).await, [true, true]);
+
+        // Under-length body is caught at the end of the stream
+        assert_eq!(send_body(&["abc", "de"], Some("6")).await, [true, false]);
+        assert_eq!(send_body(&[""], Some("6")).await, [false]);
+
+        // Over-length body is caught as soon as the limit is exceeded
+        assert_eq!(
+            send_body(&["abc", "defg", "h"], Some("6")).await,
+            [true, false, false]
+        );
+    }
+
+    #[test(tokio::test)]
+    async fn upstream_request() {
+        let app = DefaultApp::new(TestHandler);
+        let mut session = make_session("GET").await;
+        let mut ctx = app.new_ctx();
+
+        // Client address set by a handler during request_filter phase
+        let addr: SocketAddr = "192.0.2.1:1234".parse().unwrap();
+        ctx.extensions.insert(addr);
+
+        let mut upstream_request = session.req_header().clone();
+        app.upstream_request_filter(&mut session, &mut upstream_request, &mut ctx)
+            .await
+            .unwrap();
+        assert_eq!(
+            upstream_request.headers.get("X-Forwarded-For").unwrap(),
+            "192.0.2.1"
+        );
+
+        // Downstream request is unchanged
+        assert!(session
+            .req_header()
+            .headers
+            .get("X-Forwarded-For")
+            .is_none());
+    }
+
+    #[test(tokio::test)]
+    async fn forwarded_headers() {
+        #[derive(Debug)]
+        struct HostHandler;
+
+        impl TryFrom<TestConf> for HostHandler {
+            type Error = Box<Error>;
+
+            fn try_from(_conf: TestConf) -> Result<Self, Self::Error> {
+                Ok(HostHandler)
+            }
+        }
+
+        #[async_trait]
+        impl RequestFilter for HostHandler {
+            type Conf = TestConf;
+            type CTX = ();
+            fn new_ctx() -> Self::CTX {}
+
+            async fn request_filter(
+                &self,
+                session: &mut impl SessionWrapper,
+                _ctx: &mut Self::CTX,
+            ) -> Result<RequestFilterResult, Box<Error>> {
+                // Same as the Upstream Module does
+                session
+                    .req_header_mut()
+                    .insert_header(header::HOST, "backend.internal")?;
+                Ok(RequestFilterResult::Handled)
+            }
+        }
+
+        async fn forward(
+            app: &DefaultApp<HostHandler>,
+            headers: &[(&'static str, &str)],
+        ) -> RequestHeader {
+            let mut header = RequestHeader::build("GET", b"/", None).unwrap();
+            header.insert_header(header::HOST, "example.com").unwrap();
+            for (name, value) in headers {
+                header.append_header(*name, *value).unwrap();
+            }
+            let mut session = TestSession::from(header).await;
+            let mut ctx = app.new_ctx();
+            assert!(!app.request_filter(&mut session, &mut ctx).await.unwrap());
+
+            let addr: SocketAddr = "192.0.2.1:1234".parse().unwrap();
+            ctx.extensions.insert(addr);
+
+            let mut upstream_request = session.req_header().clone();
+            app.upstream_request_filter(&mut session, &mut upstream_request, &mut ctx)
+                .await
+                .unwrap();
+            upstream_request
+        }
+
+        fn values<'a>(request: &'a RequestHeader, name: &str) -> Vec<&'a str> {
+            request
+                .headers
+                .get_all(name)
+                .iter()
+                .map(|value| value.to_str().unwrap())
+                .collect()
+        }
+
+        let client_headers = [
+            ("X-Forwarded-For", "10.0.0.1"),
+            ("X-Forwarded-For", "10.0.0.2, 10.0.0.3"),
+            ("X-Forwarded-Proto", "https"),
+            ("X-Forwarded-Host", "public.example.com"),
+        ];
+
+        // Headers are left alone by default
+        let app = DefaultApp::new(HostHandler);
+        let request = forward(&app, &client_headers).await;
+        assert_eq!(
+            values(&request, "X-Forwarded-For"),
+            ["10.0.0.1", "10.0.0.2, 10.0.0.3"]
+        );
+        assert_eq!(values(&request, "X-Forwarded-Proto"), ["https"]);
+        assert_eq!(values(&request, "X-Forwarded-Host"), ["public.example.com"]);
+
+        // Untrusted headers are replaced
+        let app = DefaultApp::new(HostHandler).with_forwarded_headers(true);
+        for headers in [&client_headers[..], &[][..]] {
+            let request = forward(&app, headers).await;
+            assert_eq!(values(&request, "X-Forwarded-For"), ["192.0.2.1"]);
+            assert_eq!(values(&request, "X-Forwarded-Proto"), ["http"]);
+            assert_eq!(values(&request, "X-Forwarded-Host"), ["example.com"]);
+            assert_eq!(values(&request, "Host"), ["backend.internal"]);
+        }
+
+        // Trusted X-Forwarded-For is appended to, other trusted headers are kept
+        let app = DefaultApp::new(HostHandler)
+            .with_forwarded_headers(true)
+            .with_trust_forwarded_headers(true);
+        let request = forward(&app, &client_headers).await;
+        assert_eq!(
+            values(&request, "X-Forwarded-For"),
+            ["10.0.0.1, 10.0.0.2, 10.0.0.3, 192.0.2.1"]
+        );
+        assert_eq!(values(&request, "X-Forwarded-Proto"), ["https"]);
+        assert_eq!(values(&request, "X-Forwarded-Host"), ["public.example.com"]);
+
+        let request = forward(&app, &[]).await;
+        assert_eq!(values(&request, "X-Forwarded-For"), ["192.0.2.1"]);
+        assert_eq!(values(&request, "X-Forwarded-Proto"), ["http"]);
+        assert_eq!(values(&request, "X-Forwarded-Host"), ["example.com"]);
+    }
+
+    #[test(tokio::test)]
+    async fn hop_by_hop_headers() {
+        let app = DefaultApp::new(TestHandler);
+        let mut session = make_session("POST").await;
+        let mut ctx = app.new_ctx();
+
+        let mut upstream_request = RequestHeader::build("POST", b"/", None).unwrap();
+        for (name, value) in [
+            ("Connection", "keep-alive, X-Foo"),
+            ("Connection", "Transfer-Encoding"),
+            ("Keep-Alive", "timeout=5"),
+            ("TE", "trailers"),
+            ("X-Foo", "bar"),
+            ("X-Bar", "baz"),
+            ("Transfer-Encoding", "chunked"),
+            ("Upgrade", "websocket"),
+        ] {
+            upstream_request.append_header(name, value).unwrap();
+        }
+        app.upstream_request_filter(&mut session, &mut upstream_request, &mut ctx)
+            .await
+            .unwrap();
+
+        let mut names = upstream_request
+            .headers
+            .keys()
+            .map(|name| name.as_str())
+            .collect::<Vec<_>>();
+        names.sort();
+        assert_eq!(names, vec!["transfer-encoding", "x-bar"]);
+
+        // Protocol upgrades are passed on
+        let mut upstream_request = RequestHeader::build("GET", b"/", None).unwrap();
+        upstream_request
+            .append_header("Connection", "Upgrade, X-Foo")
+            .unwrap();
+        upstream_request
+            .append_header("Upgrade", "websocket")
+            .unwrap();
+        upstream_request.append_header("X-Foo", "bar").unwrap();
+        app.upstream_request_filter(&mut session, &mut upstream_request, &mut ctx)
+            .await
+            .unwrap();
+        assert_eq!(upstream_request.headers.len(), 2);
+        assert_eq!(
+            upstream_request.headers.get("Connection").unwrap(),
+            "upgrade"
+        );
+        assert_eq!(
+            upstream_request.headers.get("Upgrade").unwrap(),
+            "websocket"
+        );
+    }
+
+    #[test(tokio::test)]
+    async fn request_id() {
+        async fn run_request(
+            app: &DefaultApp<TestHandler>,
+            inbound: Option<&str>,
+        ) -> (Option<String>, Option<String>) {
+            let mut header = RequestHeader::build("GET", b"/", None).unwrap();
+            if let Some(inbound) = inbound {
+                header.insert_header("X-Request-Id", inbound).unwrap();
+            }
+            let mut session = TestSession::from(header).await;
+            let mut ctx = app.new_ctx();
+            app.request_filter(&mut session, &mut ctx).await.unwrap();
+
+            let mut upstream_request = session.req_header().clone();
+            app.upstream_request_filter(&mut session, &mut upstream_request, &mut ctx)
+                .await
+                .unwrap();
+
+            let mut response = ResponseHeader::build(200, None).unwrap();
+            app.upstream_response_filter(&mut session, &mut response, &mut ctx);
+
+            let get = |headers: &http::HeaderMap| {
+                headers
+                    .get("X-Request-Id")
+                    .map(|value| value.to_str().unwrap().to_owned())
+            };
+            (get(&upstream_request.headers), get(&response.headers))
+        }
+
+        let app = DefaultApp::new(TestHandler);
+        assert_eq!(run_request(&app, None).await, (None, None));
+
+        let app = DefaultApp::new(TestHandler)
+            .with_request_id_header(Some(HeaderName::from_static("x-request-id")));
+
+        let (upstream, response) = run_request(&app, None).await;
+        assert!(upstream.is_some());
+        assert_eq!(upstream, response);
+
+        let (other, _) = run_request(&app, None).await;
+        assert!(other.is_some());
+        assert_ne!(upstream, other);
+
+        let (upstream, response) = run_request(&app, Some("abc-123")).await;
+        assert_eq!(upstream.as_deref(), Some("abc-123"));
+        assert_eq!(response.as_deref(), Some("abc-123"));
+
+        // Invalid client IDs are replaced
+        let long_id = "a".repeat(MAX_REQUEST_ID_LENGTH + 1);
+        let (upstream, response) = run_request(&app, Some(long_id.as_str())).await;
+        assert!(upstream.is_some());
+        assert_ne!(upstream.as_deref(), Some(long_id.as_str()));
+        assert_eq!(upstream, response);
+    }
+
+    #[test(tokio::test)]
+    async fn header_case() {
+        for (header_case, expected) in [
+            (
+                HeaderCase::Preserve,
+                ["x-my-header: 1", "CONTENT-type: text/plain"],
+            ),
+            (
+                HeaderCase::Title,
+                ["X-My-Header: 1", "Content-Type: text/plain"],
+            ),
+            (
+                HeaderCase::Lower,
+                ["x-my-header: 1", "content-type: text/plain"],
+            ),
+        ] {
+            let app = DefaultApp::new(TestHandler).with_header_case(header_case);
+            let mut session = make_session("GET").await;
+            let mut ctx = app.new_ctx();
+
+            let mut response = ResponseHeader::build(200, None).unwrap();
+            response.append_header("x-my-header", "1").unwrap();
+            response
+                .append_header("CONTENT-type", "text/plain")
+                .unwrap();
+            app.upstream_response_filter(&mut session, &mut response, &mut ctx);
+
+            let mut wire = Vec::new();
+            response.header_to_h1_wire(&mut wire);
+            let wire = String::from_utf8(wire).unwrap();
+            for line in expected {
+                assert!(wire.contains(&format!("{line}\r\n")), "{line} in {wire}");
+            }
+            assert_eq!(response.headers.len(), 2);
+        }
+    }
+
+    #[test]
+    fn startup_conf() {
+        let conf = StartupConf::from_yaml(
+            r#"
+                header_case: title
+                request_id_header: X-Request-Id
+                server_header: none
+                allow_invalid_host: true
+                max_uri_length: 1024
+                forwarded_headers: true
+                trust_forwarded_headers: true
+                downstream_keepalive_timeout: 30
+            "#,
+        )
+        .unwrap();
+        let app = DefaultApp::new(TestHandler).with_startup_conf(&conf);
+        assert_eq!(app.header_case, HeaderCase::Title);
+        assert_eq!(
+            app.request_id_header,
+            Some(HeaderName::from_static("x-request-id"))
+        );
+        assert_eq!(app.server_header, ServerHeader::Remove);
+        assert!(app.allow_invalid_host);
+        assert_eq!(app.max_uri_length, 1024);
+        assert!(app.forwarded_headers);
+        assert!(app.trust_forwarded_headers);
+        assert_eq!(app.downstream_keepalive_timeout, Some(30));
+
+        let app = DefaultApp::new(TestHandler).with_startup_conf(&StartupConf::default());
+        assert_eq!(app.header_case, HeaderCase::Preserve);
+        assert_eq!(app.request_id_header, None);
+        assert_eq!(app.max_uri_length, DEFAULT_MAX_URI_LENGTH);
+        assert_eq!(app.downstream_keepalive_timeout, None);
+    }
+
+    #[test(tokio::test)]
+    async fn server_header() {
+        async fn run_request(app: &DefaultApp<TestHandler>) -> (ResponseHeader, ResponseHeader) {
+            let mut session = make_session("GET").await;
+            let mut ctx = app.new_ctx();
+
+            // Upstream response
+            let mut upstream = ResponseHeader::build(200, None).unwrap();
+            upstream.append_header("Server", "upstream").unwrap();
+            app.upstream_response_filter(&mut session, &mut upstream, &mut ctx);
+
+            // Pingora-generated error response
+            let err = Error::new(ErrorType::InternalError);
+            assert_eq!(app.fail_to_proxy(&mut session, &err, &mut ctx).await, 500);
+            let error = session.deref().response_written().unwrap().clone();
+            (upstream, error)
+        }
+
+        let server = |response: &ResponseHeader| {
+            response
+                .headers
+                .get(header::SERVER)
+                .map(|value| value.to_str().unwrap().to_owned())
+        };
+
+        let app = DefaultApp::new(TestHandler);
+        let (upstream, error) = run_request(&app).await;
+        assert_eq!(server(&upstream).as_deref(), Some("upstream"));
+        assert_eq!(server(&error).as_deref(), Some("Pingora"));
+
+        let app = DefaultApp::new(TestHandler).with_server_header(ServerHeader::Value(
+            http::HeaderValue::from_static("My Server"),
+        ));
+        let (upstream, error) = run_request(&app).await;
+        assert_eq!(server(&upstream).as_deref(), Some("My Server"));
+        assert_eq!(server(&error).as_deref(), Some("My Server"));
+        assert_eq!(upstream.headers.get_all(header::SERVER).iter().count(), 1);
+
+        let app = DefaultApp::new(TestHandler).with_server_header(ServerHeader::Remove);
+        let (upstream, error) = run_request(&app).await;
+        assert_eq!(server(&upstream), None);
+        assert_eq!(server(&error), None);
+    }
+
+    #[test(tokio::test)]
+    async fn keepalive_timeout() {
+        async fn run_request(
+            app: &DefaultApp<TestHandler>,
+            connection: Option<&str>,
+        ) -> (bool, Option<String>) {
+            let mut header = RequestHeader::build("GET", b"/", None).unwrap();
+            if let Some(connection) = connection {
+                header
+                    .insert_header(header::CONNECTION, connection)
+                    .unwrap();
+            }
+            let mut session = TestSession::from(header).await;
+            let mut ctx = app.new_ctx();
+            app.request_filter(&mut session, &mut ctx).await.unwrap();
+
+            let mut response = ResponseHeader::build(200, None).unwrap();
+            app.upstream_response_filter(&mut session, &mut response, &mut ctx);
+            let keepalive = session.as_http1().unwrap().will_keepalive();
+            let header = response
+                .headers
+                .get(header::KEEP_ALIVE)
+                .map(|value| value.to_str().unwrap().to_owned());
+            (keepalive, header)
+        }
+
+        let app = DefaultApp::new(TestHandler);
+        assert_eq!(run_request(&app, None).await, (true, None));
+
+        let app = DefaultApp::new(TestHandler).with_downstream_keepalive_timeout(Some(5));
+        assert_eq!(
+            run_request(&app, None).await,
+            (true, Some("timeout=5".to_owned()))
+        );
+        assert_eq!(
+            run_request(&app, Some("keep-alive")).await,
+            (true, Some("timeout=5".to_owned()))
+        );
+
+        // Connections the client wants closed aren’t kept open
+        assert_eq!(run_request(&app, Some("close")).await, (false, None));
+    }
+
+    #[test(tokio::test)]
+    async fn invalid_host() {
+        async fn run_request(app: &DefaultApp<TestHandler>, hosts: &[&str]) -> Option<u16> {
+            let mut header = RequestHeader::build("GET", b"/", None).unwrap();
+            for host in hosts {
+                let value = http::HeaderValue::from_bytes(host.as_bytes()).unwrap();
+                header.append_header(header::HOST, value).unwrap();
+            }
+            let mut session = TestSession::from(header).await;
+            let mut ctx = app.new_ctx();
+            match app.request_filter(&mut session, &mut ctx).await {
+                Ok(_) => None,
+                Err(err) => Some(app.fail_to_proxy(&mut session, &err, &mut ctx).await),
+            }
+        }
+
+        let app = DefaultApp::new(TestHandler);
+        for hosts in [
+            &[][..],
+            &[""],
+            &["localhost"],
+            &["localhost:8080"],
+            &["localhost:"],
+            &["127.0.0.1:8080"],
+            &["[::1]:8080"],
+            &["example.com"],
+        ] {
+            assert_eq!(run_request(&app, hosts).await, None, "{hosts:?}");
+        }
+
+        let overlong = "a".repeat(254);
+        for hosts in [
+            &["exa\tmple.com"][..],
+            &["exa mple.com"],
+            &["exa\u{ff}mple.com"],
+            &["user@example.com"],
+            &["example.com:port"],
+            &["example.com:99999"],
+            &["[::g]"],
+            &[overlong.as_str()],
+            &["example.com", "example.net"],
+        ] {
+            assert_eq!(run_request(&app, hosts).await, Some(400), "{hosts:?}");
+        }
+
+        // Check can be disabled
+        let app = DefaultApp::new(TestHandler).with_allow_invalid_host(true);
+        assert_eq!(run_request(&app, &["exa\tmple.com"]).await, None);
+        assert_eq!(run_request(&app, &[overlong.as_str()]).await, None);
+    }
+
+    #[test(tokio::test)]
+    async fn max_uri_length() {
+        async fn run_request(app: &DefaultApp<TestHandler>, uri: &str) -> Option<u16> {
+            let header = RequestHeader::build("GET", uri.as_bytes(), None).unwrap();
+            let mut session = TestSession::from(header).await;
+            let mut ctx = app.new_ctx();
+            match app.request_filter(&mut session, &mut ctx).await {
+                Ok(_) => None,
+                Err(err) => Some(app.fail_to_proxy(&mut session, &err, &mut ctx).await),
+            }
+        }
+
+        let app = DefaultApp::new(TestHandler);
+        assert_eq!(run_request(&app, "/index.html?page=1").await, None);
+        let long = format!("/?q={}", "a".repeat(DEFAULT_MAX_URI_LENGTH - 4));
+        assert_eq!(long.len(), DEFAULT_MAX_URI_LENGTH);
+        assert_eq!(run_request(&app, &long).await, None);
+        assert_eq!(run_request(&app, &format!("{long}a")).await, Some(414));
+
+        let app = DefaultApp::new(TestHandler).with_max_uri_length(Some(16));
+        assert_eq!(run_request(&app, "/index.html").await, None);
+        assert_eq!(run_request(&app, "/index.html?page=1").await, Some(414));
+        assert_eq!(
+            run_request(&app, "http://example.com/index.html").await,
+            Some(414)
+        );
+    }
+
+    #[test(tokio::test)]
+    async fn options_asterisk() {
+        async fn run_request(
+            app: &DefaultApp<TestHandler>,
+            method: &str,
+            path: &[u8],
+        ) -> Option<ResponseHeader> {
+            let header = RequestHeader::build(method, path, None).unwrap();
+            let mut session = TestSession::from(header).await;
+            let mut ctx = app.new_ctx();
+            if app.request_filter(&mut session, &mut ctx).await.unwrap() {
+                Some(session.deref().response_written().unwrap().clone())
+            } else {
+                None
+            }
+        }
+
+        let app = DefaultApp::new(TestHandler)
+            .with_request_id_header(Some(HeaderName::from_static("x-request-id")));
+        let response = run_request(&app, "OPTIONS", b"*").await.unwrap();
+        assert_eq!(response.status, 204);
+        assert_eq!(
+            response.headers.get(header::ALLOW).unwrap(),
+            DEFAULT_OPTIONS_ALLOW
+        );
+        assert!(response.headers.get("X-Request-Id").is_some());
+
+        // Other requests are passed on to the handler
+        assert!(run_request(&app, "OPTIONS", b"/").await.is_none());
+        assert!(run_request(&app, "GET", b"/").await.is_none());
+
+        let app = DefaultApp::new(TestHandler).with_options_allow(Some("GET, HEAD".to_owned()));
+        let response = run_request(&app, "OPTIONS", b"*").await.unwrap();
+        assert_eq!(response.headers.get(header::ALLOW).unwrap(), "GET, HEAD");
+
+        let app = DefaultApp::new(TestHandler).with_options_allow(None);
+        assert!(run_request(&app, "OPTIONS", b"*").await.is_none());
+    }
+
+    #[test(tokio::test)]
+    async fn expect_continue() {
+        #[derive(Debug)]
+        struct RejectingHandler;
+
+        impl TryFrom<TestConf> for RejectingHandler {
+            type Error = Box<Error>;
+
+            fn try_from(_conf: TestConf) -> Result<Self, Self::Error> {
+                Ok(RejectingHandler)
+            }
+        }
+
+        #[async_trait]
+        impl RequestFilter for RejectingHandler {
+            type Conf = TestConf;
+            type CTX = ();
+            fn new_ctx() -> Self::CTX {}
+
+            async fn request_filter(
+                &self,
+                session: &mut impl SessionWrapper,
+                _ctx: &mut Self::CTX,
+            ) -> Result<RequestFilterResult, Box<Error>> {
+                error_response(session, StatusCode::PAYLOAD_TOO_LARGE).await?;
+                Ok(RequestFilterResult::ResponseSent)
+            }
+        }
+
+        async fn make_session() -> TestSession {
+            let mut header = RequestHeader::build("POST", b"/upload", None).unwrap();
+            header
+                .insert_header(header::EXPECT, "100-continue")
+                .unwrap();
+            TestSession::with_body(header, "0123456789").await
+        }
+
+        // Accepted request receives 100 Continue before going upstream
+        let app = DefaultApp::new(TestHandler);
+        let mut session = make_session().await;
+        let mut ctx = app.new_ctx();
+        assert!(!app.request_filter(&mut session, &mut ctx).await.unwrap());
+        let wrapper = SessionWrapperImpl::new(&mut session, &app, &mut ctx.extensions);
+        assert!(!wrapper.expects_continue());
+        assert!(session.as_http1().unwrap().will_keepalive());
+        assert_eq!(session.body_bytes_read(), 0);
+
+        // Rejected request receives the error response without 100 Continue
+        let app = DefaultApp::new(RejectingHandler);
+        let mut session = make_session().await;
+        let mut ctx = app.new_ctx();
+        assert!(app.request_filter(&mut session, &mut ctx).await.unwrap());
+        assert_eq!(session.deref().response_written().unwrap().status, 413);
+        let wrapper = SessionWrapperImpl::new(&mut session, &app, &mut ctx.extensions);
+        assert!(wrapper.expects_continue());
+        assert!(!session.as_http1().unwrap().will_keepalive());
+    }
+}
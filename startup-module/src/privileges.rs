@@ -0,0 +1,586 @@
+// Copyright 2024 Wladimir Palant
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Switching to an unprivileged user once the server is listening
+
+use async_trait::async_trait;
+use http::HeaderMap;
+use log::{error, info};
+use nix::unistd::{initgroups, setgid, setgroups, setuid, Gid, Group, Uid, User};
+use pandora_module_utils::pingora::{
+    Bytes, Error, ErrorType, HttpPeer, ProxyHttp, RequestHeader, ResponseHeader, Session,
+};
+use pingora::cache::key::HashBinary;
+use pingora::cache::{CacheKey, CacheMeta, RespCacheable};
+use pingora::protocols::Digest;
+use pingora::proxy::PurgeStatus;
+use pingora::server::ShutdownWatch;
+use pingora::services::background::BackgroundService;
+use std::borrow::Cow;
+use std::ffi::CString;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::os::unix::io::RawFd;
+use std::time::Duration;
+use tokio::net::TcpStream;
+use tokio::sync::watch;
+use tokio::time::{sleep, Instant};
+
+use crate::error::StartupError;
+
+/// How long to wait for the server to start listening before giving up
+const LISTEN_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How often to check whether the server is listening
+const LISTEN_POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// User and group IDs resolved from the configuration
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Privileges {
+    user: Option<(CString, Uid)>,
+    gid: Gid,
+}
+
+/// Resolves a user name or numeric user ID, returns user name, user ID and primary group ID.
+fn resolve_user(name: &str) -> Result<(CString, Uid, Gid), StartupError> {
+    let user = User::from_name(name)
+        .ok()
+        .flatten()
+        .or_else(|| {
+            let uid = name.parse().ok()?;
+            User::from_uid(Uid::from_raw(uid)).ok().flatten()
+        })
+        .ok_or_else(|| StartupError::InvalidConfig(format!("Unknown user {name}")))?;
+    let user_name = CString::new(user.name)
+        .map_err(|err| StartupError::InvalidConfig(format!("Invalid user name {name}: {err}")))?;
+    Ok((user_name, user.uid, user.gid))
+}
+
+/// Resolves a group name or numeric group ID.
+fn resolve_group(name: &str) -> Result<Gid, StartupError> {
+    Group::from_name(name)
+        .ok()
+        .flatten()
+        .or_else(|| {
+            let gid = name.parse().ok()?;
+            Group::from_gid(Gid::from_raw(gid)).ok().flatten()
+        })
+        .map(|group| group.gid)
+        .ok_or_else(|| StartupError::InvalidConfig(format!("Unknown group {name}")))
+}
+
+impl Privileges {
+    /// Resolves user and group names. If no group is given, the primary group of the user is
+    /// used. Returns `None` if neither user nor group are set.
+    pub(crate) fn resolve(
+        user: Option<&str>,
+        group: Option<&str>,
+    ) -> Result<Option<Self>, StartupError> {
+        let user = user.map(resolve_user).transpose()?;
+        let group = group.map(resolve_group).transpose()?;
+        Ok(match (user, group) {
+            (Some((name, uid, primary_gid)), gid) => Some(Self {
+                user: Some((name, uid)),
+                gid: gid.unwrap_or(primary_gid),
+            }),
+            (None, Some(gid)) => Some(Self { user: None, gid }),
+            (None, None) => None,
+        })
+    }
+
+    /// Switches the process to the configured group and user.
+    fn apply(&self) -> nix::Result<()> {
+        if let Some((name, _)) = &self.user {
+            initgroups(name, self.gid)?;
+        } else {
+            setgroups(&[self.gid])?;
+        }
+        setgid(self.gid)?;
+        if let Some((_, uid)) = &self.user {
+            setuid(*uid)?;
+        }
+        Ok(())
+    }
+}
+
+/// Determines the address to connect to in order to check whether a listening address is bound
+fn probe_address(addr: &str) -> Option<SocketAddr> {
+    let mut addr: SocketAddr = addr.parse().ok()?;
+    match addr.ip() {
+        IpAddr::V4(ip) if ip.is_unspecified() => addr.set_ip(Ipv4Addr::LOCALHOST.into()),
+        IpAddr::V6(ip) if ip.is_unspecified() => addr.set_ip(Ipv6Addr::LOCALHOST.into()),
+        _ => {}
+    }
+    Some(addr)
+}
+
+/// Background service dropping privileges once all listening addresses are bound
+#[derive(Debug)]
+pub(crate) struct PrivilegesService {
+    privileges: Privileges,
+    addrs: Vec<SocketAddr>,
+    dropped: watch::Sender<bool>,
+}
+
+impl PrivilegesService {
+    /// Creates the service for the given listening addresses. The receiver returned along with
+    /// it is notified once privileges have been dropped, it should be passed to
+    /// [`PrivilegesGate`].
+    ///
+    /// This fails if it cannot be verified for some address that the server is listening on it.
+    pub(crate) fn new<'a>(
+        privileges: Privileges,
+        addrs: impl Iterator<Item = &'a str>,
+    ) -> Result<(Self, watch::Receiver<bool>), StartupError> {
+        let addrs = addrs
+            .map(|addr| {
+                probe_address(addr).ok_or_else(|| {
+                    StartupError::InvalidConfig(format!(
+                        "Cannot verify that the server is listening on {addr}"
+                    ))
+                })
+            })
+            .collect::<Result<_, _>>()?;
+        let (dropped, receiver) = watch::channel(false);
+        Ok((
+            Self {
+                privileges,
+                addrs,
+                dropped,
+            },
+            receiver,
+        ))
+    }
+}
+
+#[async_trait]
+impl BackgroundService for PrivilegesService {
+    async fn start(&self, _shutdown: ShutdownWatch) {
+        let deadline = Instant::now() + LISTEN_TIMEOUT;
+        for addr in &self.addrs {
+            while TcpStream::connect(addr).await.is_err() {
+                if Instant::now() >= deadline {
+                    error!("Server isn’t listening on {addr}, refusing to run with privileges");
+                    std::process::exit(1);
+                }
+                sleep(LISTEN_POLL_INTERVAL).await;
+            }
+        }
+
+        if let Err(err) = self.privileges.apply() {
+            error!("Failed dropping privileges: {err}");
+            std::process::exit(1);
+        }
+        info!("Dropped privileges, now running as {:?}", self.privileges);
+        self.dropped.send_replace(true);
+    }
+}
+
+/// Wraps an app, holding back request processing until privileges have been dropped
+///
+/// The server starts accepting connections as soon as it is listening, while privileges can only
+/// be dropped after that. Requests received in the meantime wait in `request_filter`, so that no
+/// request is ever processed with elevated privileges.
+pub(crate) struct PrivilegesGate<SV> {
+    inner: SV,
+    dropped: Option<watch::Receiver<bool>>,
+}
+
+impl<SV> PrivilegesGate<SV> {
+    /// Wraps an app. If `dropped` is `None`, requests are passed through without waiting.
+    pub(crate) fn new(inner: SV, dropped: Option<watch::Receiver<bool>>) -> Self {
+        Self { inner, dropped }
+    }
+
+    /// Waits until privileges have been dropped
+    async fn wait(&self) -> Result<(), Box<Error>> {
+        if let Some(dropped) = &self.dropped {
+            let mut dropped = dropped.clone();
+            if dropped.wait_for(|dropped| *dropped).await.is_err() {
+                return Err(Error::explain(
+                    ErrorType::InternalError,
+                    "privileges haven’t been dropped",
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl<SV> ProxyHttp for PrivilegesGate<SV>
+where
+    SV: ProxyHttp + Send + Sync,
+    SV::CTX: Send + Sync,
+{
+    type CTX = SV::CTX;
+
+    fn new_ctx(&self) -> Self::CTX {
+        self.inner.new_ctx()
+    }
+
+    async fn upstream_peer(
+        &self,
+        session: &mut Session,
+        ctx: &mut Self::CTX,
+    ) -> Result<Box<HttpPeer>, Box<Error>> {
+        self.inner.upstream_peer(session, ctx).await
+    }
+
+    async fn request_filter(
+        &self,
+        session: &mut Session,
+        ctx: &mut Self::CTX,
+    ) -> Result<bool, Box<Error>> {
+        self.wait().await?;
+        self.inner.request_filter(session, ctx).await
+    }
+
+    fn request_cache_filter(
+        &self,
+        session: &mut Session,
+        ctx: &mut Self::CTX,
+    ) -> Result<(), Box<Error>> {
+        self.inner.request_cache_filter(session, ctx)
+    }
+
+    fn cache_key_callback(
+        &self,
+        session: &Session,
+        ctx: &mut Self::CTX,
+    ) -> Result<CacheKey, Box<Error>> {
+        self.inner.cache_key_callback(session, ctx)
+    }
+
+    fn cache_miss(&self, session: &mut Session, ctx: &mut Self::CTX) {
+        self.inner.cache_miss(session, ctx)
+    }
+
+    async fn cache_hit_filter(
+        &self,
+        meta: &CacheMeta,
+        ctx: &mut Self::CTX,
+        req: &RequestHeader,
+    ) -> Result<bool, Box<Error>> {
+        self.inner.cache_hit_filter(meta, ctx, req).await
+    }
+
+    async fn proxy_upstream_filter(
+        &self,
+        session: &mut Session,
+        ctx: &mut Self::CTX,
+    ) -> Result<bool, Box<Error>> {
+        self.inner.proxy_upstream_filter(session, ctx).await
+    }
+
+    fn response_cache_filter(
+        &self,
+        session: &Session,
+        resp: &ResponseHeader,
+        ctx: &mut Self::CTX,
+    ) -> Result<RespCacheable, Box<Error>> {
+        self.inner.response_cache_filter(session, resp, ctx)
+    }
+
+    fn cache_vary_filter(
+        &self,
+        meta: &CacheMeta,
+        ctx: &mut Self::CTX,
+        req: &RequestHeader,
+    ) -> Option<HashBinary> {
+        self.inner.cache_vary_filter(meta, ctx, req)
+    }
+
+    fn cache_not_modified_filter(
+        &self,
+        session: &Session,
+        resp: &ResponseHeader,
+        ctx: &mut Self::CTX,
+    ) -> Result<bool, Box<Error>> {
+        self.inner.cache_not_modified_filter(session, resp, ctx)
+    }
+
+    async fn upstream_request_filter(
+        &self,
+        session: &mut Session,
+        upstream_request: &mut RequestHeader,
+        ctx: &mut Self::CTX,
+    ) -> Result<(), Box<Error>> {
+        self.inner
+            .upstream_request_filter(session, upstream_request, ctx)
+            .await
+    }
+
+    fn upstream_response_filter(
+        &self,
+        session: &mut Session,
+        upstream_response: &mut ResponseHeader,
+        ctx: &mut Self::CTX,
+    ) {
+        self.inner
+            .upstream_response_filter(session, upstream_response, ctx)
+    }
+
+    async fn response_filter(
+        &self,
+        session: &mut Session,
+        upstream_response: &mut ResponseHeader,
+        ctx: &mut Self::CTX,
+    ) -> Result<(), Box<Error>> {
+        self.inner
+            .response_filter(session, upstream_response, ctx)
+            .await
+    }
+
+    fn upstream_response_body_filter(
+        &self,
+        session: &mut Session,
+        body: &mut Option<Bytes>,
+        end_of_stream: bool,
+        ctx: &mut Self::CTX,
+    ) {
+        self.inner
+            .upstream_response_body_filter(session, body, end_of_stream, ctx)
+    }
+
+    fn upstream_response_trailer_filter(
+        &self,
+        session: &mut Session,
+        upstream_trailers: &mut HeaderMap,
+        ctx: &mut Self::CTX,
+    ) -> Result<(), Box<Error>> {
+        self.inner
+            .upstream_response_trailer_filter(session, upstream_trailers, ctx)
+    }
+
+    fn response_body_filter(
+        &self,
+        session: &mut Session,
+        body: &mut Option<Bytes>,
+        end_of_stream: bool,
+        ctx: &mut Self::CTX,
+    ) -> Result<Option<Duration>, Box<Error>> {
+        self.inner
+            .response_body_filter(session, body, end_of_stream, ctx)
+    }
+
+    async fn response_trailer_filter(
+        &self,
+        session: &mut Session,
+        upstream_trailers: &mut HeaderMap,
+        ctx: &mut Self::CTX,
+    ) -> Result<Option<Bytes>, Box<Error>> {
+        self.inner
+            .response_trailer_filter(session, upstream_trailers, ctx)
+            .await
+    }
+
+    async fn logging(&self, session: &mut Session, e: Option<&Error>, ctx: &mut Self::CTX) {
+        self.inner.logging(session, e, ctx).await
+    }
+
+    fn suppress_error_log(&self, session: &Session, ctx: &Self::CTX, error: &Error) -> bool {
+        self.inner.suppress_error_log(session, ctx, error)
+    }
+
+    fn error_while_proxy(
+        &self,
+        peer: &HttpPeer,
+        session: &mut Session,
+        e: Box<Error>,
+        ctx: &mut Self::CTX,
+        client_reused: bool,
+    ) -> Box<Error> {
+        self.inner
+            .error_while_proxy(peer, session, e, ctx, client_reused)
+    }
+
+    fn fail_to_connect(
+        &self,
+        session: &mut Session,
+        peer: &HttpPeer,
+        ctx: &mut Self::CTX,
+        e: Box<Error>,
+    ) -> Box<Error> {
+        self.inner.fail_to_connect(session, peer, ctx, e)
+    }
+
+    async fn fail_to_proxy(&self, session: &mut Session, e: &Error, ctx: &mut Self::CTX) -> u16 {
+        self.inner.fail_to_proxy(session, e, ctx).await
+    }
+
+    fn should_serve_stale(
+        &self,
+        session: &mut Session,
+        ctx: &mut Self::CTX,
+        error: Option<&Error>,
+    ) -> bool {
+        self.inner.should_serve_stale(session, ctx, error)
+    }
+
+    async fn connected_to_upstream(
+        &self,
+        session: &mut Session,
+        reused: bool,
+        peer: &HttpPeer,
+        fd: RawFd,
+        digest: Option<&Digest>,
+        ctx: &mut Self::CTX,
+    ) -> Result<(), Box<Error>> {
+        self.inner
+            .connected_to_upstream(session, reused, peer, fd, digest, ctx)
+            .await
+    }
+
+    fn request_summary(&self, session: &Session, ctx: &Self::CTX) -> String {
+        self.inner.request_summary(session, ctx)
+    }
+
+    fn is_purge(&self, session: &Session, ctx: &Self::CTX) -> bool {
+        self.inner.is_purge(session, ctx)
+    }
+
+    fn purge_response_filter(
+        &self,
+        session: &Session,
+        ctx: &mut Self::CTX,
+        purge_status: PurgeStatus,
+        purge_response: &mut Cow<'static, ResponseHeader>,
+    ) -> Result<(), Box<Error>> {
+        self.inner
+            .purge_response_filter(session, ctx, purge_status, purge_response)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use pandora_module_utils::pingora::TestSession;
+    use test_log::test;
+
+    struct App;
+
+    #[async_trait]
+    impl ProxyHttp for App {
+        type CTX = ();
+        fn new_ctx(&self) -> Self::CTX {}
+
+        async fn upstream_peer(
+            &self,
+            _session: &mut Session,
+            _ctx: &mut Self::CTX,
+        ) -> Result<Box<HttpPeer>, Box<Error>> {
+            Err(Error::new(ErrorType::HTTPStatus(404)))
+        }
+
+        async fn request_filter(
+            &self,
+            _session: &mut Session,
+            _ctx: &mut Self::CTX,
+        ) -> Result<bool, Box<Error>> {
+            Ok(true)
+        }
+    }
+
+    #[test]
+    fn resolve_root() {
+        let (name, uid, gid) = resolve_user("root").unwrap();
+        assert_eq!(name.to_str().unwrap(), "root");
+        assert_eq!(uid, Uid::from_raw(0));
+        assert_eq!(gid, Gid::from_raw(0));
+
+        let (name, uid, _) = resolve_user("0").unwrap();
+        assert_eq!(name.to_str().unwrap(), "root");
+        assert_eq!(uid, Uid::from_raw(0));
+
+        assert_eq!(resolve_group("0").unwrap(), Gid::from_raw(0));
+    }
+
+    #[test]
+    fn resolve_unknown() {
+        assert!(resolve_user("pandora-nonexistent-user").is_err());
+        assert!(resolve_user("4294967290").is_err());
+        assert!(resolve_group("pandora-nonexistent-group").is_err());
+        assert!(Privileges::resolve(Some("root"), Some("pandora-nonexistent-group")).is_err());
+    }
+
+    #[test]
+    fn resolve_privileges() {
+        assert_eq!(Privileges::resolve(None, None).unwrap(), None);
+
+        let privileges = Privileges::resolve(Some("root"), None).unwrap().unwrap();
+        assert_eq!(privileges.user.unwrap().1, Uid::from_raw(0));
+        assert_eq!(privileges.gid, Gid::from_raw(0));
+
+        let privileges = Privileges::resolve(None, Some("0")).unwrap().unwrap();
+        assert_eq!(privileges.user, None);
+        assert_eq!(privileges.gid, Gid::from_raw(0));
+    }
+
+    #[test]
+    fn probe_addresses() {
+        let addr = |addr: &str| addr.parse::<SocketAddr>().ok();
+        assert_eq!(probe_address("0.0.0.0:80"), addr("127.0.0.1:80"));
+        assert_eq!(probe_address("[::]:443"), addr("[::1]:443"));
+        assert_eq!(probe_address("192.168.1.2:8080"), addr("192.168.1.2:8080"));
+        assert_eq!(probe_address("/run/pandora.sock"), None);
+    }
+
+    #[test]
+    fn service_addresses() {
+        let privileges = Privileges::resolve(Some("root"), None).unwrap().unwrap();
+        let (service, _) =
+            PrivilegesService::new(privileges.clone(), ["0.0.0.0:80", "[::1]:443"].into_iter())
+                .unwrap();
+        assert_eq!(service.addrs.len(), 2);
+
+        // Addresses that cannot be checked must not be ignored
+        assert!(PrivilegesService::new(
+            privileges,
+            ["0.0.0.0:80", "/run/pandora.sock"].into_iter()
+        )
+        .is_err());
+    }
+
+    #[test(tokio::test)]
+    async fn gate() {
+        let make_session = || async {
+            let header = RequestHeader::build("GET", b"/", None).unwrap();
+            TestSession::from(header).await
+        };
+
+        let gate = PrivilegesGate::new(App, None);
+        let mut session = make_session().await;
+        assert!(gate.request_filter(&mut session, &mut ()).await.unwrap());
+
+        let (sender, receiver) = watch::channel(false);
+        let gate = PrivilegesGate::new(App, Some(receiver));
+        let mut session = make_session().await;
+        assert!(tokio::time::timeout(
+            Duration::from_millis(100),
+            gate.request_filter(&mut session, &mut ())
+        )
+        .await
+        .is_err());
+
+        sender.send_replace(true);
+        assert!(gate.request_filter(&mut session, &mut ()).await.unwrap());
+
+        // Requests are rejected if the service went away without dropping privileges
+        let (sender, receiver) = watch::channel(false);
+        let gate = PrivilegesGate::new(App, Some(receiver));
+        drop(sender);
+        assert!(gate.request_filter(&mut session, &mut ()).await.is_err());
+    }
+}
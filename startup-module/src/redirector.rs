@@ -21,8 +21,13 @@ use pandora_module_utils::standard_response::response_text;
 use pingora::{proxy::http_proxy_service, services::Service};
 use std::collections::HashMap;
 use std::sync::Arc;
+#[cfg(unix)]
+use tokio::sync::watch;
 
-use crate::configuration::{TlsRedirectorConf, TLS_CONF_ERR};
+use crate::configuration::TlsRedirectorConf;
+use crate::error::StartupError;
+#[cfg(unix)]
+use crate::privileges::PrivilegesGate;
 
 struct RedirectorApp {
     redirect_to: String,
@@ -88,11 +93,11 @@ impl ProxyHttp for RedirectorApp {
 pub(crate) fn create_redirector(
     conf: &TlsRedirectorConf,
     server_conf: &Arc<ServerConf>,
-) -> Result<impl Service + 'static, Box<Error>> {
+    #[cfg(unix)] privileges_dropped: Option<watch::Receiver<bool>>,
+) -> Result<impl Service + 'static, StartupError> {
     if conf.redirect_to.is_empty() {
-        return Err(Error::explain(
-            TLS_CONF_ERR,
-            "tls.redirector.redirect_to setting has to be specified for TLS redirector",
+        return Err(StartupError::InvalidConfig(
+            "tls.redirector.redirect_to setting has to be specified for TLS redirector".to_owned(),
         ));
     }
 
@@ -100,13 +105,15 @@ pub(crate) fn create_redirector(
         redirect_to: conf.redirect_to.clone(),
         redirect_by_name: conf.redirect_by_name.to_owned(),
     };
+    #[cfg(unix)]
+    let app = PrivilegesGate::new(app, privileges_dropped);
     let mut service = http_proxy_service(server_conf, app);
+    service.threads = conf.threads;
 
     for addr in &conf.listen {
         if addr.tls {
-            return Err(Error::explain(
-                TLS_CONF_ERR,
-                "tls.redirector.listen setting cannot contain any TLS addresses",
+            return Err(StartupError::InvalidConfig(
+                "tls.redirector.listen setting cannot contain any TLS addresses".to_owned(),
             ));
         }
 
@@ -16,29 +16,46 @@
 
 use bytes::Bytes;
 use http::{header, status::StatusCode};
+use log::debug;
+use pandora_module_utils::compression_algorithm::{find_matches, CompressionAlgorithm};
 use pandora_module_utils::pingora::{Error, HttpTask, ResponseHeader, SessionWrapper};
+use std::fs::Metadata;
 use std::path::{Path, PathBuf};
+use std::time::SystemTime;
 
-use crate::compression_algorithm::{find_matches, CompressionAlgorithm};
+use crate::disk_cache::{can_generate, is_compressible, write_compressed_in_background};
+
+/// Checks whether a pre-compressed file is older than the original file.
+fn is_outdated(meta: &Metadata, orig_modified: Option<SystemTime>) -> bool {
+    match (meta.modified(), orig_modified) {
+        (Ok(modified), Some(orig_modified)) => modified < orig_modified,
+        _ => false,
+    }
+}
 
 /// Encapsulates the compression state for the current session.
 pub(crate) struct Compression<'a> {
     precompressed: &'a [CompressionAlgorithm],
     precompressed_active: Option<CompressionAlgorithm>,
+    cache_to_disk: bool,
     dynamic: bool,
     dynamic_active: bool,
 }
 
 impl<'a> Compression<'a> {
     /// Creates a new compression state supporting the given compression algorithms for
-    /// pre-compressed files. *Note*: Dynamic compression is determined by the Pingora session.
+    /// pre-compressed files. If `cache_to_disk` is `true`, outdated pre-compressed files are
+    /// ignored as these will be regenerated. *Note*: Dynamic compression is determined by the
+    /// Pingora session.
     pub(crate) fn new(
         session: &impl SessionWrapper,
         precompressed: &'a [CompressionAlgorithm],
+        cache_to_disk: bool,
     ) -> Self {
         Self {
             precompressed,
             precompressed_active: None,
+            cache_to_disk,
             // Remember this now, later on request header check might flip this flag
             dynamic: session.downstream_compression.is_enabled(),
             dynamic_active: false,
@@ -46,6 +63,7 @@ impl<'a> Compression<'a> {
     }
 
     /// Checks whether the given path should be rewritten to a pre-compressed version of the file.
+    /// Pre-compressed files that aren’t smaller than the original file are ignored.
     pub(crate) fn rewrite_path(
         &mut self,
         session: &impl SessionWrapper,
@@ -56,25 +74,94 @@ impl<'a> Compression<'a> {
         }
 
         let filename = path.file_name()?;
-        let requested = session.req_header().headers.get(header::ACCEPT_ENCODING)?;
-        let overlap = find_matches(requested.to_str().ok()?, self.precompressed);
+        let overlap = find_matches(&session.accepted_encodings(), self.precompressed);
+        let meta = path.metadata().ok();
+        let size = meta.as_ref().map(|meta| meta.len());
+        let modified = meta.and_then(|meta| meta.modified().ok());
 
         for algorithm in overlap {
             let mut candidate_name = filename.to_os_string();
             candidate_name.push(".");
-            candidate_name.push(algorithm.ext());
+            candidate_name.push(algorithm.extension());
 
             let mut candidate_path = path.to_path_buf();
             candidate_path.set_file_name(candidate_name);
-            if candidate_path.is_file() {
-                self.precompressed_active = Some(algorithm);
-                return Some(candidate_path);
+            let candidate_meta = match candidate_path.metadata() {
+                Ok(meta) if meta.is_file() => meta,
+                _ => continue,
+            };
+            if size.is_some_and(|size| candidate_meta.len() >= size) {
+                debug!(
+                    "pre-compressed file {candidate_path:?} is larger than the original, ignoring"
+                );
+                continue;
+            }
+            if self.cache_to_disk && is_outdated(&candidate_meta, modified) {
+                debug!("pre-compressed file {candidate_path:?} is outdated, ignoring");
+                continue;
             }
+
+            self.precompressed_active = Some(algorithm);
+            return Some(candidate_path);
         }
 
         None
     }
 
+    /// Returns `true` if a pre-compressed version of the file is being served.
+    pub(crate) fn is_precompressed(&self) -> bool {
+        self.precompressed_active.is_some()
+    }
+
+    /// Starts generating a pre-compressed version of the file for the algorithm preferred by the
+    /// client in the background, unless an up-to-date one exists already. Only files with a
+    /// compressible MIME type and size are considered.
+    ///
+    /// Nothing is generated unless dynamic compression is enabled, so that the current response
+    /// can be compressed dynamically while the file is being generated.
+    pub(crate) fn generate_precompressed(&self, session: &impl SessionWrapper, path: &Path) {
+        if !self.dynamic {
+            return;
+        }
+
+        let meta = match path.metadata() {
+            Ok(meta) if meta.is_file() => meta,
+            _ => return,
+        };
+        let filename = match path.file_name() {
+            Some(filename) => filename,
+            None => return,
+        };
+        if !is_compressible(path, meta.len()) {
+            debug!("not generating pre-compressed versions of {path:?}, type or size unsuitable");
+            return;
+        }
+        let modified = meta.modified().ok();
+
+        let overlap = find_matches(&session.accepted_encodings(), self.precompressed);
+        for algorithm in overlap {
+            if !can_generate(algorithm) {
+                continue;
+            }
+
+            let mut candidate_name = filename.to_os_string();
+            candidate_name.push(".");
+            candidate_name.push(algorithm.extension());
+
+            let mut candidate_path = path.to_path_buf();
+            candidate_path.set_file_name(candidate_name);
+            if let Ok(candidate_meta) = candidate_path.metadata() {
+                if !is_outdated(&candidate_meta, modified) {
+                    // Pre-compressed file is up to date but was rejected, don't overwrite it
+                    continue;
+                }
+            }
+
+            write_compressed_in_background(path.to_path_buf(), candidate_path, algorithm);
+            return;
+        }
+    }
+
     /// Applies the necessary modification to the HTTP response if compression is active. This will
     /// add `Content-Encoding` HTTP header among other thins.
     pub(crate) fn transform_header(
@@ -89,7 +176,7 @@ impl<'a> Compression<'a> {
             header
         } else if let Some(algorithm) = self.precompressed_active {
             // File is pre-compressed, only need to adjust header
-            header.insert_header(header::CONTENT_ENCODING, algorithm.name())?;
+            header.insert_header(header::CONTENT_ENCODING, algorithm.encoding_token())?;
             header
         } else if header.status == StatusCode::OK {
             // Delegate to Pingora's dynamic compression implementation
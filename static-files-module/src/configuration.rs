@@ -15,23 +15,32 @@
 //! Data structures required for `StaticFilesHandler` configuration
 
 use clap::Parser;
-use pandora_module_utils::{DeserializeMap, OneOrMany};
+use pandora_module_utils::compression_algorithm::CompressionAlgorithm;
+use pandora_module_utils::{config_dir, DeserializeMap, OneOrMany};
+use serde::{Deserialize, Deserializer};
+use std::collections::HashMap;
 use std::ffi::OsString;
 use std::path::PathBuf;
 
-use crate::compression_algorithm::CompressionAlgorithm;
+use crate::path::{TrailingDots, TrailingSlash};
 
 /// Command line options of the static files module
 #[derive(Debug, Default, Parser)]
 pub struct StaticFilesOpt {
-    /// The root directory.
+    /// The root directory. This command line flag can be specified multiple times, the root
+    /// directories will be searched for files in the order given.
     #[clap(short, long, value_parser = clap::value_parser!(OsString))]
-    pub root: Option<PathBuf>,
+    pub root: Option<Vec<PathBuf>>,
 
     /// Redirect /file%2e.txt to /file.txt and /dir to /dir/.
     #[clap(long)]
     pub canonicalize_uri: Option<bool>,
 
+    /// Canonical form of URIs with regard to trailing slashes (preserve, add or remove). Default
+    /// is add: directory URIs end with a slash, file URIs don't.
+    #[clap(long, value_enum)]
+    pub trailing_slash: Option<TrailingSlash>,
+
     /// Index file to look for when displaying a directory. This command line flag can be specified
     /// multiple times.
     #[clap(long)]
@@ -41,32 +50,136 @@ pub struct StaticFilesOpt {
     #[clap(long)]
     pub page_404: Option<String>,
 
+    /// File system path of the page to display instead of the default Not Found page if
+    /// --page-404 isn't set, e.g. /var/www/errors/404.html
+    #[clap(long, value_parser = clap::value_parser!(OsString))]
+    pub page_404_file: Option<PathBuf>,
+
     /// File extension to check when looking for pre-compressed versions of a file. This command
     /// line flag can be specified multiple times. Supported file extensions are gz (gzip),
     /// zz (zlib deflate), z (compress), br (Brotli), zst (Zstandard).
     #[clap(long, value_parser = clap::value_parser!(String))]
     pub precompressed: Option<Vec<CompressionAlgorithm>>,
+
+    /// Handling of path components with trailing dots or spaces like /file.txt. (allow, reject or
+    /// strip). Default is reject on Windows, allow elsewhere.
+    #[clap(long, value_enum)]
+    pub trailing_dots: Option<TrailingDots>,
+
+    /// Charset to add to the Content-Type header of text files, e.g. utf-8
+    #[clap(long)]
+    pub default_charset: Option<String>,
+}
+
+fn deserialize_config_dir<'de, D>(deserializer: D) -> Result<Option<PathBuf>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let enabled = bool::deserialize(deserializer)?;
+    Ok(enabled.then(|| config_dir().unwrap_or_default()))
 }
 
 /// Configuration file settings of the static files module
 #[derive(Debug, Clone, PartialEq, Eq, DeserializeMap)]
 pub struct StaticFilesConf {
-    /// The root directory.
-    pub root: Option<PathBuf>,
+    /// The root directory or a list of root directories. Files are looked up in the root
+    /// directories in the order given, the first one containing the requested path is used.
+    pub root: Option<OneOrMany<PathBuf>>,
+
+    /// Directory to resolve a relative `root` path against. If `None`, relative paths are
+    /// resolved against the current working directory.
+    ///
+    /// In configuration files, this is the `root_relative_to_config` flag. If enabled, the
+    /// directory of the configuration file containing the flag will be used.
+    #[pandora(
+        rename = "root_relative_to_config",
+        deserialize_with = "deserialize_config_dir"
+    )]
+    pub root_base: Option<PathBuf>,
 
     /// Redirect /file%2e.txt to /file.txt and /dir to /dir/.
     pub canonicalize_uri: bool,
 
+    /// Canonical form of URIs with regard to trailing slashes (preserve, add or remove). Default
+    /// is add: directory URIs end with a slash, file URIs don’t. Only applies if
+    /// `canonicalize_uri` is enabled.
+    pub trailing_slash: TrailingSlash,
+
     /// List of index files to look for in a directory.
     pub index_file: OneOrMany<String>,
 
     /// URI path of the page to display instead of the default Not Found page, e.g. /404.html
     pub page_404: Option<String>,
 
+    /// File system path of the page to display instead of the default Not Found page, e.g.
+    /// `/var/www/errors/404.html`. Unlike `page_404`, this file doesn’t need to be located
+    /// within the root directory. This setting is only used if `page_404` isn’t set.
+    pub page_404_file: Option<PathBuf>,
+
     /// List of file extensions to check when looking for pre-compressed versions of a file.
     /// Supported file extensions are gz (gzip), zz (zlib deflate), z (compress), br (Brotli),
     /// zst (Zstandard).
     pub precompressed: OneOrMany<CompressionAlgorithm>,
+
+    /// If `true`, missing or outdated pre-compressed versions of requested files will be
+    /// generated in the background and written to disk next to the original file. This only
+    /// applies to algorithms listed in `precompressed` setting and requires dynamic compression
+    /// to be enabled, generating files is supported for gz (gzip), zz (zlib deflate) and br
+    /// (Brotli).
+    pub cache_compressed_to_disk: bool,
+
+    /// Path to a JSON manifest listing content-addressed files, relative to the (first) root
+    /// directory.
+    /// Files listed there will be sent with headers allowing them to be cached indefinitely.
+    pub manifest: Option<PathBuf>,
+
+    /// Handling of path components with trailing dots or spaces like /file.txt. (allow, reject or
+    /// strip). Default is reject on Windows, allow elsewhere.
+    pub trailing_dots: TrailingDots,
+
+    /// Charset to add to the `Content-Type` header of text files, e.g. `utf-8`. This applies to
+    /// all `text/*` MIME types and the ones listed in `charset_types`, unless the MIME type
+    /// already specifies a charset.
+    pub default_charset: Option<String>,
+
+    /// Additional MIME types that `default_charset` should apply to, e.g.
+    /// `application/javascript`.
+    pub charset_types: OneOrMany<String>,
+
+    /// If not empty, only files with these extensions will be served, e.g. `pdf`. Other files
+    /// result in a 404 Not Found response. Matching is case-insensitive.
+    pub allowed_extensions: OneOrMany<String>,
+
+    /// Files with these extensions won’t be served, e.g. `php`. These result in a 404 Not Found
+    /// response. Matching is case-insensitive.
+    pub denied_extensions: OneOrMany<String>,
+
+    /// Files with these extensions will be sent with a `Content-Disposition: attachment` header,
+    /// making browsers download them instead of displaying, e.g. `zip`. Matching is
+    /// case-insensitive.
+    pub attachment_extensions: OneOrMany<String>,
+
+    /// URI paths of files to be sent with a `Content-Disposition: attachment` header, making
+    /// browsers download them instead of displaying. An entry ending with `/*` applies to all
+    /// paths with the given prefix, e.g. `/downloads/*`.
+    pub attachment_paths: OneOrMany<String>,
+
+    /// `Link` headers to add to HTML responses, keyed by URI path, e.g.
+    /// `</app.css>; rel=preload; as=style`. A key ending with `/*` applies to all paths with the
+    /// given prefix, the key `*` applies to all HTML files.
+    pub preload: HashMap<String, OneOrMany<String>>,
+
+    /// If `true`, `X-Content-Type-Options: nosniff` header will be added to file responses,
+    /// preventing browsers from guessing a MIME type different from the `Content-Type` header.
+    pub nosniff: bool,
+
+    /// If `true`, requests for multiple byte ranges will be answered with a
+    /// `multipart/byteranges` response. Otherwise the full file is returned for such requests.
+    pub multiple_ranges: bool,
+
+    /// If `true`, `preload` links for HTML files will also be sent in a `103 Early Hints`
+    /// response to HTTP/1.1 and newer clients, before the file is even opened.
+    pub early_hints: bool,
 }
 
 impl StaticFilesConf {
@@ -74,13 +187,19 @@ impl StaticFilesConf {
     /// present overwrite existing settings.
     pub fn merge_with_opt(&mut self, opt: StaticFilesOpt) {
         if opt.root.is_some() {
-            self.root = opt.root;
+            // Command line paths are always relative to the current directory
+            self.root = opt.root.map(Into::into);
+            self.root_base = None;
         }
 
         if let Some(canonicalize_uri) = opt.canonicalize_uri {
             self.canonicalize_uri = canonicalize_uri;
         }
 
+        if let Some(trailing_slash) = opt.trailing_slash {
+            self.trailing_slash = trailing_slash;
+        }
+
         if let Some(index_file) = opt.index_file {
             self.index_file = index_file.into();
         }
@@ -89,9 +208,21 @@ impl StaticFilesConf {
             self.page_404 = opt.page_404;
         }
 
+        if opt.page_404_file.is_some() {
+            self.page_404_file = opt.page_404_file;
+        }
+
         if let Some(precompressed) = opt.precompressed {
             self.precompressed = precompressed.into();
         }
+
+        if let Some(trailing_dots) = opt.trailing_dots {
+            self.trailing_dots = trailing_dots;
+        }
+
+        if opt.default_charset.is_some() {
+            self.default_charset = opt.default_charset;
+        }
     }
 }
 
@@ -99,10 +230,26 @@ impl Default for StaticFilesConf {
     fn default() -> Self {
         Self {
             root: None,
+            root_base: None,
             canonicalize_uri: true,
+            trailing_slash: Default::default(),
             index_file: Default::default(),
             page_404: None,
+            page_404_file: None,
             precompressed: Default::default(),
+            cache_compressed_to_disk: false,
+            manifest: None,
+            trailing_dots: Default::default(),
+            default_charset: None,
+            charset_types: Default::default(),
+            allowed_extensions: Default::default(),
+            denied_extensions: Default::default(),
+            attachment_extensions: Default::default(),
+            attachment_paths: Default::default(),
+            preload: Default::default(),
+            nosniff: true,
+            multiple_ranges: false,
+            early_hints: false,
         }
     }
 }
@@ -0,0 +1,182 @@
+// Copyright 2024 Wladimir Palant
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Generating pre-compressed versions of files on disk.
+
+use flate2::write::{GzEncoder, ZlibEncoder};
+use log::{debug, error, warn};
+use pandora_module_utils::compression_algorithm::CompressionAlgorithm;
+use std::fs::{remove_file, rename, File};
+use std::io::{copy, Error, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+
+/// Brotli quality level, maximal since the file is only compressed once
+const BROTLI_QUALITY: u32 = 11;
+
+/// Brotli window size as recommended by the Brotli library
+const BROTLI_WINDOW: u32 = 22;
+
+/// Buffer size for Brotli compression
+const BROTLI_BUFFER_SIZE: usize = 64 * 1024;
+
+/// Files smaller than this won’t benefit from compression noticeably
+const MIN_SIZE: u64 = 1024;
+
+/// Files larger than this take too long to compress
+const MAX_SIZE: u64 = 64 * 1024 * 1024;
+
+/// MIME type prefixes of compressible files, same rules as for Pingora’s dynamic compression
+const COMPRESSIBLE_TYPES: &[&str] = &[
+    "text/",
+    "application/",
+    "font/",
+    "image/x-icon",
+    "image/svg+xml",
+    "image/vnd.microsoft.icon",
+    "binary/octet-stream",
+];
+
+/// Target paths of pre-compressed files currently being generated
+static PENDING: Mutex<Vec<PathBuf>> = Mutex::new(Vec::new());
+
+/// Checks whether pre-compressed files can be generated for the given algorithm.
+pub(crate) fn can_generate(algorithm: CompressionAlgorithm) -> bool {
+    matches!(
+        algorithm,
+        CompressionAlgorithm::Gzip | CompressionAlgorithm::Deflate | CompressionAlgorithm::Brotli
+    )
+}
+
+/// Checks whether a file is worth compressing based on its MIME type and size.
+pub(crate) fn is_compressible(path: &Path, size: u64) -> bool {
+    if !(MIN_SIZE..=MAX_SIZE).contains(&size) {
+        return false;
+    }
+
+    // Files of unknown type are likely binary
+    let mime = match mime_guess::from_path(path).first() {
+        Some(mime) => mime,
+        None => return false,
+    };
+    let mime = mime.essence_str();
+    !mime.contains("zip")
+        && COMPRESSIBLE_TYPES
+            .iter()
+            .any(|prefix| mime.starts_with(prefix))
+}
+
+fn compress(
+    source: &Path,
+    target: &mut File,
+    algorithm: CompressionAlgorithm,
+) -> Result<(), Error> {
+    let mut source = File::open(source)?;
+    match algorithm {
+        CompressionAlgorithm::Gzip => {
+            let mut encoder = GzEncoder::new(target, flate2::Compression::best());
+            copy(&mut source, &mut encoder)?;
+            encoder.finish()?.flush()
+        }
+        CompressionAlgorithm::Deflate => {
+            let mut encoder = ZlibEncoder::new(target, flate2::Compression::best());
+            copy(&mut source, &mut encoder)?;
+            encoder.finish()?.flush()
+        }
+        CompressionAlgorithm::Brotli => {
+            let mut encoder = brotli::CompressorWriter::new(
+                target,
+                BROTLI_BUFFER_SIZE,
+                BROTLI_QUALITY,
+                BROTLI_WINDOW,
+            );
+            copy(&mut source, &mut encoder)?;
+            // Brotli stream is only finalized when the encoder is consumed
+            encoder.into_inner().flush()
+        }
+        _ => Err(std::io::ErrorKind::Unsupported.into()),
+    }
+}
+
+/// Writes a compressed version of the file at `source` to `target`.
+///
+/// The data is written to a temporary file first which is then renamed, so that concurrent
+/// requests never see a partially written file. If multiple requests generate the same file
+/// concurrently, the last one to finish wins.
+fn write_compressed(
+    source: &Path,
+    target: &Path,
+    algorithm: CompressionAlgorithm,
+) -> Result<(), Error> {
+    static COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+    let mut temp_name = target.as_os_str().to_os_string();
+    temp_name.push(format!(
+        ".{}-{}.tmp",
+        std::process::id(),
+        COUNTER.fetch_add(1, Ordering::Relaxed)
+    ));
+    let temp_path = Path::new(&temp_name);
+
+    let result = File::create(temp_path)
+        .and_then(|mut file| compress(source, &mut file, algorithm))
+        .and_then(|_| rename(temp_path, target));
+    if result.is_err() {
+        let _ = remove_file(temp_path);
+    }
+    result
+}
+
+/// Generates a compressed version of the file at `source` in a background thread, unless the
+/// same file is being generated already.
+pub(crate) fn write_compressed_in_background(
+    source: PathBuf,
+    target: PathBuf,
+    algorithm: CompressionAlgorithm,
+) {
+    match PENDING.lock() {
+        Ok(mut pending) => {
+            if pending.contains(&target) {
+                return;
+            }
+            pending.push(target.clone());
+        }
+        Err(err) => {
+            error!("Failed acquiring pending files mutex, not generating {target:?}: {err}");
+            return;
+        }
+    }
+
+    tokio::task::spawn_blocking(move || {
+        debug!("generating pre-compressed file {target:?}");
+        if let Err(err) = write_compressed(&source, &target, algorithm) {
+            warn!("failed writing pre-compressed file {target:?}: {err}");
+        }
+
+        match PENDING.lock() {
+            Ok(mut pending) => pending.retain(|path| path != &target),
+            Err(err) => error!("Failed acquiring pending files mutex: {err}"),
+        }
+    });
+}
+
+/// Checks whether the given file is currently being generated.
+#[cfg(test)]
+pub(crate) fn is_pending(target: &Path) -> bool {
+    PENDING
+        .lock()
+        .map(|pending| pending.iter().any(|path| path == target))
+        .unwrap_or(false)
+}
@@ -14,7 +14,7 @@
 
 //! Writing files to Pingora session.
 
-use bytes::BytesMut;
+use bytes::{Bytes, BytesMut};
 use http::status::StatusCode;
 use log::error;
 use pandora_module_utils::pingora::{Error, ErrorType, SessionWrapper};
@@ -24,34 +24,37 @@ use std::io::{Read, Seek, SeekFrom};
 use std::path::Path;
 
 use crate::compression::Compression;
+use crate::range::Multipart;
 
 const BUFFER_SIZE: usize = 64 * 1024;
 
-/// Writes a chunk of a file as a Pingora session response. The data will be passed through the
-/// compression handler first in case dynamic compression is enabled.
-pub(crate) async fn file_response(
+/// Opens a file for reading, converting errors into Internal Server Error responses.
+fn open_file(path: &Path) -> Result<File, Box<Error>> {
+    File::open(path).map_err(|err| {
+        error!("failed opening file {path:?}: {err}");
+        Error::new(ErrorType::HTTPStatus(
+            StatusCode::INTERNAL_SERVER_ERROR.into(),
+        ))
+    })
+}
+
+/// Writes the given range of an open file to the Pingora session, passing the data through the
+/// compression handler.
+async fn write_range(
     session: &mut impl SessionWrapper,
+    file: &mut File,
     path: &Path,
     start: u64,
     end: u64,
     compression: &Compression<'_>,
 ) -> Result<(), Box<Error>> {
-    let mut file = File::open(path).map_err(|err| {
-        error!("failed opening file {path:?}: {err}");
+    file.seek(SeekFrom::Start(start)).map_err(|err| {
+        error!("failed seeking in file {path:?}: {err}");
         Error::new(ErrorType::HTTPStatus(
             StatusCode::INTERNAL_SERVER_ERROR.into(),
         ))
     })?;
 
-    if start != 0 {
-        file.seek(SeekFrom::Start(start)).map_err(|err| {
-            error!("failed seeking in file {path:?}: {err}");
-            Error::new(ErrorType::HTTPStatus(
-                StatusCode::INTERNAL_SERVER_ERROR.into(),
-            ))
-        })?;
-    }
-
     let mut remaining = (end - start + 1) as usize;
     while remaining > 0 {
         let mut buf = BytesMut::zeroed(min(remaining, BUFFER_SIZE));
@@ -74,9 +77,45 @@ pub(crate) async fn file_response(
         remaining -= len;
     }
 
+    Ok(())
+}
+
+/// Writes a chunk of a file as a Pingora session response. The data will be passed through the
+/// compression handler first in case dynamic compression is enabled.
+pub(crate) async fn file_response(
+    session: &mut impl SessionWrapper,
+    path: &Path,
+    start: u64,
+    end: u64,
+    compression: &Compression<'_>,
+) -> Result<(), Box<Error>> {
+    let mut file = open_file(path)?;
+    write_range(session, &mut file, path, start, end, compression).await?;
+
     if let Some(bytes) = compression.transform_body(session, None) {
         session.write_response_body(bytes).await?;
     }
 
     Ok(())
 }
+
+/// Writes multiple chunks of a file as a `multipart/byteranges` Pingora session response.
+pub(crate) async fn multipart_response(
+    session: &mut impl SessionWrapper,
+    path: &Path,
+    multipart: &Multipart,
+    compression: &Compression<'_>,
+) -> Result<(), Box<Error>> {
+    let mut file = open_file(path)?;
+    for (header, start, end) in multipart.parts() {
+        session
+            .write_response_body(Bytes::copy_from_slice(header.as_bytes()))
+            .await?;
+        write_range(session, &mut file, path, *start, *end, compression).await?;
+    }
+    session
+        .write_response_body(multipart.trailer().into())
+        .await?;
+
+    Ok(())
+}
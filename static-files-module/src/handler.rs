@@ -15,24 +15,406 @@
 //! Handler for the `request_filter` phase.
 
 use async_trait::async_trait;
-use http::{method::Method, status::StatusCode};
+use http::{header, method::Method, status::StatusCode, Version};
 use log::{debug, info, warn};
-use pandora_module_utils::pingora::{Error, ErrorType, SessionWrapper};
+use pandora_module_utils::pingora::{Error, ErrorType, ResponseHeader, SessionWrapper};
 use pandora_module_utils::standard_response::{error_response, redirect_response};
-use pandora_module_utils::{RequestFilter, RequestFilterResult};
+use pandora_module_utils::{OneOrMany, RequestFilter, RequestFilterResult};
+use percent_encoding::{utf8_percent_encode, AsciiSet, NON_ALPHANUMERIC};
+use std::collections::HashSet;
 use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
 
 use crate::compression::Compression;
 use crate::configuration::StaticFilesConf;
-use crate::file_writer::file_response;
+use crate::file_writer::{file_response, multipart_response};
+use crate::manifest::{load_manifest, IMMUTABLE_CACHE_CONTROL};
 use crate::metadata::Metadata;
-use crate::path::{path_to_uri, resolve_uri};
-use crate::range::{extract_range, Range};
+use crate::path::{path_to_uri, resolve_uri_in_roots};
+use crate::range::{extract_range, Multipart, Range};
+
+/// Characters that need to be percent-encoded in an RFC 5987 `filename*` parameter
+const ATTR_CHAR_ESC_CHARSET: &AsciiSet = &NON_ALPHANUMERIC
+    .remove(b'!')
+    .remove(b'#')
+    .remove(b'$')
+    .remove(b'&')
+    .remove(b'+')
+    .remove(b'-')
+    .remove(b'.')
+    .remove(b'^')
+    .remove(b'_')
+    .remove(b'`')
+    .remove(b'|')
+    .remove(b'~');
+
+/// Checks whether the file’s extension is contained in the list, ignoring case and leading dots
+/// of the list entries.
+fn has_extension(path: &Path, list: &[String]) -> bool {
+    let extension = path
+        .extension()
+        .map(|extension| extension.to_string_lossy())
+        .unwrap_or_default();
+    list.iter().any(|entry| {
+        let entry = entry.strip_prefix('.').unwrap_or(entry);
+        entry.eq_ignore_ascii_case(&extension)
+    })
+}
+
+/// Produces a `Content-Disposition` header value for a download. Non-ASCII file names are
+/// encoded as described in RFC 6266, with an ASCII-only fallback for older clients.
+fn attachment_disposition(filename: &str) -> String {
+    let fallback: String = filename
+        .chars()
+        .map(|c| {
+            if c.is_ascii() && !c.is_ascii_control() && c != '"' && c != '\\' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect();
+
+    if fallback == filename {
+        format!("attachment; filename=\"{filename}\"")
+    } else {
+        let encoded = utf8_percent_encode(filename, ATTR_CHAR_ESC_CHARSET);
+        format!("attachment; filename=\"{fallback}\"; filename*=UTF-8''{encoded}")
+    }
+}
 
 /// Handler for Pingora’s `request_filter` phase
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct StaticFilesHandler {
     conf: StaticFilesConf,
+    immutable: HashSet<PathBuf>,
+}
+
+impl StaticFilesHandler {
+    /// Checks whether the file’s extension is permitted by `allowed_extensions` and
+    /// `denied_extensions` settings.
+    fn is_extension_allowed(&self, path: &Path) -> bool {
+        (self.conf.allowed_extensions.is_empty()
+            || has_extension(path, &self.conf.allowed_extensions))
+            && !has_extension(path, &self.conf.denied_extensions)
+    }
+
+    /// Adds a `Content-Disposition: attachment` header if the file matches the
+    /// `attachment_extensions` or `attachment_paths` settings.
+    fn set_content_disposition(
+        &self,
+        header: &mut ResponseHeader,
+        uri_path: &str,
+        path: &Path,
+    ) -> Result<(), Box<Error>> {
+        let matches_path = self.conf.attachment_paths.iter().any(|entry| {
+            if let Some(prefix) = entry.strip_suffix('*') {
+                prefix.is_empty() || (prefix.ends_with('/') && uri_path.starts_with(prefix))
+            } else {
+                entry == uri_path
+            }
+        });
+        if !matches_path && !has_extension(path, &self.conf.attachment_extensions) {
+            return Ok(());
+        }
+
+        if let Some(filename) = path.file_name() {
+            let value = attachment_disposition(&filename.to_string_lossy());
+            header.insert_header(header::CONTENT_DISPOSITION, value)?;
+        }
+        Ok(())
+    }
+
+    /// Marks the response as cacheable indefinitely if the file is listed in the manifest.
+    fn set_cache_control(
+        &self,
+        header: &mut ResponseHeader,
+        path: &Path,
+    ) -> Result<(), Box<Error>> {
+        if self.immutable.contains(path) {
+            header.insert_header(header::CACHE_CONTROL, IMMUTABLE_CACHE_CONTROL)?;
+        }
+        Ok(())
+    }
+
+    /// Looks up the `Link` headers configured via `preload` setting if the response is an HTML
+    /// document. The most specific matching entry is used: exact path match, then longest prefix
+    /// match and the `*` entry last.
+    fn preload_links(&self, uri_path: &str, meta: &Metadata) -> Option<&OneOrMany<String>> {
+        if self.conf.preload.is_empty()
+            || meta
+                .mime
+                .first()
+                .map_or(true, |mime| mime.essence_str() != "text/html")
+        {
+            return None;
+        }
+
+        self.conf.preload.get(uri_path).or_else(|| {
+            self.conf
+                .preload
+                .iter()
+                .filter_map(|(key, links)| {
+                    let prefix = key.strip_suffix('*')?;
+                    (prefix.is_empty() || (prefix.ends_with('/') && uri_path.starts_with(prefix)))
+                        .then_some((prefix.len(), links))
+                })
+                .max_by_key(|(len, _)| *len)
+                .map(|(_, links)| links)
+        })
+    }
+
+    /// Adds `Link` headers configured via `preload` setting if the response is an HTML document.
+    fn set_preload_links(
+        &self,
+        header: &mut ResponseHeader,
+        uri_path: &str,
+        meta: &Metadata,
+    ) -> Result<(), Box<Error>> {
+        for link in self.preload_links(uri_path, meta).into_iter().flatten() {
+            header.append_header(header::LINK, link)?;
+        }
+        Ok(())
+    }
+
+    /// Sends a `103 Early Hints` response with the preload links if enabled via `early_hints`
+    /// setting. HTTP/1.0 clients don’t support informational responses and are skipped.
+    async fn send_early_hints(
+        &self,
+        session: &mut impl SessionWrapper,
+        meta: &Metadata,
+    ) -> Result<(), Box<Error>> {
+        if !self.conf.early_hints || session.req_header().version < Version::HTTP_11 {
+            return Ok(());
+        }
+
+        let links = match self.preload_links(session.uri().path(), meta) {
+            Some(links) if !links.is_empty() => links,
+            _ => return Ok(()),
+        };
+
+        // The http crate has no constant for 103 Early Hints
+        let mut header = ResponseHeader::build(103, Some(links.len()))?;
+        for link in links.iter() {
+            header.append_header(header::LINK, link)?;
+        }
+        session.write_response_header(Box::new(header)).await
+    }
+
+    /// Adds `X-Content-Type-Options: nosniff` header unless disabled via `nosniff` setting.
+    fn set_nosniff(&self, header: &mut ResponseHeader) -> Result<(), Box<Error>> {
+        if self.conf.nosniff {
+            header.insert_header(header::X_CONTENT_TYPE_OPTIONS, "nosniff")?;
+        }
+        Ok(())
+    }
+
+    /// Serves the file at the given path as the response to the current request, with support
+    /// for conditional requests, byte ranges and compression as configured. This is useful if
+    /// another handler decides which file should be served, e.g. a router mapping request paths
+    /// to templates.
+    ///
+    /// Unlike with regular requests, the path isn’t resolved relative to the root directory and
+    /// `allowed_extensions` and `denied_extensions` settings don’t apply to it. Any `Range`,
+    /// `If-None-Match` and similar headers of the request are respected, the preload links are
+    /// determined based on the request URI.
+    pub async fn serve_file(
+        &self,
+        session: &mut impl SessionWrapper,
+        _ctx: &mut <Self as RequestFilter>::CTX,
+        path: impl Into<PathBuf>,
+    ) -> Result<RequestFilterResult, Box<Error>> {
+        let path = path.into();
+        info!("serving file {path:?}");
+        self.serve_path(session, path, false).await
+    }
+
+    /// Resolves the error page configured via `page_404` or `page_404_file` setting if any.
+    fn page_404_path(&self, roots: &[PathBuf]) -> Option<PathBuf> {
+        if let Some(page_404) = &self.conf.page_404 {
+            debug!("error page is {page_404}");
+            match resolve_uri_in_roots(page_404, roots, self.conf.trailing_dots) {
+                Ok((path, _)) => Some(path),
+                Err(err) => {
+                    warn!("Failed resolving error page {page_404}: {err}");
+                    None
+                }
+            }
+        } else if let Some(page_404_file) = &self.conf.page_404_file {
+            debug!("error page file is {page_404_file:?}");
+            if page_404_file.is_file() {
+                Some(page_404_file.clone())
+            } else {
+                warn!("Error page file {page_404_file:?} doesn’t exist");
+                None
+            }
+        } else {
+            None
+        }
+    }
+
+    /// Produces a `404 Not Found` response, using the configured error page if any.
+    async fn serve_not_found(
+        &self,
+        session: &mut impl SessionWrapper,
+        roots: &[PathBuf],
+    ) -> Result<RequestFilterResult, Box<Error>> {
+        if let Some(path) = self.page_404_path(roots) {
+            self.serve_path(session, path, true).await
+        } else {
+            error_response(session, StatusCode::NOT_FOUND).await?;
+            Ok(RequestFilterResult::ResponseSent)
+        }
+    }
+
+    /// Produces the response for a resolved file path. If `not_found` is `true`, the file is an
+    /// error page and will be served with the `404 Not Found` status.
+    async fn serve_path(
+        &self,
+        session: &mut impl SessionWrapper,
+        path: PathBuf,
+        not_found: bool,
+    ) -> Result<RequestFilterResult, Box<Error>> {
+        match session.req_header().method {
+            Method::GET | Method::HEAD => {
+                // Allowed
+            }
+            _ => {
+                warn!("Denying method {}", session.req_header().method);
+                error_response(session, StatusCode::METHOD_NOT_ALLOWED).await?;
+                return Ok(RequestFilterResult::ResponseSent);
+            }
+        }
+
+        let mut compression = Compression::new(
+            session,
+            &self.conf.precompressed,
+            self.conf.cache_compressed_to_disk,
+        );
+
+        let precompressed_path = compression.rewrite_path(session, &path);
+        if precompressed_path.is_none() && self.conf.cache_compressed_to_disk {
+            // File will be available for subsequent requests, serve this one without it
+            compression.generate_precompressed(session, &path);
+        }
+
+        let (path, orig_path) = if let Some(precompressed_path) = precompressed_path {
+            (precompressed_path, Some(path))
+        } else {
+            (path, None)
+        };
+
+        let mut meta = match Metadata::from_path(&path, orig_path.as_ref()) {
+            Ok(meta) => meta,
+            Err(err) if err.kind() == ErrorKind::InvalidInput => {
+                warn!("Path {path:?} is not a regular file, denying access");
+                error_response(session, StatusCode::FORBIDDEN).await?;
+                return Ok(RequestFilterResult::ResponseSent);
+            }
+            Err(err) => {
+                warn!("failed retrieving metadata for path {path:?}: {err}");
+                error_response(session, StatusCode::INTERNAL_SERVER_ERROR).await?;
+                return Ok(RequestFilterResult::ResponseSent);
+            }
+        };
+
+        if let Some(charset) = &self.conf.default_charset {
+            meta.set_default_charset(charset, &self.conf.charset_types);
+        }
+
+        // Conditional requests are decided based on metadata alone, file contents are only
+        // accessed once a response with body is due.
+        if meta.has_failed_precondition(session) {
+            debug!("If-Match/If-Unmodified-Since precondition failed");
+            let mut header = meta.to_custom_header(StatusCode::PRECONDITION_FAILED)?;
+            self.set_nosniff(&mut header)?;
+            let header = compression.transform_header(session, header)?;
+            session.write_response_header(header).await?;
+            return Ok(RequestFilterResult::ResponseSent);
+        }
+
+        if meta.is_not_modified(session) {
+            debug!("If-None-Match/If-Modified-Since check resulted in Not Modified");
+            let mut header = meta.to_custom_header(StatusCode::NOT_MODIFIED)?;
+            self.set_cache_control(&mut header, orig_path.as_ref().unwrap_or(&path))?;
+            self.set_nosniff(&mut header)?;
+            let header = compression.transform_header(session, header)?;
+            session.write_response_header(header).await?;
+            return Ok(RequestFilterResult::ResponseSent);
+        }
+
+        if !not_found {
+            self.send_early_hints(session, &meta).await?;
+        }
+
+        // Multipart responses with Content-Encoding would be misinterpreted, so pre-compressed
+        // files are only ever served with a single range.
+        let allow_multiple =
+            self.conf.multiple_ranges && !not_found && !compression.is_precompressed();
+        let (mut header, start, end) = match extract_range(session, &meta, allow_multiple) {
+            Some(Range::Valid(start, end)) => {
+                debug!("bytes range requested: {start}-{end}");
+                let header = meta.to_partial_content_header(start, end)?;
+                let header = compression.transform_header(session, header)?;
+                (header, start, end)
+            }
+            Some(Range::Multiple(ranges)) => {
+                debug!("multiple bytes ranges requested: {ranges:?}");
+                let multipart = Multipart::new(&ranges, &meta.content_type(), meta.size);
+                let mut header = meta.to_multipart_header(&multipart)?;
+                self.set_nosniff(&mut header)?;
+                self.set_cache_control(&mut header, orig_path.as_ref().unwrap_or(&path))?;
+                self.set_content_disposition(
+                    &mut header,
+                    session.uri().path(),
+                    orig_path.as_ref().unwrap_or(&path),
+                )?;
+                let header = compression.transform_header(session, header)?;
+                session.write_response_header(header).await?;
+
+                if session.req_header().method == Method::GET {
+                    multipart_response(session, &path, &multipart, &compression).await?;
+                }
+                return Ok(RequestFilterResult::ResponseSent);
+            }
+            Some(Range::OutOfBounds) => {
+                debug!("requested bytes range is out of bounds");
+                let mut header = meta.to_custom_header(StatusCode::RANGE_NOT_SATISFIABLE)?;
+                self.set_nosniff(&mut header)?;
+                let header = compression.transform_header(session, header)?;
+                session.write_response_header(header).await?;
+                return Ok(RequestFilterResult::ResponseSent);
+            }
+            None => {
+                // Range is either missing or cannot be parsed, produce the entire file.
+                let header = meta.to_response_header()?;
+                let header = compression.transform_header(session, header)?;
+                (header, 0, meta.size - 1)
+            }
+        };
+
+        self.set_nosniff(&mut header)?;
+        if not_found {
+            header.set_status(StatusCode::NOT_FOUND)?;
+        } else {
+            self.set_cache_control(&mut header, orig_path.as_ref().unwrap_or(&path))?;
+            self.set_preload_links(&mut header, session.uri().path(), &meta)?;
+            self.set_content_disposition(
+                &mut header,
+                session.uri().path(),
+                orig_path.as_ref().unwrap_or(&path),
+            )?;
+        }
+
+        session.write_response_header(header).await?;
+
+        if session.req_header().method == Method::GET {
+            // sendfile would be nice but not currently possible within pingora-proxy (see
+            // https://github.com/cloudflare/pingora/issues/160)
+            file_response(session, &path, start, end, &compression).await?;
+        }
+        Ok(RequestFilterResult::ResponseSent)
+    }
 }
 
 #[async_trait]
@@ -48,8 +430,8 @@ impl RequestFilter for StaticFilesHandler {
         session: &mut impl SessionWrapper,
         _ctx: &mut Self::CTX,
     ) -> Result<RequestFilterResult, Box<Error>> {
-        let root = if let Some(root) = self.conf.root.as_ref() {
-            root
+        let roots = if let Some(roots) = self.conf.root.as_deref().filter(|r| !r.is_empty()) {
+            roots
         } else {
             debug!("received request but static files handler is not configured, ignoring");
             return Ok(RequestFilterResult::Unhandled);
@@ -58,24 +440,14 @@ impl RequestFilter for StaticFilesHandler {
         let uri = session.uri();
         debug!("received URI path {}", uri.path());
 
-        let (mut path, not_found) = match resolve_uri(uri.path(), root) {
-            Ok(path) => (path, false),
+        let trailing_dots = self.conf.trailing_dots;
+        let (mut path, root) = match resolve_uri_in_roots(uri.path(), roots, trailing_dots) {
+            Ok((path, root)) => (path, Some(root)),
             Err(err) if err.kind() == ErrorKind::NotFound => {
                 debug!("canonicalizing resulted in NotFound error");
 
-                let path = self.conf.page_404.as_ref().and_then(|page_404| {
-                    debug!("error page is {page_404}");
-                    match resolve_uri(page_404, root) {
-                        Ok(path) => Some(path),
-                        Err(err) => {
-                            warn!("Failed resolving error page {page_404}: {err}");
-                            None
-                        }
-                    }
-                });
-
-                if let Some(path) = path {
-                    (path, true)
+                if let Some(path) = self.page_404_path(roots) {
+                    (path, None)
                 } else {
                     error_response(session, StatusCode::NOT_FOUND).await?;
                     return Ok(RequestFilterResult::ResponseSent);
@@ -106,9 +478,17 @@ impl RequestFilter for StaticFilesHandler {
         };
 
         debug!("translated into file path {path:?}");
+        let not_found = root.is_none();
+
+        // Check extension before redirecting, a redirect would reveal that the file exists
+        if !not_found && !path.is_dir() && !self.is_extension_allowed(&path) {
+            debug!("file extension of {path:?} isn’t allowed");
+            return self.serve_not_found(session, roots).await;
+        }
 
-        if self.conf.canonicalize_uri && !not_found {
+        if let Some(root) = root.filter(|_| self.conf.canonicalize_uri) {
             if let Some(mut canonical) = path_to_uri(&path, root) {
+                self.conf.trailing_slash.apply(&mut canonical, uri.path());
                 if canonical != uri.path() {
                     if let Some(query) = uri.query() {
                         canonical.push('?');
@@ -142,92 +522,14 @@ impl RequestFilter for StaticFilesHandler {
             }
         }
 
-        info!("successfully resolved request path: {path:?}");
-
-        match session.req_header().method {
-            Method::GET | Method::HEAD => {
-                // Allowed
-            }
-            _ => {
-                warn!("Denying method {}", session.req_header().method);
-                error_response(session, StatusCode::METHOD_NOT_ALLOWED).await?;
-                return Ok(RequestFilterResult::ResponseSent);
-            }
-        }
-
-        let mut compression = Compression::new(session, &self.conf.precompressed);
-
-        let (path, orig_path) =
-            if let Some(precompressed_path) = compression.rewrite_path(session, &path) {
-                (precompressed_path, Some(path))
-            } else {
-                (path, None)
-            };
-
-        let meta = match Metadata::from_path(&path, orig_path.as_ref()) {
-            Ok(meta) => meta,
-            Err(err) if err.kind() == ErrorKind::InvalidInput => {
-                warn!("Path {path:?} is not a regular file, denying access");
-                error_response(session, StatusCode::FORBIDDEN).await?;
-                return Ok(RequestFilterResult::ResponseSent);
-            }
-            Err(err) => {
-                warn!("failed retrieving metadata for path {path:?}: {err}");
-                error_response(session, StatusCode::INTERNAL_SERVER_ERROR).await?;
-                return Ok(RequestFilterResult::ResponseSent);
-            }
-        };
-
-        if meta.has_failed_precondition(session) {
-            debug!("If-Match/If-Unmodified-Since precondition failed");
-            let header = meta.to_custom_header(StatusCode::PRECONDITION_FAILED)?;
-            let header = compression.transform_header(session, header)?;
-            session.write_response_header(header).await?;
-            return Ok(RequestFilterResult::ResponseSent);
-        }
-
-        if meta.is_not_modified(session) {
-            debug!("If-None-Match/If-Modified-Since check resulted in Not Modified");
-            let header = meta.to_custom_header(StatusCode::NOT_MODIFIED)?;
-            let header = compression.transform_header(session, header)?;
-            session.write_response_header(header).await?;
-            return Ok(RequestFilterResult::ResponseSent);
-        }
-
-        let (mut header, start, end) = match extract_range(session, &meta) {
-            Some(Range::Valid(start, end)) => {
-                debug!("bytes range requested: {start}-{end}");
-                let header = meta.to_partial_content_header(start, end)?;
-                let header = compression.transform_header(session, header)?;
-                (header, start, end)
-            }
-            Some(Range::OutOfBounds) => {
-                debug!("requested bytes range is out of bounds");
-                let header = meta.to_custom_header(StatusCode::RANGE_NOT_SATISFIABLE)?;
-                let header = compression.transform_header(session, header)?;
-                session.write_response_header(header).await?;
-                return Ok(RequestFilterResult::ResponseSent);
-            }
-            None => {
-                // Range is either missing or cannot be parsed, produce the entire file.
-                let header = meta.to_response_header()?;
-                let header = compression.transform_header(session, header)?;
-                (header, 0, meta.size - 1)
-            }
-        };
-
-        if not_found {
-            header.set_status(StatusCode::NOT_FOUND)?;
+        if !not_found && !path.is_dir() && !self.is_extension_allowed(&path) {
+            debug!("file extension of index file {path:?} isn’t allowed");
+            return self.serve_not_found(session, roots).await;
         }
 
-        session.write_response_header(header).await?;
+        info!("successfully resolved request path: {path:?}");
 
-        if session.req_header().method == Method::GET {
-            // sendfile would be nice but not currently possible within pingora-proxy (see
-            // https://github.com/cloudflare/pingora/issues/160)
-            file_response(session, &path, start, end, &compression).await?;
-        }
-        Ok(RequestFilterResult::ResponseSent)
+        self.serve_path(session, path, not_found).await
     }
 }
 
@@ -235,19 +537,28 @@ impl TryFrom<StaticFilesConf> for StaticFilesHandler {
     type Error = Box<Error>;
 
     fn try_from(mut conf: StaticFilesConf) -> Result<Self, Self::Error> {
-        conf.root = if let Some(root) = conf.root {
-            Some(root.canonicalize().map_err(|err| {
+        for root in conf.root.iter_mut().flatten() {
+            if let Some(base) = conf.root_base.as_ref().filter(|_| root.is_relative()) {
+                *root = base.join(&root);
+            }
+
+            *root = root.canonicalize().map_err(|err| {
                 Error::because(
                     ErrorType::InternalError,
                     format!("Failed accessing root path {:?}", root),
                     err,
                 )
-            })?)
-        } else {
-            None
+            })?;
+        }
+
+        // A relative manifest path is resolved against the first root directory
+        let root = conf.root.as_ref().and_then(|roots| roots.first());
+        let immutable = match (root, &conf.manifest) {
+            (Some(root), Some(manifest)) => load_manifest(manifest, root)?,
+            _ => HashSet::new(),
         };
 
         debug!("Initialized static files handler, settings: {conf:#?}");
-        Ok(Self { conf })
+        Ok(Self { conf, immutable })
     }
 }
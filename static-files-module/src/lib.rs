@@ -20,7 +20,8 @@
 //!
 //! * `GET` and `HEAD` requests
 //! * Configurable directory index files (`index.html` by default)
-//! * Page configurable to display on 404 Not Found errors instead of the standard error page
+//! * Page configurable to display on 404 Not Found errors instead of the standard error page,
+//!   either within the root directory (`page_404`) or anywhere in the file system (`page_404_file`)
 //! * Conditional requests via `If-Modified-Since`, `If-Unmodified-Since`, `If-Match`, `If-None`
 //!   match HTTP headers
 //! * Byte range requests via `Range` and `If-Range` HTTP headers
@@ -31,8 +32,9 @@
 //!
 //! ## Known limitations
 //!
-//! * Requests with multiple byte ranges are not supported and will result in the full file being
-//!   returned. The complexity required for implementing this feature isn’t worth this rare use case.
+//! * Requests with multiple byte ranges result in the full file being returned unless the
+//!   `multiple_ranges` setting is enabled. Pre-compressed files are always served with a single
+//!   range.
 //! * Zero-copy data transfer (a.k.a. sendfile) cannot currently be supported within the Pingora
 //!   framework.
 //!
@@ -68,12 +70,197 @@
 //! let mut conf = Conf::load_from_files(opt.startup.conf.as_deref().unwrap_or(&[])).unwrap();
 //! conf.static_files.merge_with_opt(opt.static_files);
 //!
-//! let app = DefaultApp::<StaticFilesHandler>::from_conf(conf.static_files).unwrap();
+//! let app = DefaultApp::<StaticFilesHandler>::from_conf(conf.static_files)
+//!     .unwrap()
+//!     .with_startup_conf(&conf.startup);
 //! let server = conf.startup.into_server(app, Some(opt.startup)).unwrap();
 //!
 //! // Do something with the server here, e.g. call server.run_forever()
 //! ```
 //!
+//! ## Root directory
+//!
+//! A relative `root` path is resolved against the current working directory by default. With the
+//! `root_relative_to_config` flag, it is resolved against the directory of the configuration file
+//! instead:
+//!
+//! ```yaml
+//! root: ./production-root
+//! root_relative_to_config: true
+//! ```
+//!
+//! The directory of the configuration file containing the flag is used, so it is best to keep
+//! both settings in the same file. A root directory passed via the `--root` command line option
+//! is always resolved against the current working directory.
+//!
+//! Multiple root directories can be given as a list, e.g. to overlay a theme directory on top of
+//! a base directory:
+//!
+//! ```yaml
+//! root:
+//! - /var/www/theme
+//! - /var/www/base
+//! ```
+//!
+//! The root directories are searched in the order given, the first one containing the requested
+//! path (file or directory) is used. Paths leading outside the root directory are rejected for
+//! each root directory individually. The `--root` command line option can be specified multiple
+//! times to the same effect. A relative `manifest` path is resolved against the first root
+//! directory.
+//!
+//! ## Trailing dots and spaces
+//!
+//! Windows ignores trailing dots and spaces in file names: `file.txt.` and `file.txt ` refer to
+//! the same file as `file.txt`. This can be abused to circumvent restrictions based on file names,
+//! so by default such path components are rejected with 400 Bad Request on Windows. The
+//! `trailing_dots` setting allows changing this behavior on any system:
+//!
+//! ```yaml
+//! root: /var/www/html
+//! trailing_dots: strip
+//! ```
+//!
+//! Possible values are `allow` (use paths unchanged, the default on systems other than Windows),
+//! `reject` and `strip` (remove trailing dots and spaces). With `strip`, requests like
+//! `/file.txt.` will be redirected to `/file.txt` unless `canonicalize_uri` is disabled.
+//!
+//! ## Trailing slashes
+//!
+//! With `canonicalize_uri` enabled (the default), requests for directories without a trailing
+//! slash like `/dir` are redirected to `/dir/`, and requests for files with a trailing slash like
+//! `/file.txt/` are redirected to `/file.txt`. The `trailing_slash` setting allows changing the
+//! canonical form:
+//!
+//! ```yaml
+//! root: /var/www/html
+//! trailing_slash: remove
+//! ```
+//!
+//! Possible values are `add` (the default behavior described above), `remove` (no URIs end with a
+//! slash, `/dir/` is redirected to `/dir`) and `preserve` (no redirects based on trailing
+//! slashes, both `/dir` and `/dir/` are served). The root directory `/` always keeps its slash.
+//!
+//! ## Restricting file extensions
+//!
+//! The `allowed_extensions` setting restricts the files served to the listed extensions, the
+//! `denied_extensions` setting excludes some extensions. Requests for other files result in a
+//! `404 Not Found` response, regardless of whether the file exists. The usual error page is
+//! displayed in this case, and no redirect to the canonical URI happens:
+//!
+//! ```yaml
+//! allowed_extensions: [pdf, zip]
+//! denied_extensions: [php]
+//! ```
+//!
+//! Only the final file extension is considered, matching is case-insensitive. If
+//! `allowed_extensions` is set, files without an extension won’t be served.
+//!
+//! ## Downloads
+//!
+//! Browsers display many file types like PDF documents or images rather than downloading them.
+//! The `attachment_extensions` and `attachment_paths` settings add a
+//! `Content-Disposition: attachment` header to the matching files, making browsers offer to save
+//! these instead:
+//!
+//! ```yaml
+//! attachment_extensions: [zip, iso]
+//! attachment_paths:
+//! - /downloads/*
+//! - /report.pdf
+//! ```
+//!
+//! The file name in the header is the name of the file on disk. Non-ASCII file names are encoded
+//! as described in [RFC 6266](https://www.rfc-editor.org/rfc/rfc6266#section-4.3), older
+//! clients will see an ASCII-only version with all other characters replaced by underscores.
+//!
+//! ## Default charset
+//!
+//! Browsers might guess the character encoding of text files wrongly if the `Content-Type` header
+//! doesn’t specify it. The `default_charset` setting adds a charset to all `text/*` MIME types,
+//! additional MIME types can be listed in `charset_types`:
+//!
+//! ```yaml
+//! root: /var/www/html
+//! default_charset: utf-8
+//! charset_types: [application/javascript, application/json]
+//! ```
+//!
+//! With this configuration, HTML files are served with `Content-Type: text/html; charset=utf-8`
+//! whereas images are unaffected.
+//!
+//! ## MIME type sniffing
+//!
+//! File responses always have a `Content-Type` header, `application/octet-stream` is used if the
+//! MIME type cannot be determined from the file extension. These responses also get the
+//! `X-Content-Type-Options: nosniff` header, so that browsers won’t interpret e.g. uploaded text
+//! files as HTML. This can be disabled via the `nosniff` setting:
+//!
+//! ```yaml
+//! root: /var/www/html
+//! nosniff: false
+//! ```
+//!
+//! ## Multiple byte ranges
+//!
+//! By default, requests for multiple byte ranges like `Range: bytes=0-99,200-299` are answered
+//! with the full file. With the `multiple_ranges` setting enabled, a `multipart/byteranges`
+//! response containing the requested ranges is produced instead:
+//!
+//! ```yaml
+//! root: /var/www/html
+//! multiple_ranges: true
+//! ```
+//!
+//! Overlapping or adjacent ranges are merged, out of bounds ranges are ignored.
+//!
+//! ## Preload links
+//!
+//! The `preload` setting adds `Link` headers to HTML responses, allowing browsers to start
+//! downloading resources required by the page early. The keys are URI paths, a key ending with
+//! `/*` applies to all paths starting with the given prefix, the key `*` applies to all HTML files:
+//!
+//! ```yaml
+//! root: /var/www/html
+//! preload:
+//!   /index.html:
+//!   - </app.css>; rel=preload; as=style
+//!   - </app.js>; rel=preload; as=script
+//!   /docs/*: </docs.css>; rel=preload; as=style
+//! ```
+//!
+//! Only the most specific matching entry is used: an exact match takes precedence over prefix
+//! matches, and a longer prefix takes precedence over a shorter one.
+//!
+//! With the `early_hints` setting enabled, the same links are also sent in a `103 Early Hints`
+//! informational response before the actual response. This allows browsers to start downloading
+//! resources while the server is still working on the response. HTTP/1.0 clients don’t support
+//! informational responses and won’t receive early hints.
+//!
+//! ## Immutable assets
+//!
+//! Build tools often produce file names containing a content hash, along with a manifest mapping
+//! the original file names to the hashed ones. If you point the `manifest` setting to such a JSON
+//! file, all files listed there will be served with a `Cache-Control` header allowing clients to
+//! cache them indefinitely:
+//!
+//! ```yaml
+//! root: /var/www/html
+//! manifest: assets/manifest.json
+//! ```
+//!
+//! A relative manifest path is resolved against the root directory. Manifest values can be
+//! either file names (`{"app.js": "app.3f2a1b.js"}`) or objects with a `file` property like the
+//! ones produced by Vite. File names are resolved against the manifest’s directory unless these
+//! start with a slash, in which case they are resolved against the root directory. Note that
+//! references to the original file names are not rewritten.
+//!
+//! ## Serving files from other handlers
+//!
+//! If another handler decides which file should be served, e.g. a router mapping request paths
+//! to template files, it can call [`StaticFilesHandler::serve_file`] with the file path. The
+//! response will be produced in the same way as for regular requests, including support for
+//! conditional requests, byte ranges and compression.
+//!
 //! ## Compression support
 //!
 //! You can activate support for selected compression algorithms via the `precompressed`
@@ -91,7 +278,27 @@
 //! a client requesting `file.txt` and sending HTTP header `Accept-Encoding: br, gzip` will receive
 //! `file.txt.br` file or, if not found, `file.txt.gz` file. The order in which
 //! `StaticFilesHandler` will look for pre-compressed files is determined by the client’s
-//! compression algorithm preferences.
+//! compression algorithm preferences. Pre-compressed files that aren’t smaller than the original
+//! file are ignored, this can happen with very small files.
+//!
+//! Supported file extensions are `gz` (gzip), `zz` (zlib deflate), `z` (compress), `br` (Brotli)
+//! and `zst` (Zstandard). Note that the legacy `compress` format can only be served from
+//! pre-compressed `.z` files, there is no dynamic compression support for it.
+//!
+//! If the `cache_compressed_to_disk` setting is enabled, missing pre-compressed files will be
+//! generated in the background on first request and stored next to the original file:
+//!
+//! ```yaml
+//! root: /var/www/html
+//! precompressed: [br, gz]
+//! cache_compressed_to_disk: true
+//! ```
+//!
+//! This requires the server to have write access to the root directory and dynamic compression
+//! to be enabled (see below), until the file is generated responses are compressed dynamically.
+//! Generating files is supported for `gz`, `zz` and `br` extensions. Only files with a
+//! compressible MIME type like `text/html` and a size between 1 KiB and 64 MiB are considered.
+//! Pre-compressed files older than the original file are ignored and generated anew.
 //!
 //! It is also possible to compress files dynamically on the fly via Pingora’s downstream
 //! compression. For that, activate compression for the session before calling
@@ -116,16 +323,20 @@
 //! ```
 
 mod compression;
-mod compression_algorithm;
 mod configuration;
+mod disk_cache;
 mod file_writer;
 mod handler;
+mod manifest;
 pub mod metadata;
 pub mod path;
 pub mod range;
 #[cfg(test)]
 mod tests;
 
-pub use compression_algorithm::{CompressionAlgorithm, UnsupportedCompressionAlgorithm};
 pub use configuration::{StaticFilesConf, StaticFilesOpt};
 pub use handler::StaticFilesHandler;
+pub use pandora_module_utils::compression_algorithm::{
+    CompressionAlgorithm, UnsupportedCompressionAlgorithm,
+};
+pub use path::{TrailingDots, TrailingSlash};
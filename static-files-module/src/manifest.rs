@@ -0,0 +1,88 @@
+// Copyright 2024 Wladimir Palant
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Handles build manifests listing content-addressed assets
+
+use log::warn;
+use pandora_module_utils::pingora::{Error, ErrorType};
+use serde::Deserialize;
+use std::collections::{HashMap, HashSet};
+use std::fs::File;
+use std::io::BufReader;
+use std::path::{Path, PathBuf};
+
+/// `Cache-Control` header value for assets listed in the manifest
+pub(crate) const IMMUTABLE_CACHE_CONTROL: &str = "public, max-age=31536000, immutable";
+
+/// A manifest entry, either the asset path directly or a structure with a `file` field
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum ManifestEntry {
+    Path(String),
+    Chunk { file: String },
+}
+
+impl ManifestEntry {
+    fn file(&self) -> &str {
+        match self {
+            Self::Path(file) => file,
+            Self::Chunk { file } => file,
+        }
+    }
+}
+
+/// Reads a JSON manifest mapping original file names to hashed file names. Returns the resolved
+/// paths of all hashed files.
+///
+/// Paths starting with a slash are resolved relative to the root directory, all other paths
+/// relative to the directory containing the manifest. Files that don’t exist within the root
+/// directory are ignored.
+pub(crate) fn load_manifest(manifest: &Path, root: &Path) -> Result<HashSet<PathBuf>, Box<Error>> {
+    let manifest = root.join(manifest);
+    let file = File::open(&manifest).map_err(|err| {
+        Error::because(
+            ErrorType::FileOpenError,
+            format!("failed opening manifest file {manifest:?}"),
+            err,
+        )
+    })?;
+    let entries: HashMap<String, ManifestEntry> = serde_json::from_reader(BufReader::new(file))
+        .map_err(|err| {
+            Error::because(
+                ErrorType::FileReadError,
+                format!("failed parsing manifest file {manifest:?}"),
+                err,
+            )
+        })?;
+
+    let base = manifest.parent().unwrap_or(root);
+    let mut result = HashSet::new();
+    for entry in entries.values() {
+        let file = entry.file();
+        let path = if let Some(file) = file.strip_prefix('/') {
+            root.join(file)
+        } else {
+            base.join(file)
+        };
+
+        match path.canonicalize() {
+            Ok(path) if path.starts_with(root) => {
+                result.insert(path);
+            }
+            Ok(path) => warn!("ignoring manifest entry {path:?} outside the root directory"),
+            Err(err) => warn!("ignoring manifest entry {path:?}: {err}"),
+        }
+    }
+    Ok(result)
+}
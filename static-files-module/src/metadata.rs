@@ -22,6 +22,8 @@ use std::io::{Error, ErrorKind};
 use std::path::Path;
 use std::time::SystemTime;
 
+use crate::range::Multipart;
+
 /// Helper wrapping file metadata information
 #[derive(Debug)]
 pub struct Metadata {
@@ -34,6 +36,8 @@ pub struct Metadata {
     pub modified: Option<String>,
     /// ETag header for the file, encoding last modified time and file size
     pub etag: String,
+    /// Charset to be added to the `Content-Type` header if any
+    pub charset: Option<String>,
 }
 
 impl Metadata {
@@ -69,9 +73,33 @@ impl Metadata {
             size,
             modified,
             etag,
+            charset: None,
         })
     }
 
+    /// Sets the charset to be added to the `Content-Type` header. This only applies to `text/*`
+    /// MIME types and the ones listed in `charset_types`, and only if the MIME type doesn’t
+    /// specify a charset already.
+    pub fn set_default_charset(&mut self, charset: &str, charset_types: &[String]) {
+        let mime = if let Some(mime) = self.mime.first() {
+            mime
+        } else {
+            return;
+        };
+
+        if mime.get_param("charset").is_some() {
+            return;
+        }
+
+        if mime.type_().as_str() == "text"
+            || charset_types
+                .iter()
+                .any(|ty| ty.eq_ignore_ascii_case(mime.essence_str()))
+        {
+            self.charset = Some(charset.to_owned());
+        }
+    }
+
     /// Checks `If-Match` and `If-Unmodified-Since` headers of the request to determine whether
     /// a `412 Precondition Failed` response should be produced.
     pub fn has_failed_precondition(&self, session: &impl SessionWrapper) -> bool {
@@ -122,15 +150,22 @@ impl Metadata {
         }
     }
 
+    /// Produces the value of the `Content-Type` header for the file.
+    pub(crate) fn content_type(&self) -> String {
+        let mime = self.mime.first_or_octet_stream();
+        if let Some(charset) = &self.charset {
+            format!("{mime}; charset={charset}")
+        } else {
+            mime.to_string()
+        }
+    }
+
     #[inline(always)]
     fn add_common_headers(
         &self,
         header: &mut ResponseHeader,
     ) -> Result<(), Box<pandora_module_utils::pingora::Error>> {
-        header.append_header(
-            header::CONTENT_TYPE,
-            self.mime.first_or_octet_stream().as_ref(),
-        )?;
+        header.append_header(header::CONTENT_TYPE, self.content_type())?;
         if let Some(modified) = &self.modified {
             header.append_header(header::LAST_MODIFIED, modified)?;
         }
@@ -165,6 +200,25 @@ impl Metadata {
         Ok(Box::new(header))
     }
 
+    /// Produces a `206 Partial Content` response for a `multipart/byteranges` body and adds
+    /// headers according to file metadata.
+    pub(crate) fn to_multipart_header(
+        &self,
+        multipart: &Multipart,
+    ) -> Result<Box<ResponseHeader>, Box<pandora_module_utils::pingora::Error>> {
+        let mut header = ResponseHeader::build(StatusCode::PARTIAL_CONTENT, Some(8))?;
+        header.append_header(
+            header::CONTENT_LENGTH,
+            multipart.content_length().to_string(),
+        )?;
+        header.append_header(header::CONTENT_TYPE, multipart.content_type())?;
+        if let Some(modified) = &self.modified {
+            header.append_header(header::LAST_MODIFIED, modified)?;
+        }
+        header.append_header(header::ETAG, &self.etag)?;
+        Ok(Box::new(header))
+    }
+
     /// Produces a response with specified status code and no response body (all headers added
     /// except `Content-Length``).
     pub(crate) fn to_custom_header(
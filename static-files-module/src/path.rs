@@ -14,7 +14,10 @@
 
 //! Path resolution logic
 
-use percent_encoding::{percent_decode_str, percent_encode, AsciiSet, CONTROLS};
+use clap::ValueEnum;
+use pandora_module_utils::pingora::percent_decode_path;
+use percent_encoding::{percent_encode, AsciiSet, CONTROLS};
+use serde::Deserialize;
 use std::io::{Error, ErrorKind};
 use std::path::{Path, PathBuf};
 
@@ -36,23 +39,111 @@ fn path_from_bytes(bytes: &[u8]) -> String {
     String::from_utf8_lossy(bytes).into_owned()
 }
 
+/// Determines how path components with trailing dots or spaces are treated.
+///
+/// Windows ignores trailing dots and spaces in file names, so that `file.txt.` and `file.txt `
+/// refer to the same file as `file.txt`. This can be used to circumvent restrictions based on
+/// file names.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum TrailingDots {
+    /// Path components are used unchanged (default on systems other than Windows)
+    Allow,
+    /// Requests with such path components are rejected as invalid (default on Windows)
+    Reject,
+    /// Trailing dots and spaces are removed from path components
+    Strip,
+}
+
+impl Default for TrailingDots {
+    fn default() -> Self {
+        if cfg!(windows) {
+            Self::Reject
+        } else {
+            Self::Allow
+        }
+    }
+}
+
+/// Determines the canonical form of URIs with regard to trailing slashes.
+///
+/// Only applies if `canonicalize_uri` setting is enabled, requests not matching the canonical
+/// form are redirected then.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum TrailingSlash {
+    /// Trailing slashes are left as requested, both `/dir` and `/dir/` are canonical
+    Preserve,
+    /// Directory URIs end with a slash, file URIs don’t (default)
+    #[default]
+    Add,
+    /// No URIs end with a slash, with the exception of the root directory
+    Remove,
+}
+
+impl TrailingSlash {
+    /// Adjusts a canonical URI path produced by [`path_to_uri`] to this policy. `requested` is
+    /// the URI path of the request.
+    pub fn apply(self, canonical: &mut String, requested: &str) {
+        let has_slash = match self {
+            Self::Preserve => requested.ends_with('/'),
+            Self::Add => return,
+            Self::Remove => false,
+        };
+
+        if has_slash && !canonical.ends_with('/') {
+            canonical.push('/');
+        } else if !has_slash && canonical.len() > 1 {
+            if let Some(stripped) = canonical.strip_suffix('/') {
+                canonical.truncate(stripped.len());
+            }
+        }
+    }
+}
+
+/// Applies the trailing dots handling to a decoded path component.
+fn handle_trailing_dots(component: &[u8], trailing_dots: TrailingDots) -> Result<&[u8], Error> {
+    if trailing_dots == TrailingDots::Allow || component == b"." || component == b".." {
+        return Ok(component);
+    }
+
+    let stripped = match component.iter().rposition(|c| *c != b'.' && *c != b' ') {
+        Some(pos) => &component[..=pos],
+        None => &[],
+    };
+    if stripped.len() == component.len() {
+        Ok(component)
+    } else if trailing_dots == TrailingDots::Strip && !stripped.is_empty() {
+        Ok(stripped)
+    } else {
+        Err(ErrorKind::InvalidInput.into())
+    }
+}
+
 /// Resolves the path from a URI against the path to a root directory.
 ///
 /// This will return an error under the following conditions:
 ///
-/// * Invalid path, not starting with a slash (/): results in [`ErrorKind::InvalidInput`]
+/// * Invalid path, not starting with a slash (/), containing invalid percent-encoded sequences or
+///   an encoded null byte (`%00`), or containing path components with trailing dots or spaces if
+///   rejected by `trailing_dots` setting: results in [`ErrorKind::InvalidInput`]
 /// * Resolved path outside the root directory: results in [`ErrorKind::InvalidData`]
 /// * [`std::fs::canonicalize()`] failed: results in [`ErrorKind::NotFound`],
 ///   [`ErrorKind::PermissionDenied`] and other errors
-pub fn resolve_uri(uri_path: &str, root: &Path) -> Result<PathBuf, Error> {
+pub fn resolve_uri(
+    uri_path: &str,
+    root: &Path,
+    trailing_dots: TrailingDots,
+) -> Result<PathBuf, Error> {
     let uri_path = uri_path.strip_prefix('/').ok_or(ErrorKind::InvalidInput)?;
 
     let uri_path = uri_path.strip_suffix('/').unwrap_or(uri_path);
 
     let mut path = root.to_path_buf();
     for component in uri_path.split('/') {
-        let decoded = percent_decode_str(component).collect::<Vec<_>>();
-        path.push(path_from_bytes(&decoded))
+        let decoded = percent_decode_path(component).ok_or(ErrorKind::InvalidInput)?;
+        let decoded = handle_trailing_dots(&decoded, trailing_dots)?;
+        path.push(path_from_bytes(decoded))
     }
 
     let path = path.canonicalize()?;
@@ -64,6 +155,28 @@ pub fn resolve_uri(uri_path: &str, root: &Path) -> Result<PathBuf, Error> {
     }
 }
 
+/// Resolves the path from a URI against a list of root directories, trying them in order.
+///
+/// The path is resolved against each root directory via [`resolve_uri`] and the first match is
+/// returned along with the root directory it was found in. Only [`ErrorKind::NotFound`] errors
+/// result in the next root directory being tried, any other error is returned immediately. If no
+/// root directories are given, [`ErrorKind::NotFound`] is returned.
+pub fn resolve_uri_in_roots<'a>(
+    uri_path: &str,
+    roots: &'a [PathBuf],
+    trailing_dots: TrailingDots,
+) -> Result<(PathBuf, &'a Path), Error> {
+    let mut last_error = ErrorKind::NotFound.into();
+    for root in roots {
+        match resolve_uri(uri_path, root, trailing_dots) {
+            Ok(path) => return Ok((path, root)),
+            Err(err) if err.kind() == ErrorKind::NotFound => last_error = err,
+            Err(err) => return Err(err),
+        }
+    }
+    Err(last_error)
+}
+
 /// Calculates the canonical URI path describing the path relative to a root directory.
 ///
 /// This will return `None` for paths outside the root directory.
@@ -82,3 +195,80 @@ pub fn path_to_uri(path: &Path, root: &Path) -> Option<String> {
     }
     Some(uri)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn trailing_slash() {
+        for (canonical, requested, preserve, add, remove) in [
+            ("/", "/", "/", "/", "/"),
+            ("/dir/", "/dir", "/dir", "/dir/", "/dir"),
+            ("/dir/", "/dir/", "/dir/", "/dir/", "/dir"),
+            (
+                "/file.txt",
+                "/file.txt",
+                "/file.txt",
+                "/file.txt",
+                "/file.txt",
+            ),
+            (
+                "/file.txt",
+                "/file.txt/",
+                "/file.txt/",
+                "/file.txt",
+                "/file.txt",
+            ),
+        ] {
+            for (trailing_slash, expected) in [
+                (TrailingSlash::Preserve, preserve),
+                (TrailingSlash::Add, add),
+                (TrailingSlash::Remove, remove),
+            ] {
+                let mut result = canonical.to_owned();
+                trailing_slash.apply(&mut result, requested);
+                assert_eq!(result, expected, "{requested:?} {trailing_slash:?}");
+            }
+        }
+    }
+
+    #[test]
+    fn trailing_dots() {
+        for (component, allow, reject, strip) in [
+            (
+                "file.txt",
+                Some("file.txt"),
+                Some("file.txt"),
+                Some("file.txt"),
+            ),
+            ("file.txt.", Some("file.txt."), None, Some("file.txt")),
+            ("file.txt ", Some("file.txt "), None, Some("file.txt")),
+            ("file.txt. . ", Some("file.txt. . "), None, Some("file.txt")),
+            (
+                " file.txt",
+                Some(" file.txt"),
+                Some(" file.txt"),
+                Some(" file.txt"),
+            ),
+            (".hidden", Some(".hidden"), Some(".hidden"), Some(".hidden")),
+            (".", Some("."), Some("."), Some(".")),
+            ("..", Some(".."), Some(".."), Some("..")),
+            ("...", Some("..."), None, None),
+            (" ", Some(" "), None, None),
+        ] {
+            for (trailing_dots, expected) in [
+                (TrailingDots::Allow, allow),
+                (TrailingDots::Reject, reject),
+                (TrailingDots::Strip, strip),
+            ] {
+                let result = handle_trailing_dots(component.as_bytes(), trailing_dots).ok();
+                assert_eq!(
+                    result,
+                    expected.map(|s| s.as_bytes()),
+                    "{component:?} {trailing_dots:?}"
+                );
+            }
+        }
+    }
+}
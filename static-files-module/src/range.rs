@@ -16,34 +16,32 @@
 
 use http::header;
 use pandora_module_utils::pingora::SessionWrapper;
+use std::collections::hash_map::RandomState;
+use std::hash::BuildHasher;
 use std::str::FromStr;
 
 use crate::metadata::Metadata;
 
 /// Represents the result of parsing the `Range` HTTP header.
-#[derive(Debug, Clone, Copy, PartialEq)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum Range {
     /// A valid range with the given start and end bounds
     Valid(u64, u64),
+    /// Multiple valid ranges, sorted and with overlapping ranges merged
+    Multiple(Vec<(u64, u64)>),
     /// A range that is outside of the file’s boundaries
     OutOfBounds,
 }
 
 impl Range {
-    /// Parses the value of a `Range` HTTP header. The file size is required to resolve ranges
-    /// specified relative to the end of file and to recognize out of bounds ranges. Ranges that
-    /// cannot be parsed (unexpected format) will result in `None`.
-    pub fn parse(range: &str, file_size: u64) -> Option<Self> {
-        let (units, range) = range.split_once('=')?;
-        if units != "bytes" {
-            return None;
-        }
-
-        let (start, end) = range.trim().split_once('-')?;
+    /// Parses a single range specification like `0-499`. Returns `Some(None)` for ranges outside
+    /// of the file’s boundaries.
+    fn parse_spec(spec: &str, file_size: u64) -> Option<Option<(u64, u64)>> {
+        let (start, end) = spec.trim().split_once('-')?;
         let (start, end) = if start.is_empty() {
             let len = u64::from_str(end.trim()).ok()?;
             if len > file_size {
-                return Some(Self::OutOfBounds);
+                return Some(None);
             }
             (file_size - len, file_size - 1)
         } else if end.is_empty() {
@@ -56,9 +54,52 @@ impl Range {
         };
 
         if end >= file_size || start > end {
-            Some(Self::OutOfBounds)
+            Some(None)
         } else {
-            Some(Self::Valid(start, end))
+            Some(Some((start, end)))
+        }
+    }
+
+    /// Parses the value of a `Range` HTTP header. The file size is required to resolve ranges
+    /// specified relative to the end of file and to recognize out of bounds ranges. Ranges that
+    /// cannot be parsed (unexpected format) will result in `None`.
+    ///
+    /// Multiple ranges are only accepted if `allow_multiple` is `true`, otherwise these result in
+    /// `None` as well. Out of bounds ranges are ignored in a multiple ranges request, the result
+    /// is only `OutOfBounds` if none of the ranges are valid.
+    pub fn parse(range: &str, file_size: u64, allow_multiple: bool) -> Option<Self> {
+        let (units, range) = range.split_once('=')?;
+        if units != "bytes" {
+            return None;
+        }
+
+        let specs = range.split(',').collect::<Vec<_>>();
+        if specs.len() > 1 && !allow_multiple {
+            return None;
+        }
+
+        let mut ranges = Vec::new();
+        for spec in specs {
+            if let Some(range) = Self::parse_spec(spec, file_size)? {
+                ranges.push(range);
+            }
+        }
+
+        ranges.sort_unstable();
+        let mut merged: Vec<(u64, u64)> = Vec::with_capacity(ranges.len());
+        for (start, end) in ranges {
+            match merged.last_mut() {
+                Some((_, last_end)) if start <= last_end.saturating_add(1) => {
+                    *last_end = (*last_end).max(end);
+                }
+                _ => merged.push((start, end)),
+            }
+        }
+
+        match merged.len() {
+            0 => Some(Self::OutOfBounds),
+            1 => Some(Self::Valid(merged[0].0, merged[0].1)),
+            _ => Some(Self::Multiple(merged)),
         }
     }
 }
@@ -67,10 +108,13 @@ impl Range {
 /// if any.
 ///
 /// `Range` header missing, using some unsupported format or overruled by `If-Range` header will
-/// all result in `None` being returned.
-///
-/// Note: Multiple ranges are not supported.
-pub fn extract_range(session: &impl SessionWrapper, meta: &Metadata) -> Option<Range> {
+/// all result in `None` being returned. Multiple ranges are only considered if `allow_multiple` is
+/// `true`.
+pub fn extract_range(
+    session: &impl SessionWrapper,
+    meta: &Metadata,
+    allow_multiple: bool,
+) -> Option<Range> {
     let headers = &session.req_header().headers;
     if let Some(value) = headers
         .get(header::IF_RANGE)
@@ -89,7 +133,58 @@ pub fn extract_range(session: &impl SessionWrapper, meta: &Metadata) -> Option<R
     let value = headers.get(header::RANGE)?;
     let value = value.to_str().ok()?;
 
-    Range::parse(value, meta.size)
+    Range::parse(value, meta.size, allow_multiple)
+}
+
+/// Layout of a `multipart/byteranges` response body
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Multipart {
+    boundary: String,
+    parts: Vec<(String, u64, u64)>,
+}
+
+impl Multipart {
+    /// Prepares the multipart response for the given ranges of a file with the given size and MIME
+    /// type.
+    pub(crate) fn new(ranges: &[(u64, u64)], content_type: &str, file_size: u64) -> Self {
+        let boundary = format!("{:016x}", RandomState::new().hash_one(ranges));
+        let parts = ranges
+            .iter()
+            .map(|(start, end)| {
+                let header = format!(
+                    "\r\n--{boundary}\r\n\
+                     Content-Type: {content_type}\r\n\
+                     Content-Range: bytes {start}-{end}/{file_size}\r\n\r\n"
+                );
+                (header, *start, *end)
+            })
+            .collect();
+        Self { boundary, parts }
+    }
+
+    /// Value of the `Content-Type` header for the response
+    pub(crate) fn content_type(&self) -> String {
+        format!("multipart/byteranges; boundary={}", self.boundary)
+    }
+
+    /// Parts of the response: part header along with start and end of the range
+    pub(crate) fn parts(&self) -> &[(String, u64, u64)] {
+        &self.parts
+    }
+
+    /// Closing delimiter to be sent after all parts
+    pub(crate) fn trailer(&self) -> String {
+        format!("\r\n--{}--\r\n", self.boundary)
+    }
+
+    /// Total length of the response body
+    pub(crate) fn content_length(&self) -> u64 {
+        self.parts
+            .iter()
+            .map(|(header, start, end)| header.len() as u64 + end - start + 1)
+            .sum::<u64>()
+            + self.trailer().len() as u64
+    }
 }
 
 #[cfg(test)]
@@ -106,6 +201,7 @@ mod tests {
             size: 1000,
             modified: Some("Fri, 15 May 2015 15:34:21 GMT".into()),
             etag: "\"abc\"".into(),
+            charset: None,
         }
     }
 
@@ -122,14 +218,14 @@ mod tests {
     #[test(tokio::test)]
     async fn no_range() {
         let session = make_session("").await;
-        assert_eq!(extract_range(&session, &metadata()), None);
+        assert_eq!(extract_range(&session, &metadata(), false), None);
     }
 
     #[test(tokio::test)]
     async fn valid_range() {
         let session = make_session("bytes=0-499").await;
         assert_eq!(
-            extract_range(&session, &metadata()),
+            extract_range(&session, &metadata(), false),
             Some(Range::Valid(0, 499))
         );
     }
@@ -137,14 +233,14 @@ mod tests {
     #[test(tokio::test)]
     async fn unknown_units() {
         let session = make_session("eur=0-499").await;
-        assert_eq!(extract_range(&session, &metadata()), None);
+        assert_eq!(extract_range(&session, &metadata(), false), None);
     }
 
     #[test(tokio::test)]
     async fn open_range() {
         let session = make_session("bytes=500-").await;
         assert_eq!(
-            extract_range(&session, &metadata()),
+            extract_range(&session, &metadata(), false),
             Some(Range::Valid(500, 999))
         );
     }
@@ -153,7 +249,7 @@ mod tests {
     async fn end_range() {
         let session = make_session("bytes=-10").await;
         assert_eq!(
-            extract_range(&session, &metadata()),
+            extract_range(&session, &metadata(), false),
             Some(Range::Valid(990, 999))
         );
     }
@@ -162,28 +258,58 @@ mod tests {
     async fn out_of_bounds_ranges() {
         let session = make_session("bytes=-2000").await;
         assert_eq!(
-            extract_range(&session, &metadata()),
+            extract_range(&session, &metadata(), false),
             Some(Range::OutOfBounds)
         );
 
         let session = make_session("bytes=23-22").await;
         assert_eq!(
-            extract_range(&session, &metadata()),
+            extract_range(&session, &metadata(), false),
             Some(Range::OutOfBounds)
         );
 
         let session = make_session("bytes=1000-").await;
         assert_eq!(
-            extract_range(&session, &metadata()),
+            extract_range(&session, &metadata(), false),
             Some(Range::OutOfBounds)
         );
     }
 
     #[test(tokio::test)]
     async fn multiple_ranges() {
-        // Multiple ranges are unsupported, should be treated like no Range header.
+        // Multiple ranges are disabled, should be treated like no Range header.
         let session = make_session("bytes=1-2,3-4").await;
-        assert_eq!(extract_range(&session, &metadata()), None);
+        assert_eq!(extract_range(&session, &metadata(), false), None);
+
+        let session = make_session("bytes=500-599, -100, 0-9").await;
+        assert_eq!(
+            extract_range(&session, &metadata(), true),
+            Some(Range::Multiple(vec![(0, 9), (500, 599), (900, 999)]))
+        );
+
+        // Overlapping and adjacent ranges are merged
+        let session = make_session("bytes=1-2,3-4,2-3").await;
+        assert_eq!(
+            extract_range(&session, &metadata(), true),
+            Some(Range::Valid(1, 4))
+        );
+
+        // Out of bounds ranges are ignored unless there is nothing else
+        let session = make_session("bytes=1-2,2000-").await;
+        assert_eq!(
+            extract_range(&session, &metadata(), true),
+            Some(Range::Valid(1, 2))
+        );
+
+        let session = make_session("bytes=2000-,3000-").await;
+        assert_eq!(
+            extract_range(&session, &metadata(), true),
+            Some(Range::OutOfBounds)
+        );
+
+        // A single invalid range invalidates the header
+        let session = make_session("bytes=1-2,x-y").await;
+        assert_eq!(extract_range(&session, &metadata(), true), None);
     }
 
     #[test(tokio::test)]
@@ -194,7 +320,7 @@ mod tests {
             .insert_header("If-Range", "\"abc\"")
             .unwrap();
         assert_eq!(
-            extract_range(&session, &metadata()),
+            extract_range(&session, &metadata(), false),
             Some(Range::Valid(0, 499))
         );
 
@@ -203,7 +329,7 @@ mod tests {
             .req_header_mut()
             .insert_header("If-Range", "\"xyz\"")
             .unwrap();
-        assert_eq!(extract_range(&session, &metadata()), None);
+        assert_eq!(extract_range(&session, &metadata(), false), None);
 
         let mut session = make_session("bytes=0-499").await;
         session
@@ -211,7 +337,7 @@ mod tests {
             .insert_header("If-Range", "Fri, 15 May 2015 15:34:21 GMT")
             .unwrap();
         assert_eq!(
-            extract_range(&session, &metadata()),
+            extract_range(&session, &metadata(), false),
             Some(Range::Valid(0, 499))
         );
 
@@ -220,13 +346,13 @@ mod tests {
             .req_header_mut()
             .insert_header("If-Range", "Thu, 01 Jan 1970 00:00:00 GMT")
             .unwrap();
-        assert_eq!(extract_range(&session, &metadata()), None);
+        assert_eq!(extract_range(&session, &metadata(), false), None);
 
         let mut session = make_session("bytes=0-499").await;
         session
             .req_header_mut()
             .insert_header("If-Range", "bogus")
             .unwrap();
-        assert_eq!(extract_range(&session, &metadata()), None);
+        assert_eq!(extract_range(&session, &metadata(), false), None);
     }
 }
@@ -13,15 +13,17 @@
 // limitations under the License.
 
 use crate::configuration::StaticFilesConf;
+use crate::disk_cache::is_pending;
 use crate::handler::StaticFilesHandler;
 use crate::metadata::Metadata;
 
 use const_format::{concatcp, str_repeat};
-use http::status::StatusCode;
+use http::{header, status::StatusCode};
 use pandora_module_utils::pingora::{Error, RequestHeader, SessionWrapper, TestSession};
 use pandora_module_utils::standard_response::response_text;
 use pandora_module_utils::{FromYaml, RequestFilter, RequestFilterResult};
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
 use test_log::test;
 
 fn root_path(filename: &str) -> PathBuf {
@@ -42,7 +44,20 @@ fn default_conf() -> String {
 }
 
 fn extended_conf(conf_str: impl AsRef<str>) -> String {
-    format!("{}\n{}", default_conf(), conf_str.as_ref())
+    // Remove common indentation, settings have to line up with the root setting
+    let conf_str = conf_str.as_ref();
+    let indent = conf_str
+        .lines()
+        .filter(|line| !line.trim().is_empty())
+        .map(|line| line.len() - line.trim_start().len())
+        .min()
+        .unwrap_or(0);
+    let conf_str = conf_str
+        .lines()
+        .map(|line| line.get(indent..).unwrap_or_default())
+        .collect::<Vec<_>>()
+        .join("\n");
+    format!("{}\n{}", default_conf(), conf_str)
 }
 
 fn make_handler(conf_str: impl AsRef<str>) -> StaticFilesHandler {
@@ -96,6 +111,32 @@ fn assert_body(session: &TestSession, expected: &str) {
     );
 }
 
+#[test(tokio::test)]
+async fn root_relative_to_config() -> Result<(), Box<Error>> {
+    let mut conf_path = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+    conf_path.push("testdata");
+    conf_path.push("conf");
+    conf_path.push("relative-root.yaml");
+
+    let conf = StaticFilesConf::load_from_yaml(conf_path)?;
+    assert_eq!(conf.root, Some(vec![PathBuf::from("../root")].into()));
+    let handler: StaticFilesHandler = conf.try_into()?;
+
+    let mut session = make_session("GET", "/file.txt").await;
+    assert_eq!(
+        handler.request_filter(&mut session, &mut ()).await?,
+        RequestFilterResult::ResponseSent
+    );
+    assert_status(&session, 200);
+    assert_body(&session, "Hi!\n");
+
+    // Without the flag the path is relative to the current directory and doesn't exist
+    let conf = StaticFilesConf::from_yaml("root: ../root")?;
+    assert!(StaticFilesHandler::try_from(conf).is_err());
+
+    Ok(())
+}
+
 #[test(tokio::test)]
 async fn unconfigured() -> Result<(), Box<Error>> {
     let handler = make_handler("root:");
@@ -111,6 +152,80 @@ async fn unconfigured() -> Result<(), Box<Error>> {
     Ok(())
 }
 
+#[test(tokio::test)]
+async fn multiple_roots() -> Result<(), Box<Error>> {
+    let mut overlay = root_path("");
+    overlay.set_file_name("overlay");
+    let handler = make_handler(format!(
+        r#"
+            root:
+            - {}
+            - {}
+        "#,
+        overlay.display(),
+        root_path("").display()
+    ));
+
+    // Files in the first root take precedence
+    let mut session = make_session("GET", "/file.txt").await;
+    assert_eq!(
+        handler.request_filter(&mut session, &mut ()).await?,
+        RequestFilterResult::ResponseSent
+    );
+    assert_status(&session, 200);
+    assert_body(&session, "Overlay!\n");
+
+    // Files missing in the first root are taken from the second one
+    let mut session = make_session("GET", "/tiny.txt").await;
+    assert_eq!(
+        handler.request_filter(&mut session, &mut ()).await?,
+        RequestFilterResult::ResponseSent
+    );
+    assert_status(&session, 200);
+    assert_body(&session, "Hi!\n");
+
+    // Canonicalization redirects use the root the path was found in
+    let mut session = make_session("GET", "/subdir").await;
+    assert_eq!(
+        handler.request_filter(&mut session, &mut ()).await?,
+        RequestFilterResult::ResponseSent
+    );
+    assert_status(&session, 308);
+    assert_eq!(
+        session
+            .response_written()
+            .unwrap()
+            .headers
+            .get("Location")
+            .unwrap(),
+        "/subdir/"
+    );
+
+    // Paths outside the root directories are still rejected, for each root
+    for path in [
+        "/../root/file.txt",
+        "/../errors/404.html",
+        "/subdir/../../errors/404.html",
+    ] {
+        let mut session = make_session("GET", path).await;
+        assert_eq!(
+            handler.request_filter(&mut session, &mut ()).await?,
+            RequestFilterResult::ResponseSent
+        );
+        assert_status(&session, 400);
+    }
+
+    // Not found in any root
+    let mut session = make_session("GET", "/missing.txt").await;
+    assert_eq!(
+        handler.request_filter(&mut session, &mut ()).await?,
+        RequestFilterResult::ResponseSent
+    );
+    assert_status(&session, 404);
+
+    Ok(())
+}
+
 #[test(tokio::test)]
 async fn text_file() -> Result<(), Box<Error>> {
     let meta = Metadata::from_path(&root_path("file.txt"), None).unwrap();
@@ -130,6 +245,7 @@ async fn text_file() -> Result<(), Box<Error>> {
             ("Content-Type", "text/plain"),
             ("last-modified", &meta.modified.unwrap()),
             ("etag", &meta.etag),
+            ("X-Content-Type-Options", "nosniff"),
         ],
     );
     assert_body(&session, "Hi!\n");
@@ -149,6 +265,7 @@ async fn text_file() -> Result<(), Box<Error>> {
             ("Content-Type", "text/plain"),
             ("last-modified", &meta.modified.unwrap()),
             ("etag", &meta.etag),
+            ("X-Content-Type-Options", "nosniff"),
         ],
     );
     assert_body(&session, concatcp!(str_repeat!("0123456789", 10000), "\n"));
@@ -175,6 +292,7 @@ async fn dir_index() -> Result<(), Box<Error>> {
             ("Content-Type", "text/html"),
             ("last-modified", &meta.modified.unwrap()),
             ("etag", &meta.etag),
+            ("X-Content-Type-Options", "nosniff"),
         ],
     );
     assert_body(&session, "<html>Hi!</html>\n");
@@ -263,6 +381,55 @@ async fn no_trailing_slash() -> Result<(), Box<Error>> {
     Ok(())
 }
 
+#[test(tokio::test)]
+async fn trailing_slash() -> Result<(), Box<Error>> {
+    for (policy, file_location, dir_location, dir_slash_location) in [
+        ("preserve", None, None, None),
+        ("add", Some("/file.txt"), Some("/subdir/"), None),
+        ("remove", Some("/file.txt"), None, Some("/subdir")),
+    ] {
+        let handler = make_handler(extended_conf(format!("trailing_slash: {policy}")));
+
+        for (path, location, status) in [
+            ("/file.txt/", file_location, 200),
+            ("/subdir", dir_location, 403),
+            ("/subdir/", dir_slash_location, 403),
+        ] {
+            let mut session = make_session("GET", path).await;
+            assert_eq!(
+                handler.request_filter(&mut session, &mut ()).await?,
+                RequestFilterResult::ResponseSent
+            );
+            let response = session.response_written().unwrap();
+            if let Some(location) = location {
+                assert_eq!(response.status, 308, "{policy} {path}");
+                assert_eq!(
+                    response.headers.get("Location").unwrap(),
+                    location,
+                    "{policy} {path}"
+                );
+            } else {
+                // Directory without index file results in Forbidden response
+                assert_eq!(response.status, status, "{policy} {path}");
+            }
+        }
+
+        // Policy is ignored without canonicalize_uri
+        let handler = make_handler(extended_conf(format!(
+            "trailing_slash: {policy}\ncanonicalize_uri: false"
+        )));
+        let mut session = make_session("GET", "/file.txt/").await;
+        assert_eq!(
+            handler.request_filter(&mut session, &mut ()).await?,
+            RequestFilterResult::ResponseSent
+        );
+        assert_status(&session, 200);
+        assert_body(&session, "Hi!\n");
+    }
+
+    Ok(())
+}
+
 #[test(tokio::test)]
 async fn unnecessary_percent_encoding() -> Result<(), Box<Error>> {
     let handler = make_handler(default_conf());
@@ -305,6 +472,71 @@ async fn unnecessary_percent_encoding() -> Result<(), Box<Error>> {
     Ok(())
 }
 
+#[test(tokio::test)]
+async fn trailing_dots() -> Result<(), Box<Error>> {
+    let handler = make_handler(extended_conf("trailing_dots: reject"));
+    for path in [
+        "/file.txt.",
+        "/file.txt%20",
+        "/file.txt.%20.",
+        "/subdir./file.txt",
+    ] {
+        let mut session = make_session("GET", path).await;
+        assert_eq!(
+            handler.request_filter(&mut session, &mut ()).await?,
+            RequestFilterResult::ResponseSent
+        );
+        assert_status(&session, 400);
+    }
+
+    let handler = make_handler(extended_conf("trailing_dots: strip"));
+    let text = response_text(StatusCode::PERMANENT_REDIRECT);
+    for path in ["/file.txt.", "/file.txt%20", "/file.txt.%20."] {
+        let mut session = make_session("GET", path).await;
+        assert_eq!(
+            handler.request_filter(&mut session, &mut ()).await?,
+            RequestFilterResult::ResponseSent
+        );
+        assert_status(&session, 308);
+        assert_headers(
+            &session,
+            vec![
+                ("Content-Length", &text.len().to_string()),
+                ("Content-Type", "text/html; charset=utf-8"),
+                ("location", "/file.txt"),
+            ],
+        );
+        assert_body(&session, &text);
+    }
+
+    let handler = make_handler(extended_conf(
+        "trailing_dots: strip\ncanonicalize_uri: false",
+    ));
+    for path in ["/file.txt.", "/file.txt%20"] {
+        let mut session = make_session("GET", path).await;
+        assert_eq!(
+            handler.request_filter(&mut session, &mut ()).await?,
+            RequestFilterResult::ResponseSent
+        );
+        assert_status(&session, 200);
+        assert_body(&session, "Hi!\n");
+    }
+
+    if cfg!(unix) {
+        let handler = make_handler(extended_conf("trailing_dots: allow"));
+        for path in ["/file.txt.", "/file.txt%20"] {
+            let mut session = make_session("GET", path).await;
+            assert_eq!(
+                handler.request_filter(&mut session, &mut ()).await?,
+                RequestFilterResult::ResponseSent
+            );
+            assert_status(&session, 404);
+        }
+    }
+
+    Ok(())
+}
+
 #[test(tokio::test)]
 async fn complex_path() -> Result<(), Box<Error>> {
     let handler = make_handler(default_conf());
@@ -352,6 +584,7 @@ async fn utf8_path() -> Result<(), Box<Error>> {
             ("Content-Type", "text/plain"),
             ("last-modified", &meta.modified.unwrap()),
             ("etag", &meta.etag),
+            ("X-Content-Type-Options", "nosniff"),
         ],
     );
     assert_body(&session, "Hi!\n");
@@ -402,10 +635,189 @@ async fn no_file_with_page_404() -> Result<(), Box<Error>> {
             ("Content-Type", "text/plain"),
             ("last-modified", meta.modified.as_ref().unwrap()),
             ("etag", &meta.etag),
+            ("X-Content-Type-Options", "nosniff"),
+        ],
+    );
+    assert_body(&session, "Hi!\n");
+
+    Ok(())
+}
+
+#[test(tokio::test)]
+async fn no_file_with_page_404_file() -> Result<(), Box<Error>> {
+    let mut page_404_file = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+    page_404_file.push("testdata");
+    page_404_file.push("errors");
+    page_404_file.push("404.html");
+
+    let handler = make_handler(extended_conf(format!(
+        "page_404_file: {}",
+        page_404_file.display()
+    )));
+
+    let meta = Metadata::from_path(&page_404_file, None).unwrap();
+
+    let mut session = make_session("GET", "/missing.txt").await;
+    assert_eq!(
+        handler.request_filter(&mut session, &mut ()).await?,
+        RequestFilterResult::ResponseSent
+    );
+    assert_status(&session, 404);
+    assert_headers(
+        &session,
+        vec![
+            ("Content-Length", &meta.size.to_string()),
+            ("accept-ranges", "bytes"),
+            ("Content-Type", "text/html"),
+            ("last-modified", meta.modified.as_ref().unwrap()),
+            ("etag", &meta.etag),
+            ("X-Content-Type-Options", "nosniff"),
         ],
     );
+    assert_body(&session, "<h1>Page not found</h1>\n");
+
+    // page_404 takes precedence
+    let handler = make_handler(extended_conf(format!(
+        "page_404: /file.txt\npage_404_file: {}",
+        page_404_file.display()
+    )));
+    let mut session = make_session("GET", "/missing.txt").await;
+    handler.request_filter(&mut session, &mut ()).await?;
+    assert_status(&session, 404);
     assert_body(&session, "Hi!\n");
 
+    // Missing file results in the standard error page
+    let handler = make_handler(extended_conf(format!(
+        "page_404_file: {}",
+        root_path("missing.html").display()
+    )));
+    let mut session = make_session("GET", "/missing.txt").await;
+    handler.request_filter(&mut session, &mut ()).await?;
+    assert_status(&session, 404);
+    assert_body(&session, &response_text(StatusCode::NOT_FOUND));
+
+    Ok(())
+}
+
+#[test(tokio::test)]
+async fn allowed_extensions() -> Result<(), Box<Error>> {
+    let handler = make_handler(extended_conf("allowed_extensions: [pdf, .txt]"));
+
+    for (path, status) in [
+        ("/downloads/document.pdf", 200),
+        ("/downloads/REPORT.PDF", 200),
+        ("/file.txt", 200),
+        ("/downloads/script.php", 404),
+        ("/downloads/README", 404),
+        ("/index.html", 404),
+        ("/", 404),
+    ] {
+        let mut session = make_session("GET", path).await;
+        assert_eq!(
+            handler.request_filter(&mut session, &mut ()).await?,
+            RequestFilterResult::ResponseSent
+        );
+        assert_status(&session, status);
+        if status == 404 {
+            assert_body(&session, &response_text(StatusCode::NOT_FOUND));
+        }
+    }
+
+    // Files with disallowed extensions aren’t redirected to their canonical URI
+    let mut session = make_session("GET", "/downloads/script%2Ephp").await;
+    handler.request_filter(&mut session, &mut ()).await?;
+    assert_status(&session, 404);
+    assert_body(&session, &response_text(StatusCode::NOT_FOUND));
+
+    // Custom error page is used for disallowed files, it isn’t subject to restrictions itself
+    let handler = make_handler(extended_conf(
+        "allowed_extensions: pdf\npage_404: /file.txt",
+    ));
+    let mut session = make_session("GET", "/downloads/script.php").await;
+    handler.request_filter(&mut session, &mut ()).await?;
+    assert_status(&session, 404);
+    assert_body(&session, "Hi!\n");
+
+    let mut session = make_session("GET", "/missing.pdf").await;
+    handler.request_filter(&mut session, &mut ()).await?;
+    assert_status(&session, 404);
+    assert_body(&session, "Hi!\n");
+
+    Ok(())
+}
+
+#[test(tokio::test)]
+async fn denied_extensions() -> Result<(), Box<Error>> {
+    let handler = make_handler(extended_conf("denied_extensions: [PHP]"));
+
+    for (path, status) in [
+        ("/downloads/document.pdf", 200),
+        ("/downloads/README", 200),
+        ("/downloads/script.php", 404),
+    ] {
+        let mut session = make_session("GET", path).await;
+        assert_eq!(
+            handler.request_filter(&mut session, &mut ()).await?,
+            RequestFilterResult::ResponseSent
+        );
+        assert_status(&session, status);
+    }
+
+    Ok(())
+}
+
+#[test(tokio::test)]
+async fn attachment() -> Result<(), Box<Error>> {
+    let handler = make_handler(extended_conf(
+        "attachment_paths: /downloads/*\nattachment_extensions: [.TXT]",
+    ));
+
+    for (path, expected) in [
+        (
+            "/downloads/document.pdf",
+            Some("attachment; filename=\"document.pdf\""),
+        ),
+        ("/downloads/README", Some("attachment; filename=\"README\"")),
+        ("/file.txt", Some("attachment; filename=\"file.txt\"")),
+        (
+            "/subdir/%D1%84%D0%B0%D0%B9%D0%BB%20s%C3%B6nd%C3%A4rzeichen.txt",
+            Some(concat!(
+                "attachment; filename=\"____ s_nd_rzeichen.txt\"; ",
+                "filename*=UTF-8''%D1%84%D0%B0%D0%B9%D0%BB%20s%C3%B6nd%C3%A4rzeichen.txt"
+            )),
+        ),
+        ("/index.html", None),
+        ("/image.png", None),
+    ] {
+        let mut session = make_session("GET", path).await;
+        assert_eq!(
+            handler.request_filter(&mut session, &mut ()).await?,
+            RequestFilterResult::ResponseSent
+        );
+        assert_status(&session, 200);
+        let disposition = session
+            .response_written()
+            .unwrap()
+            .headers
+            .get("Content-Disposition")
+            .map(|value| value.to_str().unwrap());
+        assert_eq!(disposition, expected, "{path}");
+    }
+
+    // Error pages aren’t downloads
+    let handler = make_handler(extended_conf(
+        "attachment_paths: \"*\"\npage_404: /file.txt",
+    ));
+    let mut session = make_session("GET", "/missing.pdf").await;
+    handler.request_filter(&mut session, &mut ()).await?;
+    assert_status(&session, 404);
+    assert!(session
+        .response_written()
+        .unwrap()
+        .headers
+        .get("Content-Disposition")
+        .is_none());
+
     Ok(())
 }
 
@@ -497,6 +909,7 @@ async fn head_request() -> Result<(), Box<Error>> {
             ("Content-Type", "text/plain"),
             ("last-modified", &meta.modified.unwrap()),
             ("etag", &meta.etag),
+            ("X-Content-Type-Options", "nosniff"),
         ],
     );
     assert_body(&session, "");
@@ -572,6 +985,17 @@ async fn bad_request() -> Result<(), Box<Error>> {
     );
     assert_body(&session, &text);
 
+    // Invalid percent encoding and encoded null bytes
+    for path in ["/file%00.txt", "/file%2", "/file%zz.txt"] {
+        let mut session = make_session("GET", path).await;
+        assert_eq!(
+            handler.request_filter(&mut session, &mut ()).await?,
+            RequestFilterResult::ResponseSent
+        );
+        assert_status(&session, 400);
+        assert_body(&session, &text);
+    }
+
     Ok(())
 }
 
@@ -595,6 +1019,7 @@ async fn if_none_match() -> Result<(), Box<Error>> {
             ("Content-Type", "text/plain"),
             ("last-modified", meta.modified.as_ref().unwrap()),
             ("etag", &meta.etag),
+            ("X-Content-Type-Options", "nosniff"),
         ],
     );
     assert_body(&session, "");
@@ -614,6 +1039,7 @@ async fn if_none_match() -> Result<(), Box<Error>> {
             ("Content-Type", "text/plain"),
             ("last-modified", meta.modified.as_ref().unwrap()),
             ("etag", &meta.etag),
+            ("X-Content-Type-Options", "nosniff"),
         ],
     );
     assert_body(&session, "");
@@ -633,6 +1059,7 @@ async fn if_none_match() -> Result<(), Box<Error>> {
             ("Content-Type", "text/plain"),
             ("last-modified", meta.modified.as_ref().unwrap()),
             ("etag", &meta.etag),
+            ("X-Content-Type-Options", "nosniff"),
         ],
     );
     assert_body(&session, "");
@@ -655,6 +1082,7 @@ async fn if_none_match() -> Result<(), Box<Error>> {
             ("Content-Type", "text/plain"),
             ("last-modified", meta.modified.as_ref().unwrap()),
             ("etag", &meta.etag),
+            ("X-Content-Type-Options", "nosniff"),
         ],
     );
     assert_body(&session, "");
@@ -676,6 +1104,7 @@ async fn if_none_match() -> Result<(), Box<Error>> {
             ("Content-Type", "text/plain"),
             ("last-modified", meta.modified.as_ref().unwrap()),
             ("etag", &meta.etag),
+            ("X-Content-Type-Options", "nosniff"),
         ],
     );
     assert_body(&session, "Hi!\n");
@@ -697,6 +1126,7 @@ async fn if_none_match() -> Result<(), Box<Error>> {
             ("Content-Type", "text/plain"),
             ("last-modified", meta.modified.as_ref().unwrap()),
             ("etag", &meta.etag),
+            ("X-Content-Type-Options", "nosniff"),
             ("vary", "Accept-Encoding"),
         ],
     );
@@ -705,6 +1135,49 @@ async fn if_none_match() -> Result<(), Box<Error>> {
     Ok(())
 }
 
+#[cfg(unix)]
+#[test(tokio::test)]
+async fn not_modified_without_reading() -> Result<(), Box<Error>> {
+    use std::fs::{self, File, Permissions};
+    use std::os::unix::fs::PermissionsExt;
+
+    let mut root = std::env::temp_dir();
+    root.push(format!("static-files-not-modified-{}", std::process::id()));
+    fs::create_dir_all(&root).unwrap();
+    let path = root.join("file.txt");
+    fs::write(&path, "Hi!\n").unwrap();
+    let meta = Metadata::from_path(&path, None).unwrap();
+
+    // File metadata stays accessible but the file itself can no longer be read
+    fs::set_permissions(&path, Permissions::from_mode(0o000)).unwrap();
+    let readable = File::open(&path).is_ok();
+
+    let handler = make_handler(format!("root: {}", root.to_str().unwrap()));
+    let mut session = make_session("GET", "/file.txt").await;
+    session
+        .req_header_mut()
+        .insert_header("If-None-Match", &meta.etag)?;
+    let result = handler.request_filter(&mut session, &mut ()).await;
+
+    let mut session2 = make_session("GET", "/file.txt").await;
+    let result2 = handler.request_filter(&mut session2, &mut ()).await;
+
+    fs::set_permissions(&path, Permissions::from_mode(0o644)).unwrap();
+    fs::remove_dir_all(&root).unwrap();
+
+    assert_eq!(result?, RequestFilterResult::ResponseSent);
+    assert_status(&session, 304);
+    assert_body(&session, "");
+
+    // Without conditional headers the file has to be opened which fails. This cannot be verified
+    // if running as root.
+    if !readable {
+        assert!(result2.is_err());
+    }
+
+    Ok(())
+}
+
 #[test(tokio::test)]
 async fn if_match() -> Result<(), Box<Error>> {
     let meta = Metadata::from_path(&root_path("file.txt"), None).unwrap();
@@ -727,6 +1200,7 @@ async fn if_match() -> Result<(), Box<Error>> {
             ("Content-Type", "text/plain"),
             ("last-modified", meta.modified.as_ref().unwrap()),
             ("etag", &meta.etag),
+            ("X-Content-Type-Options", "nosniff"),
         ],
     );
     assert_body(&session, "Hi!\n");
@@ -746,6 +1220,7 @@ async fn if_match() -> Result<(), Box<Error>> {
             ("Content-Type", "text/plain"),
             ("last-modified", meta.modified.as_ref().unwrap()),
             ("etag", &meta.etag),
+            ("X-Content-Type-Options", "nosniff"),
         ],
     );
     assert_body(&session, "Hi!\n");
@@ -767,6 +1242,7 @@ async fn if_match() -> Result<(), Box<Error>> {
             ("Content-Type", "text/plain"),
             ("last-modified", meta.modified.as_ref().unwrap()),
             ("etag", &meta.etag),
+            ("X-Content-Type-Options", "nosniff"),
         ],
     );
     assert_body(&session, "Hi!\n");
@@ -791,6 +1267,7 @@ async fn if_match() -> Result<(), Box<Error>> {
             ("Content-Type", "text/plain"),
             ("last-modified", meta.modified.as_ref().unwrap()),
             ("etag", &meta.etag),
+            ("X-Content-Type-Options", "nosniff"),
         ],
     );
     assert_body(&session, "Hi!\n");
@@ -810,6 +1287,7 @@ async fn if_match() -> Result<(), Box<Error>> {
             ("Content-Type", "text/plain"),
             ("last-modified", meta.modified.as_ref().unwrap()),
             ("etag", &meta.etag),
+            ("X-Content-Type-Options", "nosniff"),
         ],
     );
     assert_body(&session, "");
@@ -831,6 +1309,7 @@ async fn if_match() -> Result<(), Box<Error>> {
             ("Content-Type", "text/plain"),
             ("last-modified", meta.modified.as_ref().unwrap()),
             ("etag", &meta.etag),
+            ("X-Content-Type-Options", "nosniff"),
             ("vary", "Accept-Encoding"),
         ],
     );
@@ -859,6 +1338,7 @@ async fn if_modified_since() -> Result<(), Box<Error>> {
             ("Content-Type", "text/plain"),
             ("last-modified", meta.modified.as_ref().unwrap()),
             ("etag", &meta.etag),
+            ("X-Content-Type-Options", "nosniff"),
         ],
     );
     assert_body(&session, "");
@@ -880,6 +1360,7 @@ async fn if_modified_since() -> Result<(), Box<Error>> {
             ("Content-Type", "text/plain"),
             ("last-modified", meta.modified.as_ref().unwrap()),
             ("etag", &meta.etag),
+            ("X-Content-Type-Options", "nosniff"),
         ],
     );
     assert_body(&session, "Hi!\n");
@@ -904,6 +1385,7 @@ async fn if_modified_since() -> Result<(), Box<Error>> {
             ("Content-Type", "text/plain"),
             ("last-modified", meta.modified.as_ref().unwrap()),
             ("etag", &meta.etag),
+            ("X-Content-Type-Options", "nosniff"),
         ],
     );
     assert_body(&session, "Hi!\n");
@@ -925,6 +1407,7 @@ async fn if_modified_since() -> Result<(), Box<Error>> {
             ("Content-Type", "text/plain"),
             ("last-modified", meta.modified.as_ref().unwrap()),
             ("etag", &meta.etag),
+            ("X-Content-Type-Options", "nosniff"),
             ("vary", "Accept-Encoding"),
         ],
     );
@@ -955,6 +1438,7 @@ async fn if_unmodified_since() -> Result<(), Box<Error>> {
             ("Content-Type", "text/plain"),
             ("last-modified", meta.modified.as_ref().unwrap()),
             ("etag", &meta.etag),
+            ("X-Content-Type-Options", "nosniff"),
         ],
     );
     assert_body(&session, "Hi!\n");
@@ -974,6 +1458,7 @@ async fn if_unmodified_since() -> Result<(), Box<Error>> {
             ("Content-Type", "text/plain"),
             ("last-modified", meta.modified.as_ref().unwrap()),
             ("etag", &meta.etag),
+            ("X-Content-Type-Options", "nosniff"),
         ],
     );
     assert_body(&session, "");
@@ -996,6 +1481,7 @@ async fn if_unmodified_since() -> Result<(), Box<Error>> {
             ("Content-Type", "text/plain"),
             ("last-modified", meta.modified.as_ref().unwrap()),
             ("etag", &meta.etag),
+            ("X-Content-Type-Options", "nosniff"),
         ],
     );
     assert_body(&session, "");
@@ -1020,6 +1506,7 @@ async fn if_unmodified_since() -> Result<(), Box<Error>> {
             ("Content-Type", "text/plain"),
             ("last-modified", meta.modified.as_ref().unwrap()),
             ("etag", &meta.etag),
+            ("X-Content-Type-Options", "nosniff"),
             ("vary", "Accept-Encoding"),
         ],
     );
@@ -1050,6 +1537,7 @@ async fn ranged_request() -> Result<(), Box<Error>> {
             ("Content-Type", "text/plain"),
             ("last-modified", meta.modified.as_ref().unwrap()),
             ("etag", &meta.etag),
+            ("X-Content-Type-Options", "nosniff"),
         ],
     );
     assert_body(&session, "2345");
@@ -1071,6 +1559,7 @@ async fn ranged_request() -> Result<(), Box<Error>> {
             ("Content-Type", "text/plain"),
             ("last-modified", meta.modified.as_ref().unwrap()),
             ("etag", &meta.etag),
+            ("X-Content-Type-Options", "nosniff"),
         ],
     );
     assert_body(&session, "9\n");
@@ -1092,6 +1581,7 @@ async fn ranged_request() -> Result<(), Box<Error>> {
             ("Content-Type", "text/plain"),
             ("last-modified", meta.modified.as_ref().unwrap()),
             ("etag", &meta.etag),
+            ("X-Content-Type-Options", "nosniff"),
         ],
     );
     assert_body(&session, "6789\n");
@@ -1111,6 +1601,7 @@ async fn ranged_request() -> Result<(), Box<Error>> {
             ("Content-Type", "text/plain"),
             ("last-modified", meta.modified.as_ref().unwrap()),
             ("etag", &meta.etag),
+            ("X-Content-Type-Options", "nosniff"),
         ],
     );
     assert_body(&session, "");
@@ -1132,6 +1623,7 @@ async fn ranged_request() -> Result<(), Box<Error>> {
             ("Content-Type", "text/plain"),
             ("last-modified", meta.modified.as_ref().unwrap()),
             ("etag", &meta.etag),
+            ("X-Content-Type-Options", "nosniff"),
             ("vary", "Accept-Encoding"),
         ],
     );
@@ -1140,6 +1632,84 @@ async fn ranged_request() -> Result<(), Box<Error>> {
     Ok(())
 }
 
+#[test(tokio::test)]
+async fn multiple_ranges() -> Result<(), Box<Error>> {
+    let meta = Metadata::from_path(&root_path("large.txt"), None).unwrap();
+
+    // Multiple ranges disabled by default, full file is returned
+    let handler = make_handler(default_conf());
+    let mut session = make_session("GET", "/large.txt").await;
+    session
+        .req_header_mut()
+        .insert_header("Range", "bytes=2-5,10-12")?;
+    assert_eq!(
+        handler.request_filter(&mut session, &mut ()).await?,
+        RequestFilterResult::ResponseSent
+    );
+    assert_status(&session, 200);
+    assert_eq!(session.response_body.len() as u64, meta.size);
+
+    let handler = make_handler(extended_conf("multiple_ranges: true"));
+    let mut session = make_session("GET", "/large.txt").await;
+    session
+        .req_header_mut()
+        .insert_header("Range", "bytes=10-12, 2-5")?;
+    assert_eq!(
+        handler.request_filter(&mut session, &mut ()).await?,
+        RequestFilterResult::ResponseSent
+    );
+    assert_status(&session, 206);
+
+    let content_type = session
+        .response_written()
+        .unwrap()
+        .headers
+        .get("Content-Type")
+        .unwrap()
+        .to_str()
+        .unwrap()
+        .to_owned();
+    let boundary = content_type
+        .strip_prefix("multipart/byteranges; boundary=")
+        .unwrap();
+    assert!(!boundary.is_empty());
+
+    let expected_body = format!(
+        "\r\n--{boundary}\r\n\
+         Content-Type: text/plain\r\n\
+         Content-Range: bytes 2-5/100001\r\n\r\n\
+         2345\r\n--{boundary}\r\n\
+         Content-Type: text/plain\r\n\
+         Content-Range: bytes 10-12/100001\r\n\r\n\
+         012\r\n--{boundary}--\r\n"
+    );
+    assert_headers(
+        &session,
+        vec![
+            ("Content-Length", &expected_body.len().to_string()),
+            ("Content-Type", &content_type),
+            ("last-modified", meta.modified.as_ref().unwrap()),
+            ("etag", &meta.etag),
+            ("X-Content-Type-Options", "nosniff"),
+        ],
+    );
+    assert_body(&session, &expected_body);
+
+    // Overlapping ranges are merged into a single range
+    let mut session = make_session("GET", "/large.txt").await;
+    session
+        .req_header_mut()
+        .insert_header("Range", "bytes=2-5,4-7")?;
+    assert_eq!(
+        handler.request_filter(&mut session, &mut ()).await?,
+        RequestFilterResult::ResponseSent
+    );
+    assert_status(&session, 206);
+    assert_body(&session, "234567");
+
+    Ok(())
+}
+
 #[test(tokio::test)]
 async fn dynamic_compression() -> Result<(), Box<Error>> {
     let meta = Metadata::from_path(&root_path("large.txt"), None).unwrap();
@@ -1164,6 +1734,7 @@ async fn dynamic_compression() -> Result<(), Box<Error>> {
             ("Content-Type", "text/plain"),
             ("last-modified", meta.modified.as_ref().unwrap()),
             ("etag", &meta.etag),
+            ("X-Content-Type-Options", "nosniff"),
             ("Transfer-Encoding", "chunked"),
             ("vary", "Accept-Encoding"),
         ],
@@ -1188,6 +1759,7 @@ async fn dynamic_compression() -> Result<(), Box<Error>> {
             ("Content-Type", "text/plain"),
             ("last-modified", meta.modified.as_ref().unwrap()),
             ("etag", &meta.etag),
+            ("X-Content-Type-Options", "nosniff"),
             ("vary", "Accept-Encoding"),
         ],
     );
@@ -1214,6 +1786,7 @@ async fn dynamic_compression() -> Result<(), Box<Error>> {
             ("Content-Type", "text/plain"),
             ("last-modified", meta.modified.as_ref().unwrap()),
             ("etag", &meta.etag),
+            ("X-Content-Type-Options", "nosniff"),
             ("vary", "Accept-Encoding"),
         ],
     );
@@ -1249,6 +1822,7 @@ async fn static_compression() -> Result<(), Box<Error>> {
             ("Content-Type", "text/plain"),
             ("last-modified", meta_compressed.modified.as_ref().unwrap()),
             ("etag", &meta_compressed.etag),
+            ("X-Content-Type-Options", "nosniff"),
             ("Content-Encoding", "gzip"),
             ("vary", "Accept-Encoding"),
         ],
@@ -1275,6 +1849,7 @@ async fn static_compression() -> Result<(), Box<Error>> {
             ("Content-Type", "text/plain"),
             ("last-modified", meta_compressed.modified.as_ref().unwrap()),
             ("etag", &meta_compressed.etag),
+            ("X-Content-Type-Options", "nosniff"),
             ("Content-Encoding", "gzip"),
             ("vary", "Accept-Encoding"),
         ],
@@ -1301,6 +1876,7 @@ async fn static_compression() -> Result<(), Box<Error>> {
             ("Content-Type", "text/plain"),
             ("last-modified", meta.modified.as_ref().unwrap()),
             ("etag", &meta.etag),
+            ("X-Content-Type-Options", "nosniff"),
             ("vary", "Accept-Encoding"),
         ],
     );
@@ -1333,6 +1909,7 @@ async fn static_compression() -> Result<(), Box<Error>> {
             ("Content-Type", "text/plain"),
             ("last-modified", meta_compressed.modified.as_ref().unwrap()),
             ("etag", &meta_compressed.etag),
+            ("X-Content-Type-Options", "nosniff"),
             ("Content-Encoding", "gzip"),
             ("vary", "Accept-Encoding"),
         ],
@@ -1340,3 +1917,655 @@ async fn static_compression() -> Result<(), Box<Error>> {
 
     Ok(())
 }
+
+#[test(tokio::test)]
+async fn static_compression_compress() -> Result<(), Box<Error>> {
+    let meta_compressed =
+        Metadata::from_path(&root_path("large_precompressed.txt.z"), None).unwrap();
+    let handler = make_handler(extended_conf("precompressed: [gz, z]"));
+
+    let mut session = make_session("GET", "/large_precompressed.txt").await;
+    session
+        .req_header_mut()
+        .insert_header("Accept-Encoding", "compress")
+        .unwrap();
+
+    assert_eq!(
+        handler.request_filter(&mut session, &mut ()).await?,
+        RequestFilterResult::ResponseSent
+    );
+
+    assert_status(&session, 200);
+    assert_headers(
+        &session,
+        vec![
+            ("Content-Length", &meta_compressed.size.to_string()),
+            ("accept-ranges", "bytes"),
+            ("Content-Type", "text/plain"),
+            ("last-modified", meta_compressed.modified.as_ref().unwrap()),
+            ("etag", &meta_compressed.etag),
+            ("X-Content-Type-Options", "nosniff"),
+            ("Content-Encoding", "compress"),
+            ("vary", "Accept-Encoding"),
+        ],
+    );
+    assert_eq!(
+        session.response_body,
+        std::fs::read(root_path("large_precompressed.txt.z")).unwrap()
+    );
+
+    // Legacy name should be recognized as well, client preference is respected
+    let mut session = make_session("GET", "/large_precompressed.txt").await;
+    session
+        .req_header_mut()
+        .insert_header("Accept-Encoding", "gzip;q=0.5, x-compress")
+        .unwrap();
+
+    assert_eq!(
+        handler.request_filter(&mut session, &mut ()).await?,
+        RequestFilterResult::ResponseSent
+    );
+
+    assert_status(&session, 200);
+    assert_headers(
+        &session,
+        vec![
+            ("Content-Length", &meta_compressed.size.to_string()),
+            ("accept-ranges", "bytes"),
+            ("Content-Type", "text/plain"),
+            ("last-modified", meta_compressed.modified.as_ref().unwrap()),
+            ("etag", &meta_compressed.etag),
+            ("X-Content-Type-Options", "nosniff"),
+            ("Content-Encoding", "compress"),
+            ("vary", "Accept-Encoding"),
+        ],
+    );
+
+    Ok(())
+}
+
+#[test(tokio::test)]
+async fn static_compression_larger() -> Result<(), Box<Error>> {
+    let meta = Metadata::from_path(&root_path("tiny.txt"), None).unwrap();
+    let handler = make_handler(extended_conf("precompressed: [gz]"));
+
+    // Pre-compressed file is larger than the original, original should be served
+    let mut session = make_session("GET", "/tiny.txt").await;
+    session
+        .req_header_mut()
+        .insert_header("Accept-Encoding", "gzip")
+        .unwrap();
+
+    assert_eq!(
+        handler.request_filter(&mut session, &mut ()).await?,
+        RequestFilterResult::ResponseSent
+    );
+
+    assert_status(&session, 200);
+    assert_headers(
+        &session,
+        vec![
+            ("Content-Length", &meta.size.to_string()),
+            ("accept-ranges", "bytes"),
+            ("Content-Type", "text/plain"),
+            ("last-modified", meta.modified.as_ref().unwrap()),
+            ("etag", &meta.etag),
+            ("X-Content-Type-Options", "nosniff"),
+            ("vary", "Accept-Encoding"),
+        ],
+    );
+    assert_body(&session, "Hi!\n");
+
+    Ok(())
+}
+
+#[test(tokio::test)]
+async fn manifest_immutable() -> Result<(), Box<Error>> {
+    let meta = Metadata::from_path(&root_path("assets/app.0123abcd.js"), None).unwrap();
+    let handler = make_handler(extended_conf("manifest: assets/manifest.json"));
+
+    // Asset listed in the manifest
+    let mut session = make_session("GET", "/assets/app.0123abcd.js").await;
+    assert_eq!(
+        handler.request_filter(&mut session, &mut ()).await?,
+        RequestFilterResult::ResponseSent
+    );
+    assert_status(&session, 200);
+    assert_headers(
+        &session,
+        vec![
+            ("Content-Length", &meta.size.to_string()),
+            ("accept-ranges", "bytes"),
+            ("Content-Type", "application/javascript"),
+            ("last-modified", meta.modified.as_ref().unwrap()),
+            ("etag", &meta.etag),
+            ("X-Content-Type-Options", "nosniff"),
+            ("Cache-Control", "public, max-age=31536000, immutable"),
+        ],
+    );
+
+    // Not Modified response should have the same caching headers
+    let mut session = make_session("GET", "/assets/app.0123abcd.js").await;
+    session
+        .req_header_mut()
+        .insert_header("If-None-Match", &meta.etag)?;
+    assert_eq!(
+        handler.request_filter(&mut session, &mut ()).await?,
+        RequestFilterResult::ResponseSent
+    );
+    assert_status(&session, 304);
+    assert_headers(
+        &session,
+        vec![
+            ("Content-Type", "application/javascript"),
+            ("last-modified", meta.modified.as_ref().unwrap()),
+            ("etag", &meta.etag),
+            ("X-Content-Type-Options", "nosniff"),
+            ("Cache-Control", "public, max-age=31536000, immutable"),
+        ],
+    );
+
+    // File not listed in the manifest
+    let meta = Metadata::from_path(&root_path("file.txt"), None).unwrap();
+    let mut session = make_session("GET", "/file.txt").await;
+    assert_eq!(
+        handler.request_filter(&mut session, &mut ()).await?,
+        RequestFilterResult::ResponseSent
+    );
+    assert_status(&session, 200);
+    assert_headers(
+        &session,
+        vec![
+            ("Content-Length", &meta.size.to_string()),
+            ("accept-ranges", "bytes"),
+            ("Content-Type", "text/plain"),
+            ("last-modified", meta.modified.as_ref().unwrap()),
+            ("etag", &meta.etag),
+            ("X-Content-Type-Options", "nosniff"),
+        ],
+    );
+
+    Ok(())
+}
+
+#[test]
+fn manifest_missing() {
+    let conf = StaticFilesConf::from_yaml(extended_conf("manifest: missing.json")).unwrap();
+    assert!(StaticFilesHandler::try_from(conf).is_err());
+}
+
+#[test(tokio::test)]
+async fn default_charset() -> Result<(), Box<Error>> {
+    let handler = make_handler(extended_conf(
+        r#"
+            default_charset: utf-8
+            charset_types: application/javascript
+        "#,
+    ));
+
+    let meta = Metadata::from_path(&root_path("index.html"), None).unwrap();
+    let mut session = make_session("GET", "/index.html").await;
+    assert_eq!(
+        handler.request_filter(&mut session, &mut ()).await?,
+        RequestFilterResult::ResponseSent
+    );
+    assert_status(&session, 200);
+    assert_headers(
+        &session,
+        vec![
+            ("Content-Length", &meta.size.to_string()),
+            ("accept-ranges", "bytes"),
+            ("Content-Type", "text/html; charset=utf-8"),
+            ("last-modified", meta.modified.as_ref().unwrap()),
+            ("etag", &meta.etag),
+            ("X-Content-Type-Options", "nosniff"),
+        ],
+    );
+
+    let meta = Metadata::from_path(&root_path("image.png"), None).unwrap();
+    let mut session = make_session("GET", "/image.png").await;
+    assert_eq!(
+        handler.request_filter(&mut session, &mut ()).await?,
+        RequestFilterResult::ResponseSent
+    );
+    assert_status(&session, 200);
+    assert_headers(
+        &session,
+        vec![
+            ("Content-Length", &meta.size.to_string()),
+            ("accept-ranges", "bytes"),
+            ("Content-Type", "image/png"),
+            ("last-modified", meta.modified.as_ref().unwrap()),
+            ("etag", &meta.etag),
+            ("X-Content-Type-Options", "nosniff"),
+        ],
+    );
+
+    // Additional MIME types get the charset as well
+    let mut session = make_session("GET", "/assets/app.0123abcd.js").await;
+    handler.request_filter(&mut session, &mut ()).await?;
+    assert_status(&session, 200);
+    assert_eq!(
+        session.response_header.as_ref().unwrap().headers["Content-Type"],
+        "application/javascript; charset=utf-8"
+    );
+
+    // Without the setting no charset is added
+    let handler = make_handler(default_conf());
+    let mut session = make_session("GET", "/index.html").await;
+    handler.request_filter(&mut session, &mut ()).await?;
+    assert_eq!(
+        session.response_header.as_ref().unwrap().headers["Content-Type"],
+        "text/html"
+    );
+
+    Ok(())
+}
+
+#[test(tokio::test)]
+async fn nosniff() -> Result<(), Box<Error>> {
+    // Files without a recognized extension still get a Content-Type header
+    let handler = make_handler(default_conf());
+    let meta = Metadata::from_path(&root_path("downloads/README"), None).unwrap();
+    let mut session = make_session("GET", "/downloads/README").await;
+    assert_eq!(
+        handler.request_filter(&mut session, &mut ()).await?,
+        RequestFilterResult::ResponseSent
+    );
+    assert_status(&session, 200);
+    assert_headers(
+        &session,
+        vec![
+            ("Content-Length", &meta.size.to_string()),
+            ("accept-ranges", "bytes"),
+            ("Content-Type", "application/octet-stream"),
+            ("last-modified", meta.modified.as_ref().unwrap()),
+            ("etag", &meta.etag),
+            ("X-Content-Type-Options", "nosniff"),
+        ],
+    );
+
+    let handler = make_handler(extended_conf("nosniff: false"));
+    let meta = Metadata::from_path(&root_path("file.txt"), None).unwrap();
+    let mut session = make_session("GET", "/file.txt").await;
+    assert_eq!(
+        handler.request_filter(&mut session, &mut ()).await?,
+        RequestFilterResult::ResponseSent
+    );
+    assert_status(&session, 200);
+    assert_headers(
+        &session,
+        vec![
+            ("Content-Length", &meta.size.to_string()),
+            ("accept-ranges", "bytes"),
+            ("Content-Type", "text/plain"),
+            ("last-modified", meta.modified.as_ref().unwrap()),
+            ("etag", &meta.etag),
+        ],
+    );
+
+    Ok(())
+}
+
+#[test(tokio::test)]
+async fn preload_links() -> Result<(), Box<Error>> {
+    let handler = make_handler(extended_conf(
+        r#"
+            index_file: index.html
+            preload:
+                "/index.html":
+                - "</app.css>; rel=preload; as=style"
+                - "</app.js>; rel=preload; as=script"
+                "*": "</common.css>; rel=preload; as=style"
+        "#,
+    ));
+
+    let links = |session: &TestSession| {
+        session
+            .response_header
+            .as_ref()
+            .unwrap()
+            .headers
+            .get_all(header::LINK)
+            .iter()
+            .map(|value| value.to_str().unwrap().to_owned())
+            .collect::<Vec<_>>()
+    };
+
+    let mut session = make_session("GET", "/index.html").await;
+    handler.request_filter(&mut session, &mut ()).await?;
+    assert_status(&session, 200);
+    assert_eq!(
+        links(&session),
+        vec![
+            "</app.css>; rel=preload; as=style",
+            "</app.js>; rel=preload; as=script"
+        ]
+    );
+
+    // Directory index only matches the catch-all entry
+    let mut session = make_session("GET", "/").await;
+    handler.request_filter(&mut session, &mut ()).await?;
+    assert_status(&session, 200);
+    assert_eq!(
+        links(&session),
+        vec!["</common.css>; rel=preload; as=style"]
+    );
+
+    // Non-HTML responses don't get the header
+    let mut session = make_session("GET", "/file.txt").await;
+    handler.request_filter(&mut session, &mut ()).await?;
+    assert_status(&session, 200);
+    assert!(links(&session).is_empty());
+
+    Ok(())
+}
+
+#[test(tokio::test)]
+async fn early_hints() -> Result<(), Box<Error>> {
+    let conf = concat!(
+        "index_file: index.html\n",
+        r#"preload: {"/index.html": ["</app.css>; rel=preload; as=style", "</app.js>; rel=preload; as=script"]}"#,
+    );
+    let handler = make_handler(extended_conf(format!("{conf}\nearly_hints: true")));
+
+    let mut session = make_session("GET", "/index.html").await;
+    handler.request_filter(&mut session, &mut ()).await?;
+    assert_eq!(session.informational_headers.len(), 1);
+    let hints = &session.informational_headers[0];
+    assert_eq!(hints.status, 103);
+    assert_eq!(
+        hints
+            .headers
+            .get_all(header::LINK)
+            .iter()
+            .map(|value| value.to_str().unwrap())
+            .collect::<Vec<_>>(),
+        vec![
+            "</app.css>; rel=preload; as=style",
+            "</app.js>; rel=preload; as=script"
+        ]
+    );
+    assert_status(&session, 200);
+    assert_eq!(
+        session
+            .response_header
+            .as_ref()
+            .unwrap()
+            .headers
+            .get_all(header::LINK)
+            .iter()
+            .count(),
+        2
+    );
+
+    // No hints without preload links
+    let mut session = make_session("GET", "/file.txt").await;
+    handler.request_filter(&mut session, &mut ()).await?;
+    assert_status(&session, 200);
+    assert!(session.informational_headers.is_empty());
+
+    // HTTP/1.0 clients don't get any hints
+    let mut header = RequestHeader::build("GET", b"/index.html", None)?;
+    header.set_version(http::Version::HTTP_10);
+    let mut session = TestSession::from(header).await;
+    handler.request_filter(&mut session, &mut ()).await?;
+    assert_status(&session, 200);
+    assert!(session.informational_headers.is_empty());
+
+    // Early hints are disabled by default
+    let handler = make_handler(extended_conf(conf));
+    let mut session = make_session("GET", "/index.html").await;
+    handler.request_filter(&mut session, &mut ()).await?;
+    assert_status(&session, 200);
+    assert!(session.informational_headers.is_empty());
+
+    Ok(())
+}
+
+async fn make_compressed_session(path: &str, encoding: &str) -> TestSession {
+    let mut session = make_session("GET", path).await;
+    session
+        .req_header_mut()
+        .insert_header("Accept-Encoding", encoding)
+        .unwrap();
+    session.downstream_compression.adjust_level(3);
+    session
+}
+
+async fn wait_for_generated(path: &Path) {
+    for _ in 0..100 {
+        if path.exists() && !is_pending(path) {
+            return;
+        }
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+    panic!("{path:?} wasn’t generated");
+}
+
+fn decompress_gzip(path: &Path) -> String {
+    use flate2::read::GzDecoder;
+    use std::io::Read;
+
+    let mut decompressed = String::new();
+    GzDecoder::new(std::fs::File::open(path).unwrap())
+        .read_to_string(&mut decompressed)
+        .unwrap();
+    decompressed
+}
+
+#[test(tokio::test)]
+async fn cache_compressed_to_disk() -> Result<(), Box<Error>> {
+    let root = std::env::temp_dir().join(format!(
+        "static-files-cache-compressed-{}",
+        std::process::id()
+    ));
+    let _ = std::fs::remove_dir_all(&root);
+    std::fs::create_dir_all(&root).unwrap();
+    let contents = str_repeat!("0123456789", 1000);
+    std::fs::write(root.join("file.txt"), contents).unwrap();
+
+    let handler = make_handler(format!(
+        r#"
+            root: {}
+            precompressed: [br, gz]
+            cache_compressed_to_disk: true
+        "#,
+        root.display()
+    ));
+
+    for (encoding, extension) in [("br", "br"), ("gzip", "gz")] {
+        let compressed_path = root.join(format!("file.txt.{extension}"));
+        assert!(!compressed_path.exists());
+
+        // First request is compressed dynamically while the file is generated in background
+        let mut session = make_compressed_session("/file.txt", encoding).await;
+        assert_eq!(
+            handler.request_filter(&mut session, &mut ()).await?,
+            RequestFilterResult::ResponseSent
+        );
+        assert_status(&session, 200);
+        let response = session.response_header.as_ref().unwrap();
+        assert_eq!(response.headers["Content-Encoding"], encoding);
+        assert_eq!(response.headers["Accept-Ranges"], "none");
+
+        wait_for_generated(&compressed_path).await;
+
+        // Subsequent requests use the generated file
+        let mut session = make_compressed_session("/file.txt", encoding).await;
+        assert_eq!(
+            handler.request_filter(&mut session, &mut ()).await?,
+            RequestFilterResult::ResponseSent
+        );
+
+        let meta = Metadata::from_path(&compressed_path, None).unwrap();
+        assert_status(&session, 200);
+        assert_headers(
+            &session,
+            vec![
+                ("Content-Length", &meta.size.to_string()),
+                ("accept-ranges", "bytes"),
+                ("Content-Type", "text/plain"),
+                ("last-modified", meta.modified.as_ref().unwrap()),
+                ("etag", &meta.etag),
+                ("X-Content-Type-Options", "nosniff"),
+                ("Content-Encoding", encoding),
+                ("vary", "Accept-Encoding"),
+            ],
+        );
+        assert_eq!(
+            session.response_body,
+            std::fs::read(&compressed_path).unwrap()
+        );
+    }
+
+    assert_eq!(decompress_gzip(&root.join("file.txt.gz")), contents);
+
+    // No leftover temporary files
+    assert_eq!(std::fs::read_dir(&root).unwrap().count(), 3);
+
+    // Outdated file isn’t used and gets regenerated, timestamps might have one second resolution
+    tokio::time::sleep(Duration::from_millis(1100)).await;
+    let new_contents = str_repeat!("9876543210", 1000);
+    std::fs::write(root.join("file.txt"), new_contents).unwrap();
+
+    let mut session = make_compressed_session("/file.txt", "gzip").await;
+    handler.request_filter(&mut session, &mut ()).await?;
+    assert_status(&session, 200);
+    let response = session.response_header.as_ref().unwrap();
+    assert_eq!(response.headers["Accept-Ranges"], "none");
+
+    wait_for_generated(&root.join("file.txt.gz")).await;
+    assert_eq!(decompress_gzip(&root.join("file.txt.gz")), new_contents);
+
+    let mut session = make_compressed_session("/file.txt", "gzip").await;
+    handler.request_filter(&mut session, &mut ()).await?;
+    assert_status(&session, 200);
+    let response = session.response_header.as_ref().unwrap();
+    assert_eq!(response.headers["Accept-Ranges"], "bytes");
+    assert_eq!(
+        session.response_body,
+        std::fs::read(root.join("file.txt.gz")).unwrap()
+    );
+
+    // Nothing is generated without dynamic compression, for small files or binary file types
+    std::fs::remove_file(root.join("file.txt.gz")).unwrap();
+    let mut session = make_session("GET", "/file.txt").await;
+    session
+        .req_header_mut()
+        .insert_header("Accept-Encoding", "gzip")?;
+    handler.request_filter(&mut session, &mut ()).await?;
+    assert_status(&session, 200);
+    assert_body(&session, new_contents);
+    assert!(!is_pending(&root.join("file.txt.gz")));
+    assert!(!root.join("file.txt.gz").exists());
+
+    std::fs::write(root.join("small.txt"), "Hi!").unwrap();
+    std::fs::write(root.join("image.png"), contents).unwrap();
+    for path in ["/small.txt", "/image.png"] {
+        let mut session = make_compressed_session(path, "gzip").await;
+        handler.request_filter(&mut session, &mut ()).await?;
+        assert_status(&session, 200);
+
+        let compressed_path = root.join(format!("{}.gz", &path[1..]));
+        assert!(!is_pending(&compressed_path));
+        assert!(!compressed_path.exists());
+    }
+
+    // Without the setting nothing is generated
+    let handler = make_handler(format!(
+        r#"
+            root: {}
+            precompressed: gz
+        "#,
+        root.display()
+    ));
+    let mut session = make_compressed_session("/file.txt", "gzip").await;
+    handler.request_filter(&mut session, &mut ()).await?;
+    assert_status(&session, 200);
+    assert!(!is_pending(&root.join("file.txt.gz")));
+    assert!(!root.join("file.txt.gz").exists());
+
+    let _ = std::fs::remove_dir_all(&root);
+    Ok(())
+}
+
+#[test(tokio::test)]
+async fn serve_file() -> Result<(), Box<Error>> {
+    let meta = Metadata::from_path(&root_path("large.txt"), None).unwrap();
+    let handler = make_handler(default_conf());
+
+    // Request path is unrelated to the file served
+    let mut session = make_session("GET", "/route/to/template").await;
+    assert_eq!(
+        handler
+            .serve_file(&mut session, &mut (), root_path("large.txt"))
+            .await?,
+        RequestFilterResult::ResponseSent
+    );
+    assert_status(&session, 200);
+    assert_eq!(session.response_body.len(), meta.size as usize);
+
+    let mut session = make_session("GET", "/route/to/template").await;
+    session
+        .req_header_mut()
+        .insert_header("Range", "bytes=2-5")?;
+    handler
+        .serve_file(&mut session, &mut (), root_path("large.txt"))
+        .await?;
+    assert_status(&session, 206);
+    assert_headers(
+        &session,
+        vec![
+            ("Content-Length", "4"),
+            ("content-range", "bytes 2-5/100001"),
+            ("Content-Type", "text/plain"),
+            ("last-modified", meta.modified.as_ref().unwrap()),
+            ("etag", &meta.etag),
+            ("X-Content-Type-Options", "nosniff"),
+        ],
+    );
+    assert_body(&session, "2345");
+
+    let mut session = make_session("GET", "/route/to/template").await;
+    session
+        .req_header_mut()
+        .insert_header("If-None-Match", &meta.etag)?;
+    handler
+        .serve_file(&mut session, &mut (), root_path("large.txt"))
+        .await?;
+    assert_status(&session, 304);
+    assert_body(&session, "");
+
+    let mut session = make_session("HEAD", "/route/to/template").await;
+    handler
+        .serve_file(&mut session, &mut (), root_path("large.txt"))
+        .await?;
+    assert_status(&session, 200);
+    assert_body(&session, "");
+
+    let mut session = make_session("POST", "/route/to/template").await;
+    handler
+        .serve_file(&mut session, &mut (), root_path("large.txt"))
+        .await?;
+    assert_status(&session, 405);
+
+    // Files outside the root directory can be served as well
+    let mut path = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+    path.push("testdata");
+    path.push("errors");
+    path.push("404.html");
+    let mut session = make_session("GET", "/route/to/template").await;
+    handler.serve_file(&mut session, &mut (), path).await?;
+    assert_status(&session, 200);
+    assert_body(&session, "<h1>Page not found</h1>\n");
+
+    // Directories cannot be served
+    let mut session = make_session("GET", "/route/to/template").await;
+    handler
+        .serve_file(&mut session, &mut (), root_path("subdir"))
+        .await?;
+    assert_status(&session, 403);
+
+    Ok(())
+}
@@ -0,0 +1,210 @@
+// Copyright 2024 Wladimir Palant
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use serde::Deserialize;
+use std::collections::hash_map::RandomState;
+use std::hash::BuildHasher;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+/// Policy determining how requests are distributed among upstream backends
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Balancing {
+    /// Backends are selected in turn
+    #[default]
+    RoundRobin,
+    /// Backends are selected randomly
+    Random,
+    /// Backends are selected randomly, with the probability proportional to their weight
+    Weighted,
+    /// The backend with the fewest requests in flight is selected
+    LeastConn,
+}
+
+#[derive(Debug)]
+struct BalancerState {
+    counter: AtomicUsize,
+    in_flight: Vec<AtomicUsize>,
+    random: RandomState,
+}
+
+/// A list of backends along with the state necessary to distribute requests among them
+///
+/// Clones share the state.
+#[derive(Debug, Clone)]
+pub(crate) struct Balancer {
+    policy: Balancing,
+    backends: Vec<(SocketAddr, usize)>,
+    state: Arc<BalancerState>,
+}
+
+impl PartialEq for Balancer {
+    fn eq(&self, other: &Self) -> bool {
+        self.policy == other.policy && self.backends == other.backends
+    }
+}
+
+impl Eq for Balancer {}
+
+impl Balancer {
+    /// Creates a new balancer for a non-empty list of backend address/weight combinations.
+    pub(crate) fn new(policy: Balancing, backends: Vec<(SocketAddr, usize)>) -> Self {
+        let in_flight = backends.iter().map(|_| AtomicUsize::new(0)).collect();
+        Self {
+            policy,
+            backends,
+            state: Arc::new(BalancerState {
+                counter: AtomicUsize::new(0),
+                in_flight,
+                random: RandomState::new(),
+            }),
+        }
+    }
+
+    fn random(&self, max: usize) -> usize {
+        let counter = self.state.counter.fetch_add(1, Ordering::Relaxed);
+        (self.state.random.hash_one(counter) % max as u64) as usize
+    }
+
+    /// Selects a backend for a new request and returns its index. The request is considered in
+    /// flight until [`Balancer::release`] is called for this index.
+    pub(crate) fn select(&self) -> usize {
+        let count = self.backends.len();
+        let index = match self.policy {
+            Balancing::RoundRobin => self.state.counter.fetch_add(1, Ordering::Relaxed) % count,
+            Balancing::Random => self.random(count),
+            Balancing::Weighted => {
+                let total = self.backends.iter().map(|(_, weight)| weight).sum();
+                let mut value = self.random(total);
+                self.backends
+                    .iter()
+                    .position(|(_, weight)| {
+                        if value < *weight {
+                            true
+                        } else {
+                            value -= weight;
+                            false
+                        }
+                    })
+                    .unwrap_or(0)
+            }
+            Balancing::LeastConn => {
+                // Start at a different backend each time so that ties are resolved in turn
+                let offset = self.state.counter.fetch_add(1, Ordering::Relaxed);
+                (0..count)
+                    .map(|index| offset.wrapping_add(index) % count)
+                    .min_by_key(|index| self.state.in_flight[*index].load(Ordering::Relaxed))
+                    .unwrap_or(0)
+            }
+        };
+        self.state.in_flight[index].fetch_add(1, Ordering::Relaxed);
+        index
+    }
+
+    /// Marks a request to the given backend as completed.
+    pub(crate) fn release(&self, index: usize) {
+        if let Some(in_flight) = self.state.in_flight.get(index) {
+            let _ = in_flight.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |value| {
+                value.checked_sub(1)
+            });
+        }
+    }
+
+    /// Returns the address of the backend with the given index.
+    pub(crate) fn addr(&self, index: usize) -> SocketAddr {
+        self.backends[index].0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn make_balancer(policy: Balancing, weights: &[usize]) -> Balancer {
+        let backends = weights
+            .iter()
+            .enumerate()
+            .map(|(index, weight)| {
+                (
+                    SocketAddr::from(([127, 0, 0, 1], 8080 + index as u16)),
+                    *weight,
+                )
+            })
+            .collect();
+        Balancer::new(policy, backends)
+    }
+
+    fn distribution(balancer: &Balancer, selections: usize) -> Vec<usize> {
+        let mut counts = vec![0; balancer.backends.len()];
+        for _ in 0..selections {
+            let index = balancer.select();
+            counts[index] += 1;
+            balancer.release(index);
+        }
+        counts
+    }
+
+    #[test]
+    fn round_robin() {
+        let balancer = make_balancer(Balancing::RoundRobin, &[1, 1, 1]);
+        let selected = (0..6).map(|_| balancer.select()).collect::<Vec<_>>();
+        assert_eq!(selected, vec![0, 1, 2, 0, 1, 2]);
+    }
+
+    #[test]
+    fn random() {
+        let balancer = make_balancer(Balancing::Random, &[1, 1]);
+        let counts = distribution(&balancer, 4000);
+        for count in counts {
+            assert!((1700..=2300).contains(&count), "{count}");
+        }
+    }
+
+    #[test]
+    fn weighted() {
+        let balancer = make_balancer(Balancing::Weighted, &[1, 3, 6]);
+        let counts = distribution(&balancer, 10000);
+        assert!((700..=1300).contains(&counts[0]), "{counts:?}");
+        assert!((2600..=3400).contains(&counts[1]), "{counts:?}");
+        assert!((5400..=6600).contains(&counts[2]), "{counts:?}");
+    }
+
+    #[test]
+    fn least_conn() {
+        let balancer = make_balancer(Balancing::LeastConn, &[1, 1, 1]);
+
+        // All idle initially, backends are selected in turn
+        assert_eq!(balancer.select(), 0);
+        assert_eq!(balancer.select(), 1);
+        assert_eq!(balancer.select(), 2);
+
+        // Backend 1 completed its request, it is the idlest now
+        balancer.release(1);
+        assert_eq!(balancer.select(), 1);
+
+        // Backend 2 is the only one with a request in flight, it is skipped
+        balancer.release(0);
+        balancer.release(1);
+        assert_ne!(balancer.select(), 2);
+        assert_ne!(balancer.select(), 2);
+
+        // Releasing too often doesn’t break anything
+        balancer.release(2);
+        balancer.release(2);
+        balancer.release(2);
+        assert_eq!(balancer.state.in_flight[2].load(Ordering::Relaxed), 0);
+    }
+}
@@ -24,6 +24,100 @@
 //! Supported URL schemes are `http://` and `https://`. Other than the scheme, only host name and
 //! port are considered. Other parts of the URL are ignored if present.
 //!
+//! ## TLS settings
+//!
+//! TLS is used for connections to the upstream server if the `upstream` URL uses the `https://`
+//! scheme. The following settings adjust TLS connections:
+//!
+//! * `upstream_sni`: Server name to send and to verify the certificate against, by default the
+//!   host name of the `upstream` URL.
+//! * `upstream_ca_path`: Path to a file with PEM-encoded CA certificates. If set, the upstream’s
+//!   certificate is verified against these instead of the system’s trusted certificates.
+//! * `upstream_insecure_skip_verify`: If `true`, the upstream’s certificate isn’t verified at
+//!   all. This is meant for internal backends with self-signed certificates, don’t use it for
+//!   connections over untrusted networks.
+//!
+//! For example:
+//!
+//! ```yaml
+//! upstream: https://backend.internal
+//! upstream_sni: backend.example.com
+//! upstream_ca_path: /etc/pandora/internal-ca.pem
+//! ```
+//!
+//! ## Load balancing
+//!
+//! By default, requests are forwarded to the address the host name of the `upstream` URL resolves
+//! to. Alternatively, requests can be distributed among multiple backends:
+//!
+//! ```yaml
+//! upstream: http://backend.example.com
+//! upstream_backends:
+//! - addr: 127.0.0.1:8081
+//!   weight: 3
+//! - addr: 127.0.0.1:8082
+//! upstream_balancing: weighted
+//! ```
+//!
+//! The `upstream` URL still determines the scheme, `Host` header and TLS server name. Each entry
+//! of `upstream_backends` has the following settings:
+//!
+//! * `addr`: Backend address and port, e.g. `127.0.0.1:8081`. A host name is resolved once at
+//!   startup.
+//! * `weight`: Relative weight of the backend for `weighted` balancing, `1` if not set.
+//!
+//! The `upstream_balancing` setting determines how backends are selected:
+//!
+//! * `round_robin` (default): Backends are selected in turn.
+//! * `random`: Backends are selected randomly.
+//! * `weighted`: Backends are selected randomly, with the probability proportional to the
+//!   backend’s weight.
+//! * `least_conn`: The backend with the fewest requests in flight is selected. A request is
+//!   considered in flight until its `logging` phase.
+//!
+//! ## Timeouts
+//!
+//! By default, Pingora’s timeouts apply to connections to the upstream server. These can be
+//! changed with the following settings:
+//!
+//! * `upstream_connect_timeout`: Maximal time to establish a connection.
+//! * `upstream_read_timeout`: Maximal time to wait for data from the upstream server.
+//! * `upstream_write_timeout`: Maximal time to wait while sending data to the upstream server.
+//!
+//! The values are given in seconds or with an `ms` or `s` suffix:
+//!
+//! ```yaml
+//! upstream: http://127.0.0.1:8081
+//! upstream_connect_timeout: 500ms
+//! upstream_read_timeout: 30
+//! ```
+//!
+//! In combination with the Virtual Hosts Module, different timeouts can be configured for
+//! different hosts or paths, e.g. for a particularly slow backend.
+//!
+//! ## Rewriting redirects
+//!
+//! Upstream servers often don’t know the host name they are reachable under publicly. Their
+//! redirects then point to the upstream host, e.g. `Location: https://backend.internal/x`. With
+//! `upstream_rewrite_location` enabled, such URLs in the `Location`, `Content-Location` and
+//! `Refresh` response headers are rewritten to point to the public host instead:
+//!
+//! ```yaml
+//! upstream: https://backend.internal
+//! upstream_rewrite_location: true
+//! ```
+//!
+//! By default, the URLs are rewritten to the host requested by the client, using the scheme of
+//! the client’s connection. The following settings change this mapping:
+//!
+//! * `upstream_rewrite_hosts`: Additional upstream hosts (with port if non-default) whose URLs
+//!   should be rewritten. The host of the `upstream` URL is always rewritten.
+//! * `upstream_public_url`: URL like `https://example.com` to rewrite to instead of the requested
+//!   host, e.g. if the server is behind another proxy. Only scheme, host name and port are
+//!   considered.
+//!
+//! Relative URLs and URLs pointing to other hosts are left unchanged.
+//!
 //! ## Code example
 //!
 //! `UpstreamHandler` handles both `request_filter` and `upstream_peer` phases. The former selects
@@ -52,22 +146,33 @@
 //! let mut conf = Conf::load_from_files(opt.startup.conf.as_deref().unwrap_or(&[])).unwrap();
 //! conf.upstream.merge_with_opt(opt.upstream);
 //!
-//! let app = DefaultApp::<UpstreamHandler>::from_conf(conf.upstream).unwrap();
+//! let app = DefaultApp::<UpstreamHandler>::from_conf(conf.upstream)
+//!     .unwrap()
+//!     .with_startup_conf(&conf.startup);
 //! let server = conf.startup.into_server(app, Some(opt.startup)).unwrap();
 //!
 //! // Do something with the server here, e.g. call server.run_forever()
 //! ```
 
+mod balancer;
+
 use async_trait::async_trait;
 use clap::{value_parser, Parser};
 use http::header;
 use http::uri::{Scheme, Uri};
 use log::error;
-use pandora_module_utils::pingora::{Error, ErrorType, HttpPeer, SessionWrapper};
-use pandora_module_utils::{DeserializeMap, RequestFilter, RequestFilterResult};
-use serde::de::{Deserializer, Error as _};
-use serde::Deserialize as _;
+use pandora_module_utils::pingora::{Error, ErrorType, HttpPeer, ResponseHeader, SessionWrapper};
+use pandora_module_utils::{DeserializeMap, OneOrMany, RequestFilter, RequestFilterResult};
+use pingora::tls::x509::X509;
+use serde::de::{Deserializer, Error as _, Unexpected};
+use serde::Deserialize;
 use std::net::{SocketAddr, ToSocketAddrs};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+
+use balancer::Balancer;
+pub use balancer::Balancing;
 
 /// Command line options of the compression module
 #[derive(Debug, Default, Parser)]
@@ -89,6 +194,89 @@ where
     Ok(Some(uri))
 }
 
+fn deserialize_timeout<'de, D>(d: D) -> Result<Option<Duration>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Timeout {
+        Seconds(u64),
+        String(String),
+    }
+
+    let timeout = match Timeout::deserialize(d)? {
+        Timeout::Seconds(seconds) => Duration::from_secs(seconds),
+        Timeout::String(value) => {
+            let parsed = if let Some(millis) = value.strip_suffix("ms") {
+                millis.trim().parse().map(Duration::from_millis)
+            } else {
+                value
+                    .strip_suffix('s')
+                    .unwrap_or(&value)
+                    .trim()
+                    .parse()
+                    .map(Duration::from_secs)
+            };
+            parsed.map_err(|_| {
+                D::Error::invalid_value(Unexpected::Str(&value), &"a timeout like 500ms or 5s")
+            })?
+        }
+    };
+    Ok(Some(timeout))
+}
+
+fn resolve(host: &str, port: u16) -> Result<SocketAddr, Box<Error>> {
+    (host, port)
+        .to_socket_addrs()
+        .map_err(|err| {
+            error!("failed resolving upstream host name {host}: {err}");
+            Error::new(ErrorType::InternalError)
+        })?
+        .next()
+        .ok_or_else(|| {
+            error!("DNS lookup of upstream host name {host} didn't produce any results");
+            Error::new(ErrorType::InternalError)
+        })
+}
+
+/// A single backend that requests can be forwarded to
+#[derive(Debug, Default, Clone, PartialEq, Eq, DeserializeMap)]
+pub struct UpstreamBackend {
+    /// Address and port of the backend, e.g. `127.0.0.1:8081`
+    pub addr: String,
+
+    /// Relative weight of the backend for `weighted` balancing, `1` if not set
+    pub weight: Option<usize>,
+}
+
+impl UpstreamBackend {
+    fn resolve(&self) -> Result<(SocketAddr, usize), Box<Error>> {
+        let weight = self.weight.unwrap_or(1);
+        if weight == 0 {
+            error!(
+                "weight of upstream backend {} has to be at least 1",
+                self.addr
+            );
+            return Err(Error::new(ErrorType::InternalError));
+        }
+
+        let addr = if let Ok(addr) = self.addr.parse() {
+            addr
+        } else if let Some((host, port)) = self.addr.rsplit_once(':') {
+            let port = port.parse().map_err(|_| {
+                error!("invalid port in upstream backend address {}", self.addr);
+                Error::new(ErrorType::InternalError)
+            })?;
+            resolve(host, port)?
+        } else {
+            error!("upstream backend address {} has no port", self.addr);
+            return Err(Error::new(ErrorType::InternalError));
+        };
+        Ok((addr, weight))
+    }
+}
+
 /// Configuration settings of the compression module
 #[derive(Debug, Default, Clone, PartialEq, Eq, DeserializeMap)]
 pub struct UpstreamConf {
@@ -96,6 +284,53 @@ pub struct UpstreamConf {
     /// Path and query parts of the URL have no effect.
     #[pandora(deserialize_with = "deserialize_uri")]
     pub upstream: Option<Uri>,
+
+    /// Backends to distribute requests among
+    ///
+    /// If empty, requests are forwarded to the address that the host name of the `upstream` URL
+    /// resolves to.
+    pub upstream_backends: OneOrMany<UpstreamBackend>,
+
+    /// Policy determining how requests are distributed among the backends
+    pub upstream_balancing: Balancing,
+
+    /// Server name to use for TLS connections instead of the host name of the `upstream` URL
+    pub upstream_sni: Option<String>,
+
+    /// Path to a file with the PEM-encoded CA certificates to verify the upstream’s certificate
+    /// against instead of the system’s trusted certificates
+    pub upstream_ca_path: Option<PathBuf>,
+
+    /// If `true`, the upstream’s certificate won’t be verified. Only use this for internal
+    /// backends with self-signed certificates.
+    pub upstream_insecure_skip_verify: bool,
+
+    /// Timeout for establishing a connection to the upstream server, Pingora’s default if not set
+    ///
+    /// In the configuration file this is specified in seconds or with a suffix: `500ms`, `5s`.
+    #[pandora(deserialize_with = "deserialize_timeout")]
+    pub upstream_connect_timeout: Option<Duration>,
+
+    /// Timeout for each read from the upstream server, Pingora’s default if not set
+    #[pandora(deserialize_with = "deserialize_timeout")]
+    pub upstream_read_timeout: Option<Duration>,
+
+    /// Timeout for each write to the upstream server, Pingora’s default if not set
+    #[pandora(deserialize_with = "deserialize_timeout")]
+    pub upstream_write_timeout: Option<Duration>,
+
+    /// If `true`, URLs pointing to the upstream host in the `Location`, `Content-Location` and
+    /// `Refresh` response headers are rewritten to point to the public host
+    pub upstream_rewrite_location: bool,
+
+    /// Additional hosts (with port if non-default) whose URLs should be rewritten along with the
+    /// host of the `upstream` URL
+    pub upstream_rewrite_hosts: OneOrMany<String>,
+
+    /// http:// or https:// URL to rewrite upstream URLs to, by default the host requested by the
+    /// client is used. Path and query parts of the URL have no effect.
+    #[pandora(deserialize_with = "deserialize_uri")]
+    pub upstream_public_url: Option<Uri>,
 }
 
 impl UpstreamConf {
@@ -112,15 +347,167 @@ impl UpstreamConf {
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct UpstreamContext {
     addr: SocketAddr,
+    backend: usize,
+    public_origin: Option<String>,
+}
+
+/// CA certificates loaded from a file
+#[derive(Debug, Clone)]
+struct CaBundle {
+    path: PathBuf,
+    certs: Arc<Box<[X509]>>,
+}
+
+impl CaBundle {
+    fn load(path: PathBuf) -> Result<Self, Box<Error>> {
+        let data = std::fs::read(&path).map_err(|err| {
+            Error::because(
+                ErrorType::FileReadError,
+                format!("failed reading CA certificates from {}", path.display()),
+                err,
+            )
+        })?;
+        let certs = X509::stack_from_pem(&data).map_err(|err| {
+            Error::because(
+                ErrorType::InternalError,
+                format!("failed parsing CA certificates in {}", path.display()),
+                err,
+            )
+        })?;
+        if certs.is_empty() {
+            return Err(Error::explain(
+                ErrorType::InternalError,
+                format!("no CA certificates found in {}", path.display()),
+            ));
+        }
+
+        Ok(Self {
+            path,
+            certs: Arc::new(certs.into_boxed_slice()),
+        })
+    }
+}
+
+impl PartialEq for CaBundle {
+    fn eq(&self, other: &Self) -> bool {
+        self.path == other.path
+    }
+}
+
+impl Eq for CaBundle {}
+
+/// Response headers containing URLs that might need rewriting
+const REWRITE_HEADERS: [header::HeaderName; 2] = [header::LOCATION, header::CONTENT_LOCATION];
+
+/// Location rewriting settings of a configured handler
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct LocationRewrite {
+    hosts: Vec<String>,
+    public_origin: Option<String>,
+}
+
+impl LocationRewrite {
+    /// Returns the rewritten URL if it is an absolute URL pointing to one of the upstream hosts.
+    fn rewrite_url(&self, url: &str, public_origin: &str) -> Option<String> {
+        let (scheme, rest) = url.split_once("://")?;
+        if !scheme.eq_ignore_ascii_case("http") && !scheme.eq_ignore_ascii_case("https") {
+            return None;
+        }
+
+        let end = rest
+            .find(|c: char| matches!(c, '/' | '?' | '#'))
+            .unwrap_or(rest.len());
+        let (authority, remainder) = rest.split_at(end);
+        self.hosts
+            .iter()
+            .any(|host| host.eq_ignore_ascii_case(authority))
+            .then(|| format!("{public_origin}{remainder}"))
+    }
+
+    /// Rewrites the URL in a `Refresh` header value like `5; url=https://example.com/`.
+    fn rewrite_refresh(&self, value: &str, public_origin: &str) -> Option<String> {
+        let (delay, url) = value.split_once(';')?;
+        let url = url.trim_start();
+        let (prefix, url) = match url.get(..4) {
+            Some(prefix) if prefix.eq_ignore_ascii_case("url=") => url.split_at(4),
+            _ => ("", url),
+        };
+        let url = self.rewrite_url(url, public_origin)?;
+        Some(format!("{delay}; {prefix}{url}"))
+    }
+
+    fn apply(&self, response: &mut ResponseHeader, public_origin: &str) {
+        for name in REWRITE_HEADERS {
+            let rewritten = response
+                .headers
+                .get(&name)
+                .and_then(|value| value.to_str().ok())
+                .and_then(|value| self.rewrite_url(value, public_origin));
+            if let Some(rewritten) = rewritten {
+                // Conversion from String is infallible, ignore errors.
+                let _ = response.insert_header(name, rewritten);
+            }
+        }
+
+        let rewritten = response
+            .headers
+            .get(header::REFRESH)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| self.rewrite_refresh(value, public_origin));
+        if let Some(rewritten) = rewritten {
+            let _ = response.insert_header(header::REFRESH, rewritten);
+        }
+    }
+}
+
+fn origin_from_uri(uri: &Uri) -> Result<String, Box<Error>> {
+    match (uri.scheme(), uri.authority()) {
+        (Some(scheme), Some(authority)) if scheme == &Scheme::HTTP || scheme == &Scheme::HTTPS => {
+            Ok(format!("{scheme}://{}", authority.as_str()))
+        }
+        _ => {
+            error!("provided public URL isn't an absolute HTTP or HTTPS URL: {uri}");
+            Err(Error::new(ErrorType::InternalError))
+        }
+    }
+}
+
+/// Upstream settings of a configured handler
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Upstream {
     tls: bool,
     sni: String,
+    ca: Option<CaBundle>,
+    insecure_skip_verify: bool,
+    connect_timeout: Option<Duration>,
+    read_timeout: Option<Duration>,
+    write_timeout: Option<Duration>,
+    balancer: Balancer,
+    rewrite_location: Option<LocationRewrite>,
+}
+
+impl Upstream {
+    fn peer(&self, addr: SocketAddr) -> HttpPeer {
+        let mut peer = HttpPeer::new(addr, self.tls, self.sni.clone());
+        if self.tls {
+            if self.insecure_skip_verify {
+                peer.options.verify_cert = false;
+                peer.options.verify_hostname = false;
+            }
+            peer.options.ca = self.ca.as_ref().map(|ca| ca.certs.clone());
+        }
+        peer.options.connection_timeout = self.connect_timeout;
+        peer.options.read_timeout = self.read_timeout;
+        peer.options.write_timeout = self.write_timeout;
+        peer
+    }
 }
 
 /// Handler for Pingora’s `request_filter` phase
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct UpstreamHandler {
     host_port: String,
-    context: Option<UpstreamContext>,
+    upstream: Option<Upstream>,
 }
 
 impl TryFrom<UpstreamConf> for UpstreamHandler {
@@ -149,17 +536,23 @@ impl TryFrom<UpstreamConf> for UpstreamHandler {
 
             let port = upstream.port_u16().unwrap_or(if tls { 443 } else { 80 });
 
-            let addr = (host, port)
-                .to_socket_addrs()
-                .map_err(|err| {
-                    error!("failed resolving upstream host name {host}: {err}");
-                    Error::new(ErrorType::InternalError)
-                })?
-                .next()
-                .ok_or_else(|| {
-                    error!("DNS lookup of upstream host name {host} didn't produce any results");
-                    Error::new(ErrorType::InternalError)
-                })?;
+            let backends = if conf.upstream_backends.is_empty() {
+                vec![(resolve(host, port)?, 1)]
+            } else {
+                conf.upstream_backends
+                    .iter()
+                    .map(UpstreamBackend::resolve)
+                    .collect::<Result<_, _>>()?
+            };
+
+            let has_tls_settings = conf.upstream_sni.is_some()
+                || conf.upstream_ca_path.is_some()
+                || conf.upstream_insecure_skip_verify;
+            if !tls && has_tls_settings {
+                error!("TLS settings are only supported with an https:// upstream URL: {upstream}");
+                return Err(Error::new(ErrorType::InternalError));
+            }
+            let ca = conf.upstream_ca_path.map(CaBundle::load).transpose()?;
 
             let mut host_port = host.to_owned();
             if let Some(port) = upstream.port() {
@@ -167,18 +560,45 @@ impl TryFrom<UpstreamConf> for UpstreamHandler {
                 host_port.push_str(port.as_str());
             }
 
+            let rewrite_location = if conf.upstream_rewrite_location {
+                let mut hosts = vec![host_port.clone()];
+                hosts.extend(conf.upstream_rewrite_hosts);
+                Some(LocationRewrite {
+                    hosts,
+                    public_origin: conf
+                        .upstream_public_url
+                        .as_ref()
+                        .map(origin_from_uri)
+                        .transpose()?,
+                })
+            } else if !conf.upstream_rewrite_hosts.is_empty() || conf.upstream_public_url.is_some()
+            {
+                error!(
+                    "Location rewriting settings require upstream_rewrite_location to be enabled"
+                );
+                return Err(Error::new(ErrorType::InternalError));
+            } else {
+                None
+            };
+
             Ok(Self {
                 host_port,
-                context: Some(UpstreamContext {
+                upstream: Some(Upstream {
                     tls,
-                    addr,
-                    sni: host.to_owned(),
+                    sni: conf.upstream_sni.unwrap_or_else(|| host.to_owned()),
+                    ca,
+                    insecure_skip_verify: conf.upstream_insecure_skip_verify,
+                    connect_timeout: conf.upstream_connect_timeout,
+                    read_timeout: conf.upstream_read_timeout,
+                    write_timeout: conf.upstream_write_timeout,
+                    balancer: Balancer::new(conf.upstream_balancing, backends),
+                    rewrite_location,
                 }),
             })
         } else {
             Ok(Self {
                 host_port: Default::default(),
-                context: None,
+                upstream: None,
             })
         }
     }
@@ -197,12 +617,34 @@ impl RequestFilter for UpstreamHandler {
         session: &mut impl SessionWrapper,
         ctx: &mut Self::CTX,
     ) -> Result<RequestFilterResult, Box<Error>> {
-        if let Some(context) = &self.context {
+        if let Some(upstream) = &self.upstream {
+            // The public host has to be determined before the Host header is replaced
+            let public_origin = match &upstream.rewrite_location {
+                Some(LocationRewrite {
+                    public_origin: Some(origin),
+                    ..
+                }) => Some(origin.clone()),
+                Some(_) => session
+                    .host()
+                    .map(|host| format!("{}://{host}", session.scheme(false))),
+                None => None,
+            };
+
             session
                 .req_header_mut()
                 .insert_header(header::HOST, &self.host_port)?;
 
-            *ctx = Some(context.clone());
+            if let Some(context) = ctx.take() {
+                // Request filter ran for this request already
+                upstream.balancer.release(context.backend);
+            }
+
+            let backend = upstream.balancer.select();
+            *ctx = Some(UpstreamContext {
+                addr: upstream.balancer.addr(backend),
+                backend,
+                public_origin,
+            });
 
             Ok(RequestFilterResult::Handled)
         } else {
@@ -215,16 +657,41 @@ impl RequestFilter for UpstreamHandler {
         _session: &mut impl SessionWrapper,
         ctx: &mut Self::CTX,
     ) -> Result<Option<Box<HttpPeer>>, Box<Error>> {
-        if let Some(context) = ctx {
-            Ok(Some(Box::new(HttpPeer::new(
-                context.addr,
-                context.tls,
-                context.sni.clone(),
-            ))))
+        if let (Some(upstream), Some(context)) = (&self.upstream, ctx) {
+            Ok(Some(Box::new(upstream.peer(context.addr))))
         } else {
             Ok(None)
         }
     }
+
+    fn response_filter(
+        &self,
+        _session: &mut impl SessionWrapper,
+        response: &mut ResponseHeader,
+        ctx: Option<&mut Self::CTX>,
+    ) {
+        let rewrite = self
+            .upstream
+            .as_ref()
+            .and_then(|upstream| upstream.rewrite_location.as_ref());
+        let public_origin = ctx
+            .and_then(|ctx| ctx.as_ref())
+            .and_then(|context| context.public_origin.as_deref());
+        if let (Some(rewrite), Some(public_origin)) = (rewrite, public_origin) {
+            rewrite.apply(response, public_origin);
+        }
+    }
+
+    async fn logging(
+        &self,
+        _session: &mut impl SessionWrapper,
+        _e: Option<&Error>,
+        ctx: &mut Self::CTX,
+    ) {
+        if let (Some(upstream), Some(context)) = (&self.upstream, ctx.take()) {
+            upstream.balancer.release(context.backend);
+        }
+    }
 }
 
 #[cfg(test)]
@@ -307,4 +774,278 @@ mod tests {
 
         Ok(())
     }
+
+    #[test(tokio::test)]
+    async fn balancing() -> Result<(), Box<Error>> {
+        let conf = UpstreamConf::from_yaml(
+            r#"
+                upstream: http://backend.example.com
+                upstream_backends:
+                - addr: 127.0.0.1:8081
+                - addr: 127.0.0.1:8082
+                upstream_balancing: least_conn
+            "#,
+        )?;
+        let app = DefaultApp::new(UpstreamHandler::try_from(conf)?);
+
+        let mut sessions = Vec::new();
+        for _ in 0..2 {
+            let mut session = make_session().await;
+            let mut ctx = app.new_ctx();
+            app.request_filter(&mut session, &mut ctx).await?;
+            let peer = app.upstream_peer(&mut session, &mut ctx).await?;
+            assert_eq!(peer.sni, "backend.example.com");
+            sessions.push((session, ctx, peer._address.to_string()));
+        }
+        assert_eq!(sessions[0].2, "127.0.0.1:8081");
+        assert_eq!(sessions[1].2, "127.0.0.1:8082");
+        assert_eq!(
+            sessions[0].0.req_header().headers.get("Host"),
+            Some(&HeaderValue::from_str("backend.example.com").unwrap())
+        );
+
+        // Second request completes, its backend is the idlest now
+        let (mut session, mut ctx, _) = sessions.pop().unwrap();
+        app.logging(&mut session, None, &mut ctx).await;
+
+        let mut session = make_session().await;
+        let mut ctx = app.new_ctx();
+        app.request_filter(&mut session, &mut ctx).await?;
+        let peer = app.upstream_peer(&mut session, &mut ctx).await?;
+        assert_eq!(peer._address.to_string(), "127.0.0.1:8082");
+
+        Ok(())
+    }
+
+    #[test]
+    fn backend_validation() -> Result<(), Box<Error>> {
+        for backend in [
+            "{addr: 127.0.0.1:8081, weight: 0}",
+            "{addr: 127.0.0.1}",
+            "{addr: localhost:port}",
+        ] {
+            let conf = UpstreamConf::from_yaml(format!(
+                "upstream: http://example.com\nupstream_backends: [{backend}]"
+            ))?;
+            assert!(UpstreamHandler::try_from(conf).is_err(), "{backend}");
+        }
+
+        let conf = UpstreamConf::from_yaml(
+            "upstream: http://example.com\nupstream_backends: [{addr: 127.0.0.1:8081, weight: 5}]",
+        )?;
+        assert!(UpstreamHandler::try_from(conf).is_ok());
+        Ok(())
+    }
+
+    fn make_peer(conf: &str) -> Result<HttpPeer, Box<Error>> {
+        let handler = UpstreamHandler::try_from(UpstreamConf::from_yaml(conf)?)?;
+        let upstream = handler.upstream.unwrap();
+        Ok(upstream.peer(upstream.balancer.addr(0)))
+    }
+
+    #[test]
+    fn tls_settings() -> Result<(), Box<Error>> {
+        let peer = make_peer(
+            r#"
+                upstream: http://backend.example.com
+                upstream_backends: {addr: 127.0.0.1:8080}
+            "#,
+        )?;
+        assert_eq!(peer.scheme.to_string(), "HTTP");
+
+        let peer = make_peer(
+            r#"
+                upstream: https://backend.example.com
+                upstream_backends: {addr: 127.0.0.1:8443}
+            "#,
+        )?;
+        assert_eq!(peer.scheme.to_string(), "HTTPS");
+        assert_eq!(peer.sni, "backend.example.com");
+        assert!(peer.options.verify_cert);
+        assert!(peer.options.verify_hostname);
+        assert!(peer.options.ca.is_none());
+
+        let peer = make_peer(
+            r#"
+                upstream: https://backend.internal
+                upstream_backends: {addr: 127.0.0.1:8443}
+                upstream_sni: backend.example.com
+                upstream_ca_path: ../startup-module/testdata/rsa.cert.pem
+            "#,
+        )?;
+        assert_eq!(peer.scheme.to_string(), "HTTPS");
+        assert_eq!(peer.sni, "backend.example.com");
+        assert!(peer.options.verify_cert);
+        assert_eq!(peer.options.ca.unwrap().len(), 1);
+
+        let peer = make_peer(
+            r#"
+                upstream: https://backend.internal
+                upstream_backends: {addr: 127.0.0.1:8443}
+                upstream_insecure_skip_verify: true
+            "#,
+        )?;
+        assert!(!peer.options.verify_cert);
+        assert!(!peer.options.verify_hostname);
+
+        // TLS settings require TLS
+        assert!(make_peer(
+            r#"
+                upstream: http://backend.internal
+                upstream_backends: {addr: 127.0.0.1:8080}
+                upstream_insecure_skip_verify: true
+            "#,
+        )
+        .is_err());
+
+        assert!(make_peer(
+            r#"
+                upstream: https://backend.internal
+                upstream_backends: {addr: 127.0.0.1:8443}
+                upstream_ca_path: nonexistent.pem
+            "#,
+        )
+        .is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn timeouts() -> Result<(), Box<Error>> {
+        let peer = make_peer(
+            r#"
+                upstream: http://backend.example.com
+                upstream_backends: {addr: 127.0.0.1:8080}
+            "#,
+        )?;
+        assert_eq!(peer.options.connection_timeout, None);
+        assert_eq!(peer.options.read_timeout, None);
+        assert_eq!(peer.options.write_timeout, None);
+
+        let peer = make_peer(
+            r#"
+                upstream: http://backend.example.com
+                upstream_backends: {addr: 127.0.0.1:8080}
+                upstream_connect_timeout: 500ms
+                upstream_read_timeout: 30
+                upstream_write_timeout: 10s
+            "#,
+        )?;
+        assert_eq!(
+            peer.options.connection_timeout,
+            Some(Duration::from_millis(500))
+        );
+        assert_eq!(peer.options.read_timeout, Some(Duration::from_secs(30)));
+        assert_eq!(peer.options.write_timeout, Some(Duration::from_secs(10)));
+
+        assert!(UpstreamConf::from_yaml("upstream_read_timeout: 5m").is_err());
+        assert!(UpstreamConf::from_yaml("upstream_read_timeout: -1").is_err());
+
+        Ok(())
+    }
+
+    async fn rewrite_headers(
+        conf: &str,
+        headers: &[(&'static str, &str)],
+    ) -> Result<ResponseHeader, Box<Error>> {
+        let app = DefaultApp::new(UpstreamHandler::try_from(UpstreamConf::from_yaml(conf)?)?);
+
+        let mut header = RequestHeader::build("GET", b"/", None)?;
+        header.insert_header("Host", "public.example.com")?;
+        let mut session = TestSession::from(header).await;
+        let mut ctx = app.new_ctx();
+        assert!(!app.request_filter(&mut session, &mut ctx).await?);
+
+        let mut response = ResponseHeader::build(302, Some(headers.len()))?;
+        for (name, value) in headers {
+            response.insert_header(*name, *value)?;
+        }
+        app.upstream_response_filter(&mut session, &mut response, &mut ctx);
+        Ok(response)
+    }
+
+    #[test(tokio::test)]
+    async fn rewrite_location() -> Result<(), Box<Error>> {
+        let conf = r#"
+            upstream: https://backend.internal
+            upstream_backends: {addr: 127.0.0.1:8443}
+            upstream_rewrite_location: true
+            upstream_rewrite_hosts: backend.internal:8443
+        "#;
+
+        let response = rewrite_headers(
+            conf,
+            &[
+                ("Location", "https://backend.internal/x"),
+                ("Content-Location", "HTTP://Backend.Internal:8443?a=b"),
+                ("Refresh", "5; url=https://backend.internal/y#z"),
+            ],
+        )
+        .await?;
+        assert_eq!(
+            response.headers.get("Location").unwrap(),
+            "http://public.example.com/x"
+        );
+        assert_eq!(
+            response.headers.get("Content-Location").unwrap(),
+            "http://public.example.com?a=b"
+        );
+        assert_eq!(
+            response.headers.get("Refresh").unwrap(),
+            "5; url=http://public.example.com/y#z"
+        );
+
+        // Relative URLs and other hosts are left alone
+        for location in [
+            "/x",
+            "//backend.internal/x",
+            "https://example.com/x",
+            "https://backend.internal.example.com/x",
+            "https://backend.internal:444/x",
+            "ftp://backend.internal/x",
+        ] {
+            let response = rewrite_headers(conf, &[("Location", location)]).await?;
+            assert_eq!(response.headers.get("Location").unwrap(), location);
+        }
+
+        // Configured public URL
+        let response = rewrite_headers(
+            &format!("{conf}\nupstream_public_url: https://www.example.com:8443/ignored"),
+            &[("Location", "https://backend.internal/x")],
+        )
+        .await?;
+        assert_eq!(
+            response.headers.get("Location").unwrap(),
+            "https://www.example.com:8443/x"
+        );
+
+        // No rewriting unless enabled
+        let response = rewrite_headers(
+            r#"
+                upstream: https://backend.internal
+                upstream_backends: {addr: 127.0.0.1:8443}
+            "#,
+            &[("Location", "https://backend.internal/x")],
+        )
+        .await?;
+        assert_eq!(
+            response.headers.get("Location").unwrap(),
+            "https://backend.internal/x"
+        );
+
+        // Rewriting settings require rewriting to be enabled
+        assert!(make_peer(
+            r#"
+                upstream: https://backend.internal
+                upstream_backends: {addr: 127.0.0.1:8443}
+                upstream_public_url: https://www.example.com
+            "#,
+        )
+        .is_err());
+
+        // Public URL has to be absolute
+        assert!(make_peer(&format!("{conf}\nupstream_public_url: /path")).is_err());
+
+        Ok(())
+    }
 }
@@ -0,0 +1,352 @@
+// Copyright 2024 Wladimir Palant
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! # URL Signing Module for Pandora Web Server
+//!
+//! This crate restricts access to URLs signed with a shared secret, e.g. for protected downloads
+//! with links expiring after some time. It is enabled by configuring the secret:
+//!
+//! ```yaml
+//! url_signing_secret: 0123456789abcdef
+//! ```
+//!
+//! Once enabled, all requests have to carry two query parameters:
+//!
+//! * `expires`: The time after which the link is no longer valid, as a Unix timestamp (seconds).
+//! * `sig`: Hex-encoded HMAC-SHA256 signature of the string `<path>?expires=<expires>` where
+//!   `<path>` is the request path exactly as sent by the client (percent-encoded).
+//!
+//! Requests with a missing, malformed or invalid signature as well as requests for expired links
+//! are rejected with `403 Forbidden`. Valid requests are passed on to the next handler. Other
+//! query parameters aren’t covered by the signature and are ignored.
+//!
+//! Signed links can be generated with the [`sign_path`] function, e.g. by the application
+//! handing out the links:
+//!
+//! ```rust
+//! use url_signing_module::sign_path;
+//!
+//! let url = sign_path("0123456789abcdef", "/downloads/file.zip", 1893456000);
+//! assert!(url.starts_with("/downloads/file.zip?expires=1893456000&sig="));
+//! ```
+//!
+//! Usually, only part of the webspace should be protected. This can be achieved by configuring the
+//! module for a particular subdirectory of a virtual host, see Virtual Hosts module. The signature
+//! is calculated over the original request path, before it is modified by Virtual Hosts or Rewrite
+//! modules.
+//!
+//! ## Using the module
+//!
+//! This module’s handler should be called prior to handlers producing the protected content, e.g.
+//! Static Files module:
+//!
+//! ```rust
+//! use pandora_module_utils::{merge_conf, FromYaml, RequestFilter};
+//! use startup_module::{DefaultApp, StartupConf};
+//! use static_files_module::StaticFilesHandler;
+//! use url_signing_module::UrlSigningHandler;
+//!
+//! #[derive(Debug, RequestFilter)]
+//! struct Handler {
+//!     url_signing: UrlSigningHandler,
+//!     static_files: StaticFilesHandler,
+//! }
+//!
+//! #[merge_conf]
+//! struct Conf {
+//!     startup: StartupConf,
+//!     handler: <Handler as RequestFilter>::Conf,
+//! }
+//!
+//! let conf = Conf::from_yaml(
+//!     r#"
+//!         root: .
+//!         url_signing_secret: 0123456789abcdef
+//!     "#,
+//! )
+//! .unwrap();
+//!
+//! let app = DefaultApp::<Handler>::from_conf(conf.handler)
+//!     .unwrap()
+//!     .with_startup_conf(&conf.startup);
+//! let server = conf.startup.into_server(app, None).unwrap();
+//!
+//! // Do something with the server here, e.g. call server.run_forever()
+//! ```
+
+use async_trait::async_trait;
+use hmac::{Hmac, Mac};
+use http::StatusCode;
+use log::info;
+use pandora_module_utils::pingora::{Error, ErrorType, SessionWrapper};
+use pandora_module_utils::standard_response::error_response;
+use pandora_module_utils::{DeserializeMap, RequestFilter, RequestFilterResult};
+use sha2::Sha256;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Name of the query parameter containing the expiration time
+const EXPIRES_PARAM: &str = "expires";
+
+/// Name of the query parameter containing the signature
+const SIGNATURE_PARAM: &str = "sig";
+
+/// URL signing configuration
+#[derive(Debug, Default, Clone, PartialEq, Eq, DeserializeMap)]
+pub struct UrlSigningConf {
+    /// Shared secret used to sign URLs
+    ///
+    /// If set, all requests have to carry a valid signature. If not set, signatures aren’t
+    /// checked.
+    pub url_signing_secret: Option<String>,
+}
+
+/// Creates the HMAC instance for the signature of the given path and expiration time.
+fn make_mac(secret: &str, path: &str, expires: &str) -> Hmac<Sha256> {
+    // HMAC accepts keys of any size, this won’t fail
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).unwrap();
+    mac.update(path.as_bytes());
+    mac.update(b"?");
+    mac.update(EXPIRES_PARAM.as_bytes());
+    mac.update(b"=");
+    mac.update(expires.as_bytes());
+    mac
+}
+
+/// Decodes a hex-encoded string, `None` if the string isn’t valid hex.
+fn decode_hex(data: &str) -> Option<Vec<u8>> {
+    if data.len() % 2 != 0 || !data.bytes().all(|byte| byte.is_ascii_hexdigit()) {
+        return None;
+    }
+    (0..data.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&data[i..i + 2], 16).ok())
+        .collect()
+}
+
+/// Checks the signature of the request, returns a description of the problem if the request
+/// should be rejected.
+fn check_signature(secret: &str, path: &str, query: Option<&str>) -> Result<(), &'static str> {
+    let mut expires = None;
+    let mut signature = None;
+    for param in query.unwrap_or("").split('&') {
+        let (name, value) = param.split_once('=').unwrap_or((param, ""));
+        let target = match name {
+            EXPIRES_PARAM => &mut expires,
+            SIGNATURE_PARAM => &mut signature,
+            _ => continue,
+        };
+        if target.replace(value).is_some() {
+            return Err("duplicate signature parameters");
+        }
+    }
+
+    let (expires, signature) = match (expires, signature) {
+        (Some(expires), Some(signature)) => (expires, signature),
+        _ => return Err("missing signature parameters"),
+    };
+
+    let signature = decode_hex(signature).ok_or("malformed signature")?;
+    // This compares signatures in constant time
+    make_mac(secret, path, expires)
+        .verify_slice(&signature)
+        .map_err(|_| "invalid signature")?;
+
+    // Only check expiration time for properly signed requests
+    let expires = expires
+        .parse::<u64>()
+        .map_err(|_| "malformed expiration time")?;
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |duration| duration.as_secs());
+    if now > expires {
+        return Err("link expired");
+    }
+
+    Ok(())
+}
+
+/// Produces a signed URL for the given path that will be valid until the `expires` timestamp
+/// (seconds since Unix epoch).
+///
+/// The path is expected to be percent-encoded already, the signature is calculated over it as is.
+/// The result is the path with `expires` and `sig` query parameters added.
+pub fn sign_path(secret: &str, path: &str, expires: u64) -> String {
+    let expires = expires.to_string();
+    let signature = make_mac(secret, path, &expires)
+        .finalize()
+        .into_bytes()
+        .iter()
+        .flat_map(|byte| [byte >> 4, byte & 0xF])
+        .filter_map(|digit| char::from_digit(digit.into(), 16))
+        .collect::<String>();
+    format!("{path}?{EXPIRES_PARAM}={expires}&{SIGNATURE_PARAM}={signature}")
+}
+
+/// Handler for Pingora’s `request_filter` phase
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UrlSigningHandler {
+    conf: UrlSigningConf,
+}
+
+impl TryFrom<UrlSigningConf> for UrlSigningHandler {
+    type Error = Box<Error>;
+
+    fn try_from(conf: UrlSigningConf) -> Result<Self, Self::Error> {
+        if conf.url_signing_secret.as_deref() == Some("") {
+            return Err(Error::explain(
+                ErrorType::InternalError,
+                "url_signing_secret setting cannot be empty",
+            ));
+        }
+        Ok(Self { conf })
+    }
+}
+
+#[async_trait]
+impl RequestFilter for UrlSigningHandler {
+    type Conf = UrlSigningConf;
+
+    type CTX = ();
+
+    fn new_ctx() -> Self::CTX {}
+
+    async fn request_filter(
+        &self,
+        session: &mut impl SessionWrapper,
+        _ctx: &mut Self::CTX,
+    ) -> Result<RequestFilterResult, Box<Error>> {
+        let secret = if let Some(secret) = &self.conf.url_signing_secret {
+            secret
+        } else {
+            return Ok(RequestFilterResult::Unhandled);
+        };
+
+        let uri = session.original_uri();
+        if let Err(reason) = check_signature(secret, uri.path(), uri.query()) {
+            info!("Rejecting request to {uri}, {reason}");
+            error_response(session, StatusCode::FORBIDDEN).await?;
+            return Ok(RequestFilterResult::ResponseSent);
+        }
+
+        Ok(RequestFilterResult::Unhandled)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use pandora_module_utils::pingora::{RequestHeader, TestSession};
+    use pandora_module_utils::FromYaml;
+    use test_log::test;
+
+    const SECRET: &str = "0123456789abcdef";
+
+    /// Expiration time far in the future, 2100-01-01
+    const FUTURE: u64 = 4102444800;
+
+    fn make_handler(conf: &str) -> UrlSigningHandler {
+        UrlSigningConf::from_yaml(conf).unwrap().try_into().unwrap()
+    }
+
+    async fn status(handler: &UrlSigningHandler, uri: &str) -> Result<Option<u16>, Box<Error>> {
+        let header = RequestHeader::build("GET", uri.as_bytes(), None)?;
+        let mut session = TestSession::from(header).await;
+        match handler.request_filter(&mut session, &mut ()).await? {
+            RequestFilterResult::ResponseSent => {
+                Ok(Some(session.response_written().unwrap().status.as_u16()))
+            }
+            _ => Ok(None),
+        }
+    }
+
+    #[test(tokio::test)]
+    async fn unconfigured() -> Result<(), Box<Error>> {
+        let handler = make_handler("{}");
+        assert_eq!(status(&handler, "/file.zip").await?, None);
+        Ok(())
+    }
+
+    #[test]
+    fn empty_secret() {
+        let conf = UrlSigningConf::from_yaml("url_signing_secret: ''").unwrap();
+        assert!(UrlSigningHandler::try_from(conf).is_err());
+    }
+
+    #[test(tokio::test)]
+    async fn valid_signature() -> Result<(), Box<Error>> {
+        let handler = make_handler("url_signing_secret: 0123456789abcdef");
+
+        let url = sign_path(SECRET, "/file.zip", FUTURE);
+        assert_eq!(status(&handler, &url).await?, None);
+
+        // Additional query parameters are ignored
+        let url = format!(
+            "{}&download=1",
+            sign_path(SECRET, "/dir/file%20name.zip", FUTURE)
+        );
+        assert_eq!(status(&handler, &url).await?, None);
+
+        // Upper-case hex is accepted as well
+        let url = sign_path(SECRET, "/file.zip", FUTURE);
+        let (path, signature) = url.split_once("&sig=").unwrap();
+        let url = format!("{path}&sig={}", signature.to_uppercase());
+        assert_eq!(status(&handler, &url).await?, None);
+
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn expired_link() -> Result<(), Box<Error>> {
+        let handler = make_handler("url_signing_secret: 0123456789abcdef");
+
+        let url = sign_path(SECRET, "/file.zip", 1);
+        assert_eq!(status(&handler, &url).await?, Some(403));
+
+        // Extending the expiration time invalidates the signature
+        let url = url.replace("expires=1&", &format!("expires={FUTURE}&"));
+        assert_eq!(status(&handler, &url).await?, Some(403));
+
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn tampered_path() -> Result<(), Box<Error>> {
+        let handler = make_handler("url_signing_secret: 0123456789abcdef");
+
+        let url = sign_path(SECRET, "/file.zip", FUTURE);
+        let url = url.replace("/file.zip", "/other.zip");
+        assert_eq!(status(&handler, &url).await?, Some(403));
+
+        // Signature with a different secret
+        let url = sign_path("another secret", "/file.zip", FUTURE);
+        assert_eq!(status(&handler, &url).await?, Some(403));
+
+        // Missing, malformed or duplicate parameters
+        let url = sign_path(SECRET, "/file.zip", FUTURE);
+        let (path, signature) = url.split_once("&sig=").unwrap();
+        for url in [
+            "/file.zip".to_owned(),
+            path.to_owned(),
+            format!("/file.zip?sig={signature}"),
+            format!("{path}&sig={}", &signature[1..]),
+            format!("{path}&sig=xyz"),
+            format!("{url}&sig={signature}"),
+        ] {
+            assert_eq!(status(&handler, &url).await?, Some(403), "{url}");
+        }
+
+        Ok(())
+    }
+}
@@ -12,8 +12,10 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use pandora_module_utils::pingora::{Error, ErrorType};
 use pandora_module_utils::serde::Deserialize;
-use pandora_module_utils::{DeserializeMap, OneOrMany};
+use pandora_module_utils::{AccessLogConf, DeserializeMap, OneOrMany};
+use startup_module::CertKeyConf;
 use std::collections::HashMap;
 
 /// Determines which paths a configuration should apply to
@@ -64,13 +66,25 @@ pub struct SubPathConf<C: Default> {
 }
 
 /// Virtual host configuration
+///
+/// A string value can be given instead of the full configuration, it will be used as the `root`
+/// setting of the wrapped handler.
 #[derive(Debug, Default, Clone, PartialEq, Eq, DeserializeMap)]
+#[pandora(shorthand = "root")]
 pub struct VirtualHostConf<C: Default> {
     /// List of additional names for the virtual host
     pub aliases: OneOrMany<String>,
     /// If true, this virtual host should be used as fallback when no other virtual host
     /// configuration applies
     pub default: bool,
+    /// If true, requests for any of the aliases will be redirected to the virtual host's main
+    /// name with a `301 Moved Permanently` response instead of being handled
+    pub canonical_host: bool,
+    /// Access log settings for this virtual host, e.g. `{enabled: false}` to exclude its requests
+    /// from the access log
+    pub access_log: AccessLogConf,
+    /// TLS certificate/key combinations for the host name and its aliases
+    pub tls: OneOrMany<CertKeyConf>,
     /// Maps virtual host's paths to their special configurations
     pub subpaths: HashMap<PathMatchRule, SubPathConf<C>>,
     /// Generic handler settings
@@ -81,9 +95,141 @@ pub struct VirtualHostConf<C: Default> {
     pub config: C,
 }
 
+impl<C: Default> VirtualHostConf<C> {
+    /// Creates a virtual host configuration with the given handler settings.
+    pub fn new(config: C) -> Self {
+        Self {
+            config,
+            ..Default::default()
+        }
+    }
+
+    /// Adds an additional name for the virtual host.
+    pub fn with_alias(mut self, alias: impl Into<String>) -> Self {
+        self.aliases.push(alias.into());
+        self
+    }
+
+    /// Sets the `default` flag, making this virtual host the fallback when no other virtual host
+    /// configuration applies.
+    pub fn with_default(mut self, default: bool) -> Self {
+        self.default = default;
+        self
+    }
+
+    /// Sets the `canonical_host` flag, redirecting requests for aliases to the main name.
+    pub fn with_canonical_host(mut self, canonical_host: bool) -> Self {
+        self.canonical_host = canonical_host;
+        self
+    }
+
+    /// Sets the access log settings for this virtual host.
+    pub fn with_access_log(mut self, access_log: AccessLogConf) -> Self {
+        self.access_log = access_log;
+        self
+    }
+
+    /// Adds a TLS certificate/key combination for the host name and its aliases.
+    pub fn with_certificate(mut self, cert_key: CertKeyConf) -> Self {
+        self.tls.push(cert_key);
+        self
+    }
+
+    /// Adds a subpath configuration. A path ending with `/*` will also match any files within the
+    /// subdirectory, same as in the configuration file.
+    pub fn with_subpath(
+        mut self,
+        path: impl Into<PathMatchRule>,
+        strip_prefix: bool,
+        config: C,
+    ) -> Self {
+        self.subpaths.insert(
+            path.into(),
+            SubPathConf {
+                strip_prefix,
+                config,
+            },
+        );
+        self
+    }
+}
+
 /// Virtual hosts configuration
 #[derive(Debug, Default, Clone, PartialEq, Eq, DeserializeMap)]
 pub struct VirtualHostsConf<C: Default> {
     /// Maps virtual host names to their configuration
     pub vhosts: HashMap<String, VirtualHostConf<C>>,
+    /// If `true`, an `X-Matched-Vhost` response header will name the virtual host that handled
+    /// the request: `default` for the fallback host, `none` if no host matched.
+    pub debug_host_header: bool,
+    /// If `true`, repeated slashes in the request path will be collapsed and `.` and `..` path
+    /// segments resolved before routing, e.g. `//dir/./subdir/../file` becomes `/dir/file`.
+    pub normalize_path: bool,
+}
+
+impl<C: Default> VirtualHostsConf<C> {
+    /// Adds a virtual host configuration, replacing any existing configuration for this host name.
+    ///
+    /// Together with the builder methods of [`VirtualHostConf`], this allows setting up virtual
+    /// hosts in code rather than via a configuration file.
+    pub fn with_host(mut self, host: impl Into<String>, conf: VirtualHostConf<C>) -> Self {
+        self.vhosts.insert(host.into(), conf);
+        self
+    }
+
+    /// Sets the `debug_host_header` flag.
+    pub fn with_debug_host_header(mut self, debug_host_header: bool) -> Self {
+        self.debug_host_header = debug_host_header;
+        self
+    }
+
+    /// Sets the `normalize_path` flag.
+    pub fn with_normalize_path(mut self, normalize_path: bool) -> Self {
+        self.normalize_path = normalize_path;
+        self
+    }
+
+    /// Collects the TLS certificates declared for virtual hosts, keyed by server name.
+    ///
+    /// Host names and aliases are listed with the port number removed. The result is meant to be
+    /// passed to [`TlsConf::add_server_names`](startup_module::TlsConf::add_server_names). Fails
+    /// if different certificates are declared for the same server name.
+    pub fn tls_server_names(&self) -> Result<HashMap<String, OneOrMany<CertKeyConf>>, Box<Error>> {
+        let mut result: HashMap<String, OneOrMany<CertKeyConf>> = HashMap::new();
+        for (host, host_conf) in &self.vhosts {
+            if host_conf.tls.is_empty() {
+                continue;
+            }
+
+            for name in std::iter::once(host).chain(host_conf.aliases.iter()) {
+                let name = server_name(name);
+                match result.get(name) {
+                    Some(existing) if *existing != host_conf.tls => {
+                        return Err(Error::explain(
+                            ErrorType::InternalError,
+                            format!("conflicting TLS certificates declared for server name {name}"),
+                        ));
+                    }
+                    Some(_) => {}
+                    None => {
+                        result.insert(name.to_owned(), host_conf.tls.clone());
+                    }
+                }
+            }
+        }
+        Ok(result)
+    }
+}
+
+/// Removes the port number from a host name, leaving the name to be matched against SNI.
+fn server_name(host: &str) -> &str {
+    match host.rsplit_once(':') {
+        Some((name, port))
+            if port.bytes().all(|b| b.is_ascii_digit())
+                && (!name.contains(':') || name.ends_with(']')) =>
+        {
+            name
+        }
+        _ => host,
+    }
 }
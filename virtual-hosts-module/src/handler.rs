@@ -13,11 +13,14 @@
 // limitations under the License.
 
 use async_trait::async_trait;
-use http::uri::Uri;
-use log::warn;
-use pandora_module_utils::pingora::{Error, HttpPeer, ResponseHeader, SessionWrapper};
+use http::{status::StatusCode, uri::Uri};
+use log::{debug, warn};
+use pandora_module_utils::pingora::{
+    Bytes, Error, HttpPeer, RequestHeader, ResponseHeader, SessionWrapper,
+};
 use pandora_module_utils::router::{Path, Router};
-use pandora_module_utils::{RequestFilter, RequestFilterResult};
+use pandora_module_utils::standard_response::redirect_response;
+use pandora_module_utils::{AccessLogConf, RequestFilter, RequestFilterResult};
 use std::collections::BTreeSet;
 use std::fmt::Debug;
 use std::ops::{Deref, DerefMut};
@@ -39,6 +42,36 @@ fn set_uri_path(uri: &Uri, path: &[u8]) -> Uri {
     parts.try_into().unwrap_or_else(|_| uri.clone())
 }
 
+/// Collapses repeated slashes and resolves `.` and `..` segments in a URI path. A trailing slash
+/// is preserved, `..` segments cannot go beyond the root.
+fn normalize_path(path: &str) -> String {
+    let mut segments = Vec::new();
+    let mut trailing_slash = false;
+    for segment in path.split('/') {
+        trailing_slash = true;
+        match segment {
+            "" | "." => {}
+            ".." => {
+                segments.pop();
+            }
+            segment => {
+                segments.push(segment);
+                trailing_slash = false;
+            }
+        }
+    }
+
+    let mut result = String::with_capacity(path.len());
+    for segment in segments {
+        result.push('/');
+        result.push_str(segment);
+    }
+    if trailing_slash || result.is_empty() {
+        result.push('/');
+    }
+    result
+}
+
 /// Context for the virtual hosts handler
 #[derive(Debug)]
 pub struct VirtualHostsCtx<Ctx> {
@@ -60,13 +93,56 @@ impl<Ctx> DerefMut for VirtualHostsCtx<Ctx> {
     }
 }
 
+/// Name reported in the `X-Matched-Vhost` header for requests handled by the default host
+/// because no other host matched
+const DEFAULT_HOST_NAME: &str = "default";
+
+/// Name reported in the `X-Matched-Vhost` header for requests without a matching host
+const NO_HOST_NAME: &str = "none";
+
+/// Routing table entry
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Route<H> {
+    /// Subpath matched by this route, `None` for host-level routes
+    prefix: Option<String>,
+    /// Path to be removed from the URI if `strip_prefix` is set
+    strip_path: Option<Path>,
+    /// Host name to be reported in the `X-Matched-Vhost` header
+    host: String,
+    /// Canonical host name to redirect to if the request was made for an alias
+    redirect: Option<String>,
+    /// Access log settings to be stored in session extensions, `None` for default settings
+    access_log: Option<AccessLogConf>,
+    handler: H,
+}
+
 /// Handler for Pingora’s `request_filter` phase
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct VirtualHostsHandler<H: Debug> {
-    handlers: Router<(Option<Path>, H)>,
+    handlers: Router<Route<H>>,
+    host_count: usize,
+    route_count: usize,
+    has_default: bool,
+    debug_host_header: bool,
+    normalize_path: bool,
 }
 
 impl<H: Debug> VirtualHostsHandler<H> {
+    /// Returns the number of configured virtual hosts, aliases not counted.
+    pub fn host_count(&self) -> usize {
+        self.host_count
+    }
+
+    /// Returns the number of configured subpath routes across all virtual hosts.
+    pub fn route_count(&self) -> usize {
+        self.route_count
+    }
+
+    /// Returns `true` if one of the virtual hosts is marked as default.
+    pub fn has_default(&self) -> bool {
+        self.has_default
+    }
+
     /// Retrieves the handler which was previously called for this virtual host.
     ///
     /// This will return `None` if the `request_filter` handler wasn’t called for this context yet
@@ -77,7 +153,24 @@ impl<H: Debug> VirtualHostsHandler<H> {
         H::Conf: Default,
         H::CTX: Send,
     {
-        self.handlers.retrieve(ctx.index?).map(|(_, h)| h)
+        self.handlers
+            .retrieve(ctx.index?)
+            .map(|route| &route.handler)
+    }
+
+    /// Retrieves the subpath that the request was routed by, e.g. `/subdir`.
+    ///
+    /// This will return `None` if the `request_filter` handler wasn’t called for this context yet,
+    /// it didn’t find a matching handler or the request was matched by the host configuration
+    /// rather than one of its subpaths. The prefix is returned regardless of the `strip_prefix`
+    /// setting.
+    pub fn matched_prefix(&self, ctx: &<Self as RequestFilter>::CTX) -> Option<&str>
+    where
+        H: RequestFilter + Sync,
+        H::Conf: Default,
+        H::CTX: Send,
+    {
+        self.handlers.retrieve(ctx.index?)?.prefix.as_deref()
     }
 }
 
@@ -107,13 +200,46 @@ where
         session: &mut impl SessionWrapper,
         ctx: &mut Self::CTX,
     ) -> Result<RequestFilterResult, Box<Error>> {
+        if self.normalize_path {
+            let path = session.uri().path();
+            if path.starts_with('/') {
+                let normalized = normalize_path(path);
+                if normalized != path {
+                    debug!("normalized request path {path} into {normalized}");
+                    session.set_uri(set_uri_path(session.uri(), normalized.as_bytes()));
+                }
+            }
+        }
+
         let path = session.uri().path();
         let host = session.host().unwrap_or_default();
 
         if let Some(result) = self.handlers.lookup(host.as_ref(), &path) {
-            let (strip_path, handler) = result.as_value();
+            let route = result.as_value();
             let index = result.index();
-            let new_path = strip_path.as_ref().and_then(|p| p.remove_prefix_from(path));
+
+            if let Some(access_log) = &route.access_log {
+                session.extensions_mut().insert(access_log.clone());
+            }
+
+            if let Some(canonical) = &route.redirect {
+                let location = format!(
+                    "{}://{canonical}{}",
+                    session.scheme(false),
+                    session
+                        .original_uri()
+                        .path_and_query()
+                        .map_or("/", |path_and_query| path_and_query.as_str())
+                );
+                debug!("redirecting request for alias {host} to canonical host: {location}");
+                redirect_response(session, StatusCode::MOVED_PERMANENTLY, &location).await?;
+                return Ok(RequestFilterResult::ResponseSent);
+            }
+
+            let new_path = route
+                .strip_path
+                .as_ref()
+                .and_then(|p| p.remove_prefix_from(path));
 
             ctx.index = Some(index);
 
@@ -123,7 +249,7 @@ where
             if let Some(new_path) = new_path {
                 session.set_uri(set_uri_path(session.uri(), &new_path));
             }
-            handler.request_filter(session, ctx).await
+            route.handler.request_filter(session, ctx).await
         } else {
             Ok(RequestFilterResult::Unhandled)
         }
@@ -141,20 +267,56 @@ where
         }
     }
 
+    async fn upstream_request_filter(
+        &self,
+        session: &mut impl SessionWrapper,
+        upstream_request: &mut RequestHeader,
+        ctx: &mut Self::CTX,
+    ) -> Result<(), Box<Error>> {
+        if let Some(handler) = self.as_inner(ctx) {
+            handler
+                .upstream_request_filter(session, upstream_request, ctx)
+                .await
+        } else {
+            Ok(())
+        }
+    }
+
     fn response_filter(
         &self,
         session: &mut impl SessionWrapper,
         response: &mut ResponseHeader,
         ctx: Option<&mut Self::CTX>,
     ) {
-        let handler = ctx
+        let route = ctx
             .as_ref()
             .and_then(|ctx| ctx.index)
             .or_else(|| session.extensions().get::<IndexEntry>().map(|i| i.0))
-            .and_then(|index| self.handlers.retrieve(index))
-            .map(|(_, h)| h);
-        if let Some(handler) = handler {
-            handler.response_filter(session, response, ctx.map(|ctx| ctx.deref_mut()));
+            .and_then(|index| self.handlers.retrieve(index));
+
+        if self.debug_host_header {
+            let name = route.map_or(NO_HOST_NAME, |route| route.host.as_str());
+            if let Err(err) = response.insert_header("X-Matched-Vhost", name) {
+                warn!("failed adding X-Matched-Vhost header: {err}");
+            }
+        }
+
+        if let Some(route) = route {
+            route
+                .handler
+                .response_filter(session, response, ctx.map(|ctx| ctx.deref_mut()));
+        }
+    }
+
+    fn response_body_filter(
+        &self,
+        session: &mut impl SessionWrapper,
+        body: &mut Option<Bytes>,
+        end_of_stream: bool,
+        ctx: &mut Self::CTX,
+    ) {
+        if let Some(handler) = self.as_inner(ctx) {
+            handler.response_body_filter(session, body, end_of_stream, ctx);
         }
     }
 
@@ -180,11 +342,15 @@ where
     fn try_from(conf: VirtualHostsConf<C>) -> Result<Self, Box<Error>> {
         let mut handlers = Router::builder();
         let mut default = None;
+        let mut host_count = 0;
+        let mut route_count = 0;
         for (host, host_conf) in conf.vhosts.into_iter() {
             if host.is_empty() {
                 warn!("ignoring empty host name in virtual hosts configuration, please use `default` setting instead");
                 continue;
             }
+            host_count += 1;
+            route_count += host_conf.subpaths.len();
 
             let mut aliases = BTreeSet::new();
             for alias in host_conf.aliases {
@@ -203,16 +369,41 @@ where
                 }
             }
 
-            let handler = host_conf.config.try_into()?;
+            // Requests only ending up here because no other host matched are reported as handled
+            // by the default host
+            let name_for = |alias: &str| {
+                if alias.is_empty() {
+                    DEFAULT_HOST_NAME.to_owned()
+                } else {
+                    host.clone()
+                }
+            };
+
+            // Requests for aliases are redirected if requested, the default host doesn't count as
+            // an alias
+            let redirect_for =
+                |alias: &str| (host_conf.canonical_host && !alias.is_empty()).then(|| host.clone());
+
+            let access_log =
+                Some(host_conf.access_log).filter(|access_log| *access_log != Default::default());
+
+            let route = Route {
+                prefix: None,
+                strip_path: None,
+                host: host.clone(),
+                redirect: None,
+                access_log: access_log.clone(),
+                handler: host_conf.config.try_into()?,
+            };
             for alias in &aliases {
-                handlers.push(
-                    alias,
-                    "",
-                    (None, handler.clone()),
-                    Some((None, handler.clone())),
-                );
+                let route = Route {
+                    host: name_for(alias),
+                    redirect: redirect_for(alias),
+                    ..route.clone()
+                };
+                handlers.push(alias, "", route.clone(), Some(route));
             }
-            handlers.push(&host, "", (None, handler.clone()), Some((None, handler)));
+            handlers.push(&host, "", route.clone(), Some(route));
 
             let mut subpaths = host_conf.subpaths.into_iter().collect::<Vec<_>>();
 
@@ -222,36 +413,50 @@ where
             subpaths.sort_by_key(|(rule, _)| rule.exact);
 
             for (rule, conf) in subpaths {
-                let handler = conf.config.try_into()?;
-                let strip_path = if conf.strip_prefix {
-                    Some(Path::new(&rule.path))
-                } else {
-                    None
+                let route = Route {
+                    prefix: Some(rule.path.clone()),
+                    strip_path: if conf.strip_prefix {
+                        Some(Path::new(&rule.path))
+                    } else {
+                        None
+                    },
+                    host: host.clone(),
+                    redirect: None,
+                    access_log: access_log.clone(),
+                    handler: conf.config.try_into()?,
                 };
                 for alias in &aliases {
-                    handlers.push(
-                        alias,
-                        &rule.path,
-                        (strip_path.clone(), handler.clone()),
-                        if rule.exact {
-                            None
-                        } else {
-                            Some((strip_path.clone(), handler.clone()))
-                        },
-                    );
+                    let route = Route {
+                        host: name_for(alias),
+                        redirect: redirect_for(alias),
+                        ..route.clone()
+                    };
+                    let route_prefix = if rule.exact {
+                        None
+                    } else {
+                        Some(route.clone())
+                    };
+                    handlers.push(alias, &rule.path, route, route_prefix);
                 }
 
-                let handler_prefix = if rule.exact {
+                let route_prefix = if rule.exact {
                     None
                 } else {
-                    Some((strip_path.clone(), handler.clone()))
+                    Some(route.clone())
                 };
-                handlers.push(&host, &rule.path, (strip_path, handler), handler_prefix);
+                handlers.push(&host, &rule.path, route, route_prefix);
             }
         }
         let handlers = handlers.build();
 
-        Ok(Self { handlers })
+        Ok(Self {
+            handlers,
+            host_count,
+            route_count,
+            has_default: default.is_some(),
+            debug_host_header: conf.debug_host_header,
+            normalize_path: conf.normalize_path,
+        })
     }
 }
 
@@ -259,6 +464,7 @@ where
 mod tests {
     use super::*;
 
+    use crate::configuration::VirtualHostConf;
     use pandora_module_utils::pingora::{RequestHeader, TestSession};
     use pandora_module_utils::{DeserializeMap, FromYaml};
     use test_log::test;
@@ -348,6 +554,87 @@ mod tests {
         session
     }
 
+    #[test(tokio::test)]
+    async fn built_in_code() -> Result<(), Box<Error>> {
+        let conf = |result| Conf { result };
+        let built = VirtualHostsConf::default()
+            .with_host(
+                "localhost:8080",
+                VirtualHostConf::new(conf(RequestFilterResult::ResponseSent))
+                    .with_alias("127.0.0.1:8080")
+                    .with_alias("[::1]:8080")
+                    .with_default(true)
+                    .with_subpath("/subdir/*", true, conf(RequestFilterResult::Unhandled))
+                    .with_subpath(
+                        "/subdir/file.txt",
+                        false,
+                        conf(RequestFilterResult::ResponseSent),
+                    )
+                    .with_subpath(
+                        "/subdir/subsub/*",
+                        false,
+                        conf(RequestFilterResult::Handled),
+                    ),
+            )
+            .with_host(
+                "example.com",
+                VirtualHostConf::new(conf(RequestFilterResult::Handled))
+                    .with_alias("example.com:8080"),
+            );
+
+        let (yaml_handler, _) = handler(true);
+        let handler: VirtualHostsHandler<Handler> = built.try_into()?;
+        assert_eq!(handler.host_count(), yaml_handler.host_count());
+        assert_eq!(handler.route_count(), yaml_handler.route_count());
+        assert!(handler.has_default());
+
+        for (uri, host, expected) in [
+            ("/", "example.com:8080", RequestFilterResult::Handled),
+            ("/", "[::1]:8080", RequestFilterResult::ResponseSent),
+            ("/", "unknown", RequestFilterResult::ResponseSent),
+            ("/subdir/", "localhost:8080", RequestFilterResult::Unhandled),
+            (
+                "/subdir/file.txt",
+                "localhost:8080",
+                RequestFilterResult::ResponseSent,
+            ),
+            (
+                "/subdir/subsub/file.txt",
+                "127.0.0.1:8080",
+                RequestFilterResult::Handled,
+            ),
+        ] {
+            let mut ctx = VirtualHostsHandler::<Handler>::new_ctx();
+            let mut session = make_session(uri, Some(host)).await;
+            assert_eq!(
+                handler.request_filter(&mut session, &mut ctx).await?,
+                expected,
+                "{host}{uri}"
+            );
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn counts() {
+        let (with_default, _) = handler(true);
+        assert_eq!(with_default.host_count(), 2);
+        assert_eq!(with_default.route_count(), 3);
+        assert!(with_default.has_default());
+
+        let (without_default, _) = handler(false);
+        assert_eq!(without_default.host_count(), 2);
+        assert_eq!(without_default.route_count(), 3);
+        assert!(!without_default.has_default());
+
+        let handler: VirtualHostsHandler<Handler> =
+            VirtualHostsConf::<Conf>::default().try_into().unwrap();
+        assert_eq!(handler.host_count(), 0);
+        assert_eq!(handler.route_count(), 0);
+        assert!(!handler.has_default());
+    }
+
     #[test(tokio::test)]
     async fn host_match() -> Result<(), Box<Error>> {
         let (handler, mut ctx) = handler(true);
@@ -477,6 +764,66 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn normalize_paths() {
+        assert_eq!(normalize_path("/"), "/");
+        assert_eq!(normalize_path("//"), "/");
+        assert_eq!(normalize_path("/file.txt"), "/file.txt");
+        assert_eq!(normalize_path("//foo///bar"), "/foo/bar");
+        assert_eq!(normalize_path("//foo///bar//"), "/foo/bar/");
+        assert_eq!(normalize_path("/foo/./bar/."), "/foo/bar/");
+        assert_eq!(normalize_path("/foo/../bar"), "/bar");
+        assert_eq!(normalize_path("/foo/bar/.."), "/foo/");
+        assert_eq!(normalize_path("/../../foo"), "/foo");
+        assert_eq!(normalize_path("/foo/.."), "/");
+        assert_eq!(normalize_path("/foo/..bar/.baz"), "/foo/..bar/.baz");
+    }
+
+    #[test(tokio::test)]
+    async fn normalized_path() -> Result<(), Box<Error>> {
+        let handler: VirtualHostsHandler<Handler> = VirtualHostsConf::<Conf>::from_yaml(
+            r#"
+                normalize_path: true
+                vhosts:
+                    localhost:8080:
+                        result: ResponseSent
+                        subpaths:
+                            /subdir/*:
+                                strip_prefix: true
+                                result: Unhandled
+            "#,
+        )?
+        .try_into()?;
+
+        let mut ctx = VirtualHostsHandler::<Handler>::new_ctx();
+        let mut session = make_session("//subdir///xyz//", Some("localhost:8080")).await;
+        assert_eq!(
+            handler.request_filter(&mut session, &mut ctx).await?,
+            RequestFilterResult::Unhandled
+        );
+        assert_eq!(session.uri(), "/xyz/");
+        assert_eq!(session.original_uri(), "//subdir///xyz//");
+
+        let mut ctx = VirtualHostsHandler::<Handler>::new_ctx();
+        let mut session =
+            make_session("/other/./../subdir/a/../xyz?query", Some("localhost:8080")).await;
+        assert_eq!(
+            handler.request_filter(&mut session, &mut ctx).await?,
+            RequestFilterResult::Unhandled
+        );
+        assert_eq!(session.uri(), "/xyz?query");
+
+        let mut ctx = VirtualHostsHandler::<Handler>::new_ctx();
+        let mut session = make_session("/subdir/..//file.txt", Some("localhost:8080")).await;
+        assert_eq!(
+            handler.request_filter(&mut session, &mut ctx).await?,
+            RequestFilterResult::ResponseSent
+        );
+        assert_eq!(session.uri(), "/file.txt");
+
+        Ok(())
+    }
+
     #[test(tokio::test)]
     async fn subdir_no_match() -> Result<(), Box<Error>> {
         let (handler, mut ctx) = handler(true);
@@ -565,4 +912,543 @@ mod tests {
         assert_eq!(session.original_uri(), "/subdir/file.txt/xyz");
         Ok(())
     }
+
+    #[test(tokio::test)]
+    async fn default_subdir_strip_prefix() -> Result<(), Box<Error>> {
+        let handler: VirtualHostsHandler<Handler> = VirtualHostsConf::<Conf>::from_yaml(
+            r#"
+                vhosts:
+                    localhost:
+                        default: true
+                        result: ResponseSent
+                        subpaths:
+                            /app/*:
+                                strip_prefix: true
+                                result: Handled
+                    example.com:
+                        result: Unhandled
+            "#,
+        )
+        .unwrap()
+        .try_into()
+        .unwrap();
+
+        let mut ctx = VirtualHostsHandler::<Handler>::new_ctx();
+        let mut session = make_session("/app/xyz?abc", Some("unknown")).await;
+        assert_eq!(
+            handler.request_filter(&mut session, &mut ctx).await?,
+            RequestFilterResult::Handled
+        );
+        assert_eq!(session.uri(), "/xyz?abc");
+        assert_eq!(session.original_uri(), "/app/xyz?abc");
+
+        // Subsequent phases should see the same handler as with an explicit host match
+        assert_eq!(
+            handler.as_inner(&ctx),
+            Some(&Handler {
+                result: RequestFilterResult::Handled
+            })
+        );
+
+        let mut explicit_ctx = VirtualHostsHandler::<Handler>::new_ctx();
+        let mut session = make_session("/app/xyz?abc", Some("localhost")).await;
+        assert_eq!(
+            handler
+                .request_filter(&mut session, &mut explicit_ctx)
+                .await?,
+            RequestFilterResult::Handled
+        );
+        assert_eq!(session.uri(), "/xyz?abc");
+        assert_eq!(handler.as_inner(&explicit_ctx), handler.as_inner(&ctx));
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn debug_host_header() -> Result<(), Box<Error>> {
+        async fn matched_vhost(
+            handler: &VirtualHostsHandler<Handler>,
+            host: &str,
+        ) -> Result<Option<String>, Box<Error>> {
+            let mut ctx = VirtualHostsHandler::<Handler>::new_ctx();
+            let mut session = make_session("/subdir/xyz", Some(host)).await;
+            handler.request_filter(&mut session, &mut ctx).await?;
+
+            let mut response = ResponseHeader::build(200, None)?;
+            handler.response_filter(&mut session, &mut response, Some(&mut ctx));
+            Ok(response
+                .headers
+                .get("X-Matched-Vhost")
+                .map(|value| value.to_str().unwrap().to_owned()))
+        }
+
+        // Header is off by default
+        let (handler, _) = handler(true);
+        assert_eq!(matched_vhost(&handler, "example.com").await?, None);
+        assert_eq!(matched_vhost(&handler, "example.net").await?, None);
+
+        let conf = r#"
+            debug_host_header: true
+            vhosts:
+                localhost:8080:
+                    aliases: ["127.0.0.1:8080"]
+                    default: true
+                    result: ResponseSent
+                    subpaths:
+                        /subdir/*:
+                            result: Unhandled
+                example.com:
+                    result: Handled
+        "#;
+        let handler: VirtualHostsHandler<Handler> =
+            VirtualHostsConf::<Conf>::from_yaml(conf)?.try_into()?;
+        assert_eq!(
+            matched_vhost(&handler, "example.com").await?,
+            Some("example.com".to_owned())
+        );
+        assert_eq!(
+            matched_vhost(&handler, "127.0.0.1:8080").await?,
+            Some("localhost:8080".to_owned())
+        );
+        assert_eq!(
+            matched_vhost(&handler, "localhost:8080").await?,
+            Some("localhost:8080".to_owned())
+        );
+        assert_eq!(
+            matched_vhost(&handler, "example.net").await?,
+            Some("default".to_owned())
+        );
+
+        let handler: VirtualHostsHandler<Handler> =
+            VirtualHostsConf::<Conf>::from_yaml(conf.replace("default: true", "default: false"))?
+                .try_into()?;
+        assert_eq!(
+            matched_vhost(&handler, "example.net").await?,
+            Some("none".to_owned())
+        );
+
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn canonical_host() -> Result<(), Box<Error>> {
+        let handler: VirtualHostsHandler<Handler> = VirtualHostsConf::<Conf>::from_yaml(
+            r#"
+                vhosts:
+                    example.com:
+                        aliases: [www.example.com]
+                        canonical_host: true
+                        default: true
+                        result: Handled
+                        subpaths:
+                            /subdir/*:
+                                strip_prefix: true
+                                result: Unhandled
+                    localhost:
+                        aliases: [127.0.0.1]
+                        result: Handled
+            "#,
+        )?
+        .try_into()?;
+
+        // Alias is redirected, including subpaths
+        for uri in ["/file.txt?abc", "/subdir/xyz"] {
+            let mut ctx = VirtualHostsHandler::<Handler>::new_ctx();
+            let mut session = make_session(uri, Some("www.example.com")).await;
+            assert_eq!(
+                handler.request_filter(&mut session, &mut ctx).await?,
+                RequestFilterResult::ResponseSent
+            );
+            let response = session.response_written().unwrap();
+            assert_eq!(response.status, StatusCode::MOVED_PERMANENTLY);
+            assert_eq!(
+                response.headers["Location"],
+                format!("http://example.com{uri}")
+            );
+        }
+
+        // Canonical host passes through
+        let mut ctx = VirtualHostsHandler::<Handler>::new_ctx();
+        let mut session = make_session("/file.txt", Some("example.com")).await;
+        assert_eq!(
+            handler.request_filter(&mut session, &mut ctx).await?,
+            RequestFilterResult::Handled
+        );
+        assert!(session.response_written().is_none());
+
+        // Default host fallback isn't an alias
+        let mut ctx = VirtualHostsHandler::<Handler>::new_ctx();
+        let mut session = make_session("/file.txt", Some("example.net")).await;
+        assert_eq!(
+            handler.request_filter(&mut session, &mut ctx).await?,
+            RequestFilterResult::Handled
+        );
+
+        // Without the setting aliases are served transparently
+        let mut ctx = VirtualHostsHandler::<Handler>::new_ctx();
+        let mut session = make_session("/file.txt", Some("127.0.0.1")).await;
+        assert_eq!(
+            handler.request_filter(&mut session, &mut ctx).await?,
+            RequestFilterResult::Handled
+        );
+        assert!(session.response_written().is_none());
+
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn matched_prefix() -> Result<(), Box<Error>> {
+        let (handler, _) = handler(true);
+
+        for (uri, host, expected) in [
+            ("/subdir/xyz", "localhost:8080", Some("/subdir")),
+            ("/subdir/", "127.0.0.1:8080", Some("/subdir")),
+            (
+                "/subdir/subsub/xyz",
+                "localhost:8080",
+                Some("/subdir/subsub"),
+            ),
+            (
+                "/subdir/file.txt",
+                "localhost:8080",
+                Some("/subdir/file.txt"),
+            ),
+            ("/subdir/xyz", "unknown", Some("/subdir")),
+            ("/", "localhost:8080", None),
+            ("/subdir_xyz", "localhost:8080", None),
+            ("/subdir/xyz", "example.com", None),
+        ] {
+            let mut ctx = VirtualHostsHandler::<Handler>::new_ctx();
+            assert_eq!(handler.matched_prefix(&ctx), None);
+
+            let mut session = make_session(uri, Some(host)).await;
+            handler.request_filter(&mut session, &mut ctx).await?;
+            assert_eq!(handler.matched_prefix(&ctx), expected, "{host}{uri}");
+        }
+
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn subpath_compression_override() -> Result<(), Box<Error>> {
+        use compression_module::CompressionHandler;
+
+        #[derive(Debug, Default, Clone, PartialEq, Eq, DeserializeMap)]
+        struct ProbeConf {}
+
+        /// Reports whether dynamic compression is enabled for the request
+        #[derive(Debug, Clone, PartialEq, Eq)]
+        struct Probe;
+
+        #[async_trait]
+        impl RequestFilter for Probe {
+            type Conf = ProbeConf;
+            type CTX = ();
+            fn new_ctx() -> Self::CTX {}
+            async fn request_filter(
+                &self,
+                session: &mut impl SessionWrapper,
+                _ctx: &mut Self::CTX,
+            ) -> Result<RequestFilterResult, Box<Error>> {
+                if session.downstream_compression.is_enabled() {
+                    Ok(RequestFilterResult::Handled)
+                } else {
+                    Ok(RequestFilterResult::Unhandled)
+                }
+            }
+        }
+
+        impl TryFrom<ProbeConf> for Probe {
+            type Error = Box<Error>;
+
+            fn try_from(_conf: ProbeConf) -> Result<Self, Self::Error> {
+                Ok(Self)
+            }
+        }
+
+        #[derive(Debug, Clone, PartialEq, Eq, RequestFilter)]
+        struct CompressionProbe {
+            compression: CompressionHandler,
+            probe: Probe,
+        }
+
+        // Subpath configuration replaces host configuration, so compression settings aren’t
+        // inherited by the subpath.
+        let handler: VirtualHostsHandler<CompressionProbe> =
+            VirtualHostsConf::<<CompressionProbe as RequestFilter>::Conf>::from_yaml(
+                r#"
+                    vhosts:
+                        localhost:
+                            compression_level: 6
+                            subpaths:
+                                /artifacts/*:
+                                    strip_prefix: true
+                                /artifacts/compressible/*:
+                                    compression_level: 3
+            "#,
+            )?
+            .try_into()?;
+
+        for (uri, expected) in [
+            ("/file.txt", RequestFilterResult::Handled),
+            ("/artifacts/file.tar.gz", RequestFilterResult::Unhandled),
+            (
+                "/artifacts/compressible/file.txt",
+                RequestFilterResult::Handled,
+            ),
+        ] {
+            let mut ctx = VirtualHostsHandler::<CompressionProbe>::new_ctx();
+            let mut session = make_session(uri, Some("localhost")).await;
+            assert_eq!(
+                handler.request_filter(&mut session, &mut ctx).await?,
+                expected,
+                "{uri}"
+            );
+        }
+
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn access_log() -> Result<(), Box<Error>> {
+        async fn access_log_conf(
+            handler: &VirtualHostsHandler<Handler>,
+            uri: &str,
+            host: &str,
+        ) -> Result<Option<AccessLogConf>, Box<Error>> {
+            let mut ctx = VirtualHostsHandler::<Handler>::new_ctx();
+            let mut session = make_session(uri, Some(host)).await;
+            handler.request_filter(&mut session, &mut ctx).await?;
+            Ok(session.extensions().get::<AccessLogConf>().cloned())
+        }
+
+        let conf = r#"
+            vhosts:
+                localhost:8080:
+                    aliases: ["127.0.0.1:8080"]
+                    canonical_host: true
+                    access_log:
+                        enabled: false
+                    subpaths:
+                        /subdir/*:
+                            result: Handled
+                example.com:
+                    access_log:
+                        format: [remote_addr, request, status]
+                example.net:
+                    result: Handled
+        "#;
+        let handler: VirtualHostsHandler<Handler> =
+            VirtualHostsConf::<Conf>::from_yaml(conf)?.try_into()?;
+
+        let silent = Some(AccessLogConf {
+            enabled: false,
+            format: Default::default(),
+        });
+        assert_eq!(
+            access_log_conf(&handler, "/", "localhost:8080").await?,
+            silent
+        );
+        assert_eq!(
+            access_log_conf(&handler, "/subdir/", "localhost:8080").await?,
+            silent
+        );
+        assert_eq!(
+            access_log_conf(&handler, "/", "127.0.0.1:8080").await?,
+            silent
+        );
+
+        assert_eq!(
+            access_log_conf(&handler, "/", "example.com").await?,
+            Some(AccessLogConf {
+                enabled: true,
+                format: vec![
+                    "remote_addr".to_owned(),
+                    "request".to_owned(),
+                    "status".to_owned()
+                ]
+                .into(),
+            })
+        );
+
+        // Default settings aren’t stored
+        assert_eq!(access_log_conf(&handler, "/", "example.net").await?, None);
+        assert_eq!(access_log_conf(&handler, "/", "example.org").await?, None);
+
+        Ok(())
+    }
+
+    #[test]
+    fn root_shorthand() {
+        use static_files_module::{StaticFilesConf, StaticFilesHandler};
+
+        let shorthand = VirtualHostsConf::<StaticFilesConf>::from_yaml(
+            r#"
+                vhosts:
+                    localhost: ./src
+                    example.com: .
+            "#,
+        )
+        .unwrap();
+        let explicit = VirtualHostsConf::<StaticFilesConf>::from_yaml(
+            r#"
+                vhosts:
+                    localhost:
+                        root: ./src
+                    example.com:
+                        root: .
+            "#,
+        )
+        .unwrap();
+        assert_eq!(shorthand, explicit);
+
+        let shorthand: VirtualHostsHandler<StaticFilesHandler> = shorthand.try_into().unwrap();
+        let explicit: VirtualHostsHandler<StaticFilesHandler> = explicit.try_into().unwrap();
+        assert_eq!(shorthand, explicit);
+
+        // Shorthand merges with settings from other configuration files
+        let merged = VirtualHostsConf::<StaticFilesConf>::from_yaml(
+            r#"
+                vhosts:
+                    localhost:
+                        default: true
+                        index_file: index.html
+            "#,
+        )
+        .unwrap()
+        .merge_from_yaml(
+            r#"
+                vhosts:
+                    localhost: ./local-root
+            "#,
+        )
+        .unwrap();
+        let host = &merged.vhosts["localhost"];
+        assert!(host.default);
+        assert_eq!(host.config.root, Some(vec!["./local-root".into()].into()));
+        assert_eq!(host.config.index_file, vec!["index.html".to_owned()].into());
+    }
+
+    #[test(tokio::test)]
+    async fn per_host_404_page() -> Result<(), Box<Error>> {
+        use static_files_module::{StaticFilesConf, StaticFilesHandler};
+
+        let handler: VirtualHostsHandler<StaticFilesHandler> =
+            VirtualHostsConf::<StaticFilesConf>::from_yaml(
+                r#"
+                    vhosts:
+                        example.com:
+                            root: ./testdata/example.com
+                            page_404: /404.html
+                            subpaths:
+                                /other/*:
+                                    strip_prefix: true
+                                    root: ./testdata/example.net
+                        example.net:
+                            root: ./testdata/example.net
+                            page_404: /errors/missing.html
+                "#,
+            )?
+            .try_into()?;
+
+        for (host, uri, expected) in [
+            ("example.com", "/missing.txt", Some("example.com")),
+            ("example.net", "/missing.txt", Some("example.net")),
+            // Subpath configuration doesn’t inherit the error page of the host
+            ("example.com", "/other/missing.txt", None),
+        ] {
+            let mut ctx = VirtualHostsHandler::<StaticFilesHandler>::new_ctx();
+            let mut session = make_session(uri, Some(host)).await;
+            assert_eq!(
+                handler.request_filter(&mut session, &mut ctx).await?,
+                RequestFilterResult::ResponseSent,
+                "{host}{uri}"
+            );
+            assert_eq!(
+                session.response_written().unwrap().status,
+                404,
+                "{host}{uri}"
+            );
+
+            let body = String::from_utf8_lossy(&session.response_body);
+            if let Some(expected) = expected {
+                assert_eq!(body, format!("<h1>Nothing here on {expected}</h1>\n"));
+            } else {
+                assert!(!body.contains("Nothing here"), "{host}{uri}");
+            }
+        }
+
+        // Existing files are still served
+        let mut ctx = VirtualHostsHandler::<StaticFilesHandler>::new_ctx();
+        let mut session = make_session("/file.txt", Some("example.com")).await;
+        handler.request_filter(&mut session, &mut ctx).await?;
+        assert_eq!(session.response_written().unwrap().status, 200);
+        assert_eq!(session.response_body, "Hello from example.com!\n");
+
+        Ok(())
+    }
+
+    #[test]
+    fn tls_server_names() {
+        use startup_module::CertKeyConf;
+
+        let conf = VirtualHostsConf::<Conf>::from_yaml(
+            r#"
+                vhosts:
+                    localhost:8443:
+                        aliases:
+                        - 127.0.0.1:8443
+                        - "[::1]:8443"
+                        tls:
+                            cert_path: rsa.cert.pem
+                            key_path: rsa.key.pem
+                    example.com:
+                        aliases: example.com:8443
+                        tls:
+                        - cert_path: ecdsa.cert.pem
+                          key_path: ecdsa.key.pem
+                        - cert_path: rsa.cert.pem
+                          key_path: rsa.key.pem
+                    example.net: {}
+            "#,
+        )
+        .unwrap();
+
+        let cert_key = |name: &str| CertKeyConf {
+            cert_path: Some(format!("{name}.cert.pem").into()),
+            key_path: Some(format!("{name}.key.pem").into()),
+        };
+
+        let names = conf.tls_server_names().unwrap();
+        let mut keys = names.keys().map(String::as_str).collect::<Vec<_>>();
+        keys.sort();
+        assert_eq!(keys, vec!["127.0.0.1", "[::1]", "example.com", "localhost"]);
+        assert_eq!(*names["localhost"], vec![cert_key("rsa")]);
+        assert_eq!(*names["[::1]"], vec![cert_key("rsa")]);
+        assert_eq!(
+            *names["example.com"],
+            vec![cert_key("ecdsa"), cert_key("rsa")]
+        );
+
+        // Builder produces the same result
+        let built = VirtualHostsConf::default()
+            .with_host(
+                "example.com",
+                VirtualHostConf::new(Conf::default())
+                    .with_alias("example.com:8443")
+                    .with_certificate(cert_key("ecdsa"))
+                    .with_certificate(cert_key("rsa")),
+            )
+            .with_host("example.net", VirtualHostConf::new(Conf::default()));
+        assert_eq!(
+            built.tls_server_names().unwrap()["example.com"],
+            names["example.com"]
+        );
+
+        // Different certificates for the same server name are rejected
+        let conflict = built.with_host(
+            "example.com:8080",
+            VirtualHostConf::new(Conf::default()).with_certificate(cert_key("rsa")),
+        );
+        assert!(conflict.tls_server_names().is_err());
+    }
 }
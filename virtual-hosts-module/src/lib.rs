@@ -41,10 +41,12 @@
 //!                 root: ./production-root
 //! ```
 //!
-//! A virtual host configuration adds three configuration settings to the configuration of the
+//! A virtual host configuration adds four configuration settings to the configuration of the
 //! wrapped handler:
 //!
 //! * `aliases` lists additional host names that should share the same configuration.
+//! * `canonical_host` can be set to `true` to redirect requests for any of the aliases to the
+//!   main host name with `301 Moved Permanently` instead of handling them.
 //! * `default` can be set to `true` to indicate that this configuration should apply to all host
 //!   names not listed explicitly.
 //! * `subpaths` maps paths within the virtual host to their respective configuration. If the path
@@ -53,6 +55,18 @@
 //!   setting. If `true`, this setting will remove the matched path from the URI before the request
 //!   is passed on to the handler.
 //!
+//! If the wrapped handler has a `root` setting like the Static Files Module, a virtual host
+//! needing no other settings can be configured with a shorthand. The host name is then mapped to
+//! the root directory directly:
+//!
+//! ```yaml
+//! vhosts:
+//!     localhost:8000: ./local-debug-root
+//!     example.com: ./production-root
+//! ```
+//!
+//! This is equivalent to listing `root` as the only setting of these hosts.
+//!
 //! If no default host entry is present and a request is made for an unknown host name, this
 //! handler will leave the request unhandled. Otherwise the handling is delegated to the wrapped
 //! handler.
@@ -62,11 +76,156 @@
 //! `/test` whereas the URI `/test_abc` doesn’t. If no matching path is found, the host
 //! configuration will be used.
 //!
+//! A path configuration replaces the host configuration entirely, settings aren’t inherited from
+//! the host. For example, if the wrapped handler includes the Compression Module, compression can
+//! be disabled for a subdirectory containing already compressed files simply by omitting the
+//! `compression_level` setting there:
+//!
+//! ```yaml
+//! vhosts:
+//!     example.com:
+//!         compression_level: 6
+//!         root: ./production-root
+//!         subpaths:
+//!             /downloads/*:
+//!                 root: ./production-root
+//! ```
+//!
 //! *Note*: When the `strip_prefix` option is used, the subsequent handlers will receive a URI
 //! which doesn’t match the actual URI of the request. This might result in wrong links or
 //! redirects. The Static Files and Auth modules know how to compensate. Upstream responses might
 //! have to be corrected via Pingora’s `upstream_response_filter` phase.
 //!
+//! Handlers needing to know the subpath a request was routed by, e.g. to build absolute links,
+//! can retrieve it via `VirtualHostsHandler::matched_prefix`. This works regardless of the
+//! `strip_prefix` setting.
+//!
+//! To diagnose routing issues, the `debug_host_header` setting can be enabled:
+//!
+//! ```yaml
+//! debug_host_header: true
+//! ```
+//!
+//! Responses will then contain an `X-Matched-Vhost` header with the name of the virtual host that
+//! handled the request. Aliases are reported under the host’s main name. If the default host was
+//! used because no other host matched, the header value is `default`. If no host matched at all,
+//! the value is `none`.
+//!
+//! ## Path normalization
+//!
+//! By default, request paths are passed on to the handlers unchanged. With the `normalize_path`
+//! setting, repeated slashes are collapsed and `.` and `..` path segments are resolved before the
+//! request is routed:
+//!
+//! ```yaml
+//! normalize_path: true
+//! ```
+//!
+//! A request for `//dir/./subdir/../file.txt` will then be treated like a request for
+//! `/dir/file.txt`. A trailing slash is preserved, and `..` segments cannot go beyond the root
+//! directory. Note that percent-encoded characters are not decoded before normalization.
+//!
+//! ## Access logging
+//!
+//! The `access_log` setting of a virtual host determines how its requests are logged by access
+//! logging handlers like Common Log Module, regardless of whether these are configured for the
+//! virtual host or at the top level:
+//!
+//! ```yaml
+//! vhosts:
+//!     localhost:8000:
+//!         access_log:
+//!             enabled: false
+//!     example.com:
+//!         access_log:
+//!             format: [remote_addr, time_local, request, status]
+//! ```
+//!
+//! Requests for `localhost:8000` won’t be logged at all, requests for `example.com` will be logged
+//! with the given list of fields instead of the configured log format. The settings apply to all
+//! subpaths of the virtual host.
+//!
+//! ## TLS certificates
+//!
+//! Instead of listing certificates under `server_names` in the TLS configuration of the Startup
+//! Module, these can be declared alongside the virtual host they belong to:
+//!
+//! ```yaml
+//! vhosts:
+//!     example.com:
+//!         aliases: www.example.com
+//!         tls:
+//!             cert_path: cert.example.com.pem
+//!             key_path: key.example.com.pem
+//!         root: ./production-root
+//! ```
+//!
+//! The `tls` setting accepts the same certificate/key combinations as `server_names` entries,
+//! including a list of them. The certificates apply to the host name and all its aliases, port
+//! numbers are ignored for this. `VirtualHostsConf::tls_server_names` collects these, the result
+//! has to be passed to `TlsConf::add_server_names` before the server is created:
+//!
+//! ```rust
+//! use pandora_module_utils::{merge_conf, FromYaml};
+//! use startup_module::StartupConf;
+//! use static_files_module::StaticFilesConf;
+//! use virtual_hosts_module::VirtualHostsConf;
+//!
+//! #[merge_conf]
+//! struct Conf {
+//!     startup: StartupConf,
+//!     virtual_hosts: VirtualHostsConf<StaticFilesConf>,
+//! }
+//!
+//! let mut conf = Conf::from_yaml(
+//!     r#"
+//!         vhosts:
+//!             example.com:
+//!                 tls:
+//!                     cert_path: cert.example.com.pem
+//!                     key_path: key.example.com.pem
+//!     "#,
+//! )
+//! .unwrap();
+//!
+//! let server_names = conf.virtual_hosts.tls_server_names().unwrap();
+//! conf.startup.tls.add_server_names(server_names).unwrap();
+//! assert!(conf.startup.tls.server_names.contains_key("example.com"));
+//! ```
+//!
+//! Pandora Web Server does this automatically. A default certificate still has to be configured
+//! in the Startup Module, it is used for clients requesting unknown server names.
+//!
+//! ## Configuring virtual hosts in code
+//!
+//! Instead of loading the configuration from a file, it can also be assembled in code, e.g. for
+//! tests or when embedding the server. `VirtualHostsConf` and `VirtualHostConf` provide builder
+//! methods for that, the result is equivalent to the corresponding configuration file:
+//!
+//! ```rust
+//! use pandora_module_utils::RequestFilter;
+//! use static_files_module::{StaticFilesConf, StaticFilesHandler};
+//! use virtual_hosts_module::{VirtualHostConf, VirtualHostsConf, VirtualHostsHandler};
+//!
+//! let root = |root: &str| StaticFilesConf {
+//!     root: Some(root.into()),
+//!     ..Default::default()
+//! };
+//!
+//! let conf = VirtualHostsConf::default()
+//!     .with_host(
+//!         "example.com",
+//!         VirtualHostConf::new(root("."))
+//!             .with_alias("www.example.com")
+//!             .with_default(true)
+//!             .with_subpath("/src/*", true, root("src")),
+//!     )
+//!     .with_debug_host_header(true);
+//!
+//! let handler = VirtualHostsHandler::<StaticFilesHandler>::new(conf).unwrap();
+//! assert_eq!(handler.host_count(), 1);
+//! ```
+//!
 //! ## Code example
 //!
 //! Usually, the virtual hosts configuration will be read from a configuration file and used to
@@ -95,7 +254,8 @@
 //!
 //! // Create a server from the configuration
 //! let app = DefaultApp::<VirtualHostsHandler<StaticFilesHandler>>::from_conf(conf.virtual_hosts)
-//!     .unwrap();
+//!     .unwrap()
+//!     .with_startup_conf(&conf.startup);
 //! let server = conf.startup.into_server(app, Some(opt)).unwrap();
 //!
 //! // Do something with the server here, e.g. call server.run_forever()